@@ -0,0 +1,109 @@
+//! Hot path benchmarks. The Redis-backed groups use `REDIS_URL` (default
+//! `redis://127.0.0.1:6379`) and are skipped when it can't be reached.
+//!
+//! Run with `cargo bench`; compare runs with `cargo bench -- --baseline <name>`.
+
+use std::time::Duration;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use redis_rate_limiter::{
+    AsyncRateLimiter, CaseNormalization, IdentifierPolicy, Normalization, PoolConfig, RateLimiter,
+    UnsafeCharacters,
+};
+
+/// High enough that no benchmark iteration is ever denied.
+const LIMIT: u64 = 1_000_000_000;
+
+fn redis_url() -> String {
+    std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string())
+}
+
+fn prefix(name: &str) -> String {
+    format!("bench:{}:{}", name, std::process::id())
+}
+
+fn bench_blocking_check(c: &mut Criterion) {
+    let limiter = RateLimiter::new(
+        &redis_url(),
+        &prefix("blocking"),
+        LIMIT,
+        Duration::from_secs(60),
+    )
+    .map(|limiter| limiter.with_pool(PoolConfig::new().with_min_connections(1)))
+    .and_then(RateLimiter::connect_eagerly);
+    let limiter = match limiter {
+        Ok(limiter) => limiter,
+        Err(e) => {
+            eprintln!(
+                "skipping blocking check benchmarks, Redis unavailable: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    let mut group = c.benchmark_group("blocking");
+    group.bench_function("check", |b| b.iter(|| limiter.check(black_box("user_1"))));
+    group.bench_function("check_integer_identifier", |b| {
+        b.iter(|| limiter.check(black_box(42u64)))
+    });
+    group.finish();
+}
+
+fn bench_async_check(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let limiter = runtime.block_on(async {
+        let limiter = AsyncRateLimiter::new(
+            &redis_url(),
+            &prefix("async"),
+            LIMIT,
+            Duration::from_secs(60),
+        )?;
+        limiter.connect_eagerly().await
+    });
+    let limiter = match limiter {
+        Ok(limiter) => limiter,
+        Err(e) => {
+            eprintln!("skipping async check benchmarks, Redis unavailable: {}", e);
+            return;
+        }
+    };
+
+    let mut group = c.benchmark_group("async");
+    group.bench_function("check", |b| {
+        b.to_async(&runtime)
+            .iter(|| limiter.check(black_box("user_1")))
+    });
+    group.finish();
+}
+
+fn bench_identifier_processing(c: &mut Criterion) {
+    let normalization = Normalization::new()
+        .with_trim(true)
+        .with_case(CaseNormalization::AsciiLowercase);
+    let policy = IdentifierPolicy::new().on_unsafe_characters(UnsafeCharacters::Escape);
+
+    let mut group = c.benchmark_group("identifier");
+    // Already canonical: both steps borrow and allocate nothing.
+    group.bench_function("canonical", |b| {
+        b.iter(|| {
+            let normalized = normalization.apply(black_box("user@example.com"));
+            policy.apply(&normalized).map(|id| id.len())
+        })
+    });
+    group.bench_function("rewritten", |b| {
+        b.iter(|| {
+            let normalized = normalization.apply(black_box(" User@Example.com:1 "));
+            policy.apply(&normalized).map(|id| id.len())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_blocking_check,
+    bench_async_check,
+    bench_identifier_processing
+);
+criterion_main!(benches);
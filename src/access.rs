@@ -0,0 +1,26 @@
+/// The identifier lists consulted by checks with `with_access_lists`, each a Redis set under
+/// the limiter's prefix shared by every instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum AccessList {
+    /// Identifiers that are never limited, e.g. internal health checkers. Their checks are
+    /// allowed without counting anything.
+    Allow,
+    /// Identifiers that are always rejected, with `RateLimiterError::Denylisted`. Takes
+    /// precedence over the allowlist.
+    Deny,
+}
+
+impl AccessList {
+    /// Name of the list's set, under the limiter's prefix.
+    pub(crate) fn key_name(self) -> &'static str {
+        match self {
+            AccessList::Allow => "__allowlist__",
+            AccessList::Deny => "__denylist__",
+        }
+    }
+}
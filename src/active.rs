@@ -0,0 +1,133 @@
+//! Enumeration of the identifiers with live state under a limiter's prefix.
+//!
+//! Both front-ends page through the prefix with `SCAN` and read each page's usage in one
+//! pipelined round trip, so enumerating never blocks the server the way `KEYS` would.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+
+#[cfg(feature = "blocking")]
+use crate::RateLimiter;
+use crate::{AsyncRateLimiter, RateLimiterError};
+
+/// An identifier with a live counter, as listed by `active_identifiers`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ActiveIdentifier {
+    pub identifier: String,
+    /// Units consumed in the current window, as `Usage::consumed` reports them.
+    pub used: u64,
+    /// Time until the counter expires, or `None` if it has no expiry.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "ttl_ms", with = "crate::serde_duration::option_millis")
+    )]
+    pub ttl: Option<Duration>,
+}
+
+/// One `SCAN` page: the next cursor (0 once the scan is complete) and its active identifiers.
+type Page = (u64, Vec<ActiveIdentifier>);
+
+/// Iterator returned by `RateLimiter::active_identifiers`.
+#[cfg(feature = "blocking")]
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub struct ActiveIdentifiers<'a> {
+    limiter: &'a RateLimiter,
+    /// Cursor of the next page, or `None` once the scan is complete or failed.
+    cursor: Option<u64>,
+    page: std::vec::IntoIter<ActiveIdentifier>,
+}
+
+#[cfg(feature = "blocking")]
+impl<'a> ActiveIdentifiers<'a> {
+    pub(crate) fn new(limiter: &'a RateLimiter) -> Self {
+        ActiveIdentifiers {
+            limiter,
+            cursor: Some(0),
+            page: Vec::new().into_iter(),
+        }
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl Iterator for ActiveIdentifiers<'_> {
+    type Item = Result<ActiveIdentifier, RateLimiterError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(active) = self.page.next() {
+                return Some(Ok(active));
+            }
+            let cursor = self.cursor?;
+            match self.limiter.active_page(cursor) {
+                Ok((next, page)) => {
+                    self.cursor = (next != 0).then_some(next);
+                    self.page = page.into_iter();
+                }
+                Err(error) => {
+                    self.cursor = None;
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+type PageFuture<'a> = Pin<Box<dyn Future<Output = Result<Page, RateLimiterError>> + Send + 'a>>;
+
+/// Stream returned by `AsyncRateLimiter::active_identifiers`.
+#[must_use = "streams do nothing unless polled"]
+pub struct ActiveIdentifierStream<'a> {
+    limiter: &'a AsyncRateLimiter,
+    /// Cursor of the next page, or `None` once the scan is complete or failed.
+    cursor: Option<u64>,
+    page: std::vec::IntoIter<ActiveIdentifier>,
+    pending: Option<PageFuture<'a>>,
+}
+
+impl<'a> ActiveIdentifierStream<'a> {
+    pub(crate) fn new(limiter: &'a AsyncRateLimiter) -> Self {
+        ActiveIdentifierStream {
+            limiter,
+            cursor: Some(0),
+            page: Vec::new().into_iter(),
+            pending: None,
+        }
+    }
+}
+
+impl Stream for ActiveIdentifierStream<'_> {
+    type Item = Result<ActiveIdentifier, RateLimiterError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(active) = this.page.next() {
+                return Poll::Ready(Some(Ok(active)));
+            }
+            if let Some(pending) = this.pending.as_mut() {
+                let result = ready!(pending.as_mut().poll(cx));
+                this.pending = None;
+                match result {
+                    Ok((next, page)) => {
+                        this.cursor = (next != 0).then_some(next);
+                        this.page = page.into_iter();
+                    }
+                    Err(error) => {
+                        this.cursor = None;
+                        return Poll::Ready(Some(Err(error)));
+                    }
+                }
+                continue;
+            }
+            let Some(cursor) = this.cursor else {
+                return Poll::Ready(None);
+            };
+            this.pending = Some(Box::pin(this.limiter.active_page(cursor)));
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! actix-web middleware over [`AsyncRateLimiter`].
+//!
+//! [`ActixRateLimit`] takes a key from each request with an extractor closure and checks it,
+//! weighted by a cost closure if one is set with `with_cost`:
+//! allowed requests go on to the wrapped service, denied ones are answered by the rejection
+//! closure, by default with `429 Too Many Requests` carrying `Retry-After` and `RateLimit-*`
+//! headers. Requests without a key are passed through. If the check fails (Redis can't be
+//! reached and the limiter has no `with_failure_policy`), the request is answered with
+//! `503 Service Unavailable`.
+
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+use crate::{AsyncRateLimiter, RateLimitDecision};
+
+type Extractor = Arc<dyn Fn(&ServiceRequest) -> Option<String>>;
+type Rejection = Arc<dyn Fn(&RateLimitDecision) -> HttpResponse>;
+type Cost = Arc<dyn Fn(&ServiceRequest) -> u64>;
+
+/// Rate limits the requests of an actix-web app or scope, registered with `wrap`.
+#[derive(Clone)]
+pub struct ActixRateLimit {
+    limiter: Arc<AsyncRateLimiter>,
+    extractor: Extractor,
+    rejection: Rejection,
+    cost: Option<Cost>,
+}
+
+impl ActixRateLimit {
+    /// Checks the key `extractor` takes from each request, e.g. the peer address:
+    /// `|req| req.peer_addr().map(|addr| addr.ip().to_string())`. `None` lets the request
+    /// through unchecked.
+    pub fn new<F>(limiter: Arc<AsyncRateLimiter>, extractor: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Option<String> + 'static,
+    {
+        ActixRateLimit {
+            limiter,
+            extractor: Arc::new(extractor),
+            rejection: Arc::new(too_many_requests),
+            cost: None,
+        }
+    }
+
+    /// Weighs each request by the units `cost` computes from it, e.g. from its route or
+    /// payload size, instead of counting it as one. The cost must be at least one; a weighted
+    /// request that is denied takes nothing, as with `check_with_cost`.
+    pub fn with_cost<F>(mut self, cost: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> u64 + 'static,
+    {
+        self.cost = Some(Arc::new(cost));
+        self
+    }
+
+    /// Answers denied requests with `rejection` instead of the default `429` response.
+    pub fn with_rejection<F>(mut self, rejection: F) -> Self
+    where
+        F: Fn(&RateLimitDecision) -> HttpResponse + 'static,
+    {
+        self.rejection = Arc::new(rejection);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ActixRateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ActixRateLimitService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ActixRateLimitService {
+            service: Rc::new(service),
+            config: self.clone(),
+        }))
+    }
+}
+
+/// The service [`ActixRateLimit`] wraps around each of the app's services.
+pub struct ActixRateLimitService<S> {
+    service: Rc<S>,
+    config: ActixRateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for ActixRateLimitService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            let Some(key) = (config.extractor)(&request) else {
+                return Ok(service.call(request).await?.map_into_left_body());
+            };
+            let cost = config.cost.as_ref().map(|cost| cost(&request));
+            let response = match config.limiter.check_detailed_on(key.as_str(), cost).await {
+                Ok(decision) if decision.allowed => {
+                    return Ok(service.call(request).await?.map_into_left_body());
+                }
+                Ok(decision) => (config.rejection)(&decision),
+                Err(e) => {
+                    log::warn!("rate limit check of `{}` failed: {}", key, e);
+                    HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE)
+                }
+            };
+            Ok(request.into_response(response).map_into_right_body())
+        })
+    }
+}
+
+/// The default rejection: `429 Too Many Requests` with the decision's headers.
+fn too_many_requests(decision: &RateLimitDecision) -> HttpResponse {
+    let mut response = HttpResponse::TooManyRequests();
+    for (name, value) in decision.headers() {
+        response.insert_header((name, value));
+    }
+    response.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{test, web, App};
+
+    use super::*;
+    use crate::FailurePolicy;
+
+    fn limiter(policy: Option<FailurePolicy>) -> Arc<AsyncRateLimiter> {
+        // Nothing listens on port 1.
+        let limiter =
+            AsyncRateLimiter::new("redis://127.0.0.1:1", "actix", 10, Duration::from_secs(60))
+                .unwrap();
+        Arc::new(match policy {
+            Some(policy) => limiter.with_failure_policy(policy),
+            None => limiter,
+        })
+    }
+
+    async fn status(middleware: ActixRateLimit, api_key: Option<&str>) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .wrap(middleware)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut request = test::TestRequest::get().uri("/");
+        if let Some(api_key) = api_key {
+            request = request.insert_header(("x-api-key", api_key));
+        }
+        test::call_service(&app, request.to_request())
+            .await
+            .status()
+    }
+
+    #[actix_web::test]
+    async fn test_actix_statuses() {
+        let api_key = |req: &ServiceRequest| {
+            let value = req.headers().get("x-api-key")?.to_str().ok()?;
+            Some(value.to_string())
+        };
+        let middleware = |policy| ActixRateLimit::new(limiter(policy), api_key);
+
+        assert_eq!(
+            status(middleware(Some(FailurePolicy::Open)), Some("k")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(middleware(Some(FailurePolicy::Closed)), Some("k")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            status(middleware(None), Some("k")).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // Without a key the request isn't checked.
+        assert_eq!(
+            status(middleware(Some(FailurePolicy::Closed)), None).await,
+            StatusCode::OK
+        );
+
+        let custom = middleware(Some(FailurePolicy::Closed))
+            .with_rejection(|_| HttpResponse::Forbidden().finish());
+        assert_eq!(status(custom, Some("k")).await, StatusCode::FORBIDDEN);
+
+        // The cost is validated before the failure policy applies.
+        let weighted = |cost| middleware(Some(FailurePolicy::Open)).with_cost(move |_| cost);
+        assert_eq!(status(weighted(5), Some("k")).await, StatusCode::OK);
+        assert_eq!(
+            status(weighted(0), Some("k")).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -0,0 +1,2181 @@
+//! Async rate limiting on top of a multiplexed Redis connection.
+//!
+//! # Cancellation safety
+//!
+//! Every future returned by [`AsyncRateLimiter`] may be dropped at any `.await` point (for
+//! example by `tokio::select!` or `tokio::time::timeout`) without breaking the limiter:
+//!
+//! - Commands are handed to the multiplexed connection's driver task, which keeps reading
+//!   responses even if the caller is gone, so a dropped future never leaves an unread reply
+//!   on the socket for the next caller to pick up.
+//! - If the future is dropped while the connection is being established, nothing is cached
+//!   and the next call simply connects again.
+//! - The check script is a single `EVALSHA` (preceded by `SCRIPT LOAD` only on `NOSCRIPT`),
+//!   which Redis executes atomically. A dropped `check` therefore consumed exactly one request
+//!   or none, never part of one. The script is **not** idempotent: retrying after a dropped
+//!   `check` may consume a second request.
+//! - `get_remaining`, `get_time_remaining` and `get_usage` are read-only and safe to retry.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use tokio::sync::Mutex;
+
+use crate::active::{ActiveIdentifier, ActiveIdentifierStream};
+use crate::batch::Batch;
+use crate::breaker::CircuitBreaker;
+use crate::compat;
+use crate::concurrency::unique_token;
+use crate::core::{self, LimiterCore, MIN_WAIT};
+use crate::eviction::{self, EvictionCanary, EvictionPolicyAction};
+use crate::failover::{self, ConnectionOverrides, Endpoints};
+use crate::lease::Leases;
+use crate::overrides;
+use crate::retry;
+use crate::runtime;
+use crate::sentinel::{self, Sentinel};
+use crate::top;
+use crate::trace;
+use crate::{
+    AccessList, ActiveOverride, Algorithm, BreakerState, CardinalityLimit, CircuitBreakerConfig,
+    Clock, CustomScript, EffectiveConfig, EscalationPolicy, FailurePolicy, HealthReport,
+    IdentifierPolicy, KeyTransform, LatencyStats, LimitOverride, MetricsSink, Normalization,
+    PenaltyPolicy, Rate, RateLimitDecision, RateLimitStatus, RateLimiterBuilder, RateLimiterError,
+    Reservation, RetryPolicy, RunOutcome, ServerCapabilities, SlowOperation, TelemetrySampling,
+    ToIdentifier, TopConsumer, Usage, UsageBucket, UsageHistory, WindowMode,
+};
+
+pub struct AsyncRateLimiter {
+    endpoints: Endpoints,
+    /// The shared connection and when it was established.
+    connection: Mutex<Option<(MultiplexedConnection, Instant)>>,
+    core: LimiterCore,
+}
+
+impl AsyncRateLimiter {
+    /// Starts a `RateLimiterBuilder`, an alternative to `new` with named settings.
+    pub fn builder() -> RateLimiterBuilder {
+        RateLimiterBuilder::new()
+    }
+
+    /// Creates a new AsyncRateLimiter instance. The connection is established on first use.
+    pub fn new(
+        redis_url: &str,
+        key_prefix: &str,
+        max_requests: u64,
+        window: Duration,
+    ) -> Result<Self, RateLimiterError> {
+        Self::new_with_failover(&[redis_url], key_prefix, max_requests, window)
+    }
+
+    /// Creates an AsyncRateLimiter over an ordered list of Redis endpoints, most preferred
+    /// first. When the active endpoint can't be reached, the next one is used; the first is
+    /// probed periodically (see `with_failback_interval`) and used again once it recovers.
+    ///
+    /// Counters aren't carried over, so each switch starts fresh windows unless the endpoints
+    /// replicate each other.
+    pub fn new_with_failover(
+        redis_urls: &[&str],
+        key_prefix: &str,
+        max_requests: u64,
+        window: Duration,
+    ) -> Result<Self, RateLimiterError> {
+        Ok(AsyncRateLimiter {
+            endpoints: Endpoints::open(redis_urls)?,
+            connection: Mutex::new(None),
+            core: LimiterCore::new(key_prefix, max_requests, window),
+        })
+    }
+
+    /// Creates an AsyncRateLimiter for the master Redis Sentinel monitors as `master_name`,
+    /// asking the sentinels at `sentinel_urls` (in order) for its address. After a failover
+    /// the new master is looked up as soon as a connection to the old one fails or finds a
+    /// replica, so callers don't need to track the topology. The master is connected to with
+    /// the TLS mode, database and credentials of the first sentinel URL.
+    ///
+    /// Unlike `new`, this connects at once: it fails if no sentinel can name the master.
+    pub async fn new_with_sentinel(
+        sentinel_urls: &[&str],
+        master_name: &str,
+        key_prefix: &str,
+        max_requests: u64,
+        window: Duration,
+    ) -> Result<Self, RateLimiterError> {
+        let sentinel = Sentinel::open(sentinel_urls, master_name)?;
+        let master = sentinel.discover_async(None).await?;
+        Ok(AsyncRateLimiter {
+            endpoints: Endpoints::open_sentinel(sentinel, master),
+            connection: Mutex::new(None),
+            core: LimiterCore::new(key_prefix, max_requests, window),
+        })
+    }
+
+    /// Sets how often the preferred endpoint is probed while failed over. Defaults to 30 seconds.
+    pub fn with_failback_interval(mut self, interval: Duration) -> Self {
+        self.endpoints.failback_interval = interval;
+        self
+    }
+
+    /// Re-establishes the connection once it is older than `ttl`, resolving the Redis hostname
+    /// again, so a DNS change (e.g. a managed Redis failover) is picked up even while the old
+    /// address still answers. Connections are otherwise only replaced after an error.
+    pub fn with_dns_ttl(mut self, ttl: Duration) -> Self {
+        self.endpoints.dns_ttl = Some(ttl);
+        self
+    }
+
+    /// Gives up on establishing a connection after `timeout`, treating the endpoint as
+    /// unreachable (and failing over, if there is another one). Unbounded by default.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.endpoints.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails Redis commands that get no reply within `timeout` with a timeout error, treating
+    /// the endpoint as unhealthy. Unbounded by default.
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.endpoints.command_timeout = Some(timeout);
+        self
+    }
+
+    /// Replaces the TLS mode, credentials and database of every endpoint's URL.
+    pub(crate) fn with_connection_overrides(
+        mut self,
+        overrides: &ConnectionOverrides,
+    ) -> Result<Self, RateLimiterError> {
+        self.endpoints.apply(overrides)?;
+        Ok(self)
+    }
+
+    /// Index, in the list given to `new_with_failover`, of the endpoint currently in use.
+    pub fn active_endpoint(&self) -> usize {
+        self.endpoints.active().0
+    }
+
+    /// State of the circuit breaker set with `with_circuit_breaker`, or `None` without one.
+    pub fn breaker_state(&self) -> Option<BreakerState> {
+        self.core
+            .failure
+            .breaker
+            .as_ref()
+            .map(|breaker| breaker.state())
+    }
+
+    /// Sets how the window expiry is maintained. Defaults to `WindowMode::SlidingInactivity`.
+    pub fn with_window_mode(mut self, mode: WindowMode) -> Self {
+        self.core.window_mode = mode;
+        self
+    }
+
+    /// Selects how requests are counted. Defaults to `Algorithm::FixedWindow`; changing it for
+    /// a prefix that already has live keys fails their checks until the keys expire.
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.core.algorithm = algorithm;
+        self
+    }
+
+    /// Refills token buckets at `rate` instead of the limit per window, e.g. a bucket of 100
+    /// tokens refilled at 10 per second. Only used with `Algorithm::TokenBucket`.
+    pub fn with_refill_rate(mut self, rate: Rate) -> Self {
+        self.core.refill_rate = Some(rate);
+        self
+    }
+
+    /// Caps the tokens a token bucket refills over one gap between requests, and those a new
+    /// bucket starts with, at `tokens`, separately from the bucket's capacity (the limit): a
+    /// client returning from idle can burst at most `tokens` more than it had left, while one
+    /// sending steadily still saves up to the full bucket. A client away for as long as
+    /// filling the bucket from empty takes starts over with the credit. Only used with
+    /// `Algorithm::TokenBucket`.
+    pub fn with_idle_credit(mut self, tokens: u64) -> Self {
+        self.core.idle_credit = Some(tokens);
+        self
+    }
+
+    /// Drains leaky buckets at `rate` instead of the limit per window, e.g. a bucket of 100
+    /// drained at 10 per second. Only used with `Algorithm::LeakyBucket`, and shares its
+    /// setting with `with_refill_rate`.
+    pub fn with_drain_rate(self, rate: Rate) -> Self {
+        self.with_refill_rate(rate)
+    }
+
+    /// Splits each `Algorithm::SlidingWindowCounter` window into `buckets` counters (1, the
+    /// default, keeps one per window), at the cost of `buckets + 1` hash fields per key, so
+    /// only the oldest slice of the window is estimated; see the algorithm for the bound this
+    /// gives. Buckets are whole milliseconds.
+    pub fn with_counter_buckets(mut self, buckets: u32) -> Self {
+        self.core.counter_buckets = buckets.max(1);
+        self
+    }
+
+    /// Times sliding window logs, token and leaky buckets, GCRA and sliding window counters, and
+    /// the usage history, unique consumers and top consumers periods, by `clock` instead of the
+    /// Redis server clock, passing its time to the check script, e.g. a shared `ManualClock` to
+    /// test the algorithms deterministically. Redis still expires keys by its own clock, so
+    /// fixed windows are unaffected.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.core.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Enforces `rate` on every identifier together with the primary limit, e.g. 10 per second
+    /// and 1000 per hour. All limits are checked in the same script call and a request is
+    /// only counted once every one allows it; `RateLimitDecision::violated_rule` tells which
+    /// denied it. Additional limits are fixed windows from their first request, whatever the
+    /// algorithm and `WindowMode`, and are not subject to overrides.
+    pub fn with_additional_limit(mut self, rate: Rate) -> Self {
+        self.core.additional_limits.push(rate);
+        self
+    }
+
+    /// Caps the number of distinct identifiers per window under this limiter's prefix.
+    pub fn with_cardinality_limit(mut self, limit: CardinalityLimit) -> Self {
+        self.core.cardinality = Some(limit);
+        self
+    }
+
+    /// Temporarily bans identifiers that keep getting denied (see `EscalationPolicy`): their
+    /// checks fail with `RateLimiterError::Banned` until the ban lifts or `reset`.
+    pub fn with_escalation(mut self, policy: EscalationPolicy) -> Self {
+        self.core.escalation = Some(policy);
+        self
+    }
+
+    /// Blocks identifiers for exponentially longer after each consecutive denial (see
+    /// `PenaltyPolicy`): while blocked, their checks fail with `RateLimiterError::Banned`.
+    pub fn with_penalty(mut self, policy: PenaltyPolicy) -> Self {
+        self.core.penalty = Some(policy);
+        self
+    }
+
+    /// Consults the prefix's allowlist and denylist (see `AccessList`) in every check, at the
+    /// cost of two set lookups per check.
+    pub fn with_access_lists(mut self) -> Self {
+        self.core.access_lists = true;
+        self
+    }
+
+    /// Lets an identifier exceed the limit by up to `max_units` per window, deducting the
+    /// overage from its next window's budget, for clients whose bursts straddle window
+    /// boundaries. Debt not repaid in the window right after it is forgiven.
+    pub fn with_borrowing(mut self, max_units: u64) -> Self {
+        self.core.max_borrow = max_units;
+        self
+    }
+
+    /// Keeps the final count of each identifier's window for `retention` after the window
+    /// closes, readable with `last_window_usage`.
+    pub fn with_window_analytics(mut self, retention: Duration) -> Self {
+        self.core.analytics_retention = Some(retention);
+        self
+    }
+
+    /// Records every identifier in a per-`period` HyperLogLog, so `unique_consumers` can report
+    /// how many distinct identifiers were seen (allowed or not) in the current period.
+    pub fn with_unique_consumers(mut self, period: Duration) -> Self {
+        self.core.unique_consumers_period = Some(period);
+        self
+    }
+
+    /// Randomly shortens or lengthens each fixed window's expiry by up to `fraction` of the
+    /// window (e.g. 0.1 for ±10%), so the counters of identifiers that started together don't
+    /// all reset at the same instant and send a burst of traffic. Clamped to between 0 and 0.5;
+    /// 0 (the default) disables it. Only applies to `Algorithm::FixedWindow` outside the
+    /// calendar window modes, and not to `check_all`.
+    pub fn with_expiry_jitter(mut self, fraction: f64) -> Self {
+        self.core.expiry_jitter = if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 0.5)
+        };
+        self
+    }
+
+    /// Splits each identifier's fixed window counter across `shards` keys, each holding its
+    /// share of the limit, for a hot identifier (e.g. all traffic to one partner) whose single
+    /// key would take every check. A check touches one shard picked at random, so a request
+    /// may be denied while another shard still has room; reads such as `get_usage` add the
+    /// shards up. Decisions report the whole limit, with `remaining` estimated from the shard
+    /// checked. Borrowing and usage analytics are disabled for sharded counters. 1 (the
+    /// default) disables sharding; only applies to `Algorithm::FixedWindow`, and `check_all`
+    /// rejects sharded limiters.
+    pub fn with_sharding(mut self, shards: u32) -> Self {
+        self.core.shards = shards.max(1);
+        self
+    }
+
+    /// Holds capacity reserved ahead with `reserve` back from other requests in checks, at
+    /// the cost of a sorted set lookup per check. Only applies to unsharded
+    /// `Algorithm::FixedWindow` limiters outside the calendar window modes.
+    pub fn with_reservations(mut self) -> Self {
+        self.core.reservations = true;
+        self
+    }
+
+    /// Checks with plain commands instead of the Lua check script, for managed Redis
+    /// offerings and proxies (e.g. some Envoy and Twemproxy setups) that block `EVAL`.
+    /// `check`, `check_with_cost`, `check_with_limit` and `check_detailed` then run the fixed
+    /// window counter as a pipeline of `SET NX PX`, `INCRBY` and `PTTL`, with the configured
+    /// or per-call limit and window and the window mode; `connect_eagerly` and `health_check`
+    /// skip the script cache. Guarantees are weaker: the pipeline isn't atomic as a whole, a
+    /// denied weighted request is taken back in a second round trip, and stored overrides,
+    /// additional limits, borrowing, escalation, access lists and the other script features
+    /// don't apply. Methods that need scripts, such as `try_consume_up_to` and `reserve`,
+    /// still fail on such servers.
+    ///
+    /// Fails with `RateLimiterError::InvalidConfig` unless the algorithm is
+    /// `Algorithm::FixedWindow`, as do checks if another algorithm is set afterwards.
+    pub fn with_script_free_mode(mut self) -> Result<Self, RateLimiterError> {
+        self.core.validate_script_free()?;
+        self.core.script_free = true;
+        Ok(self)
+    }
+
+    /// Ranks identifiers per `period` in two sorted sets maintained by the check script, so
+    /// `top_consumers` and `violations_top` can tell who uses the most and who is denied the
+    /// most. Costs a sorted set update per check; the sets are kept for two periods.
+    pub fn with_top_consumers(mut self, period: Duration) -> Self {
+        self.core.top_consumers_period = Some(period);
+        self
+    }
+
+    /// Counts allowed requests per identifier in time buckets, so `usage_history` can chart
+    /// consumption, e.g. `UsageHistory::default()` for per-minute counts over the last day.
+    pub fn with_usage_history(mut self, history: UsageHistory) -> Self {
+        self.core.history = Some(history);
+        self
+    }
+
+    /// Rewrites every identifier into a canonical form (trimmed, lowercased, NFC) before it is
+    /// validated and keyed. Without it identifiers are used as given.
+    pub fn with_identifier_normalization(mut self, normalization: Normalization) -> Self {
+        self.core.normalization = Some(normalization);
+        self
+    }
+
+    /// Validates, and optionally escapes or hashes, every identifier before it is used in a
+    /// key. Without a policy identifiers are used verbatim.
+    pub fn with_identifier_policy(mut self, policy: IdentifierPolicy) -> Self {
+        self.core.identifier_policy = Some(policy);
+        self
+    }
+
+    /// Transforms every identifier, after normalization and the identifier policy, into the
+    /// form written to Redis, e.g. `SaltedHash` so personal data such as email addresses
+    /// never appears verbatim in the keyspace. Everything reading identifiers back
+    /// (`active_overrides`, `access_list`, metrics and tracing labels) reports the transformed
+    /// form, and changing the transform starts every identifier afresh.
+    pub fn with_key_transform(mut self, transform: impl KeyTransform + 'static) -> Self {
+        self.core.key_transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// Logs (and reports to the slow-operation hook) every Redis operation slower than `threshold`.
+    pub fn with_slow_threshold(mut self, threshold: Duration) -> Self {
+        self.core.latency.set_slow_threshold(threshold);
+        self
+    }
+
+    /// Registers a callback invoked whenever a Redis operation exceeds the slow threshold.
+    pub fn on_slow_operation<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&SlowOperation) + Send + Sync + 'static,
+    {
+        self.core.latency.set_slow_hook(Arc::new(hook));
+        self
+    }
+
+    /// Retries checks that fail with a transient Redis error (e.g. a dropped connection) by
+    /// `policy` before the failure policy or circuit breaker sees the error. Checks aren't
+    /// retried by default.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.core.retry = policy;
+        self
+    }
+
+    /// Answers checks by `policy` when Redis can't be reached (a connection error, a timeout
+    /// or an exhausted pool) instead of returning the error. Denials, invalid identifiers and
+    /// errors Redis replies with are unaffected.
+    pub fn with_failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.core.failure.policy = Some(policy);
+        self
+    }
+
+    /// Calls `hook` with every error of a check that couldn't reach Redis, whether or not a
+    /// failure policy then answers it, e.g. to count outages.
+    pub fn on_backend_error<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&RateLimiterError) + Send + Sync + 'static,
+    {
+        self.core.failure.on_error = Some(Arc::new(hook));
+        self
+    }
+
+    /// Reserves `permits` at a time from Redis for each identifier and answers its next
+    /// `check`s from that lease in process, until it runs out or `max_age` passes: one round
+    /// trip per batch instead of per request, for identifiers checked thousands of times a
+    /// second. The trade-off is accuracy: permits reserved but unused by the time their lease
+    /// expires are lost, and a lease outliving its window lets up to `permits - 1` requests
+    /// through against the next one. Only `check` uses leases. Below 2 permits leasing is off.
+    pub fn with_leasing(mut self, permits: u64, max_age: Duration) -> Self {
+        self.core.leases = (permits > 1).then(|| Leases::new(permits, max_age));
+        self
+    }
+
+    /// Sets how identifiers are recorded in the `rate_limit` tracing spans of checks and usage
+    /// reads: hashed by default, so traces can be correlated without exposing user ids or IPs.
+    #[cfg(feature = "tracing")]
+    pub fn with_traced_identifiers(mut self, traced: crate::TracedIdentifier) -> Self {
+        self.core.traced_identifier = traced;
+        self
+    }
+
+    /// Stops sending checks to Redis once `config`'s threshold of consecutive checks failed to
+    /// reach it, answering them with an in-process fixed window limiter of the same limit and
+    /// window until a probe finds Redis healthy again. Failures below the threshold are
+    /// answered by that limiter too, so this takes precedence over `with_failure_policy`.
+    /// The fallback counts per process and forgets its windows on recovery.
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.core.failure.breaker = Some(Arc::new(CircuitBreaker::new(config)));
+        self
+    }
+
+    /// Samples latency recording, decision traces and alert hooks, e.g. to keep
+    /// observability affordable at tens of thousands of checks per second.
+    pub fn with_telemetry_sampling(mut self, sampling: TelemetrySampling) -> Self {
+        self.core.set_sampling(sampling);
+        self
+    }
+
+    /// Reports check outcomes and Redis operation timings to `sink`, sampled according to
+    /// `with_telemetry_sampling`.
+    pub fn with_metrics_sink(mut self, sink: impl MetricsSink + 'static) -> Self {
+        self.core.set_metrics(Arc::new(sink));
+        self
+    }
+
+    /// Caps how many callers may block in `wait` or `retrying` at once; further callers fail
+    /// fast with `RateLimiterError::WaitQueueFull` instead of piling up.
+    pub fn with_max_waiters(mut self, max_waiters: usize) -> Self {
+        self.core.max_waiters = Some(max_waiters);
+        self
+    }
+
+    /// Number of callers currently blocked in `wait` or `retrying` (and the stream, iterator
+    /// and drainer helpers built on `wait`).
+    pub fn waiting(&self) -> usize {
+        self.core.waiters.load(Ordering::Relaxed)
+    }
+
+    /// Returns rolling latency statistics for the most recent Redis operations.
+    pub fn latency_stats(&self) -> LatencyStats {
+        self.core.latency.stats()
+    }
+
+    /// Clears the collected latency samples and the slow operation counter.
+    pub fn reset_latency_stats(&self) {
+        self.core.latency.reset();
+    }
+
+    /// Connects to Redis now rather than on first use, failing fast if it can't be reached.
+    pub async fn warm_up(&self) -> Result<(), RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let pong = redis::cmd("PING").query_async::<_, String>(&mut conn).await;
+        self.discard_connection_on(pong).await?;
+        Ok(())
+    }
+
+    /// Consuming form of `warm_up`, for establishing the connection eagerly at construction:
+    /// `AsyncRateLimiter::new(...)?.connect_eagerly().await?`. It is lazy by default. Also
+    /// runs `verify_compatibility`, so an unsuitable server fails at startup, and, unless in
+    /// script-free mode, `preload_scripts`.
+    pub async fn connect_eagerly(self) -> Result<Self, RateLimiterError> {
+        self.warm_up().await?;
+        self.verify_compatibility().await?;
+        if !self.core.script_free {
+            self.preload_scripts().await?;
+        }
+        Ok(self)
+    }
+
+    /// Loads the limiter's scripts into the server's script cache, e.g. at startup, so the
+    /// first checks don't have to. Checks send only the scripts' SHA1 and load them on their
+    /// own if the server doesn't have them (after a restart, failover or `SCRIPT FLUSH`), so
+    /// this is an optimization, not a requirement. `connect_eagerly` calls it.
+    pub async fn preload_scripts(&self) -> Result<(), RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let pipe = core::preload_pipeline();
+        let loaded = self
+            .core
+            .latency
+            .time_async("preload_scripts", pipe.query_async::<_, ()>(&mut conn))
+            .await;
+        Ok(self.discard_connection_on(loaded).await?)
+    }
+
+    /// Checks that Redis is ready to answer checks, e.g. for a readiness probe: pings it,
+    /// timing the round trip, and loads any built-in script missing from its script cache;
+    /// in script-free mode the script cache is left alone and reported empty. Fails with the
+    /// Redis error if it can't be reached.
+    pub async fn health_check(&self) -> Result<HealthReport, RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let started = Instant::now();
+        let ping = self
+            .core
+            .latency
+            .time_async(
+                "health_check",
+                redis::cmd("PING").query_async::<_, ()>(&mut conn),
+            )
+            .await;
+        self.discard_connection_on(ping).await?;
+        let round_trip = started.elapsed();
+        if self.core.script_free {
+            return Ok(HealthReport {
+                round_trip,
+                scripts_cached: 0,
+                scripts_reloaded: 0,
+            });
+        }
+        let cached = core::scripts_exist_cmd()
+            .query_async::<_, Vec<bool>>(&mut conn)
+            .await;
+        let cached = self.discard_connection_on(cached).await?;
+        let (reload, scripts_reloaded) = core::reload_pipeline(&cached);
+        if scripts_reloaded > 0 {
+            let loaded = reload.query_async::<_, ()>(&mut conn).await;
+            self.discard_connection_on(loaded).await?;
+        }
+        Ok(HealthReport {
+            round_trip,
+            scripts_cached: cached.iter().filter(|&&cached| cached).count(),
+            scripts_reloaded,
+        })
+    }
+
+    /// Detects the server version and fails with `RateLimiterError::Unsupported` if it lacks
+    /// a command the configured features need (see `ServerCapabilities`).
+    pub async fn verify_compatibility(&self) -> Result<(), RateLimiterError> {
+        let capabilities = self.server_capabilities().await?;
+        self.core.verify_compatibility(&capabilities)
+    }
+
+    /// The shared connection, with requests bounded by the command timeout.
+    async fn get_connection(&self) -> Result<TimedConnection, RateLimiterError> {
+        Ok(TimedConnection {
+            conn: self.shared_connection().await?,
+            timeout: self.endpoints.command_timeout,
+        })
+    }
+
+    async fn shared_connection(&self) -> Result<MultiplexedConnection, RateLimiterError> {
+        let mut cached = self.connection.lock().await;
+        if self.endpoints.failback_due() {
+            if let Ok(conn) = probe(&self.endpoints.primary(), self.endpoints.connect_timeout).await
+            {
+                self.endpoints.fail_back();
+                *cached = Some((conn.clone(), Instant::now()));
+                return Ok(conn);
+            }
+        }
+        if let Some((conn, established)) = cached.as_ref() {
+            let expired = self
+                .endpoints
+                .dns_ttl
+                .is_some_and(|ttl| established.elapsed() >= ttl);
+            if !expired {
+                return Ok(conn.clone());
+            }
+        }
+
+        let connect = async {
+            match self.endpoints.sentinel() {
+                Some(sentinel) => self.connect_to_master(sentinel).await,
+                None => self.connect_to_active().await,
+            }
+        };
+        let conn = trace::connecting_async(&self.core, connect).await?;
+        *cached = Some((conn.clone(), Instant::now()));
+        Ok(conn)
+    }
+
+    /// Opens a connection to the active endpoint, failing over if it can't be reached.
+    async fn connect_to_active(&self) -> Result<MultiplexedConnection, RateLimiterError> {
+        let mut attempts = self.endpoints.len();
+        loop {
+            let (index, client) = self.endpoints.active();
+            let conn = self
+                .core
+                .latency
+                .time_async("connect", connect(&client, self.endpoints.connect_timeout))
+                .await;
+            match conn {
+                Err(e) if attempts > 1 && failover::is_unhealthy(&e) => {
+                    self.endpoints.fail_over(index);
+                    attempts -= 1;
+                }
+                conn => return Ok(conn?),
+            }
+        }
+    }
+
+    /// Opens a connection to the master the sentinels last named, asking them again if it
+    /// can't be reached or is no longer the master.
+    async fn connect_to_master(
+        &self,
+        sentinel: &Sentinel,
+    ) -> Result<MultiplexedConnection, RateLimiterError> {
+        let timeout = self.endpoints.connect_timeout;
+        let (_, master) = self.endpoints.active();
+        let conn = self
+            .core
+            .latency
+            .time_async("connect", connect_master(&master, timeout))
+            .await;
+        match conn {
+            Err(e) if failover::is_unhealthy(&e) => {
+                let master = sentinel.discover_async(timeout).await?;
+                self.endpoints.set_master(master.clone());
+                Ok(self
+                    .core
+                    .latency
+                    .time_async("connect", connect_master(&master, timeout))
+                    .await?)
+            }
+            conn => Ok(conn?),
+        }
+    }
+
+    /// Drops the cached connection after it failed, so the next call reconnects (resolving
+    /// the hostname again).
+    async fn discard_connection_on<T>(
+        &self,
+        result: Result<T, redis::RedisError>,
+    ) -> Result<T, redis::RedisError> {
+        if let Err(e) = &result {
+            if failover::is_unhealthy(e) {
+                *self.connection.lock().await = None;
+            }
+        }
+        result
+    }
+
+    pub async fn check(&self, identifier: impl ToIdentifier) -> Result<(), RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let check = async {
+            match &self.core.leases {
+                Some(leases) => self.check_leased(leases, identifier).await,
+                None => self.check_guarded(identifier, None, None).await,
+            }
+        };
+        trace::instrument(&self.core, "check", identifier, check).await
+    }
+
+    /// Answers a check from `identifier`'s lease, or reserves a new one; if Redis can't grant
+    /// a whole lease, takes a single unit.
+    async fn check_leased(
+        &self,
+        leases: &Leases,
+        identifier: &str,
+    ) -> Result<(), RateLimiterError> {
+        if leases.take(identifier) {
+            trace::record_decision("allowed", None, None);
+            return Ok(());
+        }
+        match self
+            .check_guarded(identifier, None, Some(leases.permits))
+            .await
+        {
+            Ok(()) => {
+                leases.grant(identifier);
+                Ok(())
+            }
+            Err(RateLimiterError::RateLimitExceeded { .. }) => {
+                self.check_guarded(identifier, None, None).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits until a request for `identifier` is allowed, sleeping until the window resets
+    /// whenever it is exhausted. Fails fast with `RateLimiterError::WaitQueueFull` if the
+    /// `with_max_waiters` cap is reached. Cancellation safe.
+    pub async fn wait(&self, identifier: impl ToIdentifier) -> Result<(), RateLimiterError> {
+        let mut waiting = None;
+        loop {
+            match self.check(&identifier).await {
+                Err(RateLimiterError::RateLimitExceeded { retry_after }) => {
+                    if waiting.is_none() {
+                        waiting = Some(self.core.enter_wait()?);
+                    }
+                    runtime::sleep(retry_after.max(MIN_WAIT)).await;
+                }
+                other => return other,
+            }
+        }
+    }
+
+    /// Resolves once a request for `identifier` would be allowed, without consuming anything:
+    /// for work that should only start when it can go ahead, e.g. opening an expensive
+    /// upstream connection before the `check` that pays for it. Rather than polling, each
+    /// denial schedules one wakeup for its `retry_after`, with a little jitter so callers
+    /// waiting together don't all peek at once. Another caller may still take the capacity
+    /// between this resolving and the next `check`. Counts as waiting for `with_max_waiters`.
+    /// Cancellation safe.
+    pub async fn wait_until_ready(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<(), RateLimiterError> {
+        let mut waiting = None;
+        loop {
+            let decision = self.peek(&identifier).await?;
+            if decision.allowed {
+                return Ok(());
+            }
+            if waiting.is_none() {
+                waiting = Some(self.core.enter_wait()?);
+            }
+            runtime::sleep(retry::retry_delay(decision.retry_after)).await;
+        }
+    }
+
+    /// Runs `f` if a request for `identifier` is allowed now, without waiting; when denied,
+    /// returns `RunOutcome::Rejected` with the decision (reason, `Retry-After` and the like)
+    /// instead of running it. Only failures (Redis errors the failure policy doesn't cover,
+    /// invalid identifiers) are errors.
+    pub async fn run<T, F, Fut>(
+        &self,
+        identifier: impl ToIdentifier,
+        f: F,
+    ) -> Result<RunOutcome<T>, RateLimiterError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let decision = self.check_detailed(identifier).await?;
+        Ok(if decision.allowed {
+            RunOutcome::Completed(f().await)
+        } else {
+            RunOutcome::Rejected(decision)
+        })
+    }
+
+    /// Runs `f` once a request for `identifier` is allowed. Whenever the limiter (or `f`
+    /// itself) reports `RateLimitExceeded`, sleeps until the window resets, with a little
+    /// jitter, and tries again. Gives up with `RateLimitExceeded` as soon as the next sleep
+    /// would take the total wait past `max_wait`. Other errors from `f` are returned as is.
+    pub async fn retrying<T, F, Fut>(
+        &self,
+        identifier: impl ToIdentifier,
+        max_wait: Duration,
+        mut f: F,
+    ) -> Result<T, RateLimiterError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RateLimiterError>>,
+    {
+        let start = Instant::now();
+        let mut waiting = None;
+        loop {
+            let result = match self.check(&identifier).await {
+                Ok(()) => f().await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(RateLimiterError::RateLimitExceeded { retry_after }) => {
+                    // A denial from `f` may not know when to retry; fall back to the reset.
+                    let resets_in = match retry_after {
+                        Duration::ZERO => self.get_usage(&identifier).await?.resets_in,
+                        retry_after => Some(retry_after),
+                    };
+                    let delay = retry::retry_delay(resets_in);
+                    if start.elapsed() + delay > max_wait {
+                        return Err(RateLimiterError::RateLimitExceeded { retry_after: delay });
+                    }
+                    if waiting.is_none() {
+                        waiting = Some(self.core.enter_wait()?);
+                    }
+                    runtime::sleep(delay).await;
+                }
+                other => return other,
+            }
+        }
+    }
+
+    /// Like `check`, but with a limit and/or window for this call only. It takes precedence
+    /// over any override stored for `identifier` (see `effective_config`).
+    pub async fn check_with_override(
+        &self,
+        identifier: impl ToIdentifier,
+        per_call: LimitOverride,
+    ) -> Result<(), RateLimiterError> {
+        self.check_on(identifier, Some(&per_call), None).await
+    }
+
+    /// Like `check`, but the request takes `cost` units of the limit instead of one, e.g. a
+    /// bulk export that counts as ten requests. A denied request takes nothing, so a
+    /// smaller one can still get through. Fails with `RateLimiterError::InvalidConfig` for a
+    /// zero cost.
+    pub async fn check_with_cost(
+        &self,
+        identifier: impl ToIdentifier,
+        cost: u64,
+    ) -> Result<(), RateLimiterError> {
+        LimiterCore::validate_cost(cost)?;
+        self.check_on(identifier, None, Some(cost)).await
+    }
+
+    /// Like `check`, for a request carrying a client-chosen `request_id` (e.g. an
+    /// `Idempotency-Key` header): a retry whose ID was already allowed within the window is
+    /// allowed again without consuming anything, so retries aren't charged twice. Denied
+    /// requests aren't remembered, so their retries are checked as usual. IDs are kept in a
+    /// set per identifier for one window from the first. Leases aren't used, and while Redis
+    /// is unavailable the failure policy or circuit breaker decides without deduplicating.
+    /// Fails with `RateLimiterError::InvalidConfig` for an empty `request_id`.
+    pub async fn check_idempotent(
+        &self,
+        identifier: impl ToIdentifier,
+        request_id: &str,
+    ) -> Result<(), RateLimiterError> {
+        LimiterCore::validate_request_id(request_id)?;
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let check = || async move {
+            let mut conn = self.get_connection().await?;
+            let call = self.core.idempotent_check_call(identifier, request_id);
+            let result = self
+                .core
+                .latency
+                .time_async("check", call.invoke_async(&mut conn))
+                .await;
+
+            let result = self.discard_connection_on(result).await;
+            self.core.check_outcome(identifier, result)
+        };
+        let check = async {
+            let result = if self.core.failure.admitted() {
+                Some(self.core.retry.run_async(check).await)
+            } else {
+                None
+            };
+            self.core.on_failure(identifier, None, result)
+        };
+        trace::instrument(&self.core, "check_idempotent", identifier, check).await
+    }
+
+    /// Takes as many units as `identifier` has left, up to `max`, and returns how many it got,
+    /// e.g. to admit as much of a batch as the limit allows and defer the rest. All limits
+    /// and rules are checked and consumed atomically. With nothing left it fails like
+    /// `check_with_cost`, so the returned amount is at least one. While Redis is unavailable
+    /// the failure policy or circuit breaker decides for all `max` units at once. Fails with
+    /// `RateLimiterError::InvalidConfig` for a zero `max`.
+    pub async fn try_consume_up_to(
+        &self,
+        identifier: impl ToIdentifier,
+        max: u64,
+    ) -> Result<u64, RateLimiterError> {
+        LimiterCore::validate_cost(max)?;
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let check = || async move {
+            let mut conn = self.get_connection().await?;
+            let call = self.core.consume_up_to_call(identifier, max);
+            let result = self
+                .core
+                .latency
+                .time_async("check", call.invoke_async(&mut conn))
+                .await;
+
+            let result: redis::RedisResult<core::DecisionReply> =
+                self.discard_connection_on(result).await;
+            let granted = result.as_ref().map_or(max, |reply| reply.5);
+            self.core
+                .check_outcome(identifier, result)
+                .map(|()| granted)
+        };
+        let result = if self.core.failure.admitted() {
+            Some(self.core.retry.run_async(check).await)
+        } else {
+            None
+        };
+        self.core.on_consume_failure(identifier, max, result)
+    }
+
+    async fn check_on(
+        &self,
+        identifier: impl ToIdentifier,
+        per_call: Option<&LimitOverride>,
+        cost: Option<u64>,
+    ) -> Result<(), RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        self.check_guarded(identifier.as_ref(), per_call, cost)
+            .await
+    }
+
+    /// Checks on the shared connection, applying the failure policy or circuit breaker if
+    /// Redis can't be reached.
+    async fn check_guarded(
+        &self,
+        identifier: &str,
+        per_call: Option<&LimitOverride>,
+        cost: Option<u64>,
+    ) -> Result<(), RateLimiterError> {
+        let check = || self.check_once(identifier, per_call, cost);
+        let result = if self.core.failure.admitted() {
+            Some(self.core.retry.run_async(check).await)
+        } else {
+            None
+        };
+        self.core.on_failure(identifier, cost, result)
+    }
+
+    /// One attempt at a check, without retries or failure handling.
+    async fn check_once(
+        &self,
+        identifier: &str,
+        per_call: Option<&LimitOverride>,
+        cost: Option<u64>,
+    ) -> Result<(), RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let call = self.core.decision_call(identifier, per_call, cost)?;
+        let result = self
+            .core
+            .latency
+            .time_async("check", call.invoke_async(&mut conn))
+            .await;
+
+        let result = self.discard_connection_on(result).await;
+        self.core.check_outcome(identifier, result)
+    }
+
+    /// Like `check`, but a denial is reported in the returned decision rather than as an
+    /// error, together with the limit, remaining requests and reset and retry times, all from
+    /// the same script call. Only failures (Redis errors, invalid identifiers) are errors.
+    pub async fn check_detailed(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        self.check_detailed_on(identifier, None).await
+    }
+
+    /// Like `check_detailed`, but the request takes `cost` units of the limit instead of one,
+    /// and nothing if denied, as with `check_with_cost`.
+    pub async fn check_detailed_with_cost(
+        &self,
+        identifier: impl ToIdentifier,
+        cost: u64,
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        self.check_detailed_on(identifier, Some(cost)).await
+    }
+
+    pub(crate) async fn check_detailed_on(
+        &self,
+        identifier: impl ToIdentifier,
+        cost: Option<u64>,
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        if let Some(cost) = cost {
+            LimiterCore::validate_cost(cost)?;
+        }
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let check = || async move {
+            let mut conn = self.get_connection().await?;
+            let call = self.core.decision_call(identifier, None, cost)?;
+            let result = self
+                .core
+                .latency
+                .time_async("check", call.invoke_async(&mut conn))
+                .await;
+
+            let result = self.discard_connection_on(result).await;
+            self.core.decision_outcome(identifier, result)
+        };
+        let check = async {
+            let result = if self.core.failure.admitted() {
+                Some(self.core.retry.run_async(check).await)
+            } else {
+                None
+            };
+            self.core.on_decision_failure(identifier, cost, result)
+        };
+        trace::instrument(&self.core, "check_detailed", identifier, check).await
+    }
+
+    /// Checks several identifiers, each against its own limiter, in one round trip, e.g. the
+    /// per-user, per-IP and per-API-key limits of a request, and returns the decisions in
+    /// order. The checks are pipelined over the first limiter's connection, so all the
+    /// limiters must use the same Redis server. Each check is independent: one denial doesn't
+    /// undo what the others consumed.
+    pub async fn check_many<I: ToIdentifier>(
+        checks: &[(&AsyncRateLimiter, I)],
+    ) -> Result<Vec<RateLimitDecision>, RateLimiterError> {
+        let Some((first, _)) = checks.first() else {
+            return Ok(Vec::new());
+        };
+        let batch = Batch::new(
+            checks
+                .iter()
+                .map(|(limiter, identifier)| (&limiter.core, identifier)),
+        )?;
+        let replies = match batch.call() {
+            Some(call) => {
+                let pipelined = async {
+                    let mut conn = first.get_connection().await?;
+                    let result = first
+                        .core
+                        .latency
+                        .time_async("check_many", call.invoke_async(&mut conn))
+                        .await;
+                    Ok(first.discard_connection_on(result).await?)
+                };
+                pipelined.await
+            }
+            None => Ok(Vec::new()),
+        };
+        batch.outcomes(replies)
+    }
+
+    /// Checks one request against several fixed window limiters at once, e.g. its user's,
+    /// organization's and the global limit, and counts it in all of them only if every one
+    /// allows it: a denial consumes from none. The decision reports the scope with the fewest
+    /// requests left or, when denied, the exhausted scope that resets last, whose index in
+    /// `checks` is the decision's `violated_rule`. The check runs as one script over the first
+    /// limiter's connection, so all the limiters must use the same Redis server, and a Redis
+    /// failure is handled by the first limiter's failure policy and circuit breaker. Stored
+    /// overrides and then stored limits apply; additional limits, borrowing and the other
+    /// per-limiter extras don't.
+    ///
+    /// Fails with `InvalidConfig` if a limiter uses another algorithm than the fixed window, a
+    /// calendar window mode or sharded counters (`with_sharding`).
+    pub async fn check_all<I: ToIdentifier>(
+        checks: &[(&AsyncRateLimiter, I)],
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        let identifiers = checks
+            .iter()
+            .map(|(limiter, identifier)| limiter.core.identifier(identifier))
+            .collect::<Result<Vec<_>, _>>()?;
+        let scopes: Vec<_> = checks
+            .iter()
+            .zip(&identifiers)
+            .map(|((limiter, _), identifier)| (&limiter.core, identifier.as_ref()))
+            .collect();
+        let call = LimiterCore::composite_call(&scopes)?;
+        let (first, identifier) = (checks[0].0, scopes[0].1);
+        let call = &call;
+        let check = || async move {
+            let mut conn = first.get_connection().await?;
+            let result = first
+                .core
+                .latency
+                .time_async("check_all", call.invoke_async(&mut conn))
+                .await;
+
+            let result = first.discard_connection_on(result).await;
+            first.core.decision_outcome(identifier, result)
+        };
+        let result = if first.core.failure.admitted() {
+            Some(first.core.retry.run_async(check).await)
+        } else {
+            None
+        };
+        first.core.on_decision_failure(identifier, None, result)
+    }
+
+    /// Like `check`, but decides with `script` instead of the built-in algorithm. `args` are
+    /// appended to the script's `ARGV` (pass `()` for none).
+    pub async fn check_custom(
+        &self,
+        script: &CustomScript,
+        identifier: impl ToIdentifier,
+        args: impl redis::ToRedisArgs,
+    ) -> Result<(), RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let call = &self.core.custom_call(script, identifier, args);
+        let check = || async move {
+            let mut conn = self.get_connection().await?;
+            let result = self
+                .core
+                .latency
+                .time_async("check_custom", call.invoke_async(&mut conn))
+                .await;
+
+            let result = self.discard_connection_on(result).await;
+            self.core.custom_outcome(script, identifier, result)
+        };
+        let result = if self.core.failure.admitted() {
+            Some(self.core.retry.run_async(check).await)
+        } else {
+            None
+        };
+        self.core.on_failure(identifier, None, result)
+    }
+
+    /// Like `check`, but gives up once `deadline` passes. A missed deadline counts as a Redis
+    /// failure: the circuit breaker sees it and the failure policy answers it, or without one
+    /// it's returned as `RateLimiterError::DeadlineExceeded`. Relies on the check being
+    /// cancellation safe.
+    pub async fn check_with_deadline(
+        &self,
+        identifier: impl ToIdentifier,
+        deadline: Instant,
+    ) -> Result<(), RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        if !self.core.failure.admitted() {
+            return self.core.on_failure(identifier, None, None);
+        }
+        let check = || self.check_once(identifier, None, None);
+        let result = runtime::timeout_at(deadline, self.core.retry.run_async(check))
+            .await
+            .unwrap_or(Err(RateLimiterError::DeadlineExceeded));
+        self.core.on_failure(identifier, None, Some(result))
+    }
+
+    /// Requests left for `identifier` in its current window. To answer a request, prefer
+    /// `check_detailed` over `check` followed by this and `get_time_remaining`: its decision
+    /// has the same state from the check's own round trip.
+    pub async fn get_remaining(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<u64, RateLimiterError> {
+        Ok(self.get_usage(identifier).await?.remaining)
+    }
+
+    /// Time until `identifier`'s window resets, to the millisecond, or `None` if it has no
+    /// active window.
+    pub async fn get_time_remaining(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<Option<Duration>, RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let key = self.core.key(identifier);
+        let mut conn = self.get_connection().await?;
+        let pttl = self
+            .core
+            .latency
+            .time_async("get_time_remaining", conn.pttl::<_, i64>(&key))
+            .await;
+        let pttl = self.discard_connection_on(pttl).await?;
+        Ok(u64::try_from(pttl).ok().map(Duration::from_millis))
+    }
+
+    /// Reports the decision a request for `identifier` would get right now, without
+    /// consuming anything: for showing users their status. Read from the same snapshot as
+    /// `get_usage`, so unlike `check_detailed` it ignores borrowing and the cardinality limit.
+    pub async fn peek(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        Ok(RateLimitDecision::from_usage(
+            &self.get_usage(identifier).await?,
+        ))
+    }
+
+    /// Deletes `identifier`'s counter and related state (borrowed units, analytics shadow,
+    /// pacing slot) in one atomic `DEL`, so its next request starts a fresh window, e.g. to
+    /// unblock a customer after a false positive. Its override and usage history are kept.
+    pub async fn reset(&self, identifier: impl ToIdentifier) -> Result<(), RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let keys = self.core.state_keys(identifier.as_ref());
+        if let Some(leases) = &self.core.leases {
+            leases.forget(identifier.as_ref());
+        }
+        let mut conn = self.get_connection().await?;
+        let deleted = self
+            .core
+            .latency
+            .time_async("reset", conn.del::<_, ()>(&keys))
+            .await;
+        Ok(self.discard_connection_on(deleted).await?)
+    }
+
+    /// Deletes every key under the prefix, for test teardown or offboarding a tenant, and
+    /// returns how many were removed. Keys are found with `SCAN` and deleted a batch at a
+    /// time, so Redis is never blocked by `KEYS`; the purge is not atomic, and keys written
+    /// while it runs may survive it.
+    pub async fn reset_all(&self) -> Result<u64, RateLimiterError> {
+        if let Some(leases) = &self.core.leases {
+            leases.clear();
+        }
+        let mut conn = self.get_connection().await?;
+        let purge = async {
+            let mut removed = 0;
+            let mut cursor = 0;
+            loop {
+                let (next, keys): (u64, Vec<String>) = self
+                    .core
+                    .scan_prefix_cmd(cursor)
+                    .query_async(&mut conn)
+                    .await?;
+                if !keys.is_empty() {
+                    removed += conn.del::<_, u64>(&keys).await?;
+                }
+                if next == 0 {
+                    return Ok::<_, redis::RedisError>(removed);
+                }
+                cursor = next;
+            }
+        };
+        let removed = self.core.latency.time_async("reset_all", purge).await;
+        Ok(self.discard_connection_on(removed).await?)
+    }
+
+    /// Streams the identifiers with a live counter under the prefix and their usage, e.g.
+    /// for dashboards and debugging. Pages through the keyspace with `SCAN`, one round trip per
+    /// page plus one for its usage, so like `SCAN` it may yield an identifier more than once
+    /// and may miss ones created meanwhile. The limiter's own keys are skipped, but the keys of
+    /// custom scripts' named keys and of limiters whose prefix extends this one's are listed
+    /// too. Identifiers are as keyed, i.e. normalized and transformed. A failed page ends the
+    /// stream with its error.
+    pub fn active_identifiers(&self) -> ActiveIdentifierStream<'_> {
+        ActiveIdentifierStream::new(self)
+    }
+
+    /// Subscribes to the expiry notifications of the prefix's counters on a dedicated pub/sub
+    /// connection to the active endpoint, yielding each identifier the moment its window
+    /// resets (see `ResetEvents`). Redis must have keyspace events for expired keys enabled,
+    /// e.g. `CONFIG SET notify-keyspace-events Kx`; without them the stream stays silent. As
+    /// with `active_identifiers`, limiters whose prefix extends this one's are reported too.
+    #[cfg(feature = "notifications")]
+    pub async fn reset_events(&self) -> Result<crate::ResetEvents, RateLimiterError> {
+        let (_, client) = self.endpoints.active();
+        let db = client.get_connection_info().redis.db;
+        let subscribe = async {
+            let conn = client.get_async_connection().await?;
+            crate::ResetEvents::subscribe(&self.core, conn, db).await
+        };
+        let events = match self.endpoints.connect_timeout {
+            Some(timeout) => match runtime::timeout(timeout, subscribe).await {
+                Ok(events) => events,
+                Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+            },
+            None => subscribe.await,
+        };
+        Ok(events?)
+    }
+
+    /// Scans the page at `cursor` and reads the usage of its identifiers.
+    pub(crate) async fn active_page(
+        &self,
+        cursor: u64,
+    ) -> Result<(u64, Vec<ActiveIdentifier>), RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let page = async {
+            let (next, keys): (u64, Vec<String>) = self
+                .core
+                .scan_prefix_cmd(cursor)
+                .query_async(&mut conn)
+                .await?;
+            let identifiers = self.core.identifiers_in(keys);
+            if identifiers.is_empty() {
+                return Ok::<_, redis::RedisError>((next, Vec::new()));
+            }
+            let now = self.usage_time(&mut conn).await?;
+            let reply = self
+                .core
+                .usage_batch_pipeline(&identifiers, now)
+                .query_async(&mut conn)
+                .await?;
+            Ok((next, self.core.active_from_reply(identifiers, reply, now)?))
+        };
+        let page = self
+            .core
+            .latency
+            .time_async("active_identifiers", page)
+            .await;
+        Ok(self.discard_connection_on(page).await?)
+    }
+
+    /// `identifier`'s limit, usage and reset time as a `RateLimitStatus`, e.g. to return in an
+    /// API response. Read like `get_usage`; the reset time is counted from this host's clock
+    /// (or the injected one).
+    pub async fn status(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<RateLimitStatus, RateLimiterError> {
+        Ok(self.get_usage(identifier).await?.status_at(self.core.now()))
+    }
+
+    /// Returns consumed count, limit and window timing for `identifier` in one round trip, or
+    /// two for a sliding window without an injected clock: it's counted back from the Redis
+    /// server's time, read first.
+    pub async fn get_usage(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<Usage, RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let usage = async {
+            let mut conn = self.get_connection().await?;
+            let read = async {
+                let now = self.usage_time(&mut conn).await?;
+                let reply = self
+                    .core
+                    .usage_pipeline(identifier, now)
+                    .query_async(&mut conn)
+                    .await?;
+                Ok::<_, redis::RedisError>((now, reply))
+            };
+            let read = self.core.latency.time_async("get_usage", read).await;
+            let (now, reply) = self.discard_connection_on(read).await?;
+            Ok(self.core.usage_from_reply(reply, now))
+        };
+        trace::instrument(&self.core, "get_usage", identifier, usage).await
+    }
+
+    /// The time the scripts go by: the Redis server's, or the injected clock's.
+    async fn server_now(&self, conn: &mut TimedConnection) -> redis::RedisResult<SystemTime> {
+        let time = match self.core.server_time_cmd() {
+            Some(cmd) => Some(cmd.query_async(conn).await?),
+            None => None,
+        };
+        Ok(self.core.server_now(time))
+    }
+
+    /// The time to read usage as of: `server_now` if the algorithm depends on it.
+    async fn usage_time(&self, conn: &mut TimedConnection) -> redis::RedisResult<SystemTime> {
+        if self.core.usage_needs_time() {
+            self.server_now(conn).await
+        } else {
+            Ok(self.core.now())
+        }
+    }
+
+    /// Returns the allowed requests of `identifier` per history bucket overlapping `range`,
+    /// oldest first and including empty buckets. Requires `with_usage_history`.
+    pub async fn usage_history(
+        &self,
+        identifier: impl ToIdentifier,
+        range: Range<SystemTime>,
+    ) -> Result<Vec<UsageBucket>, RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let mut conn = self.get_connection().await?;
+        let now = self.server_now(&mut conn).await;
+        let now = self.discard_connection_on(now).await?;
+        let (pipe, buckets) = self
+            .core
+            .history_pipeline(identifier.as_ref(), &range, now)?;
+        let reply = self
+            .core
+            .latency
+            .time_async("usage_history", pipe.query_async(&mut conn))
+            .await;
+        Ok(self
+            .core
+            .history_from_reply(buckets, self.discard_connection_on(reply).await?))
+    }
+
+    /// Restarts the window on every request, denied ones included, so the counter lives
+    /// exactly as long as the identifier keeps sending requests.
+    pub(crate) fn with_renewal_on_any_request(mut self) -> Self {
+        self.core.renew_on_any_request = true;
+        self
+    }
+
+    /// Fraction of `identifier`'s budget consumed in the current window, from 0.0 to 1.0, so
+    /// producers can back off gradually (e.g. from 0.8) instead of running into denials. Feed
+    /// readings through a `SaturationSmoother` to even out window resets.
+    pub async fn saturation(&self, identifier: impl ToIdentifier) -> Result<f64, RateLimiterError> {
+        Ok(self.get_usage(identifier).await?.saturation())
+    }
+
+    /// Final count of `identifier`'s previous window (denied requests included), or `None` if
+    /// it had none within the retention. Requires `with_window_analytics`.
+    pub async fn last_window_usage(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<Option<u64>, RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let pipe = self.core.last_window_pipeline(identifier.as_ref())?;
+        let mut conn = self.get_connection().await?;
+        let reply = self
+            .core
+            .latency
+            .time_async("last_window_usage", pipe.query_async(&mut conn))
+            .await;
+        Ok(self
+            .core
+            .last_window_from_reply(self.discard_connection_on(reply).await?))
+    }
+
+    /// Time from now until `identifier`'s theoretical arrival time (TAT), or `None` if it is
+    /// idle. Requires `Algorithm::Gcra`. The next request is admitted once the TAT is at most
+    /// `window - window / max_requests` away, so a denied caller can retry after the
+    /// difference.
+    pub async fn theoretical_arrival(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<Option<Duration>, RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let cmd = self.core.theoretical_arrival_cmd(identifier.as_ref())?;
+        let mut conn = self.get_connection().await?;
+        let pttl = self
+            .core
+            .latency
+            .time_async("theoretical_arrival", cmd.query_async::<_, i64>(&mut conn))
+            .await;
+        let pttl = self.discard_connection_on(pttl).await?;
+        Ok(u64::try_from(pttl).ok().map(Duration::from_millis))
+    }
+
+    /// Stores an override of the limit and/or window for `identifier`, replacing any previous
+    /// one. It applies from the next request; an active window keeps its current expiry.
+    ///
+    /// With a `ttl` the override reverts automatically once it elapses, e.g. for a temporary
+    /// boost granted by support.
+    pub async fn set_override(
+        &self,
+        identifier: impl ToIdentifier,
+        limit_override: LimitOverride,
+        ttl: Option<Duration>,
+    ) -> Result<(), RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let pipe = overrides::set_pipeline(
+            &self.core.override_key(identifier),
+            &self.core.override_index_key(),
+            identifier,
+            &limit_override,
+            ttl,
+        );
+        let mut conn = self.get_connection().await?;
+        let result = self
+            .core
+            .latency
+            .time_async("set_override", pipe.query_async(&mut conn))
+            .await;
+        Ok(self.discard_connection_on(result).await?)
+    }
+
+    /// Returns the override stored for `identifier`, if any.
+    pub async fn get_override(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<Option<LimitOverride>, RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let cmd = overrides::get_cmd(&self.core.override_key(identifier));
+        let mut conn = self.get_connection().await?;
+        let reply = self
+            .core
+            .latency
+            .time_async("get_override", cmd.query_async(&mut conn))
+            .await;
+        let (limit, window) = self.discard_connection_on(reply).await?;
+        Ok(LimitOverride::from_fields(limit, window))
+    }
+
+    /// Stores limits replacing the configured ones for every identifier without an override,
+    /// on every instance sharing the prefix, from their next check; e.g. to tighten a limit
+    /// during an incident without a redeploy. Fields left `None` keep the configured value;
+    /// `LimitOverride::default()` reverts to the configuration entirely.
+    ///
+    /// Each instance keeps the last limits it read or wrote, and enforces them rather than the
+    /// configured ones when it falls back to a local decision without Redis.
+    pub async fn set_limits(&self, limits: LimitOverride) -> Result<(), RateLimiterError> {
+        let pipe = overrides::limits_pipeline(&self.core.limits_key(), &limits);
+        let mut conn = self.get_connection().await?;
+        let result = self
+            .core
+            .latency
+            .time_async("set_limits", pipe.query_async::<_, ()>(&mut conn))
+            .await;
+        self.discard_connection_on(result).await?;
+        self.core
+            .cache_limits(Some(limits).filter(|l| *l != LimitOverride::default()));
+        Ok(())
+    }
+
+    /// Reports the limit and window enforced for identifiers without an override, and whether
+    /// each one comes from the configuration or from `set_limits`.
+    pub async fn get_effective_limits(&self) -> Result<EffectiveConfig, RateLimiterError> {
+        let cmd = overrides::get_cmd(&self.core.limits_key());
+        let mut conn = self.get_connection().await?;
+        let reply = self
+            .core
+            .latency
+            .time_async("get_effective_limits", cmd.query_async(&mut conn))
+            .await;
+        let stored = self.discard_connection_on(reply).await?;
+        Ok(self.core.effective_config(stored, (None, None)))
+    }
+
+    /// Removes the override for `identifier`, reverting it to the configured limits.
+    pub async fn remove_override(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<(), RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let pipe = overrides::remove_pipeline(
+            &self.core.override_key(identifier),
+            &self.core.override_index_key(),
+            identifier,
+        );
+        let mut conn = self.get_connection().await?;
+        let result = self
+            .core
+            .latency
+            .time_async("remove_override", pipe.query_async(&mut conn))
+            .await;
+        Ok(self.discard_connection_on(result).await?)
+    }
+
+    /// Adds `identifier` to `list`, taking effect from the next check on every instance using
+    /// `with_access_lists`. Permits this instance leased for a denylisted identifier are
+    /// dropped; other instances serve theirs until they run out or expire.
+    pub async fn add_to_access_list(
+        &self,
+        list: AccessList,
+        identifier: impl ToIdentifier,
+    ) -> Result<(), RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        if let (AccessList::Deny, Some(leases)) = (list, &self.core.leases) {
+            leases.forget(identifier);
+        }
+        let key = self.core.access_list_key(list);
+        let mut conn = self.get_connection().await?;
+        let result = self
+            .core
+            .latency
+            .time_async(
+                "add_to_access_list",
+                conn.sadd::<_, _, ()>(&key, identifier),
+            )
+            .await;
+        Ok(self.discard_connection_on(result).await?)
+    }
+
+    pub async fn remove_from_access_list(
+        &self,
+        list: AccessList,
+        identifier: impl ToIdentifier,
+    ) -> Result<(), RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let key = self.core.access_list_key(list);
+        let mut conn = self.get_connection().await?;
+        let result = self
+            .core
+            .latency
+            .time_async(
+                "remove_from_access_list",
+                conn.srem::<_, _, ()>(&key, identifier.as_ref()),
+            )
+            .await;
+        Ok(self.discard_connection_on(result).await?)
+    }
+
+    /// The identifiers on `list`, in no particular order.
+    pub async fn access_list(&self, list: AccessList) -> Result<Vec<String>, RateLimiterError> {
+        let key = self.core.access_list_key(list);
+        let mut conn = self.get_connection().await?;
+        let result = self
+            .core
+            .latency
+            .time_async("access_list", conn.smembers(&key))
+            .await;
+        Ok(self.discard_connection_on(result).await?)
+    }
+
+    /// Lists all overrides currently in effect, with the time left on temporary ones.
+    pub async fn active_overrides(&self) -> Result<Vec<ActiveOverride>, RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let index = overrides::index_pipeline(&self.core.override_index_key());
+        let reply = self
+            .core
+            .latency
+            .time_async("active_overrides", index.query_async(&mut conn))
+            .await;
+        let (identifiers,): (Vec<String>,) = self.discard_connection_on(reply).await?;
+        if identifiers.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys: Vec<String> = identifiers
+            .iter()
+            .map(|identifier| self.core.override_key(identifier))
+            .collect();
+        let details = overrides::details_pipeline(&keys);
+        let reply = self
+            .core
+            .latency
+            .time_async("active_overrides", details.query_async(&mut conn))
+            .await;
+        let reply = self.discard_connection_on(reply).await?;
+        Ok(overrides::parse_details(identifiers, reply)?)
+    }
+
+    /// Checks that the server's `maxmemory-policy` can't evict limiter keys (only `noeviction`
+    /// is safe), warning or failing according to `action`. Returns the policy.
+    ///
+    /// Meant to be called once at startup; fails if `CONFIG` is disabled on the server.
+    pub async fn verify_eviction_policy(
+        &self,
+        action: EvictionPolicyAction,
+    ) -> Result<String, RateLimiterError> {
+        let capabilities = self.server_capabilities().await?;
+        capabilities.require(
+            capabilities.eviction_policy_config,
+            "CONFIG GET maxmemory-policy",
+        )?;
+        let mut conn = self.get_connection().await?;
+        let cmd = eviction::policy_cmd();
+        let reply = self
+            .core
+            .latency
+            .time_async("config_get", cmd.query_async::<_, Vec<String>>(&mut conn))
+            .await;
+        let policy = eviction::parse_policy(self.discard_connection_on(reply).await?)?;
+        eviction::apply_action(policy, action)
+    }
+
+    /// Detects the server implementation (Redis, Valkey, KeyDB, Dragonfly) and what it
+    /// supports. The result is cached after the first successful call.
+    pub async fn server_capabilities(&self) -> Result<ServerCapabilities, RateLimiterError> {
+        if let Some(capabilities) = self.core.capabilities.get() {
+            return Ok(capabilities.clone());
+        }
+        let mut conn = self.get_connection().await?;
+        let info = self
+            .core
+            .latency
+            .time_async(
+                "info",
+                compat::info_cmd().query_async::<_, String>(&mut conn),
+            )
+            .await;
+        let info = self.discard_connection_on(info).await?;
+        Ok(self
+            .core
+            .capabilities
+            .get_or_init(|| ServerCapabilities::from_info(&info))
+            .clone())
+    }
+
+    /// Spawns a task that maintains a canary key under the limiter's prefix and calls
+    /// `on_evicted` whenever it disappears early, i.e. whenever Redis evicted it. The task
+    /// runs on tokio, or on async-std outside a tokio runtime with the `async-std` feature.
+    pub fn spawn_eviction_canary<F>(&self, interval: Duration, on_evicted: F) -> EvictionCanary
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        EvictionCanary::spawn_task(
+            self.endpoints.active().1,
+            self.core.key(crate::EVICTION_CANARY),
+            interval,
+            Arc::new(on_evicted),
+        )
+    }
+
+    /// Reserves the next free slot for `identifier` and returns how long to wait until it.
+    ///
+    /// Slots are spaced evenly at the configured rate (one every `window / max_requests`)
+    /// across all instances, so jobs that run at their slot collectively respect the rate.
+    /// They are tracked separately from `check`'s counter and ignore overrides.
+    pub async fn reserve_slot(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<Duration, RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let identifier = identifier.as_ref();
+        let capabilities = self.server_capabilities().await?;
+        capabilities.require(
+            capabilities.script_time_writes,
+            "writes after TIME in scripts",
+        )?;
+        let mut conn = self.get_connection().await?;
+        let call = self.core.reserve_call(identifier);
+        let wait_ms = self
+            .core
+            .latency
+            .time_async("reserve_slot", call.invoke_async::<u64, _>(&mut conn))
+            .await;
+        Ok(Duration::from_millis(
+            self.discard_connection_on(wait_ms).await?,
+        ))
+    }
+
+    /// Normalizes `identifier` as the limiter's methods do.
+    pub(crate) fn normalize<'a, I: ToIdentifier + ?Sized>(
+        &self,
+        identifier: &'a I,
+    ) -> Result<Cow<'a, str>, RateLimiterError> {
+        self.core.identifier(identifier)
+    }
+
+    /// Acquires the concurrency permit `token` for `identifier` unless `limit` are held, and
+    /// returns whether it did and how many are held (see `ConcurrencyLimiter`).
+    pub(crate) async fn acquire_permit(
+        &self,
+        identifier: &str,
+        token: &str,
+        limit: u64,
+    ) -> Result<(bool, u64), RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let call = self.core.acquire_call(identifier, token, limit);
+        let reply = self
+            .core
+            .latency
+            .time_async(
+                "acquire_permit",
+                call.invoke_async::<(u8, u64), _>(&mut conn),
+            )
+            .await;
+        let (acquired, held) = self.discard_connection_on(reply).await?;
+        Ok((acquired == 1, held))
+    }
+
+    /// Extends the permit `token` to a full window from now; false if it is gone.
+    pub(crate) async fn renew_permit(
+        &self,
+        identifier: &str,
+        token: &str,
+    ) -> Result<bool, RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let call = self.core.renew_call(identifier, token);
+        let reply = self
+            .core
+            .latency
+            .time_async("renew_permit", call.invoke_async::<u8, _>(&mut conn))
+            .await;
+        Ok(self.discard_connection_on(reply).await? == 1)
+    }
+
+    pub(crate) async fn release_permit(
+        &self,
+        identifier: &str,
+        token: &str,
+    ) -> Result<(), RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let key = self.core.permits_key(identifier);
+        let reply = self
+            .core
+            .latency
+            .time_async("release_permit", conn.zrem::<_, _, ()>(&key, token))
+            .await;
+        Ok(self.discard_connection_on(reply).await?)
+    }
+
+    /// Earmarks `units` of `identifier`'s fixed window from `not_before` for planned work,
+    /// e.g. a batch job, so interactive traffic can't use them up first. The units count as
+    /// taken in every check within a window of `not_before` (see `with_reservations`, which
+    /// this requires) until the returned `Reservation` is confirmed, cancelled or lapses a
+    /// window after `not_before`.
+    ///
+    /// Fails with `RateLimiterError::RateLimitExceeded` if the units don't fit alongside the
+    /// reservations within a window of `not_before`, and the live window's count if
+    /// `not_before` falls in it. Reservations are timed by the Redis server clock (or the
+    /// injected one) and ignore overrides.
+    pub async fn reserve(
+        &self,
+        identifier: impl ToIdentifier,
+        units: u64,
+        not_before: SystemTime,
+    ) -> Result<Reservation, RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?.into_owned();
+        let token = unique_token();
+        let call = self
+            .core
+            .reserve_ahead_call(&identifier, units, not_before, &token)?;
+        let capabilities = self.server_capabilities().await?;
+        capabilities.require(
+            capabilities.script_time_writes,
+            "writes after TIME in scripts",
+        )?;
+        let mut conn = self.get_connection().await?;
+        let reply = self
+            .core
+            .latency
+            .time_async("reserve", call.invoke_async::<u64, _>(&mut conn))
+            .await;
+        let at_ms = self.discard_connection_on(reply).await?;
+        if at_ms == 0 {
+            return Err(RateLimiterError::RateLimitExceeded {
+                retry_after: Duration::ZERO,
+            });
+        }
+        Ok(Reservation {
+            identifier,
+            token,
+            units,
+            not_before: UNIX_EPOCH + Duration::from_millis(at_ms),
+        })
+    }
+
+    /// Consumes a reservation's units, counting them in `identifier`'s window whether or not
+    /// it is full. Returns false, consuming nothing, if the reservation had lapsed or been
+    /// cancelled.
+    pub async fn confirm_reservation(
+        &self,
+        reservation: Reservation,
+    ) -> Result<bool, RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let call = self.core.confirm_reservation_call(
+            &reservation.identifier,
+            &reservation.token,
+            reservation.units,
+        );
+        let reply = self
+            .core
+            .latency
+            .time_async("confirm_reservation", call.invoke_async::<u8, _>(&mut conn))
+            .await;
+        let confirmed = self.discard_connection_on(reply).await?;
+        Ok(confirmed == 1)
+    }
+
+    /// Gives a reservation's units back. Returns false if it had already lapsed.
+    pub async fn cancel_reservation(
+        &self,
+        reservation: Reservation,
+    ) -> Result<bool, RateLimiterError> {
+        let mut conn = self.get_connection().await?;
+        let cmd = self.core.cancel_reservation_cmd(
+            &reservation.identifier,
+            &reservation.token,
+            reservation.units,
+        );
+        let reply = self
+            .core
+            .latency
+            .time_async("cancel_reservation", cmd.query_async::<_, u8>(&mut conn))
+            .await;
+        let removed = self.discard_connection_on(reply).await?;
+        Ok(removed == 1)
+    }
+
+    /// Gives up to `units` back to `identifier`'s current window, e.g. when the work a
+    /// request was allowed for failed right away and will be retried, so the retry isn't
+    /// charged twice. Returns the units given back: none once the window has expired, and
+    /// never more than the window holds. Additional limits get the same units back. Denied
+    /// requests that counted in a fixed window can be refunded the same way.
+    pub async fn refund(
+        &self,
+        identifier: impl ToIdentifier,
+        units: u64,
+    ) -> Result<u64, RateLimiterError> {
+        LimiterCore::validate_cost(units)?;
+        let identifier = self.core.identifier(&identifier)?;
+        let mut conn = self.get_connection().await?;
+        let call = self.core.refund_call(&identifier, units);
+        let refunded = self
+            .core
+            .latency
+            .time_async("refund", call.invoke_async::<u64, _>(&mut conn))
+            .await;
+        Ok(self.discard_connection_on(refunded).await?)
+    }
+
+    /// Returns the approximate number (standard error 0.81%) of distinct identifiers seen in
+    /// the current period, or 0 if `with_unique_consumers` wasn't configured.
+    pub async fn unique_consumers(&self) -> Result<u64, RateLimiterError> {
+        if self.core.unique_consumers_period.is_none() {
+            return Ok(0);
+        }
+        let mut conn = self.get_connection().await?;
+        let now = self.server_now(&mut conn).await;
+        let Some(key) = self
+            .core
+            .consumers_key(self.discard_connection_on(now).await?)
+        else {
+            return Ok(0);
+        };
+        let count = self
+            .core
+            .latency
+            .time_async("unique_consumers", conn.pfcount::<_, u64>(&key))
+            .await;
+        Ok(self.discard_connection_on(count).await?)
+    }
+
+    /// The `n` identifiers allowed the most units in the current period, most first, with
+    /// their counts. Empty if `with_top_consumers` wasn't configured.
+    pub async fn top_consumers(&self, n: usize) -> Result<Vec<TopConsumer>, RateLimiterError> {
+        self.top("top_consumers", |now| self.core.top_consumers_cmd(n, now))
+            .await
+    }
+
+    /// The `n` identifiers denied the most requests in the current period, most first, with
+    /// their counts. Empty if `with_top_consumers` wasn't configured.
+    pub async fn violations_top(&self, n: usize) -> Result<Vec<TopConsumer>, RateLimiterError> {
+        self.top("violations_top", |now| self.core.violations_top_cmd(n, now))
+            .await
+    }
+
+    /// Reads the top consumers with the command `cmd` builds for the period containing the
+    /// server's time.
+    async fn top(
+        &self,
+        operation: &'static str,
+        cmd: impl FnOnce(SystemTime) -> Option<redis::Cmd>,
+    ) -> Result<Vec<TopConsumer>, RateLimiterError> {
+        if self.core.top_consumers_period.is_none() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.get_connection().await?;
+        let now = self.server_now(&mut conn).await;
+        let Some(cmd) = cmd(self.discard_connection_on(now).await?) else {
+            return Ok(Vec::new());
+        };
+        let reply = self
+            .core
+            .latency
+            .time_async(operation, cmd.query_async(&mut conn))
+            .await;
+        Ok(top::from_reply(self.discard_connection_on(reply).await?))
+    }
+
+    /// Reports the limit and window enforced for `identifier` and which source (configuration,
+    /// stored limits or stored override) each one comes from.
+    pub async fn effective_config(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<EffectiveConfig, RateLimiterError> {
+        let identifier = self.core.identifier(&identifier)?;
+        let pipe = self.core.effective_config_pipeline(identifier.as_ref());
+        let mut conn = self.get_connection().await?;
+        let reply = self
+            .core
+            .latency
+            .time_async("effective_config", pipe.query_async(&mut conn))
+            .await;
+        let (redis_override, stored) = self.discard_connection_on(reply).await?;
+        Ok(self.core.effective_config(stored, redis_override))
+    }
+}
+
+/// Prints the configuration; Redis URLs are shown with credentials redacted.
+impl fmt::Debug for AsyncRateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncRateLimiter")
+            .field("endpoints", &self.endpoints)
+            .field("core", &self.core)
+            .finish_non_exhaustive()
+    }
+}
+
+async fn connect(
+    client: &redis::Client,
+    timeout: Option<Duration>,
+) -> Result<MultiplexedConnection, redis::RedisError> {
+    let Some(timeout) = timeout else {
+        return client.get_multiplexed_async_connection().await;
+    };
+    match runtime::timeout(timeout, client.get_multiplexed_async_connection()).await {
+        Ok(conn) => conn,
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+    }
+}
+
+/// The shared connection as handed to one operation: requests without a reply after
+/// `timeout` fail with a timeout error. The multiplexer drops the late reply.
+#[derive(Clone)]
+struct TimedConnection {
+    conn: MultiplexedConnection,
+    timeout: Option<Duration>,
+}
+
+impl TimedConnection {
+    async fn bounded<T>(
+        timeout: Option<Duration>,
+        request: impl Future<Output = redis::RedisResult<T>>,
+    ) -> redis::RedisResult<T> {
+        let Some(timeout) = timeout else {
+            return request.await;
+        };
+        match runtime::timeout(timeout, request).await {
+            Ok(reply) => reply,
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        }
+    }
+}
+
+impl redis::aio::ConnectionLike for TimedConnection {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        let timeout = self.timeout;
+        Box::pin(Self::bounded(timeout, self.conn.req_packed_command(cmd)))
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        let timeout = self.timeout;
+        Box::pin(Self::bounded(
+            timeout,
+            self.conn.req_packed_commands(cmd, offset, count),
+        ))
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+}
+
+/// Connects to a sentinel's master, failing if it turns out to be a replica.
+async fn connect_master(
+    client: &redis::Client,
+    timeout: Option<Duration>,
+) -> Result<MultiplexedConnection, redis::RedisError> {
+    let mut conn = connect(client, timeout).await?;
+    let role: Vec<redis::Value> = sentinel::role_cmd().query_async(&mut conn).await?;
+    sentinel::ensure_master(&role)?;
+    Ok(conn)
+}
+
+async fn probe(
+    client: &redis::Client,
+    timeout: Option<Duration>,
+) -> Result<MultiplexedConnection, redis::RedisError> {
+    let mut conn = connect(client, timeout).await?;
+    redis::cmd("PING")
+        .query_async::<_, String>(&mut conn)
+        .await?;
+    Ok(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const REDIS_URL: &str = "redis://127.0.0.1:6379";
+
+    static PREFIX_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    fn get_unique_prefix() -> String {
+        let n = PREFIX_COUNTER.fetch_add(1, Ordering::Relaxed);
+        format!("test_async_rate_limiter_{}", n)
+    }
+
+    #[tokio::test]
+    async fn test_async_basic_rate_limiting() -> Result<(), RateLimiterError> {
+        let prefix = get_unique_prefix();
+        let limiter = AsyncRateLimiter::new(REDIS_URL, &prefix, 2, Duration::from_secs(5))?;
+
+        assert!(limiter.check("user_1").await.is_ok());
+        assert!(limiter.check("user_1").await.is_ok());
+        assert!(limiter.check("user_1").await.is_err());
+        assert_eq!(limiter.get_remaining("user_1").await?, 0);
+        assert!(limiter.get_time_remaining("user_1").await?.is_some());
+        assert_eq!(limiter.get_usage("user_1").await?.consumed, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_connection_is_replaced() -> Result<(), RateLimiterError> {
+        let prefix = get_unique_prefix();
+        // Every call reconnects (and resolves `127.0.0.1` again).
+        let limiter = AsyncRateLimiter::new(REDIS_URL, &prefix, 2, Duration::from_secs(5))?
+            .with_dns_ttl(Duration::ZERO);
+
+        limiter.check("user_1").await?;
+        limiter.check("user_1").await?;
+        assert_eq!(limiter.get_remaining("user_1").await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_check_leaves_limiter_usable() -> Result<(), RateLimiterError> {
+        let prefix = get_unique_prefix();
+        let limiter = AsyncRateLimiter::new(REDIS_URL, &prefix, 100, Duration::from_secs(5))?;
+        limiter.check("warm_up").await?;
+
+        // Cancel checks at increasingly late points of their execution.
+        let mut cancelled = 0;
+        for delay_us in 0..50 {
+            tokio::select! {
+                biased;
+                _ = tokio::time::sleep(Duration::from_micros(delay_us * 20)) => cancelled += 1,
+                result = limiter.check("user_1") => result?,
+            }
+        }
+
+        // A cancelled check consumed one request or none: the counter is exactly the number
+        // of checks that reached Redis, and later replies are not mixed up with earlier ones.
+        let remaining = limiter.get_remaining("user_1").await?;
+        assert!((50..=100 - (50 - cancelled)).contains(&remaining));
+        assert_eq!(limiter.get_remaining("warm_up").await?, 99);
+        assert!(limiter.check("user_1").await.is_ok());
+        assert_eq!(limiter.get_remaining("user_1").await?, remaining - 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_with_deadline_expired() -> Result<(), RateLimiterError> {
+        let prefix = get_unique_prefix();
+        let limiter = AsyncRateLimiter::new(REDIS_URL, &prefix, 1, Duration::from_secs(5))?;
+
+        assert!(matches!(
+            limiter.check_with_deadline("user_1", Instant::now()).await,
+            Err(RateLimiterError::DeadlineExceeded)
+        ));
+        assert!(limiter
+            .check_with_deadline("user_1", Instant::now() + Duration::from_secs(1))
+            .await
+            .is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_ready_consumes_nothing() -> Result<(), RateLimiterError> {
+        let prefix = get_unique_prefix();
+        let limiter = AsyncRateLimiter::new(REDIS_URL, &prefix, 1, Duration::from_secs(1))?;
+
+        limiter.wait_until_ready("user_1").await?;
+        limiter.check("user_1").await?;
+
+        let start = Instant::now();
+        limiter.wait_until_ready("user_1").await?;
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(limiter.get_remaining("user_1").await?, 1);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "notifications")]
+    #[tokio::test]
+    async fn test_reset_events_report_expired_windows() -> Result<(), RateLimiterError> {
+        use futures_util::StreamExt;
+
+        let mut conn = redis::Client::open(REDIS_URL)?
+            .get_multiplexed_async_connection()
+            .await?;
+        redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("notify-keyspace-events")
+            .arg("Kx")
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        let prefix = get_unique_prefix();
+        let limiter = AsyncRateLimiter::new(REDIS_URL, &prefix, 1, Duration::from_millis(200))?;
+        let mut events = limiter.reset_events().await?;
+
+        limiter.check("user_1").await?;
+        let reset = tokio::time::timeout(Duration::from_secs(2), events.next()).await;
+        assert_eq!(reset.ok().flatten().as_deref(), Some("user_1"));
+        limiter.check("user_1").await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! The storage-facing operations of a limiter, as a trait.
+//!
+//! [`RateLimitBackend`] covers what callers need to enforce and inspect a limit, so code can be
+//! written against it and run on Redis ([`RateLimiter`](crate::RateLimiter)), another store, or
+//! a wrapper adding its own logic (allow-lists, logging, fallbacks) around an inner backend.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::RateLimiterError;
+
+/// Enforces and reports a limit per identifier. Implementations share the limiter's error
+/// type: a denial is `RateLimiterError::RateLimitExceeded`.
+pub trait RateLimitBackend: Send + Sync {
+    /// Counts a request for `identifier`, failing with `RateLimitExceeded` if it is over the
+    /// limit.
+    fn check(&self, identifier: &str) -> Result<(), RateLimiterError>;
+
+    /// Requests still available to `identifier` in its current window.
+    fn remaining(&self, identifier: &str) -> Result<u64, RateLimiterError>;
+
+    /// Time until `identifier`'s window resets, or `None` if it has no active window.
+    fn ttl(&self, identifier: &str) -> Result<Option<Duration>, RateLimiterError>;
+
+    /// Clears `identifier`'s state, so its next request starts a fresh window.
+    fn reset(&self, identifier: &str) -> Result<(), RateLimiterError>;
+}
+
+impl<B: RateLimitBackend + ?Sized> RateLimitBackend for Arc<B> {
+    fn check(&self, identifier: &str) -> Result<(), RateLimiterError> {
+        (**self).check(identifier)
+    }
+
+    fn remaining(&self, identifier: &str) -> Result<u64, RateLimiterError> {
+        (**self).remaining(identifier)
+    }
+
+    fn ttl(&self, identifier: &str) -> Result<Option<Duration>, RateLimiterError> {
+        (**self).ttl(identifier)
+    }
+
+    fn reset(&self, identifier: &str) -> Result<(), RateLimiterError> {
+        (**self).reset(identifier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Counts without expiry; enough to exercise the trait.
+    struct Counter {
+        limit: u64,
+        counts: Mutex<HashMap<String, u64>>,
+    }
+
+    impl RateLimitBackend for Counter {
+        fn check(&self, identifier: &str) -> Result<(), RateLimiterError> {
+            let mut counts = self.counts.lock().unwrap();
+            let count = counts.entry(identifier.to_string()).or_insert(0);
+            *count += 1;
+            if *count > self.limit {
+                return Err(RateLimiterError::RateLimitExceeded {
+                    retry_after: Duration::ZERO,
+                });
+            }
+            Ok(())
+        }
+
+        fn remaining(&self, identifier: &str) -> Result<u64, RateLimiterError> {
+            let counts = self.counts.lock().unwrap();
+            let count = counts.get(identifier).copied().unwrap_or(0);
+            Ok(self.limit.saturating_sub(count))
+        }
+
+        fn ttl(&self, _identifier: &str) -> Result<Option<Duration>, RateLimiterError> {
+            Ok(None)
+        }
+
+        fn reset(&self, identifier: &str) -> Result<(), RateLimiterError> {
+            self.counts.lock().unwrap().remove(identifier);
+            Ok(())
+        }
+    }
+
+    /// Lets some identifiers through without counting them.
+    struct AllowList<B> {
+        inner: B,
+        allowed: Vec<String>,
+    }
+
+    impl<B: RateLimitBackend> RateLimitBackend for AllowList<B> {
+        fn check(&self, identifier: &str) -> Result<(), RateLimiterError> {
+            if self.allowed.iter().any(|a| a == identifier) {
+                return Ok(());
+            }
+            self.inner.check(identifier)
+        }
+
+        fn remaining(&self, identifier: &str) -> Result<u64, RateLimiterError> {
+            self.inner.remaining(identifier)
+        }
+
+        fn ttl(&self, identifier: &str) -> Result<Option<Duration>, RateLimiterError> {
+            self.inner.ttl(identifier)
+        }
+
+        fn reset(&self, identifier: &str) -> Result<(), RateLimiterError> {
+            self.inner.reset(identifier)
+        }
+    }
+
+    #[test]
+    fn test_wrapped_backend() {
+        let backend: Arc<dyn RateLimitBackend> = Arc::new(AllowList {
+            inner: Counter {
+                limit: 1,
+                counts: Mutex::new(HashMap::new()),
+            },
+            allowed: vec!["admin".to_string()],
+        });
+
+        for _ in 0..3 {
+            backend.check("admin").unwrap();
+        }
+        backend.check("user").unwrap();
+        assert!(matches!(
+            backend.check("user"),
+            Err(RateLimiterError::RateLimitExceeded { .. })
+        ));
+        assert_eq!(backend.remaining("user").unwrap(), 0);
+        backend.reset("user").unwrap();
+        assert_eq!(backend.remaining("user").unwrap(), 1);
+    }
+}
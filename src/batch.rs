@@ -0,0 +1,124 @@
+//! Checks of several identifiers, each against its own limiter, in one round trip.
+//!
+//! The checks are sent as one pipeline over a single connection, so the limiters must share
+//! a Redis server. Each check is still decided on its own: a check denied by one limiter
+//! doesn't undo what the others consumed. Every limiter applies its own identifier
+//! normalization, circuit breaker and failure policy to its checks.
+
+use std::borrow::Cow;
+
+use crate::core::{DecisionReply, LimiterCore, ScriptBatch};
+use crate::{RateLimitDecision, RateLimiterError, ToIdentifier};
+
+pub(crate) struct Batch<'a> {
+    /// Each check's limiter, normalized identifier and whether its breaker let it through.
+    checks: Vec<(&'a LimiterCore, Cow<'a, str>, bool)>,
+}
+
+impl<'a> Batch<'a> {
+    pub(crate) fn new<I: ToIdentifier + 'a>(
+        checks: impl IntoIterator<Item = (&'a LimiterCore, &'a I)>,
+    ) -> Result<Self, RateLimiterError> {
+        let checks = checks
+            .into_iter()
+            .map(|(core, identifier)| Ok((core, core.identifier(identifier)?, false)))
+            .collect::<Result<Vec<_>, RateLimiterError>>()?;
+        // Only ask the breakers once every identifier is valid, so no probe is wasted.
+        let checks = checks
+            .into_iter()
+            .map(|(core, identifier, _)| (core, identifier, core.failure.admitted()))
+            .collect();
+        Ok(Batch { checks })
+    }
+
+    /// The pipeline of the checks to send to Redis, or `None` if every breaker turned its
+    /// check away.
+    pub(crate) fn call(&self) -> Option<ScriptBatch<'static>> {
+        ScriptBatch::new(
+            self.checks
+                .iter()
+                .filter(|(_, _, admitted)| *admitted)
+                .map(|(core, identifier, _)| core.check_call(identifier, None, None)),
+        )
+    }
+
+    /// Decides every check from the pipeline's replies, in order, falling back on each
+    /// limiter's failure handling for checks that couldn't reach Redis.
+    pub(crate) fn outcomes(
+        self,
+        replies: Result<Vec<DecisionReply>, RateLimiterError>,
+    ) -> Result<Vec<RateLimitDecision>, RateLimiterError> {
+        let mut replies = replies.map(Vec::into_iter);
+        let decisions: Vec<_> = self
+            .checks
+            .iter()
+            .map(|(core, identifier, admitted)| {
+                let result = admitted.then(|| match &mut replies {
+                    Ok(replies) => match replies.next() {
+                        Some(reply) => core.decision_outcome(identifier, Ok(reply)),
+                        None => Err(missing_reply()),
+                    },
+                    Err(e) => Err(duplicate(e)),
+                });
+                core.on_decision_failure(identifier, None, result)
+            })
+            .collect();
+        decisions.into_iter().collect()
+    }
+}
+
+/// A copy of a batch's failure for each check it failed, as errors can't be cloned.
+fn duplicate(error: &RateLimiterError) -> RateLimiterError {
+    match error {
+        RateLimiterError::Redis(e) => RateLimiterError::Redis(redis::RedisError::from((
+            e.kind(),
+            "batched check failed",
+            e.to_string(),
+        ))),
+        RateLimiterError::PoolExhausted => RateLimiterError::PoolExhausted,
+        e => RateLimiterError::InvalidConfig(e.to_string()),
+    }
+}
+
+fn missing_reply() -> RateLimiterError {
+    RateLimiterError::Redis(redis::RedisError::from((
+        redis::ErrorKind::TypeError,
+        "batched check got fewer replies than checks",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::FailurePolicy;
+
+    #[test]
+    fn test_outcomes_in_order() {
+        let user = LimiterCore::new("user", 10, Duration::from_secs(60));
+        let mut ip = LimiterCore::new("ip", 5, Duration::from_secs(60));
+        ip.failure.policy = Some(FailurePolicy::Closed);
+        let batch = Batch::new([(&user, &"alice"), (&ip, &"10.0.0.1")]).unwrap();
+        assert!(batch.call().is_some());
+
+        let decisions = batch
+            .outcomes(Ok(vec![
+                (1, 10, 9, 60_000, 0, 0),
+                (0, 5, 0, 30_000, 30_000, 0),
+            ]))
+            .unwrap();
+        assert!(decisions[0].allowed);
+        assert_eq!(decisions[0].remaining, 9);
+        assert!(!decisions[1].allowed);
+        assert_eq!(decisions[1].retry_after, Some(Duration::from_secs(30)));
+
+        // A failed round trip fails every check: `ip` answers with its policy, `user` has none.
+        let batch = Batch::new([(&ip, &"10.0.0.1"), (&user, &"alice")]).unwrap();
+        let failure = redis::RedisError::from((redis::ErrorKind::IoError, "down"));
+        assert!(matches!(
+            batch.outcomes(Err(failure.into())),
+            Err(RateLimiterError::Redis(e)) if e.kind() == redis::ErrorKind::IoError
+        ));
+    }
+}
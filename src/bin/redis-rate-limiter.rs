@@ -0,0 +1,355 @@
+//! `redis-rate-limiter`: inspects and adjusts a limiter's state in Redis, for on-call use
+//! instead of poking at keys by hand. Built with the `cli` feature.
+
+use std::env;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use redis_rate_limiter::{
+    parse_duration, Algorithm, ConfigSource, LimitOverride, Rate, RateLimiter, RateLimiterError,
+};
+
+const USAGE: &str = "\
+Usage: redis-rate-limiter --prefix <PREFIX> [OPTIONS] <COMMAND>
+
+Commands:
+  status <IDENTIFIER>         Show an identifier's usage and the limits it gets
+  reset <IDENTIFIER>          Delete an identifier's counters, starting a fresh window
+  list                        List the identifiers with a live counter and their usage
+  override get <IDENTIFIER>   Show an identifier's stored override
+  override set <IDENTIFIER> [--limit <N>] [--window <DURATION>] [--ttl <DURATION>]
+                              Store an override, reverting after --ttl if given
+  override remove <IDENTIFIER>
+                              Remove an identifier's override
+  override list               List the overrides in effect
+
+Options:
+  --prefix <PREFIX>           Key prefix of the limiter
+  --url <URL>                 Redis URL [default: $REDIS_URL, or redis://127.0.0.1:6379]
+  --rate <RATE>               The limiter's configured rate, e.g. 100/min; required by
+                              `status` and `list`
+  --algorithm <ALGORITHM>     fixed_window (default), sliding_window_log, token_bucket,
+                              gcra, sliding_window_counter or leaky_bucket
+  -h, --help                  Print this help
+";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Status(String),
+    Reset(String),
+    List,
+    OverrideGet(String),
+    OverrideSet {
+        identifier: String,
+        limit_override: LimitOverride,
+        ttl: Option<Duration>,
+    },
+    OverrideRemove(String),
+    OverrideList,
+}
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    url: String,
+    prefix: String,
+    rate: Option<Rate>,
+    algorithm: Algorithm,
+    command: Command,
+}
+
+/// A command line that can't be run, and why.
+#[derive(Debug, PartialEq)]
+struct UsageError(String);
+
+fn usage_error(reason: impl Into<String>) -> UsageError {
+    UsageError(reason.into())
+}
+
+/// Parses the arguments after the program name; `None` asks for the help text.
+fn parse_args(
+    args: impl IntoIterator<Item = String>,
+    default_url: String,
+) -> Result<Option<Args>, UsageError> {
+    let mut options: Vec<(String, String)> = Vec::new();
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        }
+        match arg.strip_prefix("--") {
+            Some(name) => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| usage_error(format!("--{} needs a value", name)))?;
+                options.push((name.to_string(), value));
+            }
+            None => positional.push(arg),
+        }
+    }
+
+    let mut option = |name: &str| {
+        options
+            .iter()
+            .position(|(option, _)| option == name)
+            .map(|i| options.remove(i).1)
+    };
+    let prefix = option("prefix").ok_or_else(|| usage_error("--prefix is required"))?;
+    let url = option("url").unwrap_or(default_url);
+    let rate = option("rate").map(|rate| rate.parse()).transpose();
+    let rate = rate.map_err(|e: RateLimiterError| usage_error(e.to_string()))?;
+    let algorithm =
+        option("algorithm").map_or(Ok(Algorithm::FixedWindow), |name| parse_algorithm(&name))?;
+    let duration = |value: Option<String>| {
+        value
+            .map(|value| parse_duration(&value))
+            .transpose()
+            .map_err(|e| usage_error(e.to_string()))
+    };
+
+    let positional: Vec<&str> = positional.iter().map(String::as_str).collect();
+    let command = match positional[..] {
+        ["status", identifier] => Command::Status(identifier.to_string()),
+        ["reset", identifier] => Command::Reset(identifier.to_string()),
+        ["list"] => Command::List,
+        ["override", "get", identifier] => Command::OverrideGet(identifier.to_string()),
+        ["override", "set", identifier] => {
+            let limit = option("limit")
+                .map(|limit| limit.parse::<u64>())
+                .transpose()
+                .map_err(|_| usage_error("--limit must be a whole number"))?;
+            let window = duration(option("window"))?;
+            if limit.is_none() && window.is_none() {
+                return Err(usage_error("override set needs --limit and/or --window"));
+            }
+            Command::OverrideSet {
+                identifier: identifier.to_string(),
+                limit_override: LimitOverride {
+                    max_requests: limit,
+                    window,
+                },
+                ttl: duration(option("ttl"))?,
+            }
+        }
+        ["override", "remove", identifier] => Command::OverrideRemove(identifier.to_string()),
+        ["override", "list"] => Command::OverrideList,
+        [] => return Err(usage_error("no command given")),
+        _ => {
+            return Err(usage_error(format!(
+                "unknown command `{}`",
+                positional.join(" ")
+            )))
+        }
+    };
+    if let Some((name, _)) = options.first() {
+        return Err(usage_error(format!("unexpected option --{}", name)));
+    }
+    if rate.is_none() && matches!(command, Command::Status(_) | Command::List) {
+        return Err(usage_error("--rate is required to report usage"));
+    }
+
+    Ok(Some(Args {
+        url,
+        prefix,
+        rate,
+        algorithm,
+        command,
+    }))
+}
+
+fn parse_algorithm(name: &str) -> Result<Algorithm, UsageError> {
+    Ok(match name {
+        "fixed_window" => Algorithm::FixedWindow,
+        "sliding_window_log" => Algorithm::SlidingWindowLog,
+        "token_bucket" => Algorithm::TokenBucket,
+        "gcra" => Algorithm::Gcra,
+        "sliding_window_counter" => Algorithm::SlidingWindowCounter,
+        "leaky_bucket" => Algorithm::LeakyBucket,
+        _ => return Err(usage_error(format!("unknown algorithm `{}`", name))),
+    })
+}
+
+fn source_name(source: ConfigSource) -> &'static str {
+    match source {
+        ConfigSource::Configured => "configured",
+        ConfigSource::RedisOverride => "override",
+        ConfigSource::StoredLimits => "stored limits",
+        ConfigSource::PerCall => "per call",
+        _ => "other",
+    }
+}
+
+fn describe(limit_override: &LimitOverride) -> String {
+    let limit = limit_override
+        .max_requests
+        .map_or("configured".to_string(), |limit| limit.to_string());
+    let window = limit_override
+        .window
+        .map_or("configured".to_string(), |window| format!("{:?}", window));
+    format!("limit {}, window {}", limit, window)
+}
+
+fn run(args: Args) -> Result<(), RateLimiterError> {
+    // Commands that don't report usage don't depend on the configured rate.
+    let rate = args.rate.unwrap_or(Rate {
+        max_requests: 1,
+        window: Duration::from_secs(1),
+    });
+    let limiter = RateLimiter::new(&args.url, &args.prefix, rate.max_requests, rate.window)?
+        .with_algorithm(args.algorithm);
+
+    match args.command {
+        Command::Status(identifier) => {
+            let usage = limiter.get_usage(identifier.as_str())?;
+            let config = limiter.effective_config(identifier.as_str())?;
+            println!("identifier: {}", identifier);
+            println!(
+                "used:       {} of {} ({} remaining)",
+                usage.consumed, usage.limit, usage.remaining
+            );
+            match usage.resets_in {
+                Some(resets_in) => println!("resets in:  {:?}", resets_in),
+                None => println!("resets in:  no active window"),
+            }
+            println!(
+                "limit:      {} ({})",
+                config.max_requests,
+                source_name(config.max_requests_source)
+            );
+            println!(
+                "window:     {:?} ({})",
+                config.window,
+                source_name(config.window_source)
+            );
+        }
+        Command::Reset(identifier) => {
+            limiter.reset(identifier.as_str())?;
+            println!("reset {}", identifier);
+        }
+        Command::List => {
+            for active in limiter.active_identifiers() {
+                let active = active?;
+                let ttl = active
+                    .ttl
+                    .map_or("-".to_string(), |ttl| format!("{:?}", ttl));
+                println!("{}\t{}\t{}", active.identifier, active.used, ttl);
+            }
+        }
+        Command::OverrideGet(identifier) => match limiter.get_override(identifier.as_str())? {
+            Some(limit_override) => println!("{}", describe(&limit_override)),
+            None => println!("no override"),
+        },
+        Command::OverrideSet {
+            identifier,
+            limit_override,
+            ttl,
+        } => {
+            limiter.set_override(identifier.as_str(), limit_override, ttl)?;
+            match ttl {
+                Some(ttl) => println!(
+                    "set {}: {}, for {:?}",
+                    identifier,
+                    describe(&limit_override),
+                    ttl
+                ),
+                None => println!("set {}: {}", identifier, describe(&limit_override)),
+            }
+        }
+        Command::OverrideRemove(identifier) => {
+            limiter.remove_override(identifier.as_str())?;
+            println!("removed the override of {}", identifier);
+        }
+        Command::OverrideList => {
+            for active in limiter.active_overrides()? {
+                let expires_in = active
+                    .expires_in
+                    .map_or("-".to_string(), |expires_in| format!("{:?}", expires_in));
+                println!(
+                    "{}\t{}\t{}",
+                    active.identifier,
+                    describe(&active.limit_override),
+                    expires_in
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let default_url =
+        env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    match parse_args(env::args().skip(1), default_url) {
+        Ok(Some(args)) => match run(args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        Ok(None) => {
+            print!("{}", USAGE);
+            ExitCode::SUCCESS
+        }
+        Err(UsageError(reason)) => {
+            eprintln!("error: {}\n\n{}", reason, USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Option<Args>, UsageError> {
+        parse_args(
+            args.split_whitespace().map(String::from),
+            "redis://default".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_parse_override_set() {
+        let args = parse("--prefix api override set user_1 --limit 50 --ttl 1h")
+            .unwrap()
+            .unwrap();
+        assert_eq!(args.url, "redis://default");
+        assert_eq!(args.prefix, "api");
+        assert_eq!(
+            args.command,
+            Command::OverrideSet {
+                identifier: "user_1".into(),
+                limit_override: LimitOverride::max_requests(50),
+                ttl: Some(Duration::from_secs(3600)),
+            }
+        );
+        assert_eq!(parse("status user_1 -h"), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |args| parse(args).unwrap_err().0;
+        assert_eq!(error("status user_1"), "--prefix is required");
+        assert_eq!(
+            error("--prefix api status user_1"),
+            "--rate is required to report usage"
+        );
+        assert_eq!(
+            error("--prefix api override set user_1"),
+            "override set needs --limit and/or --window"
+        );
+        assert_eq!(
+            error("--prefix api reset user_1 --limit 5"),
+            "unexpected option --limit"
+        );
+        assert_eq!(error("--prefix api purge"), "unknown command `purge`");
+
+        let args = parse("--url redis://other --prefix api --rate 10/min --algorithm gcra list")
+            .unwrap()
+            .unwrap();
+        assert_eq!(args.url, "redis://other");
+        assert_eq!(args.algorithm, Algorithm::Gcra);
+        assert_eq!(args.command, Command::List);
+    }
+}
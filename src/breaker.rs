@@ -0,0 +1,238 @@
+//! A circuit breaker around the Redis checks, with an in-process fallback limiter.
+//!
+//! After `failure_threshold` consecutive checks fail to reach Redis the breaker opens: checks
+//! stop going to Redis and are answered by an [`InMemoryRateLimiter`] with the limiter's limit
+//! and window. Once `open_duration` has passed a single check is let through to Redis as a
+//! probe (the breaker is half-open meanwhile); if Redis answers the breaker closes, otherwise
+//! it opens again for another `open_duration`.
+//!
+//! The fallback is approximate: each process counts on its own and it always uses fixed
+//! windows, whatever the limiter's algorithm. Redis keeps its counters while the breaker is
+//! open, so on recovery the fallback's windows are discarded and Redis is authoritative again.
+
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::InMemoryRateLimiter;
+
+/// Tuning for a limiter's circuit breaker (see `RateLimiter::with_circuit_breaker`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    pub(crate) failure_threshold: u32,
+    pub(crate) open_duration: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn new() -> Self {
+        CircuitBreakerConfig::default()
+    }
+
+    /// Consecutive failed checks that open the breaker. Defaults to 5.
+    pub fn with_failure_threshold(mut self, threshold: u32) -> Self {
+        self.failure_threshold = threshold.max(1);
+        self
+    }
+
+    /// How long the breaker stays open before probing Redis again. Defaults to 30 seconds.
+    pub fn with_open_duration(mut self, duration: Duration) -> Self {
+        self.open_duration = duration;
+        self
+    }
+}
+
+/// Whether checks currently go to Redis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum BreakerState {
+    /// Redis is healthy; checks go to it.
+    Closed,
+    /// Redis is failing; checks are answered by the fallback limiter.
+    Open,
+    /// A probe check is testing whether Redis recovered; other checks still use the fallback.
+    HalfOpen,
+}
+
+struct Circuit {
+    state: BreakerState,
+    consecutive_failures: u32,
+    /// When the breaker last opened or started a probe.
+    since: Instant,
+}
+
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    circuit: Mutex<Circuit>,
+    /// Created on first use, so it picks up the limiter's final configuration.
+    fallback: OnceLock<InMemoryRateLimiter>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            circuit: Mutex::new(Circuit {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                since: Instant::now(),
+            }),
+            fallback: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn state(&self) -> BreakerState {
+        self.circuit
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .state
+    }
+
+    /// Whether a check may go to Redis. Once the breaker has been open for `open_duration`
+    /// the caller is let through as the probe; a probe that never reports back (e.g. a
+    /// cancelled future) is replaced after another `open_duration`.
+    pub(crate) fn admit(&self) -> bool {
+        let mut circuit = self
+            .circuit
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match circuit.state {
+            BreakerState::Closed => true,
+            BreakerState::Open | BreakerState::HalfOpen
+                if circuit.since.elapsed() >= self.config.open_duration =>
+            {
+                circuit.state = BreakerState::HalfOpen;
+                circuit.since = Instant::now();
+                true
+            }
+            BreakerState::Open | BreakerState::HalfOpen => false,
+        }
+    }
+
+    /// Records that Redis answered a check.
+    pub(crate) fn record_success(&self) {
+        let mut circuit = self
+            .circuit
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        circuit.consecutive_failures = 0;
+        if circuit.state != BreakerState::Closed {
+            circuit.state = BreakerState::Closed;
+            log::info!("Redis is reachable again, closing the circuit breaker");
+            if let Some(fallback) = self.fallback.get() {
+                let _ = fallback.reset_all();
+            }
+        }
+    }
+
+    /// Records that a check couldn't reach Redis.
+    pub(crate) fn record_failure(&self) {
+        let mut circuit = self
+            .circuit
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match circuit.state {
+            BreakerState::Closed => {
+                circuit.consecutive_failures += 1;
+                if circuit.consecutive_failures < self.config.failure_threshold {
+                    return;
+                }
+                log::warn!(
+                    "{} consecutive Redis failures, opening the circuit breaker for {:?}",
+                    circuit.consecutive_failures,
+                    self.config.open_duration
+                );
+            }
+            BreakerState::HalfOpen => {
+                log::warn!(
+                    "Redis probe failed, keeping the circuit breaker open for {:?}",
+                    self.config.open_duration
+                );
+            }
+            BreakerState::Open => return,
+        }
+        circuit.state = BreakerState::Open;
+        circuit.since = Instant::now();
+    }
+
+    pub(crate) fn fallback(
+        &self,
+        create: impl FnOnce() -> InMemoryRateLimiter,
+    ) -> &InMemoryRateLimiter {
+        self.fallback.get_or_init(create)
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("config", &self.config)
+            .field("state", &self.state())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_opens_probes_and_closes() {
+        let breaker = CircuitBreaker::new(
+            CircuitBreakerConfig::new()
+                .with_failure_threshold(2)
+                .with_open_duration(Duration::from_millis(20)),
+        );
+
+        assert!(breaker.admit());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.admit());
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.admit());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        // Only one probe at a time.
+        assert!(!breaker.admit());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.admit());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.admit());
+    }
+
+    #[test]
+    fn test_breaker_survives_a_poisoned_lock() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig::new());
+        let _ = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _circuit = breaker.circuit.lock().unwrap();
+                    panic!("poisoning the breaker");
+                })
+                .join()
+        });
+        assert!(breaker.circuit.is_poisoned());
+
+        assert!(breaker.admit());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
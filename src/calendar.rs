@@ -0,0 +1,89 @@
+//! UTC day and month boundaries for the calendar window modes, mirroring the check script.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::WindowMode;
+
+const DAY_MS: u64 = 86_400_000;
+
+/// When the calendar window containing `now` ends under `mode`: the next UTC midnight or
+/// the start of the next UTC month. `None` for the modes that aren't calendar aligned.
+pub(crate) fn window_end(mode: WindowMode, now: SystemTime) -> Option<SystemTime> {
+    let now_ms = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let day = now_ms / DAY_MS;
+    let next = match mode {
+        WindowMode::CalendarDay => day + 1,
+        WindowMode::CalendarMonth => {
+            let (year, month, day_of_month) = civil_from_days(day);
+            day - (day_of_month - 1) + days_in_month(year, month)
+        }
+        WindowMode::FixedFromFirstRequest | WindowMode::SlidingInactivity => return None,
+    };
+    Some(UNIX_EPOCH + Duration::from_millis(next * DAY_MS))
+}
+
+/// Year, month (1-12) and day of the month (1-31) of a day counted from 1970-01-01, after
+/// Howard Hinnant's `civil_from_days`, restricted to dates from the epoch on.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_calendar_window_ends() {
+        // 2024-02-29T13:20:00Z, in a leap year.
+        let now = at(1_709_212_800);
+        assert_eq!(
+            window_end(WindowMode::CalendarDay, now),
+            Some(at(1_709_251_200)) // 2024-03-01T00:00:00Z
+        );
+        assert_eq!(
+            window_end(WindowMode::CalendarMonth, now),
+            Some(at(1_709_251_200))
+        );
+        // 2023-12-31T23:59:59Z rolls over into the new year.
+        assert_eq!(
+            window_end(WindowMode::CalendarMonth, at(1_704_067_199)),
+            Some(at(1_704_067_200))
+        );
+        // Midnight itself starts a new window.
+        assert_eq!(
+            window_end(WindowMode::CalendarDay, at(1_704_067_200)),
+            Some(at(1_704_153_600))
+        );
+        assert_eq!(
+            window_end(WindowMode::CalendarMonth, at(1_704_067_200)),
+            Some(at(1_706_745_600)) // 2024-02-01T00:00:00Z
+        );
+        assert_eq!(window_end(WindowMode::SlidingInactivity, now), None);
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// What happens when a new identifier shows up after the distinct identifier cap is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum CardinalityPolicy {
+    /// Reject requests from identifiers that don't already have an active window.
+    DenyNew,
+    /// Allow the request but log a warning and invoke the alert hook, if any.
+    Alert,
+}
+
+pub(crate) type CardinalityHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Caps the number of distinct identifiers that may start a window under one prefix within
+/// one window length, protecting Redis from attackers rotating identifiers.
+///
+/// Identifiers are counted when their window starts, so the count is the number of distinct
+/// windows opened since the prefix's own window started; it is reset together with it.
+#[derive(Clone)]
+pub struct CardinalityLimit {
+    pub max_identifiers: u64,
+    pub policy: CardinalityPolicy,
+    on_exceeded: Option<CardinalityHook>,
+}
+
+impl CardinalityLimit {
+    pub fn new(max_identifiers: u64, policy: CardinalityPolicy) -> Self {
+        CardinalityLimit {
+            max_identifiers,
+            policy,
+            on_exceeded: None,
+        }
+    }
+
+    /// Registers a callback invoked with the identifier that went over the cap
+    /// under `CardinalityPolicy::Alert`.
+    pub fn on_exceeded<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.on_exceeded = Some(Arc::new(hook));
+        self
+    }
+
+    pub(crate) fn alert(&self, key_prefix: &str, identifier: &str) {
+        log::warn!(
+            "rate limiter `{}` is tracking more than {} distinct identifiers (new: `{}`)",
+            key_prefix,
+            self.max_identifiers,
+            identifier
+        );
+        if let Some(hook) = &self.on_exceeded {
+            hook(identifier);
+        }
+    }
+}
+
+impl fmt::Debug for CardinalityLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CardinalityLimit")
+            .field("max_identifiers", &self.max_identifiers)
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
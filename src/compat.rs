@@ -0,0 +1,215 @@
+//! Detection of the server implementation behind the Redis protocol.
+//!
+//! Redis-compatible servers differ in the commands they implement. The limiter detects the
+//! server from `INFO server` on first use of [`ServerCapabilities`] and avoids what it lacks:
+//!
+//! | Server       | Scripts (`EVALSHA`) | HyperLogLog | `CONFIG GET maxmemory-policy` | `FUNCTION` |
+//! |--------------|---------------------|-------------|-------------------------------|------------|
+//! | Redis < 7    | yes                 | yes         | yes                           | no         |
+//! | Redis >= 7   | yes                 | yes         | yes                           | yes        |
+//! | Valkey       | yes                 | yes         | yes                           | yes        |
+//! | KeyDB        | yes                 | yes         | yes                           | no         |
+//! | Dragonfly    | yes                 | yes         | no                            | no         |
+//!
+//! Rate limiting itself only needs scripts, so it works on all of them. Without
+//! `CONFIG GET maxmemory-policy`, `verify_eviction_policy` fails with
+//! `RateLimiterError::Unsupported` rather than guessing; use `spawn_eviction_canary` instead.
+//!
+//! Command support is gated on the Redis version the server reports (`redis_version`, which
+//! compatible servers set to the Redis release they emulate):
+//!
+//! | Needed for                                  | Commands                         | Since  |
+//! |---------------------------------------------|----------------------------------|--------|
+//! | Every check                                 | `EVALSHA`                        | 2.6    |
+//! | Every check, usage and analytics            | `PTTL`, `SET ... PX`/`EX`        | 2.6.12 |
+//! | `with_unique_consumers`                     | `PFADD`, `PFCOUNT`               | 2.8.9  |
+//! | `reserve_slot`, `Pacer`                     | writes after `TIME` in a script  | 3.2    |
+//! | Not used; reported for custom scripts       | `UNLINK`                         | 4.0    |
+//! | Not used; reported for custom scripts       | `EXPIRE ... NX`                  | 7.0    |
+
+/// Implementation of the server the limiter talks to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServerKind {
+    Redis,
+    Valkey,
+    KeyDb,
+    Dragonfly,
+    /// Answered `INFO` in an unrecognized way; assumed to behave like Redis.
+    Unknown,
+}
+
+/// What the server supports, as far as this crate is concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    pub kind: ServerKind,
+    /// The implementation's own version, e.g. `7.2.4` for Redis or `df-v1.14.0` for Dragonfly.
+    pub version: String,
+    /// `EVAL`/`EVALSHA`, required by every check.
+    pub scripting: bool,
+    /// `PTTL` and the `PX`/`EX` options of `SET`, required by every check.
+    pub millisecond_expiry: bool,
+    /// Writes after `TIME` in a script (effects replication), used by `reserve_slot`.
+    pub script_time_writes: bool,
+    /// `PFADD`/`PFCOUNT`, used by `with_unique_consumers`.
+    pub hyperloglog: bool,
+    /// `UNLINK`.
+    pub unlink: bool,
+    /// The `NX`/`XX`/`GT`/`LT` options of `EXPIRE`.
+    pub expire_options: bool,
+    /// `CONFIG GET maxmemory-policy`, used by `verify_eviction_policy`.
+    pub eviction_policy_config: bool,
+    /// `FUNCTION LOAD`/`FCALL` (Redis 7 functions).
+    pub functions: bool,
+}
+
+impl ServerCapabilities {
+    /// Derives the capabilities from an `INFO server` reply.
+    pub(crate) fn from_info(info: &str) -> Self {
+        let field = |name: &str| {
+            info.lines().find_map(|line| {
+                line.strip_prefix(name)
+                    .and_then(|rest| rest.strip_prefix(':'))
+                    .map(|value| value.trim().to_string())
+            })
+        };
+        let redis_version = field("redis_version");
+        let mentions_keydb = info.to_ascii_lowercase().contains("keydb");
+
+        let (kind, version) = if let Some(version) = field("dragonfly_version") {
+            (ServerKind::Dragonfly, version)
+        } else if let Some(version) = field("valkey_version") {
+            (ServerKind::Valkey, version)
+        } else if field("server_name").is_some_and(|name| name == "valkey") {
+            (ServerKind::Valkey, redis_version.unwrap_or_default())
+        } else if mentions_keydb {
+            (ServerKind::KeyDb, redis_version.unwrap_or_default())
+        } else if let Some(version) = redis_version {
+            (ServerKind::Redis, version)
+        } else {
+            (ServerKind::Unknown, String::new())
+        };
+
+        let major: u32 = version
+            .split('.')
+            .next()
+            .and_then(|major| major.parse().ok())
+            .unwrap_or(0);
+        // Without a Redis version to go by, assume a current server.
+        let since = |required: (u32, u32, u32)| {
+            !matches!(
+                field("redis_version").and_then(|v| parse_version(&v)),
+                Some(version) if version < required
+            )
+        };
+
+        ServerCapabilities {
+            kind,
+            scripting: since((2, 6, 0)),
+            millisecond_expiry: since((2, 6, 12)),
+            script_time_writes: since((3, 2, 0)),
+            hyperloglog: since((2, 8, 9)),
+            unlink: since((4, 0, 0)),
+            expire_options: since((7, 0, 0)),
+            eviction_policy_config: kind != ServerKind::Dragonfly,
+            functions: match kind {
+                ServerKind::Redis => major >= 7,
+                ServerKind::Valkey => true,
+                _ => false,
+            },
+            version,
+        }
+    }
+
+    pub(crate) fn require(
+        &self,
+        supported: bool,
+        feature: &str,
+    ) -> Result<(), crate::RateLimiterError> {
+        if supported {
+            return Ok(());
+        }
+        Err(crate::RateLimiterError::Unsupported {
+            feature: feature.to_string(),
+            server: format!("{:?} {}", self.kind, self.version),
+        })
+    }
+}
+
+/// Parses `major.minor.patch`, treating missing components as 0.
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = version.trim().split('.').map(|part| part.parse::<u32>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+    let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+    Some((major, minor, patch))
+}
+
+pub(crate) fn info_cmd() -> redis::Cmd {
+    let mut cmd = redis::cmd("INFO");
+    cmd.arg("server");
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_server_kind() {
+        let redis = ServerCapabilities::from_info(
+            "# Server\r\nredis_version:7.2.4\r\nredis_mode:standalone\r\n",
+        );
+        assert_eq!(redis.kind, ServerKind::Redis);
+        assert_eq!(redis.version, "7.2.4");
+        assert!(redis.functions);
+
+        let old_redis = ServerCapabilities::from_info("redis_version:6.2.14\r\n");
+        assert!(!old_redis.functions);
+
+        let valkey = ServerCapabilities::from_info(
+            "redis_version:7.2.4\r\nserver_name:valkey\r\nvalkey_version:8.0.1\r\n",
+        );
+        assert_eq!(
+            (valkey.kind, valkey.version.as_str()),
+            (ServerKind::Valkey, "8.0.1")
+        );
+
+        let dragonfly = ServerCapabilities::from_info(
+            "redis_version:6.2.11\r\ndragonfly_version:df-v1.14.0\r\n",
+        );
+        assert_eq!(dragonfly.kind, ServerKind::Dragonfly);
+        assert!(!dragonfly.eviction_policy_config);
+        assert!(dragonfly
+            .require(dragonfly.eviction_policy_config, "CONFIG GET")
+            .is_err());
+
+        let keydb = ServerCapabilities::from_info(
+            "redis_version:6.3.4\r\nexecutable:/usr/bin/keydb-server\r\n",
+        );
+        assert_eq!(keydb.kind, ServerKind::KeyDb);
+
+        assert_eq!(ServerCapabilities::from_info("").kind, ServerKind::Unknown);
+    }
+
+    #[test]
+    fn test_gates_commands_on_version() {
+        let ancient = ServerCapabilities::from_info("redis_version:2.8.4\r\n");
+        assert!(ancient.scripting && ancient.millisecond_expiry);
+        assert!(!ancient.hyperloglog);
+        assert!(!ancient.script_time_writes);
+
+        let modern = ServerCapabilities::from_info("redis_version:6.2.14\r\n");
+        assert!(modern.script_time_writes && modern.unlink);
+        assert!(!modern.expire_options);
+
+        // Dragonfly reports the Redis version it emulates.
+        let dragonfly = ServerCapabilities::from_info(
+            "redis_version:6.2.11\r\ndragonfly_version:df-v1.14.0\r\n",
+        );
+        assert!(dragonfly.hyperloglog && !dragonfly.expire_options);
+
+        assert_eq!(parse_version("7.2"), Some((7, 2, 0)));
+        assert_eq!(parse_version("df-v1"), None);
+    }
+}
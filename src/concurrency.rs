@@ -0,0 +1,182 @@
+//! Caps on operations in flight at once per identifier, as opposed to requests per window.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::runtime;
+use crate::{AsyncRateLimiter, RateLimiterError, ToIdentifier};
+
+/// Limits how many operations per identifier run at once across all instances, e.g. at most 5
+/// simultaneous downloads per user.
+///
+/// [`acquire`](ConcurrencyLimiter::acquire) takes a slot atomically in Redis and returns a
+/// [`Permit`] that frees it when dropped. Every permit also expires `ttl` after it was acquired
+/// (or last [`renew`](Permit::renew)ed), so the slots of a process that crashed or lost its
+/// connection while holding them come back on their own: pick a `ttl` comfortably above the
+/// longest operation, or renew long-running ones. Expiry is timed by the Redis server clock.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter {
+    limiter: Arc<AsyncRateLimiter>,
+    max_in_flight: u64,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(
+        redis_url: &str,
+        key_prefix: &str,
+        max_in_flight: u64,
+        ttl: Duration,
+    ) -> Result<Self, RateLimiterError> {
+        let limiter = AsyncRateLimiter::new(redis_url, key_prefix, max_in_flight, ttl)?;
+        Ok(ConcurrencyLimiter {
+            limiter: Arc::new(limiter),
+            max_in_flight,
+        })
+    }
+
+    /// The underlying limiter, e.g. for `latency_stats`.
+    pub fn limiter(&self) -> &AsyncRateLimiter {
+        &self.limiter
+    }
+
+    /// Takes one of `identifier`'s slots, failing fast with
+    /// `RateLimiterError::ConcurrencyLimitExceeded` if all `max_in_flight` are held.
+    pub async fn acquire(&self, identifier: impl ToIdentifier) -> Result<Permit, RateLimiterError> {
+        let identifier = self.limiter.normalize(&identifier)?.into_owned();
+        let token = unique_token();
+        let (acquired, _) = self
+            .limiter
+            .acquire_permit(&identifier, &token, self.max_in_flight)
+            .await?;
+        if !acquired {
+            return Err(RateLimiterError::ConcurrencyLimitExceeded {
+                max_in_flight: self.max_in_flight,
+            });
+        }
+        Ok(Permit {
+            limiter: self.limiter.clone(),
+            identifier,
+            token: Some(token),
+        })
+    }
+
+    /// How many of `identifier`'s slots are held, not counting expired permits.
+    pub async fn in_flight(&self, identifier: impl ToIdentifier) -> Result<u64, RateLimiterError> {
+        let identifier = self.limiter.normalize(&identifier)?;
+        let (_, held) = self.limiter.acquire_permit(&identifier, "", 0).await?;
+        Ok(held)
+    }
+}
+
+/// A slot held in a [`ConcurrencyLimiter`], freed when dropped.
+///
+/// Dropping a permit frees its slot from a task spawned on the current tokio runtime (or on
+/// async-std, with the `async-std` feature), so the slot may stay taken for a moment after the
+/// drop; outside a runtime it is left to expire.
+/// Use [`release`](Permit::release) to free it before going on, or to see errors.
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<AsyncRateLimiter>,
+    identifier: String,
+    /// `None` once released.
+    token: Option<String>,
+}
+
+impl Permit {
+    /// The normalized identifier the permit was acquired for.
+    pub fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    /// Extends the permit to a full `ttl` from now, for operations that may outlast it.
+    /// Returns false if the permit had already expired and its slot may have been taken.
+    pub async fn renew(&self) -> Result<bool, RateLimiterError> {
+        match &self.token {
+            Some(token) => self.limiter.renew_permit(&self.identifier, token).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Frees the slot now.
+    pub async fn release(mut self) -> Result<(), RateLimiterError> {
+        match self.token.take() {
+            Some(token) => self.limiter.release_permit(&self.identifier, &token).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let Some(token) = self.token.take() else {
+            return;
+        };
+        if runtime::can_spawn() {
+            let limiter = self.limiter.clone();
+            let identifier = std::mem::take(&mut self.identifier);
+            runtime::spawn(async move {
+                // On failure the permit expires at its TTL instead.
+                let _ = limiter.release_permit(&identifier, &token).await;
+            });
+        }
+    }
+}
+
+/// A token unique to one permit or reservation across processes and hosts, short of a
+/// collision of the process ID and nanosecond clock of two hosts.
+pub(crate) fn unique_token() -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{:x}-{:x}-{:x}",
+        std::process::id(),
+        nanos,
+        SEQUENCE.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_permits_release_on_drop_and_expire() -> Result<(), RateLimiterError> {
+        let prefix = format!("test_concurrency_{}", std::process::id());
+        let downloads = ConcurrencyLimiter::new(
+            "redis://127.0.0.1:6379",
+            &prefix,
+            2,
+            Duration::from_millis(300),
+        )?;
+
+        let first = downloads.acquire("alice").await?;
+        let second = downloads.acquire("alice").await?;
+        assert!(matches!(
+            downloads.acquire("alice").await,
+            Err(RateLimiterError::ConcurrencyLimitExceeded { max_in_flight: 2 })
+        ));
+        assert_eq!(downloads.in_flight("alice").await?, 2);
+        downloads.acquire("bob").await?.release().await?;
+
+        first.release().await?;
+        drop(second);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(downloads.in_flight("alice").await?, 0);
+
+        // A holder that never releases loses its slot at the TTL.
+        std::mem::forget(downloads.acquire("alice").await?);
+        let held = downloads.acquire("alice").await?;
+        assert!(downloads.acquire("alice").await.is_err());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(held.renew().await?);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(downloads.in_flight("alice").await?, 1);
+        held.release().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,3255 @@
+//! Configuration and script plumbing shared by the blocking and async limiters.
+//!
+//! Both front-ends only own their connection handling; everything that decides what is sent
+//! to Redis and how replies are interpreted lives here so the two can't drift apart.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::access::AccessList;
+use crate::active::ActiveIdentifier;
+use crate::breaker::CircuitBreaker;
+use crate::calendar;
+use crate::cardinality::{CardinalityLimit, CardinalityPolicy};
+use crate::clock::Clock;
+use crate::compat::ServerCapabilities;
+use crate::custom::CustomScript;
+use crate::decision::RateLimitDecision;
+use crate::effective::EffectiveConfig;
+use crate::escalation::{EscalationPolicy, PenaltyPolicy};
+use crate::failure::{FailureHandling, Fallback};
+use crate::history::{UsageBucket, UsageHistory};
+use crate::identifier::{IdentifierPolicy, Normalization, ToIdentifier};
+use crate::latency::LatencyTracker;
+use crate::lease::Leases;
+use crate::metrics::{CheckOutcome, CheckRecorder, MetricsSink};
+use crate::overrides::{self, LIMITS_KEY, OVERRIDE_INDEX_KEY, OVERRIDE_KEY};
+use crate::plain::PlainCheck;
+use crate::retry::{self, RetryPolicy};
+use crate::telemetry::{Sampler, TelemetrySampling};
+use crate::top;
+use crate::trace;
+#[cfg(feature = "tracing")]
+use crate::trace::TracedIdentifier;
+use crate::transform::KeyTransform;
+use crate::usage::Usage;
+use crate::{
+    Algorithm, DenialReason, InMemoryRateLimiter, LimitOverride, Rate, RateLimiterError, WindowMode,
+};
+
+/// Name of the counter tracking distinct identifiers per window, under the limiter's prefix.
+const IDENTIFIERS_KEY: &str = "__identifiers__";
+/// Name of the per-period HyperLogLogs of identifiers, under the limiter's prefix.
+const CONSUMERS_KEY: &str = "__consumers__";
+/// Name of the per-period sorted sets of units allowed per identifier, under the prefix.
+const TOP_KEY: &str = "__top__";
+/// Name of the per-period sorted sets of denials per identifier, under the prefix.
+const TOP_VIOLATIONS_KEY: &str = "__top_violations__";
+/// Name of the per-identifier next free pacing slot, under the limiter's prefix.
+const SLOTS_KEY: &str = "__slots__";
+/// Name of the per-identifier count of denials towards an escalation ban, under the prefix.
+const VIOLATIONS_KEY: &str = "__violations__";
+/// Name of the per-identifier temporary ban set by escalation, under the limiter's prefix.
+const BAN_KEY: &str = "__ban__";
+/// Name of the per-identifier streak of consecutive denials behind a penalty, under the prefix.
+const PENALTY_KEY: &str = "__penalty__";
+/// Name of the per-identifier shards of a sharded counter after the first, under the prefix.
+const SHARD_KEY: &str = "__shard__";
+/// Name of the per-identifier sorted set of capacity reserved ahead, under the prefix.
+const RESERVATIONS_KEY: &str = "__reservations__";
+/// Name of the per-identifier set of request IDs allowed by idempotent checks, under the prefix.
+const REQUESTS_KEY: &str = "__requests__";
+/// Name of the per-identifier sorted set of held concurrency permits, under the limiter's prefix.
+const PERMITS_KEY: &str = "__permits__";
+/// Name of the per-identifier, per-period usage history hashes, under the limiter's prefix.
+const HISTORY_KEY: &str = "__history__";
+/// Name of the per-identifier units borrowed from the next window, under the limiter's prefix.
+const DEBT_KEY: &str = "__debt__";
+/// Name of the per-identifier counter of an additional limit, followed by its window in ms.
+const RULE_KEY: &str = "__rule__";
+/// Name of the per-identifier copy of the live counter, under the limiter's prefix.
+const SHADOW_KEY: &str = "__shadow__";
+/// Name of the per-identifier final count of the previous window, under the limiter's prefix.
+const LAST_WINDOW_KEY: &str = "__last_window__";
+
+/// Keys asked for per `SCAN` step, and so at most deleted per `DEL`, when purging the prefix.
+const SCAN_BATCH: usize = 500;
+
+/// Shortest pause before re-checking after a denial, so a window that resets between the check
+/// and the TTL read doesn't turn into a busy loop.
+pub(crate) const MIN_WAIT: Duration = Duration::from_millis(10);
+
+/// Raw reply of a check: code, limit, remaining, reset after and retry after (ms), and the
+/// index of the rule that decided it (0 for the primary limit), or for an allowed partial
+/// consumption the units granted.
+pub(crate) type DecisionReply = (u64, u64, u64, u64, u64, u64);
+
+/// Raw reply of [`LimiterCore::usage_pipeline`].
+pub(crate) type UsageReply = (
+    redis::Value,
+    i64,
+    (Option<u64>, Option<u64>),
+    (Option<u64>, Option<u64>),
+);
+
+// Return codes of the check script.
+pub(crate) const DENIED: u64 = 0;
+pub(crate) const ALLOWED: u64 = 1;
+const ALLOWED_OVER_CARDINALITY: u64 = 2;
+const DENIED_OVER_CARDINALITY: u64 = 3;
+const BANNED: u64 = 4;
+const DENYLISTED: u64 = 5;
+
+#[derive(Debug)]
+pub(crate) struct LimiterCore {
+    pub(crate) key_prefix: String,
+    pub(crate) max_requests: u64,
+    pub(crate) window: Duration,
+    pub(crate) window_mode: WindowMode,
+    pub(crate) algorithm: Algorithm,
+    /// Token bucket refill or leaky bucket drain rate; defaults to the limit per window.
+    pub(crate) refill_rate: Option<Rate>,
+    /// Further limits enforced with the primary one, as fixed windows from the first request.
+    pub(crate) additional_limits: Vec<Rate>,
+    /// Replaces the server and system clocks; see `with_clock`.
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+    pub(crate) failure: FailureHandling,
+    /// Retries of transient Redis errors in checks, before `failure` sees them.
+    pub(crate) retry: RetryPolicy,
+    /// Permits reserved ahead and served in process by `check`; see `with_leasing`.
+    pub(crate) leases: Option<Leases>,
+    /// Restart the window on denied requests too; set by `SessionLimiter`.
+    pub(crate) renew_on_any_request: bool,
+    pub(crate) cardinality: Option<CardinalityLimit>,
+    pub(crate) escalation: Option<EscalationPolicy>,
+    pub(crate) penalty: Option<PenaltyPolicy>,
+    /// Consult the allowlist and denylist in checks; see `with_access_lists`.
+    pub(crate) access_lists: bool,
+    pub(crate) unique_consumers_period: Option<Duration>,
+    /// Period of the sorted sets ranking identifiers; see `with_top_consumers`.
+    pub(crate) top_consumers_period: Option<Duration>,
+    /// Largest fraction fixed window expiries are randomly shortened or lengthened by; see
+    /// `with_expiry_jitter`.
+    pub(crate) expiry_jitter: f64,
+    /// Counters a fixed window is split across, each holding its share of the limit; see
+    /// `with_sharding`.
+    pub(crate) shards: u32,
+    /// Buckets a sliding window counter's window is split into; see `with_counter_buckets`.
+    pub(crate) counter_buckets: u32,
+    /// Most tokens a token bucket refills over one gap between requests, and what a new
+    /// bucket starts with; see `with_idle_credit`.
+    pub(crate) idle_credit: Option<u64>,
+    /// Hold back capacity reserved ahead in fixed window checks; see `with_reservations`.
+    pub(crate) reservations: bool,
+    /// Check with plain commands instead of the check script; see `with_script_free_mode`.
+    pub(crate) script_free: bool,
+    pub(crate) history: Option<UsageHistory>,
+    /// Units an identifier may borrow from its next window; 0 disables borrowing.
+    pub(crate) max_borrow: u64,
+    /// How long final window counts are kept for analytics, if at all.
+    pub(crate) analytics_retention: Option<Duration>,
+    pub(crate) normalization: Option<Normalization>,
+    pub(crate) identifier_policy: Option<IdentifierPolicy>,
+    /// Applied last, e.g. to hash identifiers; see `with_key_transform`.
+    pub(crate) key_transform: Option<Arc<dyn KeyTransform>>,
+    /// Callers currently blocked in `wait`.
+    pub(crate) waiters: AtomicUsize,
+    pub(crate) max_waiters: Option<usize>,
+    /// The limiter-wide limits last read from or written to Redis, enforced by the failure
+    /// fallback in place of the configured ones.
+    stored_limits: Mutex<Option<LimitOverride>>,
+    /// Detected on first use and kept for the limiter's lifetime.
+    pub(crate) capabilities: OnceLock<ServerCapabilities>,
+    pub(crate) latency: LatencyTracker,
+    pub(crate) sampling: TelemetrySampling,
+    #[cfg(feature = "tracing")]
+    pub(crate) traced_identifier: TracedIdentifier,
+    metrics: Option<CheckRecorder>,
+    sample_decisions: Sampler,
+    sample_alerts: Sampler,
+}
+
+impl LimiterCore {
+    pub(crate) fn new(key_prefix: &str, max_requests: u64, window: Duration) -> Self {
+        LimiterCore {
+            key_prefix: key_prefix.to_string(),
+            max_requests,
+            window,
+            window_mode: WindowMode::default(),
+            algorithm: Algorithm::default(),
+            refill_rate: None,
+            additional_limits: Vec::new(),
+            clock: None,
+            failure: FailureHandling::default(),
+            retry: RetryPolicy::default(),
+            leases: None,
+            renew_on_any_request: false,
+            cardinality: None,
+            escalation: None,
+            penalty: None,
+            access_lists: false,
+            unique_consumers_period: None,
+            top_consumers_period: None,
+            expiry_jitter: 0.0,
+            shards: 1,
+            counter_buckets: 1,
+            idle_credit: None,
+            reservations: false,
+            script_free: false,
+            history: None,
+            max_borrow: 0,
+            analytics_retention: None,
+            normalization: None,
+            identifier_policy: None,
+            key_transform: None,
+            waiters: AtomicUsize::new(0),
+            max_waiters: None,
+            stored_limits: Mutex::new(None),
+            capabilities: OnceLock::new(),
+            latency: LatencyTracker::new(),
+            sampling: TelemetrySampling::default(),
+            #[cfg(feature = "tracing")]
+            traced_identifier: TracedIdentifier::default(),
+            metrics: None,
+            sample_decisions: Sampler::new(1.0),
+            sample_alerts: Sampler::new(1.0),
+        }
+    }
+
+    pub(crate) fn set_sampling(&mut self, sampling: TelemetrySampling) {
+        self.latency.set_sampling(&sampling);
+        self.sample_decisions = Sampler::new(sampling.decision_rate());
+        self.sample_alerts = Sampler::new(sampling.hook_rate());
+        if let Some(metrics) = &self.metrics {
+            let sink = Arc::clone(metrics.sink());
+            self.metrics = Some(CheckRecorder::new(sink, sampling.max_label_values()));
+        }
+        self.sampling = sampling;
+    }
+
+    pub(crate) fn set_metrics(&mut self, sink: Arc<dyn MetricsSink>) {
+        self.latency
+            .set_metrics(Arc::clone(&sink), &self.key_prefix);
+        self.metrics = Some(CheckRecorder::new(sink, self.sampling.max_label_values()));
+    }
+
+    /// Registers a caller about to block for capacity, failing fast with
+    /// `RateLimiterError::WaitQueueFull` if `max_waiters` are already waiting. The caller
+    /// counts as waiting until the guard is dropped.
+    pub(crate) fn enter_wait(&self) -> Result<WaiterGuard<'_>, RateLimiterError> {
+        let waiting = self.waiters.fetch_add(1, Ordering::Relaxed);
+        let guard = WaiterGuard(&self.waiters);
+        match self.max_waiters {
+            Some(max_waiters) if waiting >= max_waiters => {
+                Err(RateLimiterError::WaitQueueFull { max_waiters })
+            }
+            _ => Ok(guard),
+        }
+    }
+
+    /// Keys holding `identifier`'s consumption state (not its override or usage history).
+    pub(crate) fn state_keys(&self, identifier: &str) -> Vec<String> {
+        vec![
+            self.key(identifier),
+            self.key(&format!("{}:{}", DEBT_KEY, identifier)),
+            self.key(&format!("{}:{}", SHADOW_KEY, identifier)),
+            self.key(&format!("{}:{}", LAST_WINDOW_KEY, identifier)),
+            self.key(&format!("{}:{}", SLOTS_KEY, identifier)),
+            self.key(&format!("{}:{}", VIOLATIONS_KEY, identifier)),
+            self.key(&format!("{}:{}", BAN_KEY, identifier)),
+            self.key(&format!("{}:{}", PENALTY_KEY, identifier)),
+            self.reservations_key(identifier),
+            self.key(&format!("{}:{}", REQUESTS_KEY, identifier)),
+        ]
+        .into_iter()
+        .chain(self.additional_limits.iter().map(|rate| {
+            self.key(&format!(
+                "{}:{}:{}",
+                RULE_KEY,
+                rate.window.as_millis(),
+                identifier
+            ))
+        }))
+        .chain((1..self.shards).map(|shard| self.shard_key(identifier, shard)))
+        .collect()
+    }
+
+    /// Shards in effect: only fixed windows are sharded.
+    fn shard_count(&self) -> u32 {
+        match self.algorithm {
+            Algorithm::FixedWindow => self.shards.max(1),
+            _ => 1,
+        }
+    }
+
+    /// The counter of `identifier`'s `shard`: the first is the identifier's own key, so an
+    /// unsharded limiter reads it as usual.
+    fn shard_key(&self, identifier: &str, shard: u32) -> String {
+        match shard {
+            0 => self.key(identifier),
+            _ => self.key(&format!("{}:{}:{}", SHARD_KEY, shard, identifier)),
+        }
+    }
+
+    /// Fails with `RateLimiterError::Unsupported` if the server lacks a command this limiter's
+    /// configuration needs for checks.
+    pub(crate) fn verify_compatibility(
+        &self,
+        capabilities: &ServerCapabilities,
+    ) -> Result<(), RateLimiterError> {
+        capabilities.require(capabilities.scripting, "Lua scripting (EVALSHA)")?;
+        capabilities.require(capabilities.millisecond_expiry, "PTTL and SET PX/EX")?;
+        if self.unique_consumers_period.is_some() {
+            capabilities.require(capabilities.hyperloglog, "HyperLogLog (PFADD)")?;
+        }
+        let calendar = matches!(
+            self.window_mode,
+            WindowMode::CalendarDay | WindowMode::CalendarMonth
+        );
+        if self.algorithm != Algorithm::FixedWindow || calendar {
+            capabilities.require(
+                capabilities.script_time_writes,
+                "writes after TIME in scripts",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Converts `identifier`, then applies the normalization and the identifier policy, if
+    /// any; every public method taking an identifier calls this once before building keys.
+    pub(crate) fn identifier<'a, I: ToIdentifier + ?Sized>(
+        &self,
+        identifier: &'a I,
+    ) -> Result<Cow<'a, str>, RateLimiterError> {
+        let mut identifier = identifier.to_identifier();
+        if let Some(normalization) = &self.normalization {
+            identifier = map_cow(identifier, |s| Ok(normalization.apply(s)))?;
+        }
+        if let Some(policy) = &self.identifier_policy {
+            identifier = map_cow(identifier, |s| policy.apply(s))?;
+        }
+        if let Some(transform) = &self.key_transform {
+            identifier = map_cow(identifier, |s| Ok(transform.transform(s)))?;
+        }
+        Ok(identifier)
+    }
+
+    /// The injected clock's time, or the system's.
+    pub(crate) fn now(&self) -> SystemTime {
+        self.clock
+            .as_ref()
+            .map_or_else(SystemTime::now, |clock| clock.now())
+    }
+
+    /// Reads the Redis server clock, which the scripts time windows and periods by, or `None`
+    /// with an injected clock, which replaces it.
+    pub(crate) fn server_time_cmd(&self) -> Option<redis::Cmd> {
+        self.clock.is_none().then(|| redis::cmd("TIME"))
+    }
+
+    /// The time read by `server_time_cmd` (seconds and microseconds), or the injected clock's.
+    pub(crate) fn server_now(&self, time: Option<(u64, u64)>) -> SystemTime {
+        match time {
+            Some((secs, micros)) => {
+                UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(micros)
+            }
+            None => self.now(),
+        }
+    }
+
+    /// Whether reading usage depends on the time: sliding windows are counted back from it,
+    /// and a token bucket with an idle credit refilled up to it.
+    pub(crate) fn usage_needs_time(&self) -> bool {
+        match self.algorithm {
+            Algorithm::SlidingWindowLog | Algorithm::SlidingWindowCounter => true,
+            Algorithm::TokenBucket => self.idle_credit.is_some(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn key(&self, identifier: &str) -> String {
+        format!("{}:{}", self.key_prefix, identifier)
+    }
+
+    /// One step of iterating over every key under the prefix with `SCAN`, starting at
+    /// `cursor`. Glob characters in the prefix are escaped so they match only themselves.
+    pub(crate) fn scan_prefix_cmd(&self, cursor: u64) -> redis::Cmd {
+        let mut pattern = String::with_capacity(self.key_prefix.len() + 2);
+        for c in self.key_prefix.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push_str(":*");
+        let mut cmd = redis::cmd("SCAN");
+        cmd.arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(SCAN_BATCH);
+        cmd
+    }
+
+    pub(crate) fn override_key(&self, identifier: &str) -> String {
+        self.key(&format!("{}:{}", OVERRIDE_KEY, identifier))
+    }
+
+    pub(crate) fn override_index_key(&self) -> String {
+        self.key(OVERRIDE_INDEX_KEY)
+    }
+
+    pub(crate) fn limits_key(&self) -> String {
+        self.key(LIMITS_KEY)
+    }
+
+    /// Remembers the limiter-wide limits just read from or written to Redis.
+    pub(crate) fn cache_limits(&self, limits: Option<LimitOverride>) {
+        // A poisoned lock only means a panic mid-assignment; the value is still usable.
+        *self
+            .stored_limits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = limits;
+    }
+
+    /// The limit and window to enforce without Redis: the cached stored limits over the
+    /// configured ones.
+    fn fallback_limits(&self) -> (u64, Duration) {
+        let stored = *self
+            .stored_limits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let config =
+            EffectiveConfig::resolve(self.max_requests, self.window, stored.as_ref(), None, None);
+        (config.max_requests, config.window)
+    }
+
+    /// Reads the counter, its remaining TTL, any override and the stored limits in a single
+    /// round trip.
+    ///
+    /// A sliding window log is counted from the configured window back, and a sliding window
+    /// counter weighted, as of `now`, which should be the server's (see `server_now`).
+    /// A token bucket's key expires once it is full again, a leaky bucket's once it has
+    /// drained and a GCRA key at its theoretical arrival time, so their `PTTL` gives the
+    /// consumed capacity (see `usage_from_reply`).
+    pub(crate) fn usage_pipeline(&self, identifier: &str, now: SystemTime) -> redis::Pipeline {
+        let mut pipe = redis::pipe();
+        self.add_usage_cmds(&mut pipe, identifier, now);
+        pipe
+    }
+
+    fn add_usage_cmds(&self, pipe: &mut redis::Pipeline, identifier: &str, now: SystemTime) {
+        let key = self.key(identifier);
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self.algorithm {
+            Algorithm::FixedWindow if self.shard_count() > 1 => {
+                let shards: Vec<String> = (0..self.shard_count())
+                    .map(|shard| self.shard_key(identifier, shard))
+                    .collect();
+                pipe.cmd("MGET").arg(shards)
+            }
+            Algorithm::FixedWindow => pipe.get(&key),
+            Algorithm::TokenBucket if self.idle_credit.is_some() => {
+                pipe.hget(&key, &["tokens", "ts"])
+            }
+            Algorithm::TokenBucket | Algorithm::Gcra | Algorithm::LeakyBucket => pipe.exists(&key),
+            Algorithm::SlidingWindowLog => {
+                let since = now.saturating_sub(self.window).as_millis() as u64;
+                pipe.cmd("ZCOUNT")
+                    .arg(&key)
+                    .arg(format!("({}", since))
+                    .arg("+inf")
+            }
+            Algorithm::SlidingWindowCounter => {
+                let index = counter_window(now, self.counter_bucket()).0;
+                let buckets = u64::from(self.counter_buckets.max(1));
+                let fields: Vec<u64> = (index.saturating_sub(buckets)..=index).collect();
+                pipe.hget(&key, fields)
+            }
+        };
+        pipe.cmd("PTTL")
+            .arg(&key)
+            .add_command(overrides::get_cmd(&self.override_key(identifier)))
+            .add_command(overrides::get_cmd(&self.limits_key()));
+    }
+
+    /// The identifiers of the counters among `keys`, a page of `scan_prefix_cmd`, skipping the
+    /// limiter's own `__name__` keys.
+    pub(crate) fn identifiers_in(&self, keys: Vec<String>) -> Vec<String> {
+        keys.into_iter()
+            .filter_map(|mut key| {
+                let identifier = key.get(self.key_prefix.len() + 1..)?;
+                if identifier.starts_with("__") {
+                    return None;
+                }
+                Some(key.split_off(self.key_prefix.len() + 1))
+            })
+            .collect()
+    }
+
+    /// Reads the usage of each of `identifiers` in one round trip, as `usage_pipeline` does.
+    pub(crate) fn usage_batch_pipeline(
+        &self,
+        identifiers: &[String],
+        now: SystemTime,
+    ) -> redis::Pipeline {
+        let mut pipe = redis::pipe();
+        for identifier in identifiers {
+            self.add_usage_cmds(&mut pipe, identifier, now);
+        }
+        pipe
+    }
+
+    /// Pairs identifiers with the reply of `usage_batch_pipeline`, skipping counters that
+    /// expired since they were scanned.
+    pub(crate) fn active_from_reply(
+        &self,
+        identifiers: Vec<String>,
+        reply: Vec<redis::Value>,
+        now: SystemTime,
+    ) -> redis::RedisResult<Vec<ActiveIdentifier>> {
+        let mut active = Vec::with_capacity(identifiers.len());
+        for (identifier, reads) in identifiers.into_iter().zip(reply.chunks(4)) {
+            let pttl: i64 = redis::from_redis_value(&reads[1])?;
+            if pttl == -2 {
+                continue;
+            }
+            let usage = self.usage_from_reply(
+                (
+                    reads[0].clone(),
+                    pttl,
+                    redis::from_redis_value(&reads[2])?,
+                    redis::from_redis_value(&reads[3])?,
+                ),
+                now,
+            );
+            active.push(ActiveIdentifier {
+                identifier,
+                used: usage.consumed,
+                ttl: usage.resets_in,
+            });
+        }
+        Ok(active)
+    }
+
+    /// Decodes the reply of `usage_pipeline`, read as of `now`.
+    pub(crate) fn usage_from_reply(&self, reply: UsageReply, now: SystemTime) -> Usage {
+        let (value, pttl, (limit, window), stored) = reply;
+        let config = self.effective_config(stored, (limit, window));
+        let count = match self.algorithm {
+            Algorithm::FixedWindow if self.shard_count() > 1 => {
+                let counts: Vec<Option<u64>> = redis::from_redis_value(&value).unwrap_or_default();
+                counts
+                    .into_iter()
+                    .flatten()
+                    .reduce(|total, count| total + count)
+            }
+            Algorithm::FixedWindow | Algorithm::SlidingWindowLog => {
+                redis::from_redis_value(&value).ok().flatten()
+            }
+            Algorithm::TokenBucket if self.idle_credit.is_some() => {
+                // What the last request left, refilled since then as the script would.
+                let fields: Vec<Option<f64>> = redis::from_redis_value(&value).unwrap_or_default();
+                let limit = config.max_requests as f64;
+                let credit = self
+                    .idle_credit
+                    .map_or(limit, |credit| limit.min(credit as f64));
+                let (tokens, period) = self.refill(config.max_requests, config.window);
+                let rate = tokens as f64 / period.as_millis().max(1) as f64;
+                let available = match fields[..] {
+                    [Some(left), Some(at)] => {
+                        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                        let idle = (now.as_millis() as f64 - at).max(0.0);
+                        limit.min(left + credit.min(idle * rate))
+                    }
+                    _ => credit,
+                };
+                Some(config.max_requests - available.floor() as u64)
+            }
+            Algorithm::TokenBucket | Algorithm::Gcra | Algorithm::LeakyBucket => {
+                let (tokens, period) = self.refill(config.max_requests, config.window);
+                let missing = pttl.max(0) as f64 * tokens as f64 / period.as_millis().max(1) as f64;
+                Some((missing.ceil() as u64).min(config.max_requests))
+            }
+            Algorithm::SlidingWindowCounter => {
+                let counts: Vec<Option<u64>> = redis::from_redis_value(&value).unwrap_or_default();
+                let (oldest, recent) = match counts.split_first() {
+                    Some((oldest, recent)) => {
+                        (oldest.unwrap_or(0), recent.iter().flatten().sum::<u64>())
+                    }
+                    None => (0, 0),
+                };
+                let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let overlap = 1.0 - counter_window(now, self.counter_bucket()).1;
+                Some((oldest as f64 * overlap).floor() as u64 + recent)
+            }
+        };
+        let usage = Usage::from_raw(count, pttl, config.max_requests, config.window);
+        trace::record_remaining(usage.remaining);
+        usage
+    }
+
+    /// Reads an identifier's override and the stored limits, the reply of
+    /// `effective_config`.
+    pub(crate) fn effective_config_pipeline(&self, identifier: &str) -> redis::Pipeline {
+        let mut pipe = redis::pipe();
+        pipe.add_command(overrides::get_cmd(&self.override_key(identifier)))
+            .add_command(overrides::get_cmd(&self.limits_key()));
+        pipe
+    }
+
+    /// Resolves the configuration from the fields of the stored limits and of an override,
+    /// caching the stored limits.
+    pub(crate) fn effective_config(
+        &self,
+        (stored_limit, stored_window): (Option<u64>, Option<u64>),
+        (limit, window): (Option<u64>, Option<u64>),
+    ) -> EffectiveConfig {
+        let stored = LimitOverride::from_fields(stored_limit, stored_window);
+        self.cache_limits(stored);
+        EffectiveConfig::resolve(
+            self.max_requests,
+            self.window,
+            stored.as_ref(),
+            LimitOverride::from_fields(limit, window).as_ref(),
+            None,
+        )
+    }
+
+    /// Index of the unique consumers period containing `now`, if tracking is enabled.
+    fn consumers_period(&self, now: SystemTime) -> Option<u64> {
+        Some(period_index(self.unique_consumers_period?, now))
+    }
+
+    /// Reads the `n` identifiers ranking highest in the `name` sorted set of the top consumers
+    /// period containing `now`, or `None` if tracking is off or `n` is 0.
+    fn top_cmd(&self, name: &str, n: usize, now: SystemTime) -> Option<redis::Cmd> {
+        let period = period_index(self.top_consumers_period?, now);
+        (n > 0).then(|| top::query_cmd(&self.key(&format!("{}:{}", name, period)), n))
+    }
+
+    pub(crate) fn top_consumers_cmd(&self, n: usize, now: SystemTime) -> Option<redis::Cmd> {
+        self.top_cmd(TOP_KEY, n, now)
+    }
+
+    pub(crate) fn violations_top_cmd(&self, n: usize, now: SystemTime) -> Option<redis::Cmd> {
+        self.top_cmd(TOP_VIOLATIONS_KEY, n, now)
+    }
+
+    /// Key of the HyperLogLog for the period containing `now`, if tracking is enabled.
+    pub(crate) fn consumers_key(&self, now: SystemTime) -> Option<String> {
+        let period = self.consumers_period(now)?;
+        Some(self.key(&format!("{}:{}", CONSUMERS_KEY, period)))
+    }
+
+    fn history_key(&self, identifier: &str, period: u64) -> String {
+        self.key(&format!("{}:{}:{}", HISTORY_KEY, identifier, period))
+    }
+
+    /// Reads the history hashes covering `range`, up to `now`, returning the pipeline and the
+    /// bucket starts to decode its reply with.
+    pub(crate) fn history_pipeline(
+        &self,
+        identifier: &str,
+        range: &Range<SystemTime>,
+        now: SystemTime,
+    ) -> Result<(redis::Pipeline, Vec<u64>), RateLimiterError> {
+        let history = self.history.as_ref().ok_or_else(|| {
+            RateLimiterError::InvalidConfig("usage history is not enabled".to_string())
+        })?;
+        let buckets = history.buckets(range, now);
+        let mut periods: Vec<u64> = buckets.iter().map(|&b| history.period(b)).collect();
+        periods.dedup();
+
+        let mut pipe = redis::pipe();
+        for period in periods {
+            pipe.hgetall(self.history_key(identifier, period));
+        }
+        Ok((pipe, buckets))
+    }
+
+    /// Reads what's needed to report the previous window's final count: whether a window is
+    /// live, the shadow copy of the counter and the archived count.
+    pub(crate) fn last_window_pipeline(
+        &self,
+        identifier: &str,
+    ) -> Result<redis::Pipeline, RateLimiterError> {
+        if self.analytics_retention.is_none() {
+            return Err(RateLimiterError::InvalidConfig(
+                "window analytics are not enabled".to_string(),
+            ));
+        }
+        let mut pipe = redis::pipe();
+        pipe.exists(self.key(identifier))
+            .get(self.key(&format!("{}:{}", SHADOW_KEY, identifier)))
+            .get(self.key(&format!("{}:{}", LAST_WINDOW_KEY, identifier)));
+        Ok(pipe)
+    }
+
+    pub(crate) fn last_window_from_reply(
+        &self,
+        (live, shadow, archived): (bool, Option<u64>, Option<u64>),
+    ) -> Option<u64> {
+        // Once the live window expires the shadow holds its final count until it's archived.
+        if live {
+            archived
+        } else {
+            shadow
+        }
+    }
+
+    /// Reads the time left until `identifier`'s theoretical arrival time: its key expires then.
+    pub(crate) fn theoretical_arrival_cmd(
+        &self,
+        identifier: &str,
+    ) -> Result<redis::Cmd, RateLimiterError> {
+        if self.algorithm != Algorithm::Gcra {
+            return Err(RateLimiterError::InvalidConfig(
+                "the theoretical arrival time requires Algorithm::Gcra".to_string(),
+            ));
+        }
+        let mut cmd = redis::cmd("PTTL");
+        cmd.arg(self.key(identifier));
+        Ok(cmd)
+    }
+
+    pub(crate) fn history_from_reply(
+        &self,
+        buckets: Vec<u64>,
+        reply: Vec<HashMap<u64, u64>>,
+    ) -> Vec<UsageBucket> {
+        let counts: HashMap<u64, u64> = reply.into_iter().flatten().collect();
+        buckets
+            .into_iter()
+            .map(|start| UsageBucket {
+                start: UNIX_EPOCH + Duration::from_secs(start),
+                count: counts.get(&start).copied().unwrap_or(0),
+            })
+            .collect()
+    }
+
+    /// Builds the check script call; `per_call` takes precedence over any override stored in
+    /// Redis, which takes precedence over the stored limits, then the configured values. With a
+    /// `cost`, the request takes that many units and takes nothing if denied.
+    ///
+    /// This is the hot path: keys are rendered into one scratch buffer and numbers are encoded
+    /// straight into the command, so beyond the identifier's own processing a check allocates
+    /// only the command and that buffer.
+    pub(crate) fn check_call(
+        &self,
+        identifier: &str,
+        per_call: Option<&LimitOverride>,
+        cost: Option<u64>,
+    ) -> ScriptCall<'static> {
+        self.check_call_with(identifier, per_call, cost, false, None)
+    }
+
+    /// Builds the check script call for a request with the ID `request_id`, which is allowed
+    /// without counting anything if a request with that ID was already allowed in the window.
+    pub(crate) fn idempotent_check_call(
+        &self,
+        identifier: &str,
+        request_id: &str,
+    ) -> ScriptCall<'static> {
+        self.check_call_with(identifier, None, None, false, Some(request_id))
+    }
+
+    /// Builds the call answering `check` and `check_detailed`: the check script, or in
+    /// script-free mode the fixed window counter as plain commands, with the per-call limits
+    /// or the configured ones.
+    pub(crate) fn decision_call(
+        &self,
+        identifier: &str,
+        per_call: Option<&LimitOverride>,
+        cost: Option<u64>,
+    ) -> Result<DecisionCall, RateLimiterError> {
+        if !self.script_free {
+            return Ok(DecisionCall::Script(
+                self.check_call(identifier, per_call, cost),
+            ));
+        }
+        self.validate_script_free()?;
+        let window = per_call.and_then(|o| o.window).unwrap_or(self.window);
+        let now = self.now();
+        let expiry = calendar::window_end(self.window_mode, now)
+            .map_or(window, |end| end.duration_since(now).unwrap_or_default());
+        Ok(DecisionCall::Plain(PlainCheck {
+            key: self.key(identifier),
+            cost: cost.unwrap_or(1),
+            weighted: cost.is_some(),
+            limit: per_call
+                .and_then(|o| o.max_requests)
+                .unwrap_or(self.max_requests),
+            expiry_ms: expiry.as_millis().max(1) as u64,
+            extend: match self.window_mode {
+                _ if self.renew_on_any_request => 2,
+                WindowMode::SlidingInactivity => 1,
+                _ => 0,
+            },
+        }))
+    }
+
+    /// Rejects script-free mode with an algorithm other than the fixed window, the only one
+    /// plain commands can run.
+    pub(crate) fn validate_script_free(&self) -> Result<(), RateLimiterError> {
+        if self.algorithm != Algorithm::FixedWindow {
+            return Err(RateLimiterError::InvalidConfig(
+                "script-free mode requires Algorithm::FixedWindow".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Builds the check script call taking as many units as are left of up to `max`, at least
+    /// one; the units taken are the last field of an allowed reply (see `granted`).
+    pub(crate) fn consume_up_to_call(&self, identifier: &str, max: u64) -> ScriptCall<'static> {
+        self.check_call_with(identifier, None, Some(max), true, None)
+    }
+
+    fn check_call_with(
+        &self,
+        identifier: &str,
+        per_call: Option<&LimitOverride>,
+        cost: Option<u64>,
+        partial: bool,
+        request_id: Option<&str>,
+    ) -> ScriptCall<'static> {
+        let (cardinality_max, deny_new) = match &self.cardinality {
+            Some(limit) => (
+                limit.max_identifiers,
+                limit.policy == CardinalityPolicy::DenyNew,
+            ),
+            None => (0, false),
+        };
+        // Periods are in seconds; the script picks the current one by the server clock and
+        // appends its index to the key.
+        let consumers_period = self
+            .unique_consumers_period
+            .map(|period| period.as_secs().max(1));
+        let top = self
+            .top_consumers_period
+            .map(|period| period.as_secs().max(1));
+        let history = self
+            .history
+            .as_ref()
+            .map(|history| (history.bucket().as_secs(), history.retention().as_secs()));
+
+        // A sharded counter is checked on one shard picked at random.
+        let shards = self.shard_count();
+        let shard = match shards {
+            1 => 0,
+            _ => ((retry::random_fraction() * f64::from(shards)) as u32).min(shards - 1),
+        };
+        let max_borrow = match self.algorithm {
+            Algorithm::FixedWindow if shards == 1 => self.max_borrow,
+            _ => 0,
+        };
+        // The analytics shadow mirrors a whole counter, which a shard isn't.
+        let analytics_retention = self.analytics_retention.filter(|_| shards == 1);
+        let reservations =
+            self.reservations && self.algorithm == Algorithm::FixedWindow && shards == 1;
+
+        // Only the keys in use are passed, so that on Redis Cluster all of a check's keys can
+        // share the key prefix's slot; the script learns which from this mask.
+        let optional_keys = [
+            self.cardinality.is_some(),
+            consumers_period.is_some(),
+            history.is_some(),
+            max_borrow > 0,
+            analytics_retention.is_some(),
+            analytics_retention.is_some(),
+            self.access_lists,
+            self.access_lists,
+            top.is_some(),
+            top.is_some(),
+            self.penalty.is_some(),
+            reservations,
+            request_id.is_some(),
+        ];
+        let key_mask = optional_keys
+            .iter()
+            .rev()
+            .fold(0u32, |mask, &used| mask << 1 | u32::from(used));
+        let unused_keys = optional_keys.iter().filter(|&&used| !used).count();
+
+        let rules = self.additional_limits.len();
+        let mut call = ScriptCall::new(
+            check_script(),
+            CHECK_SCRIPT,
+            18 + rules - unused_keys,
+            (6 + rules) * (self.key_prefix.len() + identifier.len()) + 256 + 48 * rules,
+        );
+        let mut key = String::with_capacity(self.key_prefix.len() + identifier.len() + 32);
+        let mut push_key = |cmd: &mut redis::Cmd, parts: fmt::Arguments<'_>| {
+            key.clear();
+            // Writing to a `String` can't fail.
+            let _ = write!(key, "{}:{}", self.key_prefix, parts);
+            cmd.arg(key.as_str());
+        };
+
+        let cmd = &mut call.cmd;
+        match shard {
+            0 => push_key(cmd, format_args!("{}", identifier)),
+            _ => push_key(cmd, format_args!("{}:{}:{}", SHARD_KEY, shard, identifier)),
+        }
+        if self.cardinality.is_some() {
+            push_key(cmd, format_args!("{}", IDENTIFIERS_KEY));
+        }
+        if consumers_period.is_some() {
+            push_key(cmd, format_args!("{}", CONSUMERS_KEY));
+        }
+        push_key(cmd, format_args!("{}:{}", OVERRIDE_KEY, identifier));
+        if history.is_some() {
+            push_key(cmd, format_args!("{}:{}", HISTORY_KEY, identifier));
+        }
+        if max_borrow > 0 {
+            push_key(cmd, format_args!("{}:{}", DEBT_KEY, identifier));
+        }
+        if analytics_retention.is_some() {
+            push_key(cmd, format_args!("{}:{}", SHADOW_KEY, identifier));
+            push_key(cmd, format_args!("{}:{}", LAST_WINDOW_KEY, identifier));
+        }
+        for rate in &self.additional_limits {
+            push_key(
+                cmd,
+                format_args!("{}:{}:{}", RULE_KEY, rate.window.as_millis(), identifier),
+            );
+        }
+        push_key(cmd, format_args!("{}:{}", VIOLATIONS_KEY, identifier));
+        push_key(cmd, format_args!("{}:{}", BAN_KEY, identifier));
+        if self.access_lists {
+            push_key(cmd, format_args!("{}", AccessList::Allow.key_name()));
+            push_key(cmd, format_args!("{}", AccessList::Deny.key_name()));
+        }
+        push_key(cmd, format_args!("{}", LIMITS_KEY));
+        if top.is_some() {
+            push_key(cmd, format_args!("{}", TOP_KEY));
+            push_key(cmd, format_args!("{}", TOP_VIOLATIONS_KEY));
+        }
+        if self.penalty.is_some() {
+            push_key(cmd, format_args!("{}:{}", PENALTY_KEY, identifier));
+        }
+        if reservations {
+            push_key(cmd, format_args!("{}:{}", RESERVATIONS_KEY, identifier));
+        }
+        if request_id.is_some() {
+            push_key(cmd, format_args!("{}:{}", REQUESTS_KEY, identifier));
+        }
+
+        cmd.arg(self.max_requests)
+            .arg(self.window.as_millis().max(1) as u64)
+            .arg(if self.renew_on_any_request {
+                2
+            } else {
+                self.window_mode.as_arg()
+            })
+            .arg(cardinality_max)
+            .arg(u8::from(deny_new))
+            .arg(consumers_period.unwrap_or(0))
+            .arg(identifier);
+        match per_call.and_then(|o| o.max_requests) {
+            Some(limit) => cmd.arg(limit),
+            None => cmd.arg(""),
+        };
+        match per_call.and_then(|o| o.window) {
+            Some(window) => cmd.arg(window.as_millis().max(1) as u64),
+            None => cmd.arg(""),
+        };
+        let (bucket, retention) = history.unwrap_or((0, 0));
+        cmd.arg(bucket)
+            .arg(retention)
+            .arg(max_borrow)
+            .arg(analytics_retention.map_or(0, |r| r.as_secs().max(1)))
+            .arg(self.algorithm.as_arg());
+        match self.refill_rate {
+            Some(rate) => cmd
+                .arg(rate.max_requests)
+                .arg(rate.window.as_millis().max(1) as u64),
+            None => cmd.arg("").arg(""),
+        };
+        match cost {
+            Some(cost) => cmd.arg(cost),
+            None => cmd.arg(""),
+        };
+        match &self.clock {
+            Some(clock) => cmd.arg(
+                clock
+                    .now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+            ),
+            None => cmd.arg(""),
+        };
+        cmd.arg(rules);
+        for rate in &self.additional_limits {
+            cmd.arg(rate.max_requests)
+                .arg(rate.window.as_millis().max(1) as u64);
+        }
+        match &self.escalation {
+            Some(policy) => cmd
+                .arg(policy.max_violations)
+                .arg(policy.period.as_millis().max(1) as u64)
+                .arg(policy.ban_duration.as_millis().max(1) as u64),
+            None => cmd.arg(0).arg(0).arg(0),
+        };
+        let jitter = if self.expiry_jitter > 0.0 {
+            self.expiry_jitter * (2.0 * retry::random_fraction() - 1.0)
+        } else {
+            0.0
+        };
+        cmd.arg(top.unwrap_or(0)).arg(u8::from(partial)).arg(jitter);
+        match &self.penalty {
+            Some(policy) => cmd
+                .arg(policy.initial.as_millis().max(1) as u64)
+                .arg(policy.max.max(policy.initial).as_millis().max(1) as u64),
+            None => cmd.arg(0).arg(0),
+        };
+        cmd.arg(shards)
+            .arg(shard)
+            .arg(request_id.unwrap_or(""))
+            .arg(key_mask)
+            .arg(self.counter_buckets.max(1));
+        match self.idle_credit {
+            Some(credit) => cmd.arg(credit),
+            None => cmd.arg(""),
+        };
+        call
+    }
+
+    /// Length of a sliding window counter's buckets: the window split `counter_buckets` ways,
+    /// in whole milliseconds as the scripts count them.
+    fn counter_bucket(&self) -> Duration {
+        let window_ms = self.window.as_millis().max(1) as u64;
+        Duration::from_millis((window_ms / u64::from(self.counter_buckets.max(1))).max(1))
+    }
+
+    /// Rejects a zero cost, which would neither consume anything nor ever be denied.
+    pub(crate) fn validate_cost(cost: u64) -> Result<(), RateLimiterError> {
+        if cost == 0 {
+            return Err(RateLimiterError::InvalidConfig(
+                "cost must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rejects an empty request ID, which can't tell requests apart.
+    pub(crate) fn validate_request_id(request_id: &str) -> Result<(), RateLimiterError> {
+        if request_id.is_empty() {
+            return Err(RateLimiterError::InvalidConfig(
+                "request ID must not be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_outcome(
+        &self,
+        identifier: &str,
+        result: Result<DecisionReply, redis::RedisError>,
+    ) -> Result<(), RateLimiterError> {
+        let remaining = result.as_ref().ok().map(|&(_, _, remaining, ..)| remaining);
+        let outcome = match result.map(|(code, _, _, _, retry_after, _)| (code, retry_after)) {
+            Ok((DENIED, retry_after)) => Err(RateLimiterError::RateLimitExceeded {
+                retry_after: Duration::from_millis(retry_after),
+            }),
+            Ok((ALLOWED_OVER_CARDINALITY, _)) => {
+                if let Some(limit) = &self.cardinality {
+                    if self.sample_alerts.sample() {
+                        limit.alert(&self.key_prefix, identifier);
+                    }
+                }
+                Ok(())
+            }
+            Ok((DENIED_OVER_CARDINALITY, _)) => Err(RateLimiterError::CardinalityLimitExceeded),
+            Ok((DENYLISTED, _)) => Err(RateLimiterError::Denylisted),
+            Ok((BANNED, banned_for)) => Err(RateLimiterError::Banned {
+                until: self.now() + Duration::from_millis(banned_for),
+            }),
+            Ok((ALLOWED, _)) => Ok(()),
+            Ok(_) => Ok(()), // Any other value means we're under the limit
+            Err(e) => Err(RateLimiterError::Redis(e)),
+        };
+        self.record_decision(identifier, &outcome, remaining);
+        outcome
+    }
+
+    /// Interprets the reply of a check as a decision: denials become part of the decision, only
+    /// failures are errors.
+    pub(crate) fn decision_outcome(
+        &self,
+        identifier: &str,
+        result: Result<DecisionReply, redis::RedisError>,
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        let reply = result.as_ref().copied().unwrap_or_default();
+        let outcome = self.check_outcome(identifier, result);
+        let reason = match outcome {
+            Ok(()) => None,
+            Err(e) => Some(e.denial_reason().ok_or(e)?),
+        };
+        let (_, limit, remaining, reset_after, retry_after, rule) = reply;
+        Ok(RateLimitDecision {
+            allowed: reason.is_none(),
+            limit,
+            remaining,
+            reset_after: Duration::from_millis(reset_after),
+            retry_after: reason.map(|_| Duration::from_millis(retry_after)),
+            violated_rule: (reason == Some(DenialReason::WindowExhausted)).then_some(rule as usize),
+            reason,
+        })
+    }
+
+    /// Applies the failure policy or circuit breaker to a check that couldn't reach Redis, or
+    /// (`None`) that the breaker turned away (see `FailureHandling`).
+    pub(crate) fn on_failure(
+        &self,
+        identifier: &str,
+        cost: Option<u64>,
+        result: Option<Result<(), RateLimiterError>>,
+    ) -> Result<(), RateLimiterError> {
+        self.failure.apply(
+            &self.key_prefix,
+            identifier,
+            result,
+            |fallback| match fallback {
+                Fallback::Policy(policy) => policy.check_outcome(),
+                Fallback::Local(breaker) => {
+                    let local = self.fallback_limiter(breaker);
+                    match cost {
+                        Some(cost) => local.check_with_cost(identifier, cost),
+                        None => local.check(identifier),
+                    }
+                }
+            },
+        )
+    }
+
+    /// Like `on_failure`, for `try_consume_up_to`: without Redis, all `max` units are allowed
+    /// or denied at once.
+    pub(crate) fn on_consume_failure(
+        &self,
+        identifier: &str,
+        max: u64,
+        result: Option<Result<u64, RateLimiterError>>,
+    ) -> Result<u64, RateLimiterError> {
+        self.failure.apply(
+            &self.key_prefix,
+            identifier,
+            result,
+            |fallback| match fallback {
+                Fallback::Policy(policy) => policy.check_outcome().map(|()| max),
+                Fallback::Local(breaker) => self
+                    .fallback_limiter(breaker)
+                    .check_with_cost(identifier, max)
+                    .map(|()| max),
+            },
+        )
+    }
+
+    /// Like `on_failure`, for checks answering with a decision.
+    pub(crate) fn on_decision_failure(
+        &self,
+        identifier: &str,
+        cost: Option<u64>,
+        result: Option<Result<RateLimitDecision, RateLimiterError>>,
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        self.failure.apply(
+            &self.key_prefix,
+            identifier,
+            result,
+            |fallback| match fallback {
+                Fallback::Policy(policy) => Ok(RateLimitDecision::from_failure(
+                    policy,
+                    self.fallback_limits().0,
+                )),
+                Fallback::Local(breaker) => {
+                    let local = self.fallback_limiter(breaker);
+                    match cost {
+                        Some(cost) => local.check_detailed_with_cost(identifier, cost),
+                        None => local.check_detailed(identifier),
+                    }
+                }
+            },
+        )
+    }
+
+    /// The circuit breaker's in-process limiter, with this limiter's window mode and clock and
+    /// the limit and window in effect when it first opened (see `fallback_limits`).
+    fn fallback_limiter<'a>(&self, breaker: &'a CircuitBreaker) -> &'a InMemoryRateLimiter {
+        breaker.fallback(|| {
+            let (max_requests, window) = self.fallback_limits();
+            let local =
+                InMemoryRateLimiter::new(max_requests, window).with_window_mode(self.window_mode);
+            match &self.clock {
+                Some(clock) => local.with_clock(clock.clone()),
+                None => local,
+            }
+        })
+    }
+
+    /// Interprets the reply of a custom script call and records the decision.
+    pub(crate) fn custom_outcome(
+        &self,
+        script: &CustomScript,
+        identifier: &str,
+        result: Result<redis::Value, redis::RedisError>,
+    ) -> Result<(), RateLimiterError> {
+        let outcome = result
+            .map_err(RateLimiterError::Redis)
+            .and_then(|reply| script.decode(reply));
+        self.record_decision(identifier, &outcome, None);
+        outcome
+    }
+
+    /// Traces a sampled check decision and reports it to the metrics sink, if any.
+    fn record_decision(
+        &self,
+        identifier: &str,
+        result: &Result<(), RateLimiterError>,
+        remaining: Option<u64>,
+    ) {
+        let outcome = match result {
+            Ok(()) => CheckOutcome::Allowed,
+            Err(e) => e
+                .denial_reason()
+                .map_or(CheckOutcome::Error, CheckOutcome::Denied),
+        };
+        let retry_after = match result {
+            Err(RateLimiterError::RateLimitExceeded { retry_after }) => Some(*retry_after),
+            Err(RateLimiterError::Banned { until }) => {
+                Some(until.duration_since(self.now()).unwrap_or_default())
+            }
+            _ => None,
+        };
+        trace::record_decision(outcome.as_str(), remaining, retry_after);
+        if !self.sample_decisions.sample() {
+            return;
+        }
+        log::trace!(
+            "rate limiter `{}`: `{}` {}",
+            self.key_prefix,
+            identifier,
+            outcome.as_str()
+        );
+        if let Some(metrics) = &self.metrics {
+            metrics.record(
+                &self.key_prefix,
+                identifier,
+                outcome,
+                self.sampling.decision_rate(),
+            );
+        }
+    }
+
+    /// Tokens added to a token bucket, or drained from a leaky bucket, per period, given the
+    /// limit and window in effect.
+    fn refill(&self, max_requests: u64, window: Duration) -> (u64, Duration) {
+        self.refill_rate.map_or((max_requests, window), |rate| {
+            (rate.max_requests, rate.window)
+        })
+    }
+
+    /// Spacing between pacing slots: the configured window spread evenly over its requests.
+    pub(crate) fn slot_interval(&self) -> Duration {
+        // In nanoseconds, since limits can exceed the `u32` that `Duration` divides by.
+        let nanos = self.window.as_nanos() / u128::from(self.max_requests.max(1));
+        Duration::from_nanos(nanos as u64).max(Duration::from_millis(1))
+    }
+
+    /// Builds the script call giving back up to `units` of `identifier`'s current window, in
+    /// its counter (every shard, first to last) and its additional limits. The reply is the
+    /// units given back.
+    pub(crate) fn refund_call(&self, identifier: &str, units: u64) -> ScriptCall<'static> {
+        let shards = self.shard_count();
+        let rules = self.additional_limits.len();
+        let keys = 2 + shards as usize + rules;
+        let mut call = ScriptCall::new(refund_script(), REFUND_SCRIPT, keys, 64 * keys + 64);
+        call.cmd
+            .arg(self.override_key(identifier))
+            .arg(self.limits_key());
+        for shard in 0..shards {
+            call.cmd.arg(self.shard_key(identifier, shard));
+        }
+        for rate in &self.additional_limits {
+            call.cmd.arg(self.key(&format!(
+                "{}:{}:{}",
+                RULE_KEY,
+                rate.window.as_millis(),
+                identifier
+            )));
+        }
+        call.cmd
+            .arg(self.algorithm.as_arg())
+            .arg(units)
+            .arg(self.max_requests)
+            .arg(self.window.as_millis().max(1) as u64)
+            .arg(shards);
+        match &self.clock {
+            Some(clock) => call.cmd.arg(
+                clock
+                    .now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+            ),
+            None => call.cmd.arg(""),
+        };
+        call.cmd.arg(self.counter_buckets.max(1));
+        call
+    }
+
+    /// Builds the slot reservation script call, whose reply is the wait in milliseconds.
+    pub(crate) fn reserve_call(&self, identifier: &str) -> ScriptCall<'static> {
+        let mut call = ScriptCall::new(reserve_script(), RESERVE_SCRIPT, 1, 64);
+        call.cmd
+            .arg(self.key(&format!("{}:{}", SLOTS_KEY, identifier)))
+            .arg(self.slot_interval().as_millis() as u64);
+        call
+    }
+
+    /// Key of the sorted set of capacity reserved ahead for `identifier`.
+    pub(crate) fn reservations_key(&self, identifier: &str) -> String {
+        self.key(&format!("{}:{}", RESERVATIONS_KEY, identifier))
+    }
+
+    /// Builds the script call earmarking `units` of `identifier`'s window from `not_before`
+    /// as the reservation `token`. The reply is the time in ms the units were reserved for,
+    /// no earlier than now, or 0 if they don't fit.
+    pub(crate) fn reserve_ahead_call(
+        &self,
+        identifier: &str,
+        units: u64,
+        not_before: SystemTime,
+        token: &str,
+    ) -> Result<ScriptCall<'static>, RateLimiterError> {
+        if !self.reservations
+            || self.algorithm != Algorithm::FixedWindow
+            || self.shard_count() > 1
+            || matches!(
+                self.window_mode,
+                WindowMode::CalendarDay | WindowMode::CalendarMonth
+            )
+        {
+            return Err(RateLimiterError::InvalidConfig(
+                "reservations require `with_reservations` on an unsharded Algorithm::FixedWindow \
+                 limiter with a window from the first request or since the last activity"
+                    .to_string(),
+            ));
+        }
+        Self::validate_cost(units)?;
+        let mut call = ScriptCall::new(reserve_ahead_script(), RESERVE_AHEAD_SCRIPT, 2, 128);
+        call.cmd
+            .arg(self.reservations_key(identifier))
+            .arg(self.key(identifier))
+            .arg(self.max_requests)
+            .arg(self.window.as_millis().max(1) as u64)
+            .arg(
+                not_before
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+            )
+            .arg(units)
+            .arg(token);
+        match &self.clock {
+            Some(clock) => call.cmd.arg(
+                clock
+                    .now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+            ),
+            None => call.cmd.arg(""),
+        };
+        Ok(call)
+    }
+
+    /// Builds the script call consuming the `units` reserved as `token` for `identifier`.
+    /// The reply is 1, or 0 if the reservation had lapsed or been cancelled.
+    pub(crate) fn confirm_reservation_call(
+        &self,
+        identifier: &str,
+        token: &str,
+        units: u64,
+    ) -> ScriptCall<'static> {
+        let mut call = ScriptCall::new(
+            confirm_reservation_script(),
+            CONFIRM_RESERVATION_SCRIPT,
+            2,
+            128,
+        );
+        call.cmd
+            .arg(self.reservations_key(identifier))
+            .arg(self.key(identifier))
+            .arg(format!("{}:{}", token, units))
+            .arg(units)
+            .arg(self.window.as_millis().max(1) as u64);
+        call
+    }
+
+    /// Removes the reservation `token` of `units`; the reply is the number removed.
+    pub(crate) fn cancel_reservation_cmd(
+        &self,
+        identifier: &str,
+        token: &str,
+        units: u64,
+    ) -> redis::Cmd {
+        let mut cmd = redis::cmd("ZREM");
+        cmd.arg(self.reservations_key(identifier))
+            .arg(format!("{}:{}", token, units));
+        cmd
+    }
+
+    /// Key of the prefix's `list` set.
+    pub(crate) fn access_list_key(&self, list: AccessList) -> String {
+        self.key(list.key_name())
+    }
+
+    /// Key of the sorted set of permits held for `identifier`.
+    pub(crate) fn permits_key(&self, identifier: &str) -> String {
+        self.key(&format!("{}:{}", PERMITS_KEY, identifier))
+    }
+
+    /// Builds the permit acquisition script call for `token`, at most `limit` held at once for
+    /// up to `window` each. The reply is whether the permit was acquired and how many
+    /// are held; a limit of 0 acquires nothing, only counting the permits held.
+    pub(crate) fn acquire_call(
+        &self,
+        identifier: &str,
+        token: &str,
+        limit: u64,
+    ) -> ScriptCall<'static> {
+        let mut call = ScriptCall::new(acquire_script(), ACQUIRE_SCRIPT, 1, 128);
+        call.cmd
+            .arg(self.permits_key(identifier))
+            .arg(limit)
+            .arg(self.window.as_millis().max(1) as u64)
+            .arg(token);
+        call
+    }
+
+    /// Builds the call pushing `token`'s expiry out to a full `window` from now, whose reply
+    /// is 0 if the permit was already released or reclaimed.
+    pub(crate) fn renew_call(&self, identifier: &str, token: &str) -> ScriptCall<'static> {
+        let mut call = ScriptCall::new(renew_script(), RENEW_SCRIPT, 1, 128);
+        call.cmd
+            .arg(self.permits_key(identifier))
+            .arg(self.window.as_millis().max(1) as u64)
+            .arg(token);
+        call
+    }
+
+    /// Builds a call of a user-supplied script with its keys, the configured limit and window,
+    /// the identifier and `args`.
+    pub(crate) fn custom_call<'a>(
+        &self,
+        script: &'a CustomScript,
+        identifier: &str,
+        args: impl redis::ToRedisArgs,
+    ) -> ScriptCall<'a> {
+        let mut call = ScriptCall::new(
+            script.script(),
+            script.source(),
+            1 + script.keys().len(),
+            2 * (self.key_prefix.len() + identifier.len()) + 64,
+        );
+        call.cmd.arg(self.key(identifier));
+        for name in script.keys() {
+            call.cmd.arg(self.key(&format!("{}:{}", name, identifier)));
+        }
+        call.cmd
+            .arg(self.max_requests)
+            .arg(self.window.as_millis() as u64)
+            .arg(identifier)
+            .arg(args);
+        call
+    }
+
+    /// Builds the all-or-nothing check of one request against several fixed window limiters
+    /// (`check_all`): each scope's counter, stored override and stored limits, then its limit,
+    /// window and window mode. Other algorithms can't be checked without consuming, so they
+    /// are rejected.
+    pub(crate) fn composite_call(
+        scopes: &[(&LimiterCore, &str)],
+    ) -> Result<ScriptCall<'static>, RateLimiterError> {
+        if scopes.is_empty() {
+            return Err(RateLimiterError::InvalidConfig(
+                "check_all needs at least one limiter".to_string(),
+            ));
+        }
+        if let Some((core, _)) = scopes
+            .iter()
+            .find(|(core, _)| core.algorithm != Algorithm::FixedWindow)
+        {
+            return Err(RateLimiterError::InvalidConfig(format!(
+                "check_all only supports fixed window limiters, `{}` uses {:?}",
+                core.key_prefix, core.algorithm
+            )));
+        }
+        if let Some((core, _)) = scopes.iter().find(|(core, _)| {
+            matches!(
+                core.window_mode,
+                WindowMode::CalendarDay | WindowMode::CalendarMonth
+            )
+        }) {
+            return Err(RateLimiterError::InvalidConfig(format!(
+                "check_all doesn't support calendar windows, `{}` uses {:?}",
+                core.key_prefix, core.window_mode
+            )));
+        }
+        if let Some((core, _)) = scopes.iter().find(|(core, _)| core.shards > 1) {
+            return Err(RateLimiterError::InvalidConfig(format!(
+                "check_all doesn't support sharded counters, `{}` has {} shards",
+                core.key_prefix, core.shards
+            )));
+        }
+        let mut call = ScriptCall::new(
+            composite_script(),
+            COMPOSITE_SCRIPT,
+            3 * scopes.len(),
+            128 * scopes.len(),
+        );
+        for (core, identifier) in scopes {
+            call.cmd
+                .arg(core.key(identifier))
+                .arg(core.override_key(identifier))
+                .arg(core.limits_key());
+        }
+        for (core, _) in scopes {
+            call.cmd
+                .arg(core.max_requests)
+                .arg(core.window.as_millis().max(1) as u64)
+                .arg(core.window_mode.as_arg());
+        }
+        Ok(call)
+    }
+}
+
+/// Decrements the waiter count when a waiting caller returns or is cancelled.
+pub(crate) struct WaiterGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A check as sent to Redis: see `decision_call`.
+pub(crate) enum DecisionCall {
+    Script(ScriptCall<'static>),
+    Plain(PlainCheck),
+}
+
+impl DecisionCall {
+    #[cfg(feature = "blocking")]
+    pub(crate) fn invoke(
+        &self,
+        conn: &mut dyn redis::ConnectionLike,
+    ) -> redis::RedisResult<DecisionReply> {
+        match self {
+            DecisionCall::Script(call) => call.invoke(conn),
+            DecisionCall::Plain(check) => check.invoke(conn),
+        }
+    }
+
+    pub(crate) async fn invoke_async<C>(&self, conn: &mut C) -> redis::RedisResult<DecisionReply>
+    where
+        C: redis::aio::ConnectionLike,
+    {
+        match self {
+            DecisionCall::Script(call) => call.invoke_async(conn).await,
+            DecisionCall::Plain(check) => check.invoke_async(conn).await,
+        }
+    }
+}
+
+/// A script call sent as a single `EVALSHA`. Only if the server doesn't have the script cached
+/// (first use, or after `SCRIPT FLUSH` or a restart) does it load the script and retry.
+pub(crate) struct ScriptCall<'a> {
+    source: &'a str,
+    cmd: redis::Cmd,
+}
+
+impl<'a> ScriptCall<'a> {
+    fn new(script: &redis::Script, source: &'a str, num_keys: usize, capacity: usize) -> Self {
+        let mut cmd = redis::Cmd::with_capacity(16, capacity);
+        cmd.arg("EVALSHA").arg(script.get_hash()).arg(num_keys);
+        ScriptCall { source, cmd }
+    }
+
+    fn load_cmd(&self) -> redis::Cmd {
+        let mut cmd = redis::cmd("SCRIPT");
+        cmd.arg("LOAD").arg(self.source);
+        cmd
+    }
+
+    #[cfg(feature = "blocking")]
+    pub(crate) fn invoke<T: redis::FromRedisValue>(
+        &self,
+        conn: &mut dyn redis::ConnectionLike,
+    ) -> redis::RedisResult<T> {
+        match self.cmd.query(conn) {
+            Err(e) if e.kind() == redis::ErrorKind::NoScriptError => {
+                self.load_cmd().query::<()>(conn)?;
+                self.cmd.query(conn)
+            }
+            result => result,
+        }
+    }
+
+    pub(crate) async fn invoke_async<T, C>(&self, conn: &mut C) -> redis::RedisResult<T>
+    where
+        T: redis::FromRedisValue,
+        C: redis::aio::ConnectionLike,
+    {
+        match self.cmd.query_async(conn).await {
+            Err(e) if e.kind() == redis::ErrorKind::NoScriptError => {
+                self.load_cmd().query_async::<_, ()>(conn).await?;
+                self.cmd.query_async(conn).await
+            }
+            result => result,
+        }
+    }
+}
+
+/// Script calls sent together as one pipeline of `EVALSHA`s. The calls must all run the same
+/// script: then if the server doesn't have it cached every call fails with `NOSCRIPT`, so none
+/// ran and loading the script and resending the whole pipeline is safe.
+pub(crate) struct ScriptBatch<'a> {
+    source: &'a str,
+    pipe: redis::Pipeline,
+}
+
+impl<'a> ScriptBatch<'a> {
+    /// `None` if there are no calls.
+    pub(crate) fn new(calls: impl IntoIterator<Item = ScriptCall<'a>>) -> Option<Self> {
+        let mut calls = calls.into_iter().peekable();
+        let source = calls.peek()?.source;
+        let mut pipe = redis::pipe();
+        for call in calls {
+            pipe.add_command(call.cmd);
+        }
+        Some(ScriptBatch { source, pipe })
+    }
+
+    fn load_cmd(&self) -> redis::Cmd {
+        let mut cmd = redis::cmd("SCRIPT");
+        cmd.arg("LOAD").arg(self.source);
+        cmd
+    }
+
+    #[cfg(feature = "blocking")]
+    pub(crate) fn invoke<T: redis::FromRedisValue>(
+        &self,
+        conn: &mut dyn redis::ConnectionLike,
+    ) -> redis::RedisResult<T> {
+        match self.pipe.query(conn) {
+            Err(e) if e.kind() == redis::ErrorKind::NoScriptError => {
+                self.load_cmd().query::<()>(conn)?;
+                self.pipe.query(conn)
+            }
+            result => result,
+        }
+    }
+
+    pub(crate) async fn invoke_async<T, C>(&self, conn: &mut C) -> redis::RedisResult<T>
+    where
+        T: redis::FromRedisValue,
+        C: redis::aio::ConnectionLike,
+    {
+        match self.pipe.query_async(conn).await {
+            Err(e) if e.kind() == redis::ErrorKind::NoScriptError => {
+                self.load_cmd().query_async::<_, ()>(conn).await?;
+                self.pipe.query_async(conn).await
+            }
+            result => result,
+        }
+    }
+}
+
+/// The scripts limiters run, preloaded by `preload_scripts` and verified by `health_check`.
+const BUILT_IN_SCRIPTS: [&str; 8] = [
+    CHECK_SCRIPT,
+    RESERVE_SCRIPT,
+    RESERVE_AHEAD_SCRIPT,
+    REFUND_SCRIPT,
+    CONFIRM_RESERVATION_SCRIPT,
+    COMPOSITE_SCRIPT,
+    ACQUIRE_SCRIPT,
+    RENEW_SCRIPT,
+];
+
+/// Loads every built-in script into the server's script cache (`preload_scripts`), so the
+/// first calls don't each pay for a `NOSCRIPT` reply and a `SCRIPT LOAD`.
+pub(crate) fn preload_pipeline() -> redis::Pipeline {
+    reload_pipeline(&[]).0
+}
+
+/// Asks the server which built-in scripts it has cached, in order.
+pub(crate) fn scripts_exist_cmd() -> redis::Cmd {
+    let mut cmd = redis::cmd("SCRIPT");
+    cmd.arg("EXISTS");
+    for source in BUILT_IN_SCRIPTS {
+        cmd.arg(redis::Script::new(source).get_hash());
+    }
+    cmd
+}
+
+/// Loads the built-in scripts not marked as `cached` (in the order of `scripts_exist_cmd`'s
+/// reply; missing entries count as not cached), returning the pipeline and how many it loads.
+pub(crate) fn reload_pipeline(cached: &[bool]) -> (redis::Pipeline, usize) {
+    let mut pipe = redis::pipe();
+    let mut loading = 0;
+    for (i, source) in BUILT_IN_SCRIPTS.into_iter().enumerate() {
+        if !cached.get(i).copied().unwrap_or(false) {
+            pipe.cmd("SCRIPT").arg("LOAD").arg(source).ignore();
+            loading += 1;
+        }
+    }
+    (pipe, loading)
+}
+
+/// Index of the sliding window counter window containing `since_epoch`, and the fraction of
+/// it already elapsed.
+fn counter_window(since_epoch: Duration, window: Duration) -> (u64, f64) {
+    let window_ms = window.as_millis().max(1);
+    let now_ms = since_epoch.as_millis();
+    (
+        (now_ms / window_ms) as u64,
+        (now_ms % window_ms) as f64 / window_ms as f64,
+    )
+}
+
+/// Index of the `period` long interval since the epoch containing `now`.
+fn period_index(period: Duration, now: SystemTime) -> u64 {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    since_epoch / period.as_secs().max(1)
+}
+
+/// Applies a borrowing transformation to a `Cow`, keeping the result borrowed from the
+/// original input when neither the input nor the transformation allocated.
+fn map_cow<'a, F>(cow: Cow<'a, str>, f: F) -> Result<Cow<'a, str>, RateLimiterError>
+where
+    F: for<'b> Fn(&'b str) -> Result<Cow<'b, str>, RateLimiterError>,
+{
+    match cow {
+        Cow::Borrowed(s) => f(s),
+        Cow::Owned(s) => Ok(Cow::Owned(f(&s)?.into_owned())),
+    }
+}
+
+/// Hands out the next free slot for an identifier and returns how far ahead of now it is.
+///
+/// Slots are timed by the Redis server clock, so hosts with skewed clocks still agree on them.
+fn reserve_script() -> &'static redis::Script {
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| redis::Script::new(RESERVE_SCRIPT))
+}
+
+const RESERVE_SCRIPT: &str = r#"
+    -- Needed before writing after TIME on Redis < 5; absent or a no-op elsewhere.
+    if redis.replicate_commands then
+        pcall(redis.replicate_commands)
+    end
+    local time = redis.call("TIME")
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    local interval = tonumber(ARGV[1])
+    local next_free = tonumber(redis.call("GET", KEYS[1]) or "0")
+    local slot = math.max(now, next_free)
+    -- Once the next slot is in the past the key carries no information.
+    redis.call("SET", KEYS[1], slot + interval, "PX", slot + interval - now)
+    return slot - now
+"#;
+
+/// Gives units back to an identifier's live window, as each algorithm would have had them
+/// without the request: nothing is refunded once the window is gone, nor below empty.
+fn refund_script() -> &'static redis::Script {
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| redis::Script::new(REFUND_SCRIPT))
+}
+
+const REFUND_SCRIPT: &str = r#"
+    local algorithm = tonumber(ARGV[1])
+    if algorithm > 0 and redis.replicate_commands then
+        pcall(redis.replicate_commands)
+    end
+    local function clock_ms()
+        if ARGV[6] ~= "" then
+            return tonumber(ARGV[6])
+        end
+        local time = redis.call("TIME")
+        return tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    end
+    local units = tonumber(ARGV[2])
+    -- The limit and window in effect, as the check script picks them (KEYS[1], the
+    -- override, beats KEYS[2], the limiter-wide limits, which beat the configured ones).
+    local limit = tonumber(ARGV[3])
+    local window = tonumber(ARGV[4])
+    local override = redis.call("HMGET", KEYS[1], "limit", "window_ms")
+    local stored = redis.call("HMGET", KEYS[2], "limit", "window_ms")
+    limit = tonumber(override[1] or stored[1] or limit)
+    window = tonumber(override[2] or stored[2] or window)
+    -- Counters (the shards of a fixed window from KEYS[3], then the additional limits)
+    -- give back what they hold of the units, keeping their expiry.
+    local function decrement(key, max)
+        local count = tonumber(redis.call("GET", key) or "0")
+        local refund = math.min(count, max)
+        if refund > 0 then
+            redis.call("DECRBY", key, refund)
+        end
+        return refund
+    end
+    local shards = tonumber(ARGV[5])
+    local key = KEYS[3]
+    local refunded = 0
+    if algorithm == 1 then
+        -- The newest log entries are the ones the request added.
+        refunded = math.min(units, redis.call("ZCARD", key))
+        if refunded > 0 then
+            redis.call("ZREMRANGEBYRANK", key, -refunded, -1)
+        end
+    elseif algorithm == 2 then
+        local tokens = redis.call("HGET", key, "tokens")
+        if tokens then
+            local refilled = math.min(limit, tonumber(tokens) + units)
+            refunded = math.floor(refilled - tonumber(tokens))
+            redis.call("HSET", key, "tokens", tostring(refilled))
+        end
+    elseif algorithm == 3 then
+        local now = clock_ms()
+        local tat = tonumber(redis.call("GET", key) or "0")
+        if tat > now then
+            local interval = window / limit
+            refunded = math.min(units, math.ceil((tat - now) / interval - 1e-9))
+            tat = math.max(now, tat - interval * units)
+            if tat > now then
+                redis.call("SET", key, tostring(tat), "PX", math.ceil(tat - now))
+            else
+                redis.call("DEL", key)
+            end
+        end
+    elseif algorithm == 4 then
+        -- Counted in the current of the ARGV[7] buckets the window is split into.
+        local bucket_ms = math.max(1, math.floor(window / tonumber(ARGV[7])))
+        local index = math.floor(clock_ms() / bucket_ms)
+        local this = tonumber(redis.call("HGET", key, index) or "0")
+        refunded = math.min(units, this)
+        if refunded > 0 then
+            redis.call("HINCRBY", key, index, -refunded)
+        end
+    elseif algorithm == 5 then
+        local level = redis.call("HGET", key, "level")
+        if level then
+            refunded = math.min(units, math.ceil(tonumber(level) - 1e-9))
+            redis.call("HSET", key, "level", tostring(math.max(0, tonumber(level) - units)))
+        end
+    else
+        for i = 1, shards do
+            refunded = refunded + decrement(KEYS[2 + i], units - refunded)
+        end
+    end
+    for i = 3 + shards, #KEYS do
+        decrement(KEYS[i], refunded)
+    end
+    return refunded
+"#;
+
+/// Earmarks units of a fixed window ahead: a member `token:units` of a sorted set scored by
+/// the time in ms they're for, which checks hold back from other requests until they're
+/// confirmed, cancelled or a window past that time.
+fn reserve_ahead_script() -> &'static redis::Script {
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| redis::Script::new(RESERVE_AHEAD_SCRIPT))
+}
+
+const RESERVE_AHEAD_SCRIPT: &str = r#"
+    -- Needed before writing after TIME on Redis < 5; absent or a no-op elsewhere.
+    if redis.replicate_commands then
+        pcall(redis.replicate_commands)
+    end
+    local now
+    if ARGV[6] ~= "" then
+        now = tonumber(ARGV[6])
+    else
+        local time = redis.call("TIME")
+        now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    end
+    local limit = tonumber(ARGV[1])
+    local window = tonumber(ARGV[2])
+    local at = math.max(tonumber(ARGV[3]), now)
+    local units = tonumber(ARGV[4])
+    -- Reservations a window past their time without being confirmed have lapsed.
+    redis.call("ZREMRANGEBYSCORE", KEYS[1], "-inf", "(" .. (now - window))
+    -- Any window the units may be confirmed in has to fit them. Conservatively, that's
+    -- the units reserved within a window of `at` on either side, and those counted in the
+    -- live window if `at` falls in it.
+    local held = 0
+    for _, reservation in ipairs(redis.call("ZRANGEBYSCORE", KEYS[1], at - window,
+        at + window)) do
+        held = held + tonumber(string.match(reservation, ":(%d+)$"))
+    end
+    local pttl = redis.call("PTTL", KEYS[2])
+    if pttl > 0 and at < now + pttl then
+        held = held + tonumber(redis.call("GET", KEYS[2]) or "0")
+    end
+    if held + units > limit then
+        return 0
+    end
+    redis.call("ZADD", KEYS[1], at, ARGV[5] .. ":" .. units)
+    -- The set can go once its last reservation lapses.
+    if redis.call("PTTL", KEYS[1]) < at + window - now then
+        redis.call("PEXPIRE", KEYS[1], at + window - now)
+    end
+    return at
+"#;
+
+/// Consumes reserved units: counts them in the fixed window, where they were held back, in
+/// place of the reservation.
+fn confirm_reservation_script() -> &'static redis::Script {
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| redis::Script::new(CONFIRM_RESERVATION_SCRIPT))
+}
+
+const CONFIRM_RESERVATION_SCRIPT: &str = r#"
+    if redis.call("ZREM", KEYS[1], ARGV[1]) == 0 then
+        return 0
+    end
+    if redis.call("INCRBY", KEYS[2], ARGV[2]) == tonumber(ARGV[2]) then
+        redis.call("PEXPIRE", KEYS[2], ARGV[3])
+    end
+    return 1
+"#;
+
+/// Acquires a concurrency permit: a member of a sorted set scored by its expiry in ms by the
+/// Redis server clock. Expired permits, left behind by holders that crashed, are dropped
+/// before counting.
+fn acquire_script() -> &'static redis::Script {
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| redis::Script::new(ACQUIRE_SCRIPT))
+}
+
+const ACQUIRE_SCRIPT: &str = r#"
+    -- Needed before writing after TIME on Redis < 5; absent or a no-op elsewhere.
+    if redis.replicate_commands then
+        pcall(redis.replicate_commands)
+    end
+    local time = redis.call("TIME")
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    local limit = tonumber(ARGV[1])
+    local ttl = tonumber(ARGV[2])
+    redis.call("ZREMRANGEBYSCORE", KEYS[1], "-inf", now)
+    local held = redis.call("ZCARD", KEYS[1])
+    if held >= limit then
+        return {0, held}
+    end
+    redis.call("ZADD", KEYS[1], now + ttl, ARGV[3])
+    -- Every permit expires within `ttl`, so the set can go with the newest.
+    redis.call("PEXPIRE", KEYS[1], ttl)
+    return {1, held + 1}
+"#;
+
+/// Pushes a held permit's expiry out to `ttl` from now, unless it is gone.
+fn renew_script() -> &'static redis::Script {
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| redis::Script::new(RENEW_SCRIPT))
+}
+
+const RENEW_SCRIPT: &str = r#"
+    if redis.replicate_commands then
+        pcall(redis.replicate_commands)
+    end
+    local time = redis.call("TIME")
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    local ttl = tonumber(ARGV[1])
+    local expires = redis.call("ZSCORE", KEYS[1], ARGV[2])
+    if not expires or tonumber(expires) <= now then
+        return 0
+    end
+    redis.call("ZADD", KEYS[1], now + ttl, ARGV[2])
+    redis.call("PEXPIRE", KEYS[1], ttl)
+    return 1
+"#;
+
+/// Counts a request against several fixed window counters only if every one of them allows
+/// it. Nothing is written unless the request is allowed, so a denial consumes from no scope.
+fn composite_script() -> &'static redis::Script {
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| redis::Script::new(COMPOSITE_SCRIPT))
+}
+
+const COMPOSITE_SCRIPT: &str = r#"
+    -- Scope i is counted in KEYS[3i - 2] with its override in KEYS[3i - 1] and its limiter's
+    -- stored limits in KEYS[3i], and has a limit, window in ms and window mode (1: allowed
+    -- requests restart the window) from ARGV[3i - 2]. The override beats the stored limits,
+    -- which beat the configured ones. The reply is a check's, with the index of the deciding
+    -- scope last.
+    local scopes = {}
+    local denied
+    for i = 1, #KEYS / 3 do
+        local override = redis.call("HMGET", KEYS[3 * i - 1], "limit", "window_ms")
+        local stored = redis.call("HMGET", KEYS[3 * i], "limit", "window_ms")
+        local limit = tonumber(override[1] or stored[1] or ARGV[3 * i - 2])
+        local expiry = tonumber(override[2] or stored[2] or ARGV[3 * i - 1])
+        local used = tonumber(redis.call("GET", KEYS[3 * i - 2]) or "0")
+        local pttl = math.max(0, redis.call("PTTL", KEYS[3 * i - 2]))
+        scopes[i] = {limit = limit, expiry = expiry, used = used, pttl = pttl}
+        -- A retry can only succeed once the last exhausted scope resets.
+        if used + 1 > limit and (not denied or pttl > scopes[denied].pttl) then
+            denied = i
+        end
+    end
+    if denied then
+        local scope = scopes[denied]
+        return {0, scope.limit, 0, scope.pttl, scope.pttl, denied - 1}
+    end
+    -- Allowed: count it everywhere and report the scope with the fewest requests left.
+    local tightest
+    for i, scope in ipairs(scopes) do
+        local key = KEYS[3 * i - 2]
+        local current = redis.call("INCR", key)
+        if current == 1 or tonumber(ARGV[3 * i]) == 1 then
+            redis.call("PEXPIRE", key, scope.expiry)
+        end
+        scope.remaining = scope.limit - current
+        scope.pttl = math.max(0, redis.call("PTTL", key))
+        if not tightest or scope.remaining < scopes[tightest].remaining then
+            tightest = i
+        end
+    end
+    local scope = scopes[tightest]
+    return {1, scope.limit, math.max(0, scope.remaining), scope.pttl, 0, tightest - 1}
+"#;
+
+/// The fixed window check script.
+///
+/// The script runs atomically on the server, so a caller that gives up mid-flight either
+/// consumed one request or none; it is not idempotent, so retrying after an unknown outcome
+/// may consume twice.
+fn check_script() -> &'static redis::Script {
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| redis::Script::new(CHECK_SCRIPT))
+}
+
+pub(crate) const CHECK_SCRIPT: &str = r#"
+    -- Additional limits (ARGV[19] of them, each a limit and window in ms from ARGV[20], counted
+    -- in KEYS[9] onwards) are fixed windows from their first request. All are checked before
+    -- anything is counted, and the request is only counted in them once allowed.
+    local rules = tonumber(ARGV[19])
+    -- Only the keys in use are passed, so that on Redis Cluster they can all share a slot.
+    -- ARGV[31 + 2 * rules] has a bit per optional key, in the order below, set if it was
+    -- passed; the layout is restored with the others read as "".
+    local KEYS = (function(passed)
+        local mask = tonumber(ARGV[31 + 2 * rules])
+        local optional = {}
+        for i, position in ipairs({2, 3, 5, 6, 7, 8, 11 + rules, 12 + rules, 14 + rules,
+                15 + rules, 16 + rules, 17 + rules, 18 + rules}) do
+            optional[position] = math.floor(mask / 2 ^ (i - 1)) % 2 == 1
+        end
+        local keys = {}
+        local next_key = 1
+        for position = 1, 18 + rules do
+            if optional[position] == false then
+                keys[position] = ""
+            else
+                keys[position] = passed[next_key]
+                next_key = next_key + 1
+            end
+        end
+        return keys
+    end)(KEYS)
+    -- 0: fixed window counter, 1: sliding window log, 2: token bucket, 3: GCRA,
+    -- 4: sliding window counter, 5: leaky bucket.
+    local algorithm = tonumber(ARGV[14])
+    -- All but the fixed window are timed by the server clock, and so are calendar windows
+    -- (ARGV[3] >= 3), reservations (KEYS[17 + rules]) and the periods of unique consumers,
+    -- usage history and top consumers; needed before writing after TIME on Redis < 5.
+    local timed = algorithm > 0 or tonumber(ARGV[3]) >= 3 or KEYS[17 + rules] ~= ""
+        or tonumber(ARGV[6]) > 0 or tonumber(ARGV[11]) > 0
+        or tonumber(ARGV[23 + 2 * rules]) > 0
+    if timed and redis.replicate_commands then
+        pcall(redis.replicate_commands)
+    end
+    -- ARGV[18] is the time in ms since the epoch from an injected clock; without one, the
+    -- server clock is used.
+    local function clock_ms()
+        if ARGV[18] ~= "" then
+            return tonumber(ARGV[18])
+        end
+        local time = redis.call("TIME")
+        return tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    end
+    -- Per-period keys are passed without the period: `base` for the one of `secs` seconds
+    -- containing now is `base:<index>`, kept for two periods so the previous one can still
+    -- be read.
+    local function period_key(base, secs)
+        return base .. ":" .. math.floor(clock_ms() / 1000 / secs)
+    end
+    -- The reply carries what response headers and denials need as well:
+    -- {code, limit, remaining, reset after ms, retry after ms}.
+    local retry_after = 0
+    -- A weighted request (ARGV[17] set) takes that many units, and nothing if denied; a
+    -- plain one takes one unit, and a denied one still counts in the fixed window.
+    local weighted = ARGV[17] ~= ""
+    local cost = 1
+    if weighted then
+        cost = tonumber(ARGV[17])
+    end
+    local key = KEYS[1]
+    local identifiers_key = KEYS[2]
+    local limit = tonumber(ARGV[1])
+    -- Windows are in milliseconds.
+    local expiry = tonumber(ARGV[2])
+    -- 0: fixed window, 1: allowed requests restart it, 2: every request restarts it, 3 and 4:
+    -- fixed windows ending at the next UTC midnight or month start.
+    local extend = tonumber(ARGV[3])
+    local max_identifiers = tonumber(ARGV[4])
+    local deny_new = tonumber(ARGV[5]) == 1
+    -- Unique consumers are counted per ARGV[6] seconds (0: off) in KEYS[3].
+    local consumers_period = tonumber(ARGV[6])
+    local window = expiry
+    -- Partial consumption (ARGV[24 + 2 * rules] set) takes as many of the `cost` units as
+    -- are left, at least one, and reports the units taken in place of the deciding rule.
+    local partial = ARGV[24 + 2 * rules] == "1"
+    local function clamp(available)
+        if partial then
+            cost = math.max(1, math.min(cost, math.floor(available)))
+        end
+    end
+    -- Per-call values beat the stored override, which beats the limiter-wide limits in
+    -- KEYS[13 + rules], which beat the configured values.
+    local override = redis.call("HMGET", KEYS[4], "limit", "window_ms")
+    local stored = redis.call("HMGET", KEYS[13 + rules], "limit", "window_ms")
+    if ARGV[8] ~= "" then
+        limit = tonumber(ARGV[8])
+    elseif override[1] then
+        limit = tonumber(override[1])
+    elseif stored[1] then
+        limit = tonumber(stored[1])
+    end
+    if ARGV[9] ~= "" then
+        expiry = tonumber(ARGV[9])
+    elseif override[2] then
+        expiry = tonumber(override[2])
+    elseif stored[2] then
+        expiry = tonumber(stored[2])
+    end
+    if algorithm == 0 and extend >= 3 then
+        local now = clock_ms()
+        local day = math.floor(now / 86400000)
+        local next_day = day + 1
+        if extend == 4 then
+            -- The civil date of `day`, after Howard Hinnant's civil_from_days.
+            local z = day + 719468
+            local era = math.floor(z / 146097)
+            local doe = z - era * 146097
+            local yoe = math.floor((doe - math.floor(doe / 1460) + math.floor(doe / 36524)
+                - math.floor(doe / 146096)) / 365)
+            local doy = doe - (365 * yoe + math.floor(yoe / 4) - math.floor(yoe / 100))
+            local mp = math.floor((5 * doy + 2) / 153)
+            local mday = doy - math.floor((153 * mp + 2) / 5) + 1
+            local month = mp < 10 and mp + 3 or mp - 9
+            local year = yoe + era * 400 + (month <= 2 and 1 or 0)
+            local days = ({31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31})[month]
+            if month == 2 and year % 4 == 0 and (year % 100 ~= 0 or year % 400 == 0) then
+                days = 29
+            end
+            next_day = day - mday + 1 + days
+        end
+        expiry = next_day * 86400000 - now
+        window = expiry
+    end
+    -- Fixed windows set to expire a random fraction (ARGV[25 + 2 * rules], drawn per call
+    -- within the configured jitter) earlier or later don't all reset at once.
+    local jitter = tonumber(ARGV[25 + 2 * rules])
+    if algorithm == 0 and extend < 3 and jitter ~= 0 then
+        expiry = math.max(1, math.floor(expiry * (1 + jitter) + 0.5))
+    end
+    -- With access lists (KEYS[11 + rules], the allowlist, and KEYS[12 + rules], the denylist,
+    -- both sets of identifiers), denylisted identifiers are rejected with code 5, and
+    -- allowlisted ones allowed without counting anything.
+    if KEYS[11 + rules] ~= "" then
+        if redis.call("SISMEMBER", KEYS[12 + rules], ARGV[7]) == 1 then
+            return {5, limit, 0, 0, 0, 0}
+        end
+        if redis.call("SISMEMBER", KEYS[11 + rules], ARGV[7]) == 1 then
+            return {1, limit, limit, 0, 0, partial and cost or 0}
+        end
+    end
+    -- Idempotent checks (KEYS[18 + rules], the set of request IDs allowed in the window, and
+    -- ARGV[30 + 2 * rules], this request's ID) allow a replay of an allowed request again
+    -- without counting anything. Only fixed windows report what's remaining to a replay.
+    local requests_key = KEYS[18 + rules]
+    local request_id = ARGV[30 + 2 * rules]
+    if requests_key ~= "" and redis.call("SISMEMBER", requests_key, request_id) == 1 then
+        local remaining = 0
+        if algorithm == 0 and tonumber(ARGV[28 + 2 * rules]) == 1 then
+            remaining = math.max(0, limit - tonumber(redis.call("GET", key) or "0"))
+        end
+        return {1, limit, remaining, math.max(0, redis.call("PTTL", key)), 0, 0}
+    end
+    -- Escalation (enabled by a positive ARGV[20 + 2 * rules], the violations that earn a ban):
+    -- denials are counted in KEYS[9 + rules] for ARGV[21 + 2 * rules] ms, and a ban is kept
+    -- in KEYS[10 + rules] for ARGV[22 + 2 * rules] ms. Code 4 reports a ban, with its
+    -- remaining time as the retry after.
+    local max_violations = tonumber(ARGV[20 + 2 * rules])
+    local violations_key = KEYS[9 + rules]
+    local ban_key = KEYS[10 + rules]
+    -- With top consumers tracking (ARGV[23 + 2 * rules], the period in seconds, non-zero),
+    -- allowed units are scored per identifier in KEYS[14 + rules] and denials, bans included,
+    -- in KEYS[15 + rules].
+    local top_period = tonumber(ARGV[23 + 2 * rules])
+    local function track(top_key, count)
+        if top_period > 0 then
+            top_key = period_key(top_key, top_period)
+            redis.call("ZINCRBY", top_key, count, ARGV[7])
+            if redis.call("TTL", top_key) < 0 then
+                redis.call("EXPIRE", top_key, 2 * top_period)
+            end
+        end
+    end
+    -- Penalties (enabled by a positive ARGV[26 + 2 * rules], the first block in ms): each
+    -- consecutive denial, counted in KEYS[16 + rules], blocks the identifier in the ban key
+    -- for twice as long as the last, up to ARGV[27 + 2 * rules] ms. An allowed request ends
+    -- the streak, and so does staying away that long after a block.
+    local penalty_ms = tonumber(ARGV[26 + 2 * rules])
+    local penalty_key = KEYS[16 + rules]
+    if max_violations > 0 or penalty_ms > 0 then
+        local banned_for = redis.call("PTTL", ban_key)
+        if banned_for > 0 then
+            track(KEYS[15 + rules], 1)
+            return {4, limit, 0, banned_for, banned_for, 0}
+        end
+    end
+    local function deny(denial)
+        track(KEYS[15 + rules], 1)
+        if max_violations > 0 then
+            local violations = redis.call("INCR", violations_key)
+            if violations == 1 then
+                redis.call("PEXPIRE", violations_key, ARGV[21 + 2 * rules])
+            end
+            if violations >= max_violations then
+                local ban_ms = tonumber(ARGV[22 + 2 * rules])
+                redis.call("SET", ban_key, 1, "PX", ban_ms)
+                redis.call("DEL", violations_key)
+                return {4, denial[2], 0, ban_ms, ban_ms, 0}
+            end
+        end
+        if penalty_ms > 0 then
+            local max_ms = tonumber(ARGV[27 + 2 * rules])
+            local streak = redis.call("INCR", penalty_key)
+            local block_ms = math.min(penalty_ms * 2 ^ math.min(streak - 1, 52), max_ms)
+            block_ms = math.floor(block_ms)
+            redis.call("SET", ban_key, 1, "PX", block_ms)
+            redis.call("PEXPIRE", penalty_key, block_ms + max_ms)
+            denial[5] = math.max(denial[5], block_ms)
+        end
+        return denial
+    end
+    local rules_remaining = math.huge
+    if partial then
+        for i = 1, rules do
+            clamp(tonumber(ARGV[18 + 2 * i]) - tonumber(redis.call("GET", KEYS[8 + i]) or "0"))
+        end
+    end
+    for i = 1, rules do
+        local rule_limit = tonumber(ARGV[18 + 2 * i])
+        local used = tonumber(redis.call("GET", KEYS[8 + i]) or "0")
+        if used + cost > rule_limit then
+            local pttl = math.max(0, redis.call("PTTL", KEYS[8 + i]))
+            return deny({0, rule_limit, 0, pttl, pttl, i})
+        end
+        rules_remaining = math.min(rules_remaining, rule_limit - used - cost)
+    end
+    -- Sharding (ARGV[28 + 2 * rules] shards, of which KEYS[1] is number ARGV[29 + 2 * rules]):
+    -- each shard of a fixed window holds its share of the limit, so a check only touches one.
+    -- Replies report the whole limit, and the remaining count estimated from this shard's.
+    local shards = tonumber(ARGV[28 + 2 * rules])
+    local shard = tonumber(ARGV[29 + 2 * rules])
+    local total_limit = limit
+    if shards > 1 then
+        limit = math.floor(total_limit / shards)
+        if shard < total_limit % shards then
+            limit = limit + 1
+        end
+    end
+    -- Reservations (KEYS[17 + rules], a sorted set of `token:units` members scored by the
+    -- time in ms they're for): a fixed window holds back the units reserved within a
+    -- window of now, on either side, for their confirmation.
+    if KEYS[17 + rules] ~= "" then
+        local now = clock_ms()
+        for _, reservation in ipairs(redis.call("ZRANGEBYSCORE", KEYS[17 + rules],
+            now - tonumber(ARGV[2]), now + tonumber(ARGV[2]))) do
+            limit = limit - tonumber(string.match(reservation, ":(%d+)$"))
+        end
+        limit = math.max(0, limit)
+    end
+    local allowed = 1
+    if consumers_period > 0 then
+        local consumers_key = period_key(KEYS[3], consumers_period)
+        redis.call("PFADD", consumers_key, ARGV[7])
+        if redis.call("TTL", consumers_key) < 0 then
+            redis.call("EXPIRE", consumers_key, 2 * consumers_period)
+        end
+    end
+    local max_borrow = tonumber(ARGV[12])
+    local current
+    local new_window
+    if algorithm == 1 then
+        local now = clock_ms()
+        redis.call("ZREMRANGEBYSCORE", key, "-inf", now - expiry)
+        local logged = redis.call("ZCARD", key)
+        new_window = logged == 0
+        clamp(limit - logged)
+        current = logged + cost
+        -- Only allowed requests are logged, one entry per unit. The count makes members
+        -- unique: within one millisecond nothing is pruned, so it only grows.
+        if current <= limit then
+            for i = logged, current - 1 do
+                redis.call("ZADD", key, now, now .. ":" .. i)
+            end
+            redis.call("PEXPIRE", key, expiry)
+        else
+            -- Enough slots free up when the entry `current - limit` from the oldest leaves
+            -- the window.
+            local freeing = redis.call("ZRANGE", key, current - limit - 1, current - limit - 1,
+                "WITHSCORES")
+            retry_after = expiry
+            if freeing[2] then
+                retry_after = tonumber(freeing[2]) + expiry - now
+            end
+        end
+    elseif algorithm == 2 then
+        local now = clock_ms()
+        -- Tokens per millisecond: the refill rate if set, else the limit per window.
+        local rate = limit / expiry
+        if ARGV[15] ~= "" then
+            rate = tonumber(ARGV[15]) / tonumber(ARGV[16])
+        end
+        -- An idle credit (ARGV[33 + 2 * rules]) caps the tokens refilled over one gap between
+        -- requests, and those a new bucket starts with, below the capacity.
+        local idle_credit = limit
+        if ARGV[33 + 2 * rules] ~= "" then
+            idle_credit = math.min(limit, tonumber(ARGV[33 + 2 * rules]))
+        end
+        local bucket = redis.call("HMGET", key, "tokens", "ts")
+        local tokens = idle_credit
+        new_window = not bucket[1]
+        if not new_window then
+            local refilled = math.min(idle_credit, (now - tonumber(bucket[2])) * rate)
+            tokens = math.min(limit, tonumber(bucket[1]) + refilled)
+        end
+        clamp(tokens)
+        -- Expressed as a count so the checks below apply: over the limit means no token.
+        current = limit - tokens + cost
+        if tokens >= cost then
+            tokens = tokens - cost
+        elseif rate > 0 then
+            retry_after = (cost - tokens) / rate
+        else
+            retry_after = expiry
+        end
+        redis.call("HMSET", key, "tokens", tostring(tokens), "ts", now)
+        -- The key lives until the bucket is full again. With an idle credit it lives as long
+        -- as filling the bucket from empty takes, so only a client away that long starts over
+        -- with the credit.
+        local missing = limit - tokens
+        if ARGV[33 + 2 * rules] ~= "" then
+            missing = limit
+        end
+        local full_in = expiry
+        if rate > 0 then
+            full_in = math.max(1, math.ceil(missing / rate))
+        end
+        redis.call("PEXPIRE", key, full_in)
+    elseif algorithm == 3 then
+        local now = clock_ms()
+        local window_ms = expiry
+        local interval = window_ms / limit
+        local tat = tonumber(redis.call("GET", key) or "0")
+        new_window = tat <= now
+        clamp((window_ms - math.max(tat - now, 0)) / interval + 1e-9)
+        -- The request is admitted if pushing the theoretical arrival time out by one
+        -- interval per unit keeps it within a window of now; the key expires at that time.
+        local next_tat = math.max(tat, now) + interval * cost
+        if next_tat - now <= window_ms then
+            current = math.ceil((next_tat - now) / interval - 1e-9)
+            redis.call("SET", key, tostring(next_tat), "PX", math.ceil(next_tat - now))
+        else
+            current = limit + 1
+            retry_after = math.min(next_tat - now - window_ms, window_ms)
+        end
+    elseif algorithm == 4 then
+        -- The window is split into ARGV[32 + 2 * rules] buckets of whole ms, one hash field
+        -- each: the current bucket and the ones before it within the window count in full,
+        -- and the oldest in proportion to how much of it the last window still covers.
+        local now = clock_ms()
+        local buckets = tonumber(ARGV[32 + 2 * rules])
+        local bucket_ms = math.max(1, math.floor(expiry / buckets))
+        local index = math.floor(now / bucket_ms)
+        local fields = {}
+        for i = index - buckets, index do
+            fields[#fields + 1] = i
+        end
+        local counts = redis.call("HMGET", key, unpack(fields))
+        for i = 1, #counts do
+            counts[i] = tonumber(counts[i] or "0")
+        end
+        local recent = 0
+        for i = 2, #counts do
+            recent = recent + counts[i]
+        end
+        new_window = counts[1] == 0 and recent == 0
+        local overlap = 1 - (now - index * bucket_ms) / bucket_ms
+        local used = math.floor(counts[1] * overlap) + recent
+        clamp(limit - used)
+        current = used + cost
+        if current <= limit then
+            redis.call("HINCRBY", key, index, cost)
+            redis.call("HDEL", key, index - buckets - 1)
+            -- Kept until no later window needs this bucket.
+            redis.call("PEXPIRE", key, (index + buckets + 1) * bucket_ms - now)
+        else
+            -- From bucket index + j - 1 on, counts[j] is the oldest, counted in part, and the
+            -- ones after it in full: wait for the first one whose weight can drop far enough.
+            retry_after = (index + buckets + 1) * bucket_ms - now
+            local rest = recent
+            for j = 1, #counts do
+                if j > 1 then
+                    rest = rest - counts[j]
+                end
+                if rest + cost <= limit then
+                    local elapsed = 0
+                    if counts[j] > 0 then
+                        elapsed = math.max(0, 1 - (limit - rest - cost + 1) / counts[j])
+                    end
+                    retry_after = math.max(0, (index + j - 1 + elapsed) * bucket_ms - now)
+                    break
+                end
+            end
+        end
+    elseif algorithm == 5 then
+        local now = clock_ms()
+        -- Drained per millisecond: the drain rate if set, else the limit per window.
+        local rate = limit / expiry
+        if ARGV[15] ~= "" then
+            rate = tonumber(ARGV[15]) / tonumber(ARGV[16])
+        end
+        local bucket = redis.call("HMGET", key, "level", "ts")
+        local level = 0
+        new_window = not bucket[1]
+        if not new_window then
+            local drained = (now - tonumber(bucket[2])) * rate
+            level = math.max(0, tonumber(bucket[1]) - drained)
+        end
+        clamp(limit - level)
+        -- The request fits if it doesn't overflow the bucket; otherwise it has to wait for
+        -- enough of the level to drain.
+        current = level + cost
+        if current <= limit then
+            level = current
+        elseif rate > 0 then
+            retry_after = (current - limit) / rate
+        else
+            retry_after = expiry
+        end
+        redis.call("HMSET", key, "level", tostring(level), "ts", now)
+        -- The key lives until the bucket has drained.
+        local empty_in = expiry
+        if rate > 0 then
+            empty_in = math.max(1, math.ceil(level / rate))
+        end
+        redis.call("PEXPIRE", key, empty_in)
+    else
+        if partial then
+            clamp(limit - tonumber(redis.call("GET", key) or "0"))
+        end
+        current = redis.call("INCRBY", key, cost)
+        new_window = current == cost
+    end
+    local function reply(code)
+        local remaining = 0
+        if code == 1 or code == 2 then
+            remaining = math.max(0, math.min(total_limit, (limit - current) * shards))
+            remaining = math.floor(math.min(remaining, rules_remaining))
+        end
+        local reset_after = math.max(0, redis.call("PTTL", key))
+        local granted = 0
+        if partial and (code == 1 or code == 2) then
+            granted = cost
+        end
+        return {code, total_limit, remaining, reset_after, math.max(0, math.ceil(retry_after)),
+            granted}
+    end
+    -- A new window for this identifier counts towards the distinct identifiers
+    -- seen in the prefix's current window, once for a sharded counter.
+    if new_window and max_identifiers > 0 and shard == 0 then
+        local seen = redis.call("INCR", identifiers_key)
+        if seen == 1 then
+            redis.call("PEXPIRE", identifiers_key, window)
+        end
+        if seen > max_identifiers then
+            if deny_new then
+                redis.call("DEL", key)
+                redis.call("DECR", identifiers_key)
+                retry_after = redis.call("PTTL", identifiers_key)
+                return reply(3)
+            end
+            allowed = 2
+        end
+    end
+    -- Units borrowed in the previous window are repaid by starting this one with them
+    -- already consumed.
+    if new_window and max_borrow > 0 then
+        local debt = tonumber(redis.call("GET", KEYS[6]) or "0")
+        if debt > 0 then
+            current = redis.call("INCRBY", key, debt)
+            redis.call("DEL", KEYS[6])
+        end
+    end
+    -- The first request always starts the window; in sliding mode every allowed
+    -- request restarts it. The other algorithms have set their own expiry.
+    local restart = new_window or extend == 2 or (extend == 1 and current <= limit + max_borrow)
+    if algorithm == 0 and restart then
+        redis.call("PEXPIRE", key, expiry)
+    end
+    -- The shadow mirrors the counter but outlives it, so the next window's first request
+    -- can archive the final count of this one.
+    local analytics_ttl = tonumber(ARGV[13])
+    if analytics_ttl > 0 then
+        if new_window then
+            local previous = redis.call("GET", KEYS[7])
+            if previous then
+                redis.call("SET", KEYS[8], previous, "EX", analytics_ttl)
+            end
+        end
+        redis.call("SET", KEYS[7], current, "PX", expiry + analytics_ttl * 1000)
+    end
+    if current > limit + max_borrow then
+        if algorithm == 0 then
+            if weighted then
+                if redis.call("DECRBY", key, cost) <= 0 then
+                    redis.call("DEL", key)
+                end
+                if analytics_ttl > 0 then
+                    redis.call("DECRBY", KEYS[7], cost)
+                end
+            end
+            retry_after = redis.call("PTTL", key)
+        end
+        return deny(reply(0))
+    end
+    if current > limit then
+        -- Borrowing: the overage is owed to the window right after this one only.
+        local pttl = redis.call("PTTL", key)
+        redis.call("SET", KEYS[6], current - limit, "PX", pttl + expiry)
+    end
+    -- Usage history: allowed units per ARGV[10] seconds, in a hash per retention period of
+    -- ARGV[11] seconds (0: off) under KEYS[5].
+    local retention = tonumber(ARGV[11])
+    if retention > 0 then
+        local now = math.floor(clock_ms() / 1000)
+        local bucket = now - now % tonumber(ARGV[10])
+        local history_key = KEYS[5] .. ":" .. math.floor(bucket / retention)
+        redis.call("HINCRBY", history_key, bucket, cost)
+        redis.call("EXPIRE", history_key, 2 * retention)
+    end
+    for i = 1, rules do
+        if redis.call("INCRBY", KEYS[8 + i], cost) == cost then
+            redis.call("PEXPIRE", KEYS[8 + i], ARGV[19 + 2 * i])
+        end
+    end
+    track(KEYS[14 + rules], cost)
+    if penalty_ms > 0 then
+        redis.call("DEL", penalty_key)
+    end
+    -- Request IDs are remembered for a window from the first one.
+    if requests_key ~= "" then
+        redis.call("SADD", requests_key, request_id)
+        if redis.call("PTTL", requests_key) < 0 then
+            redis.call("PEXPIRE", requests_key, expiry)
+        end
+    end
+    return reply(allowed)
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+
+    /// The command's arguments as strings.
+    fn args(cmd: &redis::Cmd) -> Vec<String> {
+        cmd.args_iter()
+            .map(|arg| match arg {
+                redis::Arg::Simple(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                redis::Arg::Cursor => String::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_consumers_key_buckets_by_period() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        assert_eq!(core.consumers_key(SystemTime::now()), None);
+
+        core.unique_consumers_period = Some(Duration::from_secs(3600));
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(core.consumers_key(at(7200)).unwrap(), "app:__consumers__:2");
+        assert_eq!(
+            core.consumers_key(at(10799)).unwrap(),
+            "app:__consumers__:2"
+        );
+        assert_eq!(
+            core.consumers_key(at(10800)).unwrap(),
+            "app:__consumers__:3"
+        );
+    }
+
+    #[test]
+    fn test_calendar_windows_need_script_time_writes() {
+        let ancient = ServerCapabilities::from_info("redis_version:3.0.7\r\n");
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        assert!(core.verify_compatibility(&ancient).is_ok());
+
+        core.window_mode = WindowMode::CalendarDay;
+        assert!(matches!(
+            core.verify_compatibility(&ancient),
+            Err(RateLimiterError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn test_consume_up_to_call_is_partial() {
+        let core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        let partial = args(&core.consume_up_to_call("user_1", 4).cmd);
+        assert_eq!(partial[3 + 5 + 16], "4");
+        assert_eq!(partial[partial.len() - 10], "1");
+        let mut check = args(&core.check_call("user_1", None, Some(4)).cmd);
+        assert_eq!(check[check.len() - 10], "0");
+        check[partial.len() - 10] = "1".to_string();
+        assert_eq!(check, partial);
+    }
+
+    #[test]
+    fn test_check_call_draws_expiry_jitter() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        core.expiry_jitter = 0.1;
+        let jitters: Vec<f64> = (0..50)
+            .map(|_| {
+                let call = core.check_call("user_1", None, None);
+                let args: Vec<_> = call.cmd.args_iter().collect();
+                match args.get(args.len() - 9) {
+                    Some(redis::Arg::Simple(bytes)) => {
+                        String::from_utf8_lossy(bytes).parse().unwrap()
+                    }
+                    _ => panic!("no jitter argument"),
+                }
+            })
+            .collect();
+        assert!(jitters.iter().all(|jitter| jitter.abs() <= 0.1));
+        assert!(jitters.iter().any(|jitter| *jitter != jitters[0]));
+    }
+
+    #[test]
+    fn test_check_call_passes_top_consumers() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        assert!(core.top_consumers_cmd(10, core.now()).is_none());
+
+        core.top_consumers_period = Some(Duration::from_secs(3600));
+        core.clock = Some(Arc::new(ManualClock::new(
+            UNIX_EPOCH + Duration::from_secs(7300),
+        )));
+        let call = core.check_call("user_1", None, None);
+        let args = args(&call.cmd);
+        // The script appends the period, picked by the clock.
+        assert_eq!(args[8..10], ["app:__top__", "app:__top_violations__"]);
+        assert_eq!(
+            args[args.len() - 11..],
+            ["3600", "0", "0.0", "0", "0", "1", "0", "", "768", "1", ""]
+        );
+
+        let cmd = core.violations_top_cmd(3, core.now()).unwrap();
+        let args: Vec<_> = cmd.args_iter().collect();
+        assert!(matches!(
+            args[..],
+            [
+                redis::Arg::Simple(b"ZREVRANGE"),
+                redis::Arg::Simple(b"app:__top_violations__:2"),
+                redis::Arg::Simple(b"0"),
+                redis::Arg::Simple(b"2"),
+                redis::Arg::Simple(b"WITHSCORES"),
+            ]
+        ));
+        assert!(core.top_consumers_cmd(0, core.now()).is_none());
+    }
+
+    #[test]
+    fn test_active_identifiers_skip_internal_and_expired_keys() -> redis::RedisResult<()> {
+        use redis::Value;
+
+        let core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        let keys = [
+            "app:user_1",
+            "app:__override__:user_1",
+            "app:user:2",
+            "app:user_3",
+        ];
+        let identifiers = core.identifiers_in(keys.map(String::from).to_vec());
+        assert_eq!(identifiers, ["user_1", "user:2", "user_3"]);
+
+        let unset = || Value::Bulk(vec![Value::Nil, Value::Nil]);
+        let reply = vec![
+            Value::Data(b"4".to_vec()),
+            Value::Int(30_000),
+            unset(),
+            unset(),
+            Value::Nil,
+            Value::Int(-2),
+            unset(),
+            unset(),
+            Value::Data(b"1".to_vec()),
+            Value::Int(-1),
+            unset(),
+            unset(),
+        ];
+        let active = core.active_from_reply(identifiers, reply, core.now())?;
+        assert_eq!(
+            active,
+            [
+                ActiveIdentifier {
+                    identifier: "user_1".into(),
+                    used: 4,
+                    ttl: Some(Duration::from_secs(30)),
+                },
+                ActiveIdentifier {
+                    identifier: "user_3".into(),
+                    used: 1,
+                    ttl: None,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_waiters_are_capped() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        core.max_waiters = Some(1);
+
+        let first = core.enter_wait().unwrap();
+        assert!(matches!(
+            core.enter_wait(),
+            Err(RateLimiterError::WaitQueueFull { max_waiters: 1 })
+        ));
+        assert_eq!(core.waiters.load(Ordering::Relaxed), 1);
+
+        drop(first);
+        assert_eq!(core.waiters.load(Ordering::Relaxed), 0);
+        assert!(core.enter_wait().is_ok());
+    }
+
+    #[test]
+    fn test_slot_interval_spreads_window() {
+        let core = LimiterCore::new("app", 100, Duration::from_secs(1));
+        assert_eq!(core.slot_interval(), Duration::from_millis(10));
+
+        let core = LimiterCore::new("app", 10_000, Duration::from_secs(1));
+        assert_eq!(core.slot_interval(), Duration::from_millis(1));
+
+        // Limits past `u32::MAX` neither truncate nor divide by zero.
+        let core = LimiterCore::new("app", 1 << 32, Duration::from_secs(1 << 32));
+        assert_eq!(core.slot_interval(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_scan_prefix_escapes_glob_characters() {
+        let core = LimiterCore::new("app[eu]*", 10, Duration::from_secs(60));
+        let args: Vec<Vec<u8>> = core
+            .scan_prefix_cmd(17)
+            .args_iter()
+            .map(|arg| match arg {
+                redis::Arg::Simple(bytes) => bytes.to_vec(),
+                redis::Arg::Cursor => Vec::new(),
+            })
+            .collect();
+        assert_eq!(args[1], b"17");
+        assert_eq!(args[3], br"app\[eu\]\*:*");
+    }
+
+    #[test]
+    fn test_check_call_passes_additional_limits() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(1));
+        core.additional_limits.push(Rate {
+            max_requests: 1000,
+            window: Duration::from_secs(3600),
+        });
+        let call = core.check_call("user_1", None, None);
+        let args = args(&call.cmd);
+        assert_eq!(args[2], "6");
+        assert_eq!(args[5], "app:__rule__:3600000:user_1");
+        assert_eq!(args[8], "app:__limits__");
+        assert_eq!(
+            args[args.len() - 18..],
+            [
+                "", "1", "1000", "3600000", "0", "0", "0", "0", "0", "0.0", "0", "0", "1", "0", "",
+                "0", "1", ""
+            ]
+        );
+        assert!(core
+            .state_keys("user_1")
+            .contains(&"app:__rule__:3600000:user_1".to_string()));
+    }
+
+    #[test]
+    fn test_check_call_passes_escalation() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(1));
+        core.escalation = Some(EscalationPolicy::new(
+            5,
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+        ));
+        let call = core.check_call("user_1", None, None);
+        let escalation = args(&call.cmd);
+        assert_eq!(
+            escalation[5..7],
+            ["app:__violations__:user_1", "app:__ban__:user_1"]
+        );
+        assert_eq!(
+            escalation[escalation.len() - 14..],
+            ["5", "60000", "600000", "0", "0", "0.0", "0", "0", "1", "0", "", "0", "1", ""]
+        );
+        assert_eq!(escalation[7], "app:__limits__");
+
+        core.access_lists = true;
+        let call = core.check_call("user_1", None, None);
+        let keys: Vec<_> = call.cmd.args_iter().skip(7).take(2).collect();
+        assert!(matches!(
+            keys[..],
+            [
+                redis::Arg::Simple(b"app:__allowlist__"),
+                redis::Arg::Simple(b"app:__denylist__")
+            ]
+        ));
+        assert!(core
+            .state_keys("user_1")
+            .contains(&"app:__ban__:user_1".to_string()));
+
+        core.penalty = Some(PenaltyPolicy::new(
+            Duration::from_secs(1),
+            Duration::from_secs(30),
+        ));
+        let call = core.check_call("user_1", None, None);
+        let args = args(&call.cmd);
+        assert_eq!(args[10], "app:__penalty__:user_1");
+        assert_eq!(args[args.len() - 8..args.len() - 6], ["1000", "30000"]);
+        assert!(core
+            .state_keys("user_1")
+            .contains(&"app:__penalty__:user_1".to_string()));
+    }
+
+    #[test]
+    fn test_composite_call_keys_and_args() {
+        let user = LimiterCore::new("user", 10, Duration::from_secs(1));
+        let mut org = LimiterCore::new("org", 100, Duration::from_secs(60));
+        org.window_mode = WindowMode::FixedFromFirstRequest;
+        let call = LimiterCore::composite_call(&[(&user, "alice"), (&org, "acme")]).unwrap();
+        let args = args(&call.cmd);
+        assert_eq!(
+            args[2..],
+            [
+                "6",
+                "user:alice",
+                "user:__override__:alice",
+                "user:__limits__",
+                "org:acme",
+                "org:__override__:acme",
+                "org:__limits__",
+                "10",
+                "1000",
+                "1",
+                "100",
+                "60000",
+                "0"
+            ]
+        );
+
+        org.algorithm = Algorithm::Gcra;
+        assert!(matches!(
+            LimiterCore::composite_call(&[(&user, "alice"), (&org, "acme")]),
+            Err(RateLimiterError::InvalidConfig(_))
+        ));
+        assert!(LimiterCore::composite_call(&[]).is_err());
+    }
+
+    #[test]
+    fn test_check_call_keys_share_the_prefix_slot() {
+        // Redis Cluster hashes only the first non-empty `{...}` of a key, if any.
+        let hashed = |key: &str| -> String {
+            match key.find('{') {
+                Some(open) => match key[open + 1..].find('}') {
+                    Some(len) if len > 0 => key[open + 1..open + 1 + len].to_string(),
+                    _ => key.to_string(),
+                },
+                None => key.to_string(),
+            }
+        };
+        let keys = |call: ScriptCall<'static>| -> Vec<String> {
+            let args = args(&call.cmd);
+            let num_keys: usize = args[2].parse().unwrap();
+            args[3..3 + num_keys].to_vec()
+        };
+
+        let mut core = LimiterCore::new("{api}", 10, Duration::from_secs(60));
+        let plain = keys(core.check_call("user_1", None, None));
+        assert_eq!(plain.len(), 5);
+        assert!(plain.iter().all(|key| hashed(key) == "api"), "{:?}", plain);
+
+        core.cardinality = Some(CardinalityLimit::new(100, CardinalityPolicy::DenyNew));
+        core.unique_consumers_period = Some(Duration::from_secs(3600));
+        core.history = Some(UsageHistory::new(
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+        ));
+        core.max_borrow = 5;
+        core.analytics_retention = Some(Duration::from_secs(3600));
+        core.access_lists = true;
+        core.top_consumers_period = Some(Duration::from_secs(3600));
+        core.penalty = Some(PenaltyPolicy::new(
+            Duration::from_secs(1),
+            Duration::from_secs(30),
+        ));
+        core.reservations = true;
+        core.additional_limits.push(Rate {
+            max_requests: 1000,
+            window: Duration::from_secs(3600),
+        });
+        let all = keys(core.idempotent_check_call("user_1", "req-42"));
+        assert_eq!(all.len(), 18 + 1);
+        assert!(all.iter().all(|key| hashed(key) == "api"), "{:?}", all);
+    }
+
+    #[test]
+    fn test_check_call_passes_injected_time() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(1));
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(5)));
+        core.clock = Some(clock.clone());
+        clock.advance(Duration::from_millis(250));
+
+        let call = core.check_call("user_1", None, None);
+        let now_arg = call.cmd.args_iter().nth(3 + 5 + 17);
+        assert!(matches!(now_arg, Some(redis::Arg::Simple(b"5250"))));
+        assert_eq!(core.now(), UNIX_EPOCH + Duration::from_millis(5250));
+    }
+
+    #[test]
+    fn test_custom_call_keys_and_args() {
+        let core = LimiterCore::new("app", 10, Duration::from_secs(2));
+        let script = CustomScript::new("return 1", |_| Ok(())).with_key("cost");
+        let call = core.custom_call(&script, "user_1", ("a", 7));
+        let args = args(&call.cmd);
+        assert_eq!(
+            &args[2..],
+            [
+                "2",
+                "app:user_1",
+                "app:cost:user_1",
+                "10",
+                "2000",
+                "user_1",
+                "a",
+                "7"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_bucket_usage_from_ttl() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(10));
+        core.algorithm = Algorithm::TokenBucket;
+        // One token per second: 2.5 seconds to full means three tokens are missing.
+        let usage = core.usage_from_reply(
+            (redis::Value::Int(1), 2500, (None, None), (None, None)),
+            core.now(),
+        );
+        assert_eq!(usage.consumed, 3);
+        assert_eq!(usage.remaining, 7);
+
+        let usage = core.usage_from_reply(
+            (redis::Value::Int(0), -2, (None, None), (None, None)),
+            core.now(),
+        );
+        assert_eq!(usage.consumed, 0);
+    }
+
+    #[test]
+    fn test_sharded_counter_keys_and_usage() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        core.shards = 3;
+        let args = args(&core.check_call("partner_x", None, None).cmd);
+        let shard: u32 = args[args.len() - 5].parse().unwrap();
+        assert!(shard < 3);
+        assert_eq!(args[args.len() - 6], "3");
+        let expected = match shard {
+            0 => "app:partner_x".to_string(),
+            _ => format!("app:__shard__:{}:partner_x", shard),
+        };
+        assert_eq!(args[3], expected);
+        assert!(core
+            .state_keys("partner_x")
+            .contains(&"app:__shard__:2:partner_x".to_string()));
+
+        let counts = redis::Value::Bulk(vec![
+            redis::Value::Data(b"2".to_vec()),
+            redis::Value::Nil,
+            redis::Value::Data(b"3".to_vec()),
+        ]);
+        let usage = core.usage_from_reply((counts, 30_000, (None, None), (None, None)), core.now());
+        assert_eq!(usage.consumed, 5);
+        assert_eq!(usage.remaining, 5);
+
+        core.algorithm = Algorithm::Gcra;
+        let call = core.check_call("partner_x", None, None);
+        let args: Vec<_> = call.cmd.args_iter().collect();
+        assert!(matches!(args[3], redis::Arg::Simple(b"app:partner_x")));
+    }
+
+    #[test]
+    fn test_idempotent_check_call_passes_request_id() {
+        let core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        let check = args(&core.check_call("user_1", None, None).cmd);
+        let idempotent = args(&core.idempotent_check_call("user_1", "req-42").cmd);
+        assert_eq!(idempotent[2], "6");
+        assert_eq!(idempotent[3 + 5], "app:__requests__:user_1");
+        assert_eq!(idempotent[idempotent.len() - 4], "req-42");
+        assert_eq!(check[2], "5");
+        assert_eq!(check[check.len() - 4], "");
+        assert!(core
+            .state_keys("user_1")
+            .contains(&"app:__requests__:user_1".to_string()));
+        assert!(LimiterCore::validate_request_id("").is_err());
+    }
+
+    #[test]
+    fn test_reload_pipeline_loads_missing_scripts() {
+        let cmd = scripts_exist_cmd();
+        let exists: Vec<_> = cmd.args_iter().skip(1).collect();
+        assert_eq!(exists.len(), 1 + BUILT_IN_SCRIPTS.len());
+        let check_hash = redis::Script::new(CHECK_SCRIPT).get_hash().to_string();
+        assert!(matches!(exists[1], redis::Arg::Simple(hash) if hash == check_hash.as_bytes()));
+
+        assert_eq!(reload_pipeline(&[]).1, BUILT_IN_SCRIPTS.len());
+        let mut cached = vec![true; BUILT_IN_SCRIPTS.len()];
+        assert_eq!(reload_pipeline(&cached).1, 0);
+        cached[0] = false;
+        cached[3] = false;
+        assert_eq!(reload_pipeline(&cached).1, 2);
+    }
+
+    #[test]
+    fn test_refund_call_covers_shards_and_additional_limits() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        core.shards = 2;
+        core.additional_limits.push(Rate {
+            max_requests: 100,
+            window: Duration::from_secs(3600),
+        });
+        let args = args(&core.refund_call("user_1", 3).cmd);
+        assert_eq!(
+            args[2..],
+            [
+                "5",
+                "app:__override__:user_1",
+                "app:__limits__",
+                "app:user_1",
+                "app:__shard__:1:user_1",
+                "app:__rule__:3600000:user_1",
+                "0",
+                "3",
+                "10",
+                "60000",
+                "2",
+                "",
+                "1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reservations_key_and_calls() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(60));
+        let not_before = UNIX_EPOCH + Duration::from_secs(120);
+        assert!(matches!(
+            core.reserve_ahead_call("batch", 4, not_before, "t"),
+            Err(RateLimiterError::InvalidConfig(_))
+        ));
+        let key_arg = |core: &LimiterCore| {
+            let call = core.check_call("batch", None, None);
+            let args = args(&call.cmd);
+            match args[2].as_str() {
+                "6" => args[3 + 5].clone(),
+                _ => String::new(),
+            }
+        };
+        assert_eq!(key_arg(&core), "");
+
+        core.reservations = true;
+        assert_eq!(key_arg(&core), "app:__reservations__:batch");
+        assert!(core
+            .state_keys("batch")
+            .contains(&"app:__reservations__:batch".to_string()));
+        let call = core
+            .reserve_ahead_call("batch", 4, not_before, "t")
+            .unwrap();
+        let args: Vec<_> = call.cmd.args_iter().skip(3).collect();
+        assert!(matches!(args[2], redis::Arg::Simple(b"10")));
+        assert!(matches!(args[4], redis::Arg::Simple(b"120000")));
+        assert!(matches!(args[5], redis::Arg::Simple(b"4")));
+        assert!(core
+            .reserve_ahead_call("batch", 0, not_before, "t")
+            .is_err());
+
+        core.algorithm = Algorithm::TokenBucket;
+        assert_eq!(key_arg(&core), "");
+        assert!(core
+            .reserve_ahead_call("batch", 4, not_before, "t")
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_call_clamps_refill_window() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(1));
+        core.algorithm = Algorithm::TokenBucket;
+        core.refill_rate = Some(Rate {
+            max_requests: 10,
+            window: Duration::from_micros(500),
+        });
+        let args = args(&core.check_call("user_1", None, None).cmd);
+        // The script divides by the window; a sub-millisecond one is sent as 1ms, not 0.
+        assert_eq!(args[3 + 5 + 14..3 + 5 + 16], ["10", "1"]);
+    }
+
+    #[test]
+    fn test_leaky_bucket_usage_from_ttl() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(10));
+        core.algorithm = Algorithm::LeakyBucket;
+        core.refill_rate = Some(Rate {
+            max_requests: 2,
+            window: Duration::from_secs(1),
+        });
+        // Drained at two per second: 2.5 seconds to empty means a level of five.
+        let usage = core.usage_from_reply(
+            (redis::Value::Int(1), 2500, (None, None), (None, None)),
+            core.now(),
+        );
+        assert_eq!(usage.consumed, 5);
+        assert_eq!(usage.remaining, 5);
+    }
+
+    #[test]
+    fn test_key_transform_applies_last() -> Result<(), RateLimiterError> {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(1));
+        core.normalization =
+            Some(Normalization::new().with_case(crate::CaseNormalization::AsciiLowercase));
+        core.identifier_policy = Some(IdentifierPolicy::new());
+        core.key_transform = Some(Arc::new(crate::SaltedHash::new("secret")));
+
+        let hashed = core.identifier("User@Example.com")?;
+        assert_eq!(hashed.len(), 40);
+        assert_eq!(hashed, core.identifier("user@example.com")?);
+        let key = core.key(&hashed);
+        assert!(!key.contains("example"), "{}", key);
+        // The policy still sees the identifier as given.
+        assert!(core.identifier("a b").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stored_limits_are_cached_for_the_fallback() {
+        let core = LimiterCore::new("app", 10, Duration::from_secs(10));
+        assert_eq!(core.fallback_limits(), (10, Duration::from_secs(10)));
+
+        let usage = core.usage_from_reply(
+            (redis::Value::Int(4), 5000, (None, None), (Some(20), None)),
+            core.now(),
+        );
+        assert_eq!(usage.remaining, 16);
+        assert_eq!(core.fallback_limits(), (20, Duration::from_secs(10)));
+
+        // An identifier's override doesn't apply to the others.
+        let config = core.effective_config((None, Some(1000)), (Some(5), None));
+        assert_eq!(config.max_requests, 5);
+        assert_eq!(core.fallback_limits(), (10, Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_counter_window() {
+        let window = Duration::from_secs(10);
+        assert_eq!(counter_window(Duration::from_secs(125), window), (12, 0.5));
+        assert_eq!(counter_window(Duration::from_secs(130), window), (13, 0.0));
+    }
+
+    #[test]
+    fn test_idle_credit_caps_the_refill_since_the_last_request() {
+        let mut core = LimiterCore::new("app", 10, Duration::from_secs(10));
+        core.algorithm = Algorithm::TokenBucket;
+        core.idle_credit = Some(3);
+        assert!(core.usage_needs_time());
+
+        // Ten seconds would refill ten tokens, but only three are credited.
+        let now = UNIX_EPOCH + Duration::from_secs(1010);
+        let bucket = redis::Value::Bulk(vec![
+            redis::Value::Data(b"2".to_vec()),
+            redis::Value::Data(b"1000000".to_vec()),
+        ]);
+        let usage = core.usage_from_reply((bucket, 8000, (None, None), (None, None)), now);
+        assert_eq!(usage.consumed, 5);
+
+        // A new bucket starts with the credit.
+        let empty = redis::Value::Bulk(vec![redis::Value::Nil, redis::Value::Nil]);
+        let usage = core.usage_from_reply((empty, -2, (None, None), (None, None)), now);
+        assert_eq!(usage.remaining, 3);
+    }
+
+    #[test]
+    fn test_counter_buckets_weight_only_the_oldest() {
+        let mut core = LimiterCore::new("app", 100, Duration::from_secs(60));
+        core.algorithm = Algorithm::SlidingWindowCounter;
+        core.counter_buckets = 4;
+        assert_eq!(core.counter_bucket(), Duration::from_secs(15));
+
+        // A quarter into bucket 10, buckets 6 to 10 are read and bucket 6 weighs 3/4.
+        let now = UNIX_EPOCH + Duration::from_millis(153_750);
+        let pipe = core.usage_pipeline("user_1", now);
+        let reads: Vec<_> = pipe.cmd_iter().collect();
+        let fields: Vec<_> = reads[0].args_iter().skip(2).collect();
+        assert!(matches!(
+            fields[..],
+            [
+                redis::Arg::Simple(b"6"),
+                redis::Arg::Simple(b"7"),
+                redis::Arg::Simple(b"8"),
+                redis::Arg::Simple(b"9"),
+                redis::Arg::Simple(b"10"),
+            ]
+        ));
+        let counts = redis::Value::Bulk(
+            ["40", "10", "", "5", "1"]
+                .iter()
+                .map(|count| match count {
+                    &"" => redis::Value::Nil,
+                    count => redis::Value::Data(count.as_bytes().to_vec()),
+                })
+                .collect(),
+        );
+        let usage = core.usage_from_reply((counts, 60_000, (None, None), (None, None)), now);
+        assert_eq!(usage.consumed, 30 + 16);
+    }
+}
@@ -0,0 +1,84 @@
+//! Checks driven by a user-supplied Lua script.
+//!
+//! A [`CustomScript`] brings its own limiting formula; the limiter still builds the keys under
+//! its prefix, applies the identifier normalization and policy, caches the script by SHA,
+//! passes the configured limit and window, and maps Redis failures to
+//! [`RateLimiterError::Redis`].
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::RateLimiterError;
+
+type Decoder = Arc<dyn Fn(redis::Value) -> Result<(), RateLimiterError> + Send + Sync>;
+
+/// A Lua script run by `check_custom` in place of the built-in check.
+///
+/// The script is called with:
+///
+/// - `KEYS[1]`: `{prefix}:{identifier}`, then one `{prefix}:{name}:{identifier}` per
+///   [`with_key`](CustomScript::with_key), in the order they were added
+/// - `ARGV[1]`: the configured limit, `ARGV[2]`: the configured window in milliseconds,
+///   `ARGV[3]`: the identifier, followed by the arguments passed to `check_custom`
+///
+/// Overrides are not applied; the script sees the limiter's configured values. Its reply is
+/// handed to the decoder, which returns `Ok(())` to allow the request or an error (usually
+/// `RateLimiterError::RateLimitExceeded`, with a zero `retry_after` if the script doesn't
+/// report one) to deny it.
+#[derive(Clone)]
+pub struct CustomScript {
+    script: redis::Script,
+    source: String,
+    keys: Vec<String>,
+    decode: Decoder,
+}
+
+impl CustomScript {
+    pub fn new<F>(source: &str, decode: F) -> Self
+    where
+        F: Fn(redis::Value) -> Result<(), RateLimiterError> + Send + Sync + 'static,
+    {
+        CustomScript {
+            script: redis::Script::new(source),
+            source: source.to_string(),
+            keys: Vec::new(),
+            decode: Arc::new(decode),
+        }
+    }
+
+    /// Passes `{prefix}:{name}:{identifier}` as the next key, for state beyond the counter.
+    pub fn with_key(mut self, name: &str) -> Self {
+        self.keys.push(name.to_string());
+        self
+    }
+
+    /// SHA1 of the source, as used with `EVALSHA`.
+    pub fn hash(&self) -> &str {
+        self.script.get_hash()
+    }
+
+    pub(crate) fn script(&self) -> &redis::Script {
+        &self.script
+    }
+
+    pub(crate) fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub(crate) fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    pub(crate) fn decode(&self, reply: redis::Value) -> Result<(), RateLimiterError> {
+        (self.decode)(reply)
+    }
+}
+
+impl fmt::Debug for CustomScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomScript")
+            .field("hash", &self.hash())
+            .field("keys", &self.keys)
+            .finish_non_exhaustive()
+    }
+}
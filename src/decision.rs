@@ -0,0 +1,200 @@
+//! The full outcome of a check, for callers that answer with rate limit headers.
+
+use std::time::Duration;
+
+use crate::{DenialReason, FailurePolicy, RateLimiterError, Usage};
+
+/// Whether a request was allowed, with everything needed for `RateLimit-*` and `Retry-After`
+/// response headers, read in the same atomic script call that made the decision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    /// Limit in effect for the identifier, after overrides.
+    pub limit: u64,
+    /// Requests still available after this one; 0 when denied.
+    pub remaining: u64,
+    /// Time until the identifier's state fully resets (see `Usage::resets_in`).
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "reset_after_ms", with = "crate::serde_duration::millis")
+    )]
+    pub reset_after: Duration,
+    /// When denied, how long until a retry can succeed. `None` when allowed.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "retry_after_ms",
+            with = "crate::serde_duration::option_millis"
+        )
+    )]
+    pub retry_after: Option<Duration>,
+    /// Why the request was denied. `None` when allowed.
+    pub reason: Option<DenialReason>,
+    /// Which limit denied the request: 0 for the primary limit, `n` for the `n`th added with
+    /// `with_additional_limit`, whose limit and times the decision then reports. `None` unless
+    /// denied with `DenialReason::WindowExhausted`.
+    pub violated_rule: Option<usize>,
+}
+
+impl RateLimitDecision {
+    /// The answer of `policy` when Redis couldn't be reached: nothing is known about the
+    /// identifier's usage, so `remaining` and the times are zero.
+    pub(crate) fn from_failure(policy: FailurePolicy, limit: u64) -> Self {
+        let allowed = policy == FailurePolicy::Open;
+        RateLimitDecision {
+            allowed,
+            limit,
+            remaining: 0,
+            reset_after: Duration::ZERO,
+            retry_after: (!allowed).then_some(Duration::ZERO),
+            reason: (!allowed).then_some(DenialReason::WindowExhausted),
+            violated_rule: (!allowed).then_some(0),
+        }
+    }
+
+    /// The decision a request would get now, judged from a usage snapshot without making
+    /// one: `remaining` is what is left before it, and a denial waits for the window to
+    /// reset. Borrowing, the cardinality limit and additional limits are not taken into
+    /// account.
+    pub(crate) fn from_usage(usage: &Usage) -> Self {
+        let allowed = usage.remaining > 0;
+        let reset_after = usage.resets_in.unwrap_or_default();
+        RateLimitDecision {
+            allowed,
+            limit: usage.limit,
+            remaining: usage.remaining,
+            reset_after,
+            retry_after: (!allowed).then_some(reset_after),
+            reason: (!allowed).then_some(DenialReason::WindowExhausted),
+            violated_rule: (!allowed).then_some(0),
+        }
+    }
+
+    /// Units of the limit in use after this decision: `limit - remaining`, so the whole limit
+    /// when denied.
+    pub fn used(&self) -> u64 {
+        self.limit.saturating_sub(self.remaining)
+    }
+
+    /// The response headers for this decision: `RateLimit-Limit`, `RateLimit-Remaining`,
+    /// `RateLimit-Reset` and, when denied, `Retry-After`, as lowercase names and values. Times
+    /// are whole seconds, rounded up so clients don't retry early.
+    pub fn headers(&self) -> Vec<(&'static str, u64)> {
+        let seconds = |d: Duration| (d.as_millis() as u64 + 999) / 1000;
+        let mut headers = vec![
+            ("ratelimit-limit", self.limit),
+            ("ratelimit-remaining", self.remaining),
+            ("ratelimit-reset", seconds(self.reset_after)),
+        ];
+        if let Some(retry_after) = self.retry_after {
+            headers.push(("retry-after", seconds(retry_after)));
+        }
+        headers
+    }
+
+    /// `headers()` as an `http::HeaderMap`, to extend a response's headers with.
+    #[cfg(feature = "http")]
+    pub fn header_map(&self) -> http::HeaderMap {
+        self.headers()
+            .into_iter()
+            .map(|(name, value)| {
+                (
+                    http::HeaderName::from_static(name),
+                    http::HeaderValue::from(value),
+                )
+            })
+            .collect()
+    }
+
+    /// The decision as a `check` result: a denial becomes `RateLimitExceeded`.
+    pub(crate) fn into_result(self) -> Result<(), RateLimiterError> {
+        if self.allowed {
+            return Ok(());
+        }
+        Err(RateLimiterError::RateLimitExceeded {
+            retry_after: self.retry_after.unwrap_or_default(),
+        })
+    }
+}
+
+/// What `run` did with a request: ran the closure, or turned it away without running it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunOutcome<T> {
+    /// The request was allowed and the closure returned this.
+    Completed(T),
+    /// The request was denied; the decision has the reason and when to retry.
+    Rejected(RateLimitDecision),
+}
+
+impl<T> RunOutcome<T> {
+    /// The closure's value, or `RateLimitExceeded` if the request was rejected, for callers
+    /// that pass rejections on with `?`.
+    pub fn into_result(self) -> Result<T, RateLimiterError> {
+        match self {
+            RunOutcome::Completed(value) => Ok(value),
+            RunOutcome::Rejected(decision) => Err(RateLimiterError::RateLimitExceeded {
+                retry_after: decision.retry_after.unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_usage() {
+        let window = Duration::from_secs(60);
+        let decision = RateLimitDecision::from_usage(&Usage::from_raw(Some(3), 40_000, 10, window));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 7);
+        assert_eq!(decision.used(), 3);
+        assert_eq!(decision.reset_after, Duration::from_secs(40));
+        assert_eq!(decision.retry_after, None);
+
+        let decision = RateLimitDecision::from_usage(&Usage::from_raw(Some(12), 5_000, 10, window));
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.used(), 10);
+        assert_eq!(decision.retry_after, Some(Duration::from_secs(5)));
+        assert_eq!(decision.reason, Some(DenialReason::WindowExhausted));
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut decision = RateLimitDecision {
+            allowed: true,
+            limit: 10,
+            remaining: 4,
+            reset_after: Duration::from_millis(1500),
+            retry_after: None,
+            reason: None,
+            violated_rule: None,
+        };
+        assert_eq!(
+            decision.headers(),
+            [
+                ("ratelimit-limit", 10),
+                ("ratelimit-remaining", 4),
+                ("ratelimit-reset", 2)
+            ]
+        );
+
+        decision.retry_after = Some(Duration::from_millis(200));
+        assert_eq!(decision.headers()[3], ("retry-after", 1));
+        #[cfg(feature = "http")]
+        assert_eq!(decision.header_map()["retry-after"], "1");
+    }
+
+    #[test]
+    fn test_run_outcome_into_result() {
+        assert_eq!(RunOutcome::Completed(7).into_result().unwrap(), 7);
+        let decision = RateLimitDecision::from_failure(FailurePolicy::Closed, 10);
+        assert!(matches!(
+            RunOutcome::<()>::Rejected(decision).into_result(),
+            Err(RateLimiterError::RateLimitExceeded { retry_after }) if retry_after.is_zero()
+        ));
+    }
+}
@@ -0,0 +1,120 @@
+//! Machine-readable causes of denied requests.
+//!
+//! Middleware maps a [`DenialReason`] to a response (429 for an exhausted window, 403 for a
+//! ban, a challenge page for a soft limit) and metrics use [`DenialReason::as_str`] as a label,
+//! instead of both matching on error variants.
+
+use std::fmt;
+
+use crate::RateLimiterError;
+
+/// Why a request was denied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[non_exhaustive]
+pub enum DenialReason {
+    /// The identifier used up its allowance for the current window.
+    WindowExhausted,
+    /// The identifier is banned, outright or until a temporary ban (see `EscalationPolicy`)
+    /// lifts; retrying before then does not help.
+    Banned,
+    /// A cap shared by all identifiers was reached, e.g. the distinct identifier limit of
+    /// `with_cardinality_limit`.
+    GlobalCap,
+    /// Too many requests for the identifier are in flight at once.
+    ConcurrencyCap,
+    /// An operator switched the limited resource off.
+    KillSwitch,
+    /// A soft limit was crossed; the client should pass a challenge before continuing.
+    SoftLimitChallenge,
+}
+
+impl DenialReason {
+    /// Stable snake_case name, suitable as a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DenialReason::WindowExhausted => "window_exhausted",
+            DenialReason::Banned => "banned",
+            DenialReason::GlobalCap => "global_cap",
+            DenialReason::ConcurrencyCap => "concurrency_cap",
+            DenialReason::KillSwitch => "kill_switch",
+            DenialReason::SoftLimitChallenge => "soft_limit_challenge",
+        }
+    }
+
+    /// Whether the same request may succeed later without operator action.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, DenialReason::Banned | DenialReason::KillSwitch)
+    }
+}
+
+impl fmt::Display for DenialReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl RateLimiterError {
+    /// The reason the request was denied, or `None` if the error is not a denial (a Redis
+    /// failure, a missed deadline, invalid input, ...).
+    pub fn denial_reason(&self) -> Option<DenialReason> {
+        match self {
+            RateLimiterError::RateLimitExceeded { .. } => Some(DenialReason::WindowExhausted),
+            RateLimiterError::CardinalityLimitExceeded => Some(DenialReason::GlobalCap),
+            RateLimiterError::Banned { .. } | RateLimiterError::Denylisted => {
+                Some(DenialReason::Banned)
+            }
+            RateLimiterError::ConcurrencyLimitExceeded { .. } => Some(DenialReason::ConcurrencyCap),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_denial_reason() {
+        assert_eq!(
+            RateLimiterError::RateLimitExceeded {
+                retry_after: Duration::from_secs(1)
+            }
+            .denial_reason(),
+            Some(DenialReason::WindowExhausted)
+        );
+        assert_eq!(
+            RateLimiterError::CardinalityLimitExceeded.denial_reason(),
+            Some(DenialReason::GlobalCap)
+        );
+        assert_eq!(
+            RateLimiterError::ConcurrencyLimitExceeded { max_in_flight: 5 }.denial_reason(),
+            Some(DenialReason::ConcurrencyCap)
+        );
+        assert_eq!(
+            RateLimiterError::Banned {
+                until: std::time::SystemTime::now()
+            }
+            .denial_reason(),
+            Some(DenialReason::Banned)
+        );
+        assert_eq!(
+            RateLimiterError::Denylisted.denial_reason(),
+            Some(DenialReason::Banned)
+        );
+        assert_eq!(RateLimiterError::DeadlineExceeded.denial_reason(), None);
+
+        assert_eq!(
+            DenialReason::SoftLimitChallenge.to_string(),
+            "soft_limit_challenge"
+        );
+        assert!(DenialReason::WindowExhausted.is_retryable());
+        assert!(!DenialReason::Banned.is_retryable());
+    }
+}
@@ -0,0 +1,106 @@
+//! Draining a `tokio::sync::mpsc` channel at the distributed limit.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::{AsyncRateLimiter, ThrottleError};
+
+/// Spawns a task that receives messages from `receiver` and passes each one to `handler`
+/// once `limiter` allows a request for `identifier`, running at most `concurrency` handlers
+/// at a time.
+///
+/// Every replica draining its own channel against the same limiter shares one budget, e.g.
+/// an email provider's quota. Messages that couldn't be checked because of a Redis error are
+/// handed to `handler` as a `ThrottleError`, so they can be retried or dead-lettered.
+///
+/// The task finishes once the channel is closed and all handlers have completed.
+///
+/// # Panics
+///
+/// Panics if `concurrency` is zero, or outside a tokio runtime: the returned `JoinHandle` is
+/// tokio's, even with the `async-std` feature.
+pub fn spawn_drainer<T, F, Fut>(
+    mut receiver: mpsc::Receiver<T>,
+    limiter: Arc<AsyncRateLimiter>,
+    identifier: &str,
+    concurrency: usize,
+    mut handler: F,
+) -> JoinHandle<()>
+where
+    T: Send + 'static,
+    F: FnMut(Result<T, ThrottleError<T>>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    assert!(concurrency > 0, "concurrency must be at least 1");
+    let identifier = identifier.to_string();
+    let permits = Arc::new(Semaphore::new(concurrency));
+
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            let permit = Arc::clone(&permits)
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+            let message = match limiter.wait(&identifier).await {
+                Ok(()) => Ok(message),
+                Err(error) => Err(ThrottleError {
+                    item: message,
+                    error,
+                }),
+            };
+            let handled = handler(message);
+            tokio::spawn(async move {
+                handled.await;
+                drop(permit);
+            });
+        }
+
+        // Wait for in-flight handlers before reporting completion.
+        let _ = permits.acquire_many(concurrency as u32).await;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RateLimiterError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_drainer_is_paced_by_limit() -> Result<(), RateLimiterError> {
+        let limiter = Arc::new(AsyncRateLimiter::new(
+            "redis://127.0.0.1:6379",
+            "test_drainer",
+            2,
+            Duration::from_secs(1),
+        )?);
+        let (sender, receiver) = mpsc::channel(8);
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&sent);
+        let drainer = spawn_drainer(receiver, limiter, "email", 2, move |message| {
+            let counter = Arc::clone(&counter);
+            async move {
+                message.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let start = Instant::now();
+        for i in 0..3 {
+            sender.send(i).await.unwrap();
+        }
+        drop(sender);
+        drainer.await.unwrap();
+
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+        // The third message had to wait for the next window.
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        Ok(())
+    }
+}
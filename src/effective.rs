@@ -0,0 +1,145 @@
+//! Resolution of the limit and window actually enforced for an identifier.
+//!
+//! Each setting is resolved independently, highest precedence first:
+//!
+//! 1. a per-call override passed to `check_with_override`,
+//! 2. the identifier's override stored in Redis (`set_override`),
+//! 3. the limiter-wide limits stored in Redis (`set_limits`),
+//! 4. the limiter's configured value.
+//!
+//! The check script applies the same order, so `effective_config` reports what the script
+//! enforces.
+
+use std::time::Duration;
+
+use crate::LimitOverride;
+
+/// Where an effective setting came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[non_exhaustive]
+pub enum ConfigSource {
+    /// The value the limiter was constructed with.
+    Configured,
+    /// The identifier's override stored in Redis.
+    RedisOverride,
+    /// The limiter-wide limits stored in Redis with `set_limits`.
+    StoredLimits,
+    /// An override passed to a single call.
+    PerCall,
+}
+
+/// The limit and window enforced for an identifier, and which source each one came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EffectiveConfig {
+    pub max_requests: u64,
+    pub max_requests_source: ConfigSource,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "window_ms", with = "crate::serde_duration::millis")
+    )]
+    pub window: Duration,
+    pub window_source: ConfigSource,
+}
+
+impl EffectiveConfig {
+    pub(crate) fn resolve(
+        max_requests: u64,
+        window: Duration,
+        stored_limits: Option<&LimitOverride>,
+        redis_override: Option<&LimitOverride>,
+        per_call: Option<&LimitOverride>,
+    ) -> Self {
+        let (max_requests, max_requests_source) = pick(
+            max_requests,
+            [
+                (per_call.and_then(|o| o.max_requests), ConfigSource::PerCall),
+                (
+                    redis_override.and_then(|o| o.max_requests),
+                    ConfigSource::RedisOverride,
+                ),
+                (
+                    stored_limits.and_then(|o| o.max_requests),
+                    ConfigSource::StoredLimits,
+                ),
+            ],
+        );
+        let (window, window_source) = pick(
+            window,
+            [
+                (per_call.and_then(|o| o.window), ConfigSource::PerCall),
+                (
+                    redis_override.and_then(|o| o.window),
+                    ConfigSource::RedisOverride,
+                ),
+                (
+                    stored_limits.and_then(|o| o.window),
+                    ConfigSource::StoredLimits,
+                ),
+            ],
+        );
+        EffectiveConfig {
+            max_requests,
+            max_requests_source,
+            window,
+            window_source,
+        }
+    }
+}
+
+/// The first value set among `overrides`, highest precedence first, or else `configured`.
+fn pick<T>(configured: T, overrides: [(Option<T>, ConfigSource); 3]) -> (T, ConfigSource) {
+    overrides
+        .into_iter()
+        .find_map(|(value, source)| value.map(|value| (value, source)))
+        .unwrap_or((configured, ConfigSource::Configured))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence_per_setting() {
+        let window = Duration::from_secs(60);
+        let stored = LimitOverride::max_requests(50);
+        let per_call = LimitOverride::window(Duration::from_secs(1));
+
+        let config = EffectiveConfig::resolve(10, window, None, None, None);
+        assert_eq!(config.max_requests_source, ConfigSource::Configured);
+        assert_eq!(config.window_source, ConfigSource::Configured);
+
+        let config = EffectiveConfig::resolve(10, window, None, Some(&stored), Some(&per_call));
+        assert_eq!(
+            (config.max_requests, config.max_requests_source),
+            (50, ConfigSource::RedisOverride)
+        );
+        assert_eq!(
+            (config.window, config.window_source),
+            (Duration::from_secs(1), ConfigSource::PerCall)
+        );
+
+        let per_call = LimitOverride::max_requests(5);
+        let config = EffectiveConfig::resolve(10, window, None, Some(&stored), Some(&per_call));
+        assert_eq!(
+            (config.max_requests, config.max_requests_source),
+            (5, ConfigSource::PerCall)
+        );
+
+        let limits = LimitOverride::max_requests(20).with_window(Duration::from_secs(30));
+        let config = EffectiveConfig::resolve(10, window, Some(&limits), Some(&stored), None);
+        assert_eq!(
+            (config.max_requests, config.max_requests_source),
+            (50, ConfigSource::RedisOverride)
+        );
+        assert_eq!(
+            (config.window, config.window_source),
+            (Duration::from_secs(30), ConfigSource::StoredLimits)
+        );
+    }
+}
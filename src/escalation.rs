@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+/// Temporarily bans identifiers that keep sending requests after being limited: once an
+/// identifier is denied `max_violations` times within `period`, every check of it fails with
+/// `RateLimiterError::Banned` for `ban_duration`, whatever its window allows.
+///
+/// Violations and bans are tracked in the check script, so they apply across instances.
+/// Denials by the primary and additional limits count as violations; those of the cardinality
+/// limit don't. `reset` lifts a ban.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EscalationPolicy {
+    pub max_violations: u64,
+    pub period: Duration,
+    pub ban_duration: Duration,
+}
+
+impl EscalationPolicy {
+    pub fn new(max_violations: u64, period: Duration, ban_duration: Duration) -> Self {
+        EscalationPolicy {
+            max_violations,
+            period,
+            ban_duration,
+        }
+    }
+}
+
+/// Blocks identifiers for longer after each consecutive denial: the first blocks it for
+/// `initial`, the next for twice as long, and so on up to `max` (1s, 2s, 4s… with an
+/// `initial` of one second). A blocked identifier's checks fail with
+/// `RateLimiterError::Banned`, and the denial that starts a block reports it in its
+/// `retry_after`.
+///
+/// The streak is kept in the check script next to the counter, so it applies across
+/// instances. An allowed request ends it, as does staying away for `max` after a block, so
+/// clients that are never denied are unaffected. `reset` clears both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PenaltyPolicy {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl PenaltyPolicy {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        PenaltyPolicy { initial, max }
+    }
+
+    /// How long the `streak`-th consecutive denial (counting from 1) blocks for.
+    pub fn block_for(&self, streak: u32) -> Duration {
+        let factor = 1u32 << streak.saturating_sub(1).min(31);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_penalty_doubles_up_to_max() {
+        let policy = PenaltyPolicy::new(Duration::from_secs(1), Duration::from_secs(10));
+        let blocks: Vec<_> = (1..=6).map(|streak| policy.block_for(streak)).collect();
+        assert_eq!(
+            blocks,
+            [1, 2, 4, 8, 10, 10].map(Duration::from_secs).to_vec()
+        );
+        assert_eq!(policy.block_for(u32::MAX), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,248 @@
+//! Detection of Redis eviction policies that can silently reset limits.
+//!
+//! Every limiter key carries a TTL, so it is an eviction candidate under both `allkeys-*` and
+//! `volatile-*` `maxmemory-policy` settings. When Redis runs out of memory under such a
+//! policy, counters disappear and clients get a fresh budget. Only `noeviction` is safe.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::runtime;
+
+/// What to do when the server's eviction policy may evict limiter keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicyAction {
+    /// Log a warning and carry on.
+    #[default]
+    Warn,
+    /// Fail with `RateLimiterError::UnsafeEvictionPolicy`.
+    Error,
+}
+
+pub(crate) fn policy_cmd() -> redis::Cmd {
+    let mut cmd = redis::cmd("CONFIG");
+    cmd.arg("GET").arg("maxmemory-policy");
+    cmd
+}
+
+/// Extracts the policy from a `CONFIG GET` reply (`[name, value]`).
+pub(crate) fn parse_policy(reply: Vec<String>) -> Result<String, redis::RedisError> {
+    reply.into_iter().nth(1).ok_or_else(|| {
+        redis::RedisError::from((
+            redis::ErrorKind::ResponseError,
+            "CONFIG GET maxmemory-policy returned no value",
+        ))
+    })
+}
+
+pub(crate) fn is_unsafe(policy: &str) -> bool {
+    policy != "noeviction"
+}
+
+pub(crate) fn apply_action(
+    policy: String,
+    action: EvictionPolicyAction,
+) -> Result<String, crate::RateLimiterError> {
+    if !is_unsafe(&policy) {
+        return Ok(policy);
+    }
+    match action {
+        EvictionPolicyAction::Warn => {
+            log::warn!(
+                "redis maxmemory-policy is `{}`: rate limit keys may be evicted under memory pressure",
+                policy
+            );
+            Ok(policy)
+        }
+        EvictionPolicyAction::Error => {
+            Err(crate::RateLimiterError::UnsafeEvictionPolicy { policy })
+        }
+    }
+}
+
+pub(crate) type EvictionHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Handle to a background task that periodically checks a canary key and reports when it
+/// disappears before its TTL, which means Redis evicted (or someone deleted) it.
+///
+/// The task stops when the handle is dropped.
+pub struct EvictionCanary {
+    evictions: Arc<AtomicU64>,
+    stop: Option<StopHandle>,
+}
+
+enum StopHandle {
+    #[cfg(feature = "blocking")]
+    Thread(std::sync::mpsc::Sender<()>),
+    Task(tokio::sync::oneshot::Sender<()>),
+}
+
+impl EvictionCanary {
+    /// Number of evictions detected so far.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Stops the background task.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        match self.stop.take() {
+            #[cfg(feature = "blocking")]
+            Some(StopHandle::Thread(tx)) => {
+                let _ = tx.send(());
+            }
+            Some(StopHandle::Task(tx)) => {
+                let _ = tx.send(());
+            }
+            None => {}
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    pub(crate) fn spawn_thread(
+        client: redis::Client,
+        key: String,
+        interval: Duration,
+        on_evicted: EvictionHook,
+    ) -> Self {
+        let evictions = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&evictions);
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            let mut armed = false;
+            loop {
+                let probe = client
+                    .get_connection()
+                    .and_then(|mut conn| canary_pipeline(&key, interval).query(&mut conn));
+                armed = observe(probe, armed, &key, &counter, &on_evicted);
+                match rx.recv_timeout(interval) {
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+            }
+        });
+        EvictionCanary {
+            evictions,
+            stop: Some(StopHandle::Thread(tx)),
+        }
+    }
+
+    pub(crate) fn spawn_task(
+        client: redis::Client,
+        key: String,
+        interval: Duration,
+        on_evicted: EvictionHook,
+    ) -> Self {
+        let evictions = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&evictions);
+        let (tx, mut rx) = tokio::sync::oneshot::channel::<()>();
+        runtime::spawn(async move {
+            let mut armed = false;
+            let mut conn = None;
+            loop {
+                if conn.is_none() {
+                    conn = client.get_multiplexed_async_connection().await.ok();
+                }
+                if let Some(c) = conn.as_mut() {
+                    let probe = canary_pipeline(&key, interval).query_async(c).await;
+                    if matches!(&probe, Err(e) if e.is_connection_dropped()) {
+                        conn = None;
+                    }
+                    armed = observe(probe, armed, &key, &counter, &on_evicted);
+                }
+                if runtime::timeout(interval, &mut rx).await.is_ok() {
+                    break;
+                }
+            }
+        });
+        EvictionCanary {
+            evictions,
+            stop: Some(StopHandle::Task(tx)),
+        }
+    }
+}
+
+impl Drop for EvictionCanary {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Checks whether the canary still exists and re-arms it with a TTL comfortably longer than
+/// the probe interval, so only eviction or deletion can make it disappear between probes.
+fn canary_pipeline(key: &str, interval: Duration) -> redis::Pipeline {
+    let ttl_ms = interval.as_millis().saturating_mul(3).max(1000) as u64;
+    let mut pipe = redis::pipe();
+    pipe.exists(key)
+        .cmd("SET")
+        .arg(key)
+        .arg(1)
+        .arg("PX")
+        .arg(ttl_ms)
+        .ignore();
+    pipe
+}
+
+/// Handles one probe result and returns whether the canary is armed afterwards.
+fn observe(
+    probe: redis::RedisResult<(bool,)>,
+    armed: bool,
+    key: &str,
+    evictions: &AtomicU64,
+    on_evicted: &EvictionHook,
+) -> bool {
+    match probe {
+        Ok((exists,)) => {
+            if armed && !exists {
+                evictions.fetch_add(1, Ordering::Relaxed);
+                log::warn!("rate limiter canary key `{}` was evicted", key);
+                on_evicted(key);
+            }
+            true
+        }
+        Err(e) => {
+            log::warn!("rate limiter canary probe failed: {}", e);
+            armed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsafe_policies() {
+        assert!(!is_unsafe("noeviction"));
+        assert!(is_unsafe("allkeys-lru"));
+        assert!(is_unsafe("volatile-ttl"));
+
+        assert!(apply_action("allkeys-lru".into(), EvictionPolicyAction::Warn).is_ok());
+        assert!(matches!(
+            apply_action("allkeys-lru".into(), EvictionPolicyAction::Error),
+            Err(crate::RateLimiterError::UnsafeEvictionPolicy { policy }) if policy == "allkeys-lru"
+        ));
+        assert_eq!(
+            parse_policy(vec!["maxmemory-policy".into(), "noeviction".into()]).unwrap(),
+            "noeviction"
+        );
+    }
+
+    #[test]
+    fn test_observe_detects_missing_canary() {
+        let evictions = AtomicU64::new(0);
+        let hook: EvictionHook = Arc::new(|_| {});
+
+        // The first probe only arms the canary.
+        assert!(observe(Ok((false,)), false, "k", &evictions, &hook));
+        assert_eq!(evictions.load(Ordering::Relaxed), 0);
+
+        assert!(observe(Ok((true,)), true, "k", &evictions, &hook));
+        assert!(observe(Ok((false,)), true, "k", &evictions, &hook));
+        assert_eq!(evictions.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,169 @@
+//! Sink for the `metrics` crate facade, available with the `metrics` feature.
+
+use crate::metrics::{CheckEvent, CheckOutcome, MetricsSink, TimingEvent};
+
+/// Records check counters and Redis latency histograms through the `metrics` crate, exported
+/// by whichever recorder the application installed (e.g. `metrics-exporter-prometheus`):
+///
+/// - `<namespace>_checks_total{prefix, outcome[, reason]}`: check decisions, where `outcome`
+///   is `allowed`, `denied` (with the denial `reason`) or `error` for Redis failures
+/// - `<namespace>_redis_duration_seconds{prefix, operation}`: Redis operation latencies,
+///   `check` among them
+///
+/// Sampled check events are counted with their weight (`1 / sample_rate`), so the counter
+/// still estimates every check; sampled timings are recorded as they are.
+#[derive(Debug, Clone)]
+pub struct MetricsFacadeSink {
+    checks: String,
+    durations: String,
+    identifier_label: bool,
+}
+
+impl MetricsFacadeSink {
+    /// Records metrics named under `namespace`, e.g. `ratelimit`.
+    pub fn new(namespace: &str) -> Self {
+        MetricsFacadeSink {
+            checks: format!("{}_checks_total", namespace),
+            durations: format!("{}_redis_duration_seconds", namespace),
+            identifier_label: false,
+        }
+    }
+
+    /// Labels check counters with the identifier, capped by
+    /// `TelemetrySampling::with_max_label_values`.
+    pub fn with_identifier_label(mut self, enabled: bool) -> Self {
+        self.identifier_label = enabled;
+        self
+    }
+}
+
+impl MetricsSink for MetricsFacadeSink {
+    fn record_check(&self, event: &CheckEvent<'_>) {
+        let mut labels = vec![
+            ("prefix", event.key_prefix.to_string()),
+            ("outcome", event.outcome.as_str().to_string()),
+        ];
+        if let CheckOutcome::Denied(reason) = event.outcome {
+            labels.push(("reason", reason.as_str().to_string()));
+        }
+        if self.identifier_label {
+            labels.push(("identifier", event.identifier.to_string()));
+        }
+        let weight = if event.sample_rate > 0.0 {
+            (1.0 / event.sample_rate).round() as u64
+        } else {
+            0
+        };
+        ::metrics::counter!(self.checks.clone(), &labels).increment(weight);
+    }
+
+    fn record_timing(&self, event: &TimingEvent<'_>) {
+        let labels = [
+            ("prefix", event.key_prefix.to_string()),
+            ("operation", event.operation.to_string()),
+        ];
+        ::metrics::histogram!(self.durations.clone(), &labels).record(event.duration.as_secs_f64());
+    }
+
+    fn wants_identifier(&self) -> bool {
+        self.identifier_label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use ::metrics::{
+        Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+        SharedString, Unit,
+    };
+
+    use super::*;
+    use crate::DenialReason;
+
+    type Recorded = Arc<Mutex<Vec<String>>>;
+
+    /// Records every update as `name{labels} value`.
+    struct Capture(Recorded);
+
+    struct Metric(Key, Recorded);
+
+    impl Metric {
+        fn push(&self, value: impl std::fmt::Display) {
+            let labels: Vec<String> = self
+                .0
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect();
+            self.1.lock().unwrap().push(format!(
+                "{}{{{}}} {}",
+                self.0.name(),
+                labels.join(","),
+                value
+            ));
+        }
+    }
+
+    impl CounterFn for Metric {
+        fn increment(&self, value: u64) {
+            self.push(value);
+        }
+
+        fn absolute(&self, _value: u64) {}
+    }
+
+    impl HistogramFn for Metric {
+        fn record(&self, value: f64) {
+            self.push(value);
+        }
+    }
+
+    impl Recorder for Capture {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(Arc::new(Metric(key.clone(), self.0.clone())))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(Arc::new(Metric(key.clone(), self.0.clone())))
+        }
+    }
+
+    #[test]
+    fn test_records_counters_and_histograms() {
+        let recorded = Recorded::default();
+        let sink = MetricsFacadeSink::new("ratelimit");
+        ::metrics::with_local_recorder(&Capture(recorded.clone()), || {
+            sink.record_check(&CheckEvent {
+                key_prefix: "api",
+                identifier: "",
+                outcome: CheckOutcome::Denied(DenialReason::WindowExhausted),
+                sample_rate: 0.25,
+            });
+            sink.record_timing(&TimingEvent {
+                key_prefix: "api",
+                operation: "check",
+                duration: Duration::from_micros(1500),
+                sample_rate: 1.0,
+            });
+        });
+        assert_eq!(
+            *recorded.lock().unwrap(),
+            [
+                "ratelimit_checks_total{prefix=api,outcome=denied,reason=window_exhausted} 4",
+                "ratelimit_redis_duration_seconds{prefix=api,operation=check} 0.0015",
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::breaker::CircuitBreaker;
+use crate::failover;
+use crate::RateLimiterError;
+
+/// What a check answers when Redis can't be reached (a connection error, a timeout or an
+/// exhausted pool). Errors Redis replies with, e.g. a failing script, are returned as they
+/// are. Without a policy the error is returned to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum FailurePolicy {
+    /// Allow the request, trading enforcement for availability.
+    Open,
+    /// Deny the request with `RateLimitExceeded` (with a zero `retry_after`).
+    Closed,
+}
+
+impl FailurePolicy {
+    pub(crate) fn check_outcome(self) -> Result<(), RateLimiterError> {
+        match self {
+            FailurePolicy::Open => Ok(()),
+            FailurePolicy::Closed => Err(RateLimiterError::RateLimitExceeded {
+                retry_after: Duration::ZERO,
+            }),
+        }
+    }
+}
+
+pub(crate) type BackendErrorHook = Arc<dyn Fn(&RateLimiterError) + Send + Sync>;
+
+/// How a check that couldn't reach Redis is answered.
+pub(crate) enum Fallback<'a> {
+    Policy(FailurePolicy),
+    /// By the circuit breaker's in-process limiter.
+    Local(&'a CircuitBreaker),
+}
+
+/// The failure policy and circuit breaker of a limiter and the hook told about every backend
+/// failure.
+#[derive(Clone, Default)]
+pub(crate) struct FailureHandling {
+    pub(crate) policy: Option<FailurePolicy>,
+    pub(crate) breaker: Option<Arc<CircuitBreaker>>,
+    pub(crate) on_error: Option<BackendErrorHook>,
+}
+
+impl FailureHandling {
+    /// Whether a check may go to Redis, i.e. no circuit breaker is turning it away.
+    pub(crate) fn admitted(&self) -> bool {
+        self.breaker
+            .as_ref()
+            .map_or(true, |breaker| breaker.admit())
+    }
+
+    /// Reports the result of a check to the circuit breaker and a backend failure to the
+    /// hook, then answers the failure from `fallback`: by the breaker's limiter if there is
+    /// one, else by the policy. `None` is a check the breaker turned away. Other results are
+    /// returned as is.
+    pub(crate) fn apply<T>(
+        &self,
+        key_prefix: &str,
+        identifier: &str,
+        result: Option<Result<T, RateLimiterError>>,
+        fallback: impl FnOnce(Fallback<'_>) -> Result<T, RateLimiterError>,
+    ) -> Result<T, RateLimiterError> {
+        let error = match result {
+            Some(Err(e)) if is_backend_failure(&e) => {
+                if let Some(breaker) = &self.breaker {
+                    breaker.record_failure();
+                }
+                if let Some(hook) = &self.on_error {
+                    hook(&e);
+                }
+                Some(e)
+            }
+            Some(other) => {
+                // Any reply that isn't a backend failure means Redis answered, e.g. a half-open
+                // probe denied by a cardinality cap.
+                if let Some(breaker) = &self.breaker {
+                    breaker.record_success();
+                }
+                return other;
+            }
+            None => None,
+        };
+        if let Some(breaker) = &self.breaker {
+            if let Some(error) = &error {
+                log::warn!(
+                    "rate limiter `{}`: check of `{}` failed ({}); using the local limiter",
+                    key_prefix,
+                    identifier,
+                    error
+                );
+            }
+            return fallback(Fallback::Local(breaker));
+        }
+        let error = error.expect("only a circuit breaker turns checks away");
+        match self.policy {
+            Some(policy) => {
+                log::warn!(
+                    "rate limiter `{}`: check of `{}` failed ({}); failing {:?}",
+                    key_prefix,
+                    identifier,
+                    error,
+                    policy
+                );
+                fallback(Fallback::Policy(policy))
+            }
+            None => Err(error),
+        }
+    }
+}
+
+impl fmt::Debug for FailureHandling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FailureHandling")
+            .field("policy", &self.policy)
+            .field("breaker", &self.breaker)
+            .field("on_error", &self.on_error.is_some())
+            .finish()
+    }
+}
+
+/// Whether `error` means Redis couldn't be reached, or not before the caller's deadline, as
+/// opposed to a decision, bad input or an error Redis replied with (a script error,
+/// `WRONGTYPE`), which failing open would hide.
+fn is_backend_failure(error: &RateLimiterError) -> bool {
+    match error {
+        RateLimiterError::Redis(e) => failover::is_unhealthy(e),
+        RateLimiterError::PoolExhausted | RateLimiterError::DeadlineExceeded => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn unreachable() -> Result<(), RateLimiterError> {
+        Err(RateLimiterError::Redis(redis::RedisError::from((
+            redis::ErrorKind::IoError,
+            "connection refused",
+        ))))
+    }
+
+    #[test]
+    fn test_failure_policy() {
+        let errors = Arc::new(AtomicUsize::new(0));
+        let seen = errors.clone();
+        let mut handling = FailureHandling {
+            policy: None,
+            breaker: None,
+            on_error: Some(Arc::new(move |_: &RateLimiterError| {
+                seen.fetch_add(1, Ordering::Relaxed);
+            })),
+        };
+        let apply = |handling: &FailureHandling, result| {
+            handling.apply("app", "user_1", Some(result), |fallback| match fallback {
+                Fallback::Policy(policy) => policy.check_outcome(),
+                Fallback::Local(_) => unreachable!(),
+            })
+        };
+
+        assert!(matches!(
+            apply(&handling, unreachable()),
+            Err(RateLimiterError::Redis(_))
+        ));
+        handling.policy = Some(FailurePolicy::Open);
+        assert!(apply(&handling, unreachable()).is_ok());
+        handling.policy = Some(FailurePolicy::Closed);
+        assert!(matches!(
+            apply(&handling, unreachable()),
+            Err(RateLimiterError::RateLimitExceeded { .. })
+        ));
+        assert_eq!(errors.load(Ordering::Relaxed), 3);
+
+        // A missed deadline is a failure too.
+        handling.policy = Some(FailurePolicy::Open);
+        assert!(apply(&handling, Err(RateLimiterError::DeadlineExceeded)).is_ok());
+        assert_eq!(errors.load(Ordering::Relaxed), 4);
+
+        // Decisions and input errors are not failures.
+        assert!(matches!(
+            apply(
+                &handling,
+                Err(RateLimiterError::InvalidIdentifier("x".into()))
+            ),
+            Err(RateLimiterError::InvalidIdentifier(_))
+        ));
+        assert_eq!(errors.load(Ordering::Relaxed), 4);
+
+        // Nor are errors Redis replied with.
+        let script_error = RateLimiterError::Redis(redis::RedisError::from((
+            redis::ErrorKind::ResponseError,
+            "WRONGTYPE",
+        )));
+        assert!(matches!(
+            apply(&handling, Err(script_error)),
+            Err(RateLimiterError::Redis(_))
+        ));
+        assert_eq!(errors.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_any_reply_closes_the_breaker() {
+        use crate::{BreakerState, CircuitBreakerConfig};
+
+        let breaker = Arc::new(CircuitBreaker::new(
+            CircuitBreakerConfig::new()
+                .with_failure_threshold(1)
+                .with_open_duration(Duration::ZERO),
+        ));
+        let handling = FailureHandling {
+            policy: None,
+            breaker: Some(breaker.clone()),
+            on_error: None,
+        };
+        let apply = |result| {
+            handling.apply("app", "user_1", Some(result), |_| {
+                Err(RateLimiterError::Denylisted)
+            })
+        };
+
+        assert!(apply(unreachable()).is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // The probe is answered, if only with a denial that isn't a rate limit.
+        assert!(handling.admitted());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(matches!(
+            apply(Err(RateLimiterError::CardinalityLimitExceeded)),
+            Err(RateLimiterError::CardinalityLimitExceeded)
+        ));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,22 @@
+//! Readiness reports from `health_check`.
+
+use std::time::Duration;
+
+/// What `health_check` found: Redis answered, and every built-in script is in its script
+/// cache now. With the `serde` feature it serializes as
+/// `{"round_trip_ms":1,"scripts_cached":8,"scripts_reloaded":0}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HealthReport {
+    /// Round trip of a `PING` to the server.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "round_trip_ms", with = "crate::serde_duration::millis")
+    )]
+    pub round_trip: Duration,
+    /// Built-in scripts the server already had cached.
+    pub scripts_cached: usize,
+    /// Built-in scripts missing from the cache, e.g. after a restart, failover or
+    /// `SCRIPT FLUSH`, and loaded again by the check.
+    pub scripts_reloaded: usize,
+}
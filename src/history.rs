@@ -0,0 +1,121 @@
+//! Optional per-identifier usage history in fixed time buckets, so dashboards can chart
+//! consumption without an external metrics system.
+//!
+//! The check script counts allowed requests per bucket in a Redis hash. There is one hash per
+//! retention period (`{prefix}:__history__:{identifier}:{period}`), so none outgrows a period's
+//! worth of buckets, and each expires two retention periods after its last write.
+
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bucket size and retention of the usage history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsageHistory {
+    bucket: Duration,
+    retention: Duration,
+}
+
+impl Default for UsageHistory {
+    /// Per-minute counts for the last 24 hours.
+    fn default() -> Self {
+        UsageHistory {
+            bucket: Duration::from_secs(60),
+            retention: Duration::from_secs(24 * 3600),
+        }
+    }
+}
+
+impl UsageHistory {
+    /// Counts per `bucket` (whole seconds, at least one) kept for `retention` (at least one
+    /// bucket).
+    pub fn new(bucket: Duration, retention: Duration) -> Self {
+        let bucket = Duration::from_secs(bucket.as_secs().max(1));
+        UsageHistory {
+            bucket,
+            retention: retention.max(bucket),
+        }
+    }
+
+    pub fn bucket(&self) -> Duration {
+        self.bucket
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Start of the bucket containing `at`, in seconds since the Unix epoch.
+    pub(crate) fn bucket_start(&self, at: SystemTime) -> u64 {
+        let secs = unix_secs(at);
+        secs - secs % self.bucket.as_secs()
+    }
+
+    /// Retention period a bucket's count is stored under.
+    pub(crate) fn period(&self, bucket_start: u64) -> u64 {
+        bucket_start / self.retention.as_secs().max(1)
+    }
+
+    /// Starts of the buckets overlapping `range`, clipped to the retention before `now` (the
+    /// current bucket included).
+    pub(crate) fn buckets(&self, range: &Range<SystemTime>, now: SystemTime) -> Vec<u64> {
+        let oldest = unix_secs(now).saturating_sub(self.retention.as_secs());
+        let first = self
+            .bucket_start(range.start)
+            .max(self.bucket_start(UNIX_EPOCH + Duration::from_secs(oldest)));
+        let end_ms = range
+            .end
+            .min(now)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        (first..)
+            .step_by(self.bucket.as_secs() as usize)
+            .take_while(|&start| u128::from(start) * 1000 < end_ms)
+            .collect()
+    }
+}
+
+fn unix_secs(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Allowed requests within one history bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UsageBucket {
+    /// Start of the bucket.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "start_ms", with = "crate::serde_duration::unix_millis")
+    )]
+    pub start: SystemTime,
+    pub count: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_clip_to_retention() {
+        let history = UsageHistory::new(Duration::from_secs(60), Duration::from_secs(3600));
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let now = at(10_030);
+
+        assert_eq!(history.bucket_start(now), 10_020);
+        assert_eq!(
+            history.buckets(&(at(9_800)..at(9_990)), now),
+            [9_780, 9_840, 9_900, 9_960]
+        );
+        // Everything older than the retention is gone.
+        assert_eq!(history.buckets(&(at(0)..now), now).len(), 61);
+        assert_eq!(history.buckets(&(at(0)..now), now)[0], 6_420);
+
+        assert_eq!(history.period(9_960), 2);
+        let tiny = UsageHistory::new(Duration::from_millis(10), Duration::ZERO);
+        assert_eq!(
+            (tiny.bucket(), tiny.retention()),
+            (Duration::from_secs(1), Duration::from_secs(1))
+        );
+    }
+}
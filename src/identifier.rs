@@ -0,0 +1,374 @@
+//! Validation and sanitization of identifiers before they become part of a Redis key.
+//!
+//! Without a policy identifiers are used verbatim, so `a:b` and a newline-laden or
+//! megabyte-long identifier produce keys nobody expects. An [`IdentifierPolicy`] rejects or
+//! rewrites them consistently on every instance.
+
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::RateLimiterError;
+
+/// Values that can identify a rate limited subject, so callers can pass IDs, addresses and
+/// strings without formatting them into a temporary `String` first.
+pub trait ToIdentifier {
+    fn to_identifier(&self) -> Cow<'_, str>;
+}
+
+impl ToIdentifier for str {
+    fn to_identifier(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl ToIdentifier for String {
+    fn to_identifier(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl ToIdentifier for Cow<'_, str> {
+    fn to_identifier(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl<T: ToIdentifier + ?Sized> ToIdentifier for &T {
+    fn to_identifier(&self) -> Cow<'_, str> {
+        (**self).to_identifier()
+    }
+}
+
+macro_rules! to_identifier_via_display {
+    ($($t:ty),*) => {
+        $(
+            impl ToIdentifier for $t {
+                fn to_identifier(&self) -> Cow<'_, str> {
+                    Cow::Owned(self.to_string())
+                }
+            }
+        )*
+    };
+}
+
+to_identifier_via_display!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, IpAddr, Ipv4Addr, Ipv6Addr
+);
+
+/// Hyphenated lowercase form, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+#[cfg(feature = "uuid")]
+impl ToIdentifier for uuid::Uuid {
+    fn to_identifier(&self) -> Cow<'_, str> {
+        Cow::Owned(self.hyphenated().to_string())
+    }
+}
+
+/// How letter case is normalized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaseNormalization {
+    /// Keep the case as given.
+    #[default]
+    Preserve,
+    /// Lowercase ASCII letters only, e.g. for email addresses and hostnames.
+    AsciiLowercase,
+    /// Full Unicode lowercase mapping (`str::to_lowercase`), which matches Unicode case
+    /// folding for nearly all text; notable exceptions such as `ß` are kept as is.
+    Lowercase,
+}
+
+/// Rewrites identifiers into a canonical form before they are keyed, so e.g.
+/// `User@Example.com` and `user@example.com` share one bucket. Applied before the
+/// [`IdentifierPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Normalization {
+    trim: bool,
+    case: CaseNormalization,
+    nfc: bool,
+}
+
+impl Normalization {
+    /// Leaves identifiers unchanged until options are enabled.
+    pub fn new() -> Self {
+        Normalization::default()
+    }
+
+    /// Strips leading and trailing whitespace.
+    pub fn with_trim(mut self, trim: bool) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    pub fn with_case(mut self, case: CaseNormalization) -> Self {
+        self.case = case;
+        self
+    }
+
+    /// Converts to Unicode Normalization Form C, so composed and decomposed spellings of the
+    /// same text (`é` vs `e` + U+0301) match. Requires the `unicode` feature.
+    #[cfg(feature = "unicode")]
+    pub fn with_nfc(mut self, nfc: bool) -> Self {
+        self.nfc = nfc;
+        self
+    }
+
+    /// Returns the canonical form of `identifier`, borrowing it when nothing changes.
+    pub fn apply<'a>(&self, identifier: &'a str) -> Cow<'a, str> {
+        let mut normalized = Cow::Borrowed(if self.trim {
+            identifier.trim()
+        } else {
+            identifier
+        });
+
+        #[cfg(feature = "unicode")]
+        if self.nfc {
+            use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+            if is_nfc_quick(normalized.chars()) != IsNormalized::Yes {
+                normalized = Cow::Owned(normalized.nfc().collect());
+            }
+        }
+
+        match self.case {
+            CaseNormalization::Preserve => {}
+            CaseNormalization::AsciiLowercase => {
+                if normalized.bytes().any(|b| b.is_ascii_uppercase()) {
+                    normalized = Cow::Owned(normalized.to_ascii_lowercase());
+                }
+            }
+            CaseNormalization::Lowercase => {
+                if normalized
+                    .chars()
+                    .any(|c| !c.is_lowercase() && c.to_lowercase().ne([c]))
+                {
+                    normalized = Cow::Owned(normalized.to_lowercase());
+                }
+            }
+        }
+        normalized
+    }
+}
+
+/// What to do with identifiers longer than the policy's maximum length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizedIdentifier {
+    /// Fail with `RateLimiterError::InvalidIdentifier`.
+    #[default]
+    Reject,
+    /// Keep the start of the identifier and replace the rest with a stable hash of the whole,
+    /// so distinct long identifiers still get distinct keys.
+    Hash,
+}
+
+/// What to do with control characters, whitespace and `:` (the key separator).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnsafeCharacters {
+    /// Use them verbatim.
+    Allow,
+    /// Fail with `RateLimiterError::InvalidIdentifier`.
+    #[default]
+    Reject,
+    /// Percent-encode them (and `%` itself), e.g. `a:b` becomes `a%3Ab`.
+    Escape,
+}
+
+/// Rules applied to every identifier before key construction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdentifierPolicy {
+    max_len: usize,
+    oversized: OversizedIdentifier,
+    unsafe_characters: UnsafeCharacters,
+}
+
+impl Default for IdentifierPolicy {
+    fn default() -> Self {
+        IdentifierPolicy {
+            max_len: 256,
+            oversized: OversizedIdentifier::default(),
+            unsafe_characters: UnsafeCharacters::default(),
+        }
+    }
+}
+
+/// Length of the `#` and 16 hex digits appended to hashed identifiers.
+const HASH_SUFFIX_LEN: usize = 17;
+
+impl IdentifierPolicy {
+    /// Rejects identifiers over 256 bytes or containing unsafe characters.
+    pub fn new() -> Self {
+        IdentifierPolicy::default()
+    }
+
+    /// Maximum identifier length in bytes, after escaping. At least 32.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len.max(32);
+        self
+    }
+
+    pub fn on_oversized(mut self, action: OversizedIdentifier) -> Self {
+        self.oversized = action;
+        self
+    }
+
+    pub fn on_unsafe_characters(mut self, action: UnsafeCharacters) -> Self {
+        self.unsafe_characters = action;
+        self
+    }
+
+    /// Validates `identifier`, returning the form to build keys from.
+    pub fn apply<'a>(&self, identifier: &'a str) -> Result<Cow<'a, str>, RateLimiterError> {
+        if identifier.is_empty() {
+            return Err(invalid("identifier is empty"));
+        }
+
+        let mut sanitized = Cow::Borrowed(identifier);
+        match self.unsafe_characters {
+            UnsafeCharacters::Allow => {}
+            UnsafeCharacters::Reject => {
+                if identifier.chars().any(is_unsafe) {
+                    return Err(invalid(
+                        "identifier contains control characters, whitespace or `:`",
+                    ));
+                }
+            }
+            UnsafeCharacters::Escape => {
+                if identifier.chars().any(|c| is_unsafe(c) || c == '%') {
+                    sanitized = Cow::Owned(escape(identifier));
+                }
+            }
+        }
+
+        if sanitized.len() > self.max_len {
+            match self.oversized {
+                OversizedIdentifier::Reject => {
+                    return Err(invalid(&format!(
+                        "identifier is {} bytes long, the maximum is {}",
+                        sanitized.len(),
+                        self.max_len
+                    )))
+                }
+                OversizedIdentifier::Hash => {
+                    let mut keep = self.max_len - HASH_SUFFIX_LEN;
+                    while !sanitized.is_char_boundary(keep) {
+                        keep -= 1;
+                    }
+                    let hashed = format!("{}#{:016x}", &sanitized[..keep], fnv1a(&sanitized));
+                    sanitized = Cow::Owned(hashed);
+                }
+            }
+        }
+
+        Ok(sanitized)
+    }
+}
+
+fn invalid(reason: &str) -> RateLimiterError {
+    RateLimiterError::InvalidIdentifier(reason.to_string())
+}
+
+fn is_unsafe(c: char) -> bool {
+    c.is_control() || c.is_whitespace() || c == ':'
+}
+
+fn escape(identifier: &str) -> String {
+    escape_where(identifier, |c| is_unsafe(c) || c == '%')
+}
+
+/// Percent-encodes the characters matching `reserved`, which must include `%`.
+pub(crate) fn escape_where(identifier: &str, reserved: impl Fn(char) -> bool) -> String {
+    let mut escaped = String::with_capacity(identifier.len());
+    for c in identifier.chars() {
+        if reserved(c) {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// 64-bit FNV-1a: stable across processes and Rust versions, unlike `DefaultHasher`.
+pub(crate) fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_identifier() {
+        assert_eq!("user".to_identifier(), "user");
+        assert_eq!(String::from("user").to_identifier(), "user");
+        assert_eq!(42u64.to_identifier(), "42");
+        assert_eq!(
+            IpAddr::from([192, 168, 0, 1]).to_identifier(),
+            "192.168.0.1"
+        );
+        assert_eq!(Ipv6Addr::LOCALHOST.to_identifier(), "::1");
+    }
+
+    #[test]
+    fn test_normalization() {
+        let normalization = Normalization::new()
+            .with_trim(true)
+            .with_case(CaseNormalization::AsciiLowercase);
+        assert_eq!(
+            normalization.apply(" User@Example.com\n"),
+            "user@example.com"
+        );
+        assert!(matches!(normalization.apply("user"), Cow::Borrowed("user")));
+
+        let unicode = Normalization::new().with_case(CaseNormalization::Lowercase);
+        assert_eq!(unicode.apply("ÉCOLE"), "école");
+        assert_eq!(Normalization::new().apply(" Kept "), " Kept ");
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn test_nfc_normalization() {
+        let normalization = Normalization::new().with_nfc(true);
+        assert_eq!(normalization.apply("e\u{301}cole"), "\u{e9}cole");
+    }
+
+    #[test]
+    fn test_rejects_by_default() {
+        let policy = IdentifierPolicy::new();
+        assert_eq!(policy.apply("user_42").unwrap(), "user_42");
+        assert!(policy.apply("").is_err());
+        assert!(policy.apply("a:b").is_err());
+        assert!(policy.apply("line\nbreak").is_err());
+        assert!(policy.apply(&"x".repeat(257)).is_err());
+    }
+
+    #[test]
+    fn test_escapes_unsafe_characters() {
+        let policy = IdentifierPolicy::new().on_unsafe_characters(UnsafeCharacters::Escape);
+        assert_eq!(policy.apply("a:b c").unwrap(), "a%3Ab%20c");
+        // `%` is escaped too, so escaping can't map two identifiers to one key.
+        assert_eq!(policy.apply("a%3Ab").unwrap(), "a%253Ab");
+    }
+
+    #[test]
+    fn test_hashes_oversized_identifiers() {
+        let policy = IdentifierPolicy::new()
+            .with_max_len(64)
+            .on_oversized(OversizedIdentifier::Hash);
+        let (long_a, long_b) = (
+            format!("{}a", "x".repeat(100)),
+            format!("{}b", "x".repeat(100)),
+        );
+        let a = policy.apply(&long_a).unwrap();
+        let b = policy.apply(&long_b).unwrap();
+        assert_eq!(a.len(), 64);
+        assert_ne!(a, b);
+        assert_eq!(a, policy.apply(&long_a).unwrap());
+
+        // Truncation never splits a multi-byte character.
+        let accented = "é".repeat(40);
+        assert!(policy.apply(&accented).unwrap().len() <= 64);
+    }
+}
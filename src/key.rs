@@ -0,0 +1,116 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::identifier::{self, ToIdentifier};
+
+/// An identifier made of named parts, e.g. to limit per (user, route) pair:
+/// `Key::new().part("user", 42).part("route", "/search")` is `user=42/route=%2Fsearch`.
+///
+/// Parts are written `name=value` and joined by `/`. Values are percent-encoded wherever they
+/// contain `=`, `/`, braces, `%`, `:`, whitespace or control characters, so distinct parts
+/// always give distinct keys and keys pass the default [`IdentifierPolicy`] checks.
+///
+/// [`IdentifierPolicy`]: crate::IdentifierPolicy
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Key {
+    key: String,
+    tagged: bool,
+}
+
+impl Key {
+    pub fn new() -> Self {
+        Key::default()
+    }
+
+    /// Appends the part `name=value`.
+    pub fn part(mut self, name: &str, value: impl ToIdentifier) -> Self {
+        self.push(name, &value.to_identifier(), false);
+        self
+    }
+
+    /// Appends the part `name=value` as the key's Redis Cluster hash tag, `{name=value}`, so
+    /// the keys derived from identifiers sharing it map to the same slot, e.g. every key of
+    /// one user whatever the route. Only the first tagged part is a tag; later ones are
+    /// appended as plain parts. The tag only takes effect if the limiter's key prefix has no
+    /// braces. A check also reads limiter-wide keys, at least the stored limits, which can't
+    /// follow an identifier's tag, so on Redis Cluster the key prefix itself has to be tagged
+    /// (e.g. `{api}`), which keeps all of the limiter's keys in one slot.
+    pub fn tagged_part(mut self, name: &str, value: impl ToIdentifier) -> Self {
+        let tag = !self.tagged;
+        self.push(name, &value.to_identifier(), tag);
+        self.tagged = true;
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.key
+    }
+
+    pub fn into_string(self) -> String {
+        self.key
+    }
+
+    fn push(&mut self, name: &str, value: &str, tag: bool) {
+        if !self.key.is_empty() {
+            self.key.push('/');
+        }
+        if tag {
+            self.key.push('{');
+        }
+        self.key.push_str(&escape(name));
+        self.key.push('=');
+        self.key.push_str(&escape(value));
+        if tag {
+            self.key.push('}');
+        }
+    }
+}
+
+fn escape(part: &str) -> Cow<'_, str> {
+    let reserved = |c: char| {
+        matches!(c, '=' | '/' | '{' | '}' | '%' | ':') || c.is_whitespace() || c.is_control()
+    };
+    if part.chars().any(reserved) {
+        Cow::Owned(identifier::escape_where(part, reserved))
+    } else {
+        Cow::Borrowed(part)
+    }
+}
+
+impl ToIdentifier for Key {
+    fn to_identifier(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.key)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parts_are_escaped() {
+        let key = Key::new().part("user", "a=b/c").part("route", "{x}: y%");
+        assert_eq!(key.as_str(), "user=a%3Db%2Fc/route=%7Bx%7D%3A%20y%25");
+        // Without escaping, these would both be `a=b/c=d`.
+        assert_ne!(
+            Key::new().part("a", "b/c=d").as_str(),
+            Key::new().part("a", "b").part("c", "d").as_str()
+        );
+    }
+
+    #[test]
+    fn test_first_tagged_part_is_the_hash_tag() {
+        let key = Key::new()
+            .part("route", "/login")
+            .tagged_part("user", 7)
+            .tagged_part("ip", "10.0.0.1");
+        assert_eq!(key.to_string(), "route=%2Flogin/{user=7}/ip=10.0.0.1");
+        assert_eq!(key.to_identifier(), key.as_str());
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::{MetricsSink, TimingEvent};
+use crate::telemetry::{Sampler, TelemetrySampling};
+use crate::trace;
+
+/// Number of most recent samples kept for the rolling statistics.
+const WINDOW_SIZE: usize = 1024;
+
+/// A Redis operation that took longer than the configured slow threshold.
+#[derive(Debug, Clone)]
+pub struct SlowOperation {
+    pub operation: &'static str,
+    pub duration: Duration,
+    pub threshold: Duration,
+}
+
+/// Rolling latency statistics over the most recent Redis operations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LatencyStats {
+    /// Number of samples the statistics were computed from.
+    pub samples: usize,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "min_ms", with = "crate::serde_duration::millis")
+    )]
+    pub min: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "max_ms", with = "crate::serde_duration::millis")
+    )]
+    pub max: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "mean_ms", with = "crate::serde_duration::millis")
+    )]
+    pub mean: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "p50_ms", with = "crate::serde_duration::millis")
+    )]
+    pub p50: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "p95_ms", with = "crate::serde_duration::millis")
+    )]
+    pub p95: Duration,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "p99_ms", with = "crate::serde_duration::millis")
+    )]
+    pub p99: Duration,
+    /// Total number of slow operations observed since creation or the last reset.
+    pub slow_operations: u64,
+}
+
+pub(crate) type SlowOperationHook = Arc<dyn Fn(&SlowOperation) + Send + Sync>;
+
+pub(crate) struct LatencyTracker {
+    samples: Mutex<VecDeque<Duration>>,
+    slow_operations: AtomicU64,
+    slow_threshold: Option<Duration>,
+    on_slow: Option<SlowOperationHook>,
+    sample_latency: Sampler,
+    sample_hooks: Sampler,
+    latency_rate: f64,
+    /// Sink and key prefix sampled timings are reported under.
+    metrics: Option<(Arc<dyn MetricsSink>, String)>,
+}
+
+impl LatencyTracker {
+    pub(crate) fn new() -> Self {
+        LatencyTracker {
+            samples: Mutex::new(VecDeque::with_capacity(WINDOW_SIZE)),
+            slow_operations: AtomicU64::new(0),
+            slow_threshold: None,
+            on_slow: None,
+            sample_latency: Sampler::new(1.0),
+            sample_hooks: Sampler::new(1.0),
+            latency_rate: 1.0,
+            metrics: None,
+        }
+    }
+
+    pub(crate) fn set_slow_threshold(&mut self, threshold: Duration) {
+        self.slow_threshold = Some(threshold);
+    }
+
+    pub(crate) fn set_sampling(&mut self, sampling: &TelemetrySampling) {
+        self.sample_latency = Sampler::new(sampling.latency_rate());
+        self.sample_hooks = Sampler::new(sampling.hook_rate());
+        self.latency_rate = sampling.latency_rate();
+    }
+
+    pub(crate) fn set_metrics(&mut self, sink: Arc<dyn MetricsSink>, key_prefix: &str) {
+        self.metrics = Some((sink, key_prefix.to_string()));
+    }
+
+    pub(crate) fn set_slow_hook(&mut self, hook: SlowOperationHook) {
+        self.on_slow = Some(hook);
+    }
+
+    /// Runs `f`, recording how long it took under the given operation name.
+    #[cfg(feature = "blocking")]
+    pub(crate) fn time<T>(&self, operation: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(operation, start.elapsed());
+        result
+    }
+
+    /// Awaits `fut`, recording how long it took under the given operation name.
+    ///
+    /// Nothing is recorded if the future is dropped before it completes.
+    pub(crate) async fn time_async<T>(
+        &self,
+        operation: &'static str,
+        fut: impl Future<Output = T>,
+    ) -> T {
+        let start = Instant::now();
+        let result = fut.await;
+        self.record(operation, start.elapsed());
+        result
+    }
+
+    pub(crate) fn record(&self, operation: &'static str, duration: Duration) {
+        trace::redis_timing(operation, duration);
+        if self.sample_latency.sample() {
+            let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+            if samples.len() == WINDOW_SIZE {
+                samples.pop_front();
+            }
+            samples.push_back(duration);
+            drop(samples);
+            if let Some((sink, key_prefix)) = &self.metrics {
+                sink.record_timing(&TimingEvent {
+                    key_prefix,
+                    operation,
+                    duration,
+                    sample_rate: self.latency_rate,
+                });
+            }
+        }
+
+        if let Some(threshold) = self.slow_threshold {
+            if duration > threshold {
+                self.slow_operations.fetch_add(1, Ordering::Relaxed);
+                if !self.sample_hooks.sample() {
+                    return;
+                }
+                let slow = SlowOperation {
+                    operation,
+                    duration,
+                    threshold,
+                };
+                log::warn!(
+                    "slow redis operation `{}`: took {:?} (threshold {:?})",
+                    slow.operation,
+                    slow.duration,
+                    slow.threshold
+                );
+                if let Some(hook) = &self.on_slow {
+                    hook(&slow);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> LatencyStats {
+        let mut sorted: Vec<Duration> = {
+            let samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+            samples.iter().copied().collect()
+        };
+        let slow_operations = self.slow_operations.load(Ordering::Relaxed);
+        if sorted.is_empty() {
+            return LatencyStats {
+                slow_operations,
+                ..LatencyStats::default()
+            };
+        }
+        sorted.sort_unstable();
+
+        let total: Duration = sorted.iter().sum();
+        let percentile = |p: usize| sorted[((sorted.len() - 1) * p) / 100];
+        LatencyStats {
+            samples: sorted.len(),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean: total / sorted.len() as u32,
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+            slow_operations,
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.slow_operations.store(0, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Debug for LatencyTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LatencyTracker")
+            .field("slow_threshold", &self.slow_threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_percentiles() {
+        let tracker = LatencyTracker::new();
+        for ms in 1..=100 {
+            tracker.record("check", Duration::from_millis(ms));
+        }
+
+        let stats = tracker.stats();
+        assert_eq!(stats.samples, 100);
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.max, Duration::from_millis(100));
+        assert_eq!(stats.p50, Duration::from_millis(50));
+        assert_eq!(stats.p99, Duration::from_millis(99));
+        assert_eq!(stats.slow_operations, 0);
+    }
+
+    #[test]
+    fn test_sampling() {
+        let mut tracker = LatencyTracker::new();
+        tracker.set_slow_threshold(Duration::from_millis(10));
+        tracker.set_sampling(&TelemetrySampling::new().with_latency_rate(0.5));
+        for _ in 0..10 {
+            tracker.record("check", Duration::from_millis(20));
+        }
+
+        let stats = tracker.stats();
+        assert_eq!(stats.samples, 5);
+        assert_eq!(stats.slow_operations, 10);
+    }
+
+    #[test]
+    fn test_slow_operation_hook() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut tracker = LatencyTracker::new();
+        tracker.set_slow_threshold(Duration::from_millis(10));
+        let sink = Arc::clone(&seen);
+        tracker.set_slow_hook(Arc::new(move |op: &SlowOperation| {
+            sink.lock().unwrap().push(op.operation);
+        }));
+
+        tracker.record("check", Duration::from_millis(5));
+        tracker.record("get_remaining", Duration::from_millis(20));
+
+        assert_eq!(*seen.lock().unwrap(), vec!["get_remaining"]);
+        assert_eq!(tracker.stats().slow_operations, 1);
+
+        tracker.reset();
+        assert_eq!(tracker.stats(), LatencyStats::default());
+    }
+}
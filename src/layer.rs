@@ -0,0 +1,574 @@
+//! Tower middleware over [`AsyncRateLimiter`], for axum, hyper and other tower-based services.
+//!
+//! [`RateLimitLayer`] takes a key from each request with a [`KeyExtractor`] and checks it,
+//! weighted by a [`RequestCost`] if one is set with `with_cost`:
+//! allowed requests go on to the inner service, denied ones are answered with
+//! `429 Too Many Requests` carrying `Retry-After` and `RateLimit-*` headers. Requests without
+//! a key are passed through. If the check fails (Redis can't be reached and the limiter has
+//! no `with_failure_policy`), the request is answered with `503 Service Unavailable`.
+//!
+//! [`RateLimitServiceLayer`] is the protocol-agnostic variant for stacks whose requests aren't
+//! HTTP, or that answer rejections themselves (tonic, warp, custom protocols): [`RateLimitService`]
+//! checks any request type a [`KeyExtractor`] handles and fails denied or unchecked requests
+//! with a [`RateLimitServiceError`] instead of building a response.
+
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::{HeaderName, Request, Response, StatusCode};
+
+use crate::{AsyncRateLimiter, RateLimitDecision, RateLimiterError};
+
+/// Takes the rate limit key from a request, or `None` to let it through unchecked.
+///
+/// Implemented for closures over any request type, e.g. one keying by client IP with axum's
+/// `ConnectInfo`: `|req: &Request<Body>| req.extensions()
+/// .get::<ConnectInfo<SocketAddr>>().map(|c| c.0.ip().to_string())`.
+pub trait KeyExtractor<Req>: Clone {
+    fn extract(&self, request: &Req) -> Option<String>;
+}
+
+impl<Req, F> KeyExtractor<Req> for F
+where
+    F: Fn(&Req) -> Option<String> + Clone,
+{
+    fn extract(&self, request: &Req) -> Option<String> {
+        self(request)
+    }
+}
+
+/// Computes how many units of the limit a request takes, e.g. from its route or payload size,
+/// so call sites don't hardcode a cost. The cost must be at least one; a weighted request that
+/// is denied takes nothing, as with `check_with_cost`.
+///
+/// Implemented for closures over any request type, e.g. ten units for exports:
+/// `|req: &Request<Body>| if req.uri().path().starts_with("/export") { 10 } else { 1 }`.
+pub trait RequestCost<Req>: Clone {
+    fn cost(&self, request: &Req) -> u64;
+}
+
+impl<Req, F> RequestCost<Req> for F
+where
+    F: Fn(&Req) -> u64 + Clone,
+{
+    fn cost(&self, request: &Req) -> u64 {
+        self(request)
+    }
+}
+
+/// The cost of every request until `with_cost` sets another: one unit, counted as by `check`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnitCost;
+
+impl<Req> RequestCost<Req> for UnitCost {
+    fn cost(&self, _request: &Req) -> u64 {
+        1
+    }
+}
+
+/// The peer IP from a `SocketAddr` request extension, as inserted by hyper-based servers that
+/// record the remote address.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerIp;
+
+impl<B> KeyExtractor<Request<B>> for PeerIp {
+    fn extract(&self, request: &Request<B>) -> Option<String> {
+        request
+            .extensions()
+            .get::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+    }
+}
+
+/// The client IP reported by a reverse proxy: the first `X-Forwarded-For` entry, else
+/// `X-Real-IP`. Only trustworthy behind a proxy that overwrites these headers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ForwardedIp;
+
+impl<B> KeyExtractor<Request<B>> for ForwardedIp {
+    fn extract(&self, request: &Request<B>) -> Option<String> {
+        let headers = request.headers();
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next());
+        let real_ip = || {
+            headers
+                .get("x-real-ip")
+                .and_then(|value| value.to_str().ok())
+        };
+        forwarded
+            .or_else(real_ip)
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(str::to_string)
+    }
+}
+
+/// The value of a header, e.g. an API key.
+#[derive(Debug, Clone)]
+pub struct HeaderKey(pub HeaderName);
+
+impl<B> KeyExtractor<Request<B>> for HeaderKey {
+    fn extract(&self, request: &Request<B>) -> Option<String> {
+        let value = request.headers().get(&self.0)?.to_str().ok()?;
+        Some(value.to_string())
+    }
+}
+
+/// Applies [`RateLimit`] to a service.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer<K, C = UnitCost> {
+    limiter: Arc<AsyncRateLimiter>,
+    extractor: K,
+    cost: Option<C>,
+}
+
+impl<K> RateLimitLayer<K> {
+    pub fn new(limiter: Arc<AsyncRateLimiter>, extractor: K) -> Self {
+        RateLimitLayer {
+            limiter,
+            extractor,
+            cost: None,
+        }
+    }
+}
+
+impl<K, C> RateLimitLayer<K, C> {
+    /// Weighs each request by `cost` instead of counting it as one.
+    pub fn with_cost<C2>(self, cost: C2) -> RateLimitLayer<K, C2> {
+        RateLimitLayer {
+            limiter: self.limiter,
+            extractor: self.extractor,
+            cost: Some(cost),
+        }
+    }
+}
+
+impl<S, K: Clone, C: Clone> tower_layer::Layer<S> for RateLimitLayer<K, C> {
+    type Service = RateLimit<S, K, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+            extractor: self.extractor.clone(),
+            cost: self.cost.clone(),
+        }
+    }
+}
+
+/// A service that checks every request against the limiter before calling `inner`.
+#[derive(Debug, Clone)]
+pub struct RateLimit<S, K, C = UnitCost> {
+    inner: S,
+    limiter: Arc<AsyncRateLimiter>,
+    extractor: K,
+    cost: Option<C>,
+}
+
+impl<S, K, C, ReqBody, ResBody> tower_service::Service<Request<ReqBody>> for RateLimit<S, K, C>
+where
+    S: tower_service::Service<Request<ReqBody>, Response = Response<ResBody>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    K: KeyExtractor<Request<ReqBody>>,
+    C: RequestCost<Request<ReqBody>>,
+    ReqBody: Send + 'static,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        // The clone isn't necessarily ready; keep the one that is for this request.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let key = self.extractor.extract(&request);
+        let cost = self.cost.as_ref().map(|cost| cost.cost(&request));
+
+        Box::pin(async move {
+            let Some(key) = key else {
+                return inner.call(request).await;
+            };
+            match limiter.check_detailed_on(key.as_str(), cost).await {
+                Ok(decision) if decision.allowed => inner.call(request).await,
+                Ok(decision) => Ok(too_many_requests(&decision)),
+                Err(e) => {
+                    log::warn!("rate limit check of `{}` failed: {}", key, e);
+                    Ok(status_response(StatusCode::SERVICE_UNAVAILABLE))
+                }
+            }
+        })
+    }
+}
+
+/// Applies [`RateLimitService`] to a service.
+#[derive(Debug, Clone)]
+pub struct RateLimitServiceLayer<K, C = UnitCost> {
+    limiter: Arc<AsyncRateLimiter>,
+    extractor: K,
+    cost: Option<C>,
+}
+
+impl<K> RateLimitServiceLayer<K> {
+    pub fn new(limiter: Arc<AsyncRateLimiter>, extractor: K) -> Self {
+        RateLimitServiceLayer {
+            limiter,
+            extractor,
+            cost: None,
+        }
+    }
+}
+
+impl<K, C> RateLimitServiceLayer<K, C> {
+    /// Weighs each request by `cost` instead of counting it as one.
+    pub fn with_cost<C2>(self, cost: C2) -> RateLimitServiceLayer<K, C2> {
+        RateLimitServiceLayer {
+            limiter: self.limiter,
+            extractor: self.extractor,
+            cost: Some(cost),
+        }
+    }
+}
+
+impl<S, K: Clone, C: Clone> tower_layer::Layer<S> for RateLimitServiceLayer<K, C> {
+    type Service = RateLimitService<S, K, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+            extractor: self.extractor.clone(),
+            cost: self.cost.clone(),
+        }
+    }
+}
+
+/// A service that checks every request, of any type, against the limiter before calling
+/// `inner`, and fails it with a [`RateLimitServiceError`] when it may not go through.
+#[derive(Debug, Clone)]
+pub struct RateLimitService<S, K, C = UnitCost> {
+    inner: S,
+    limiter: Arc<AsyncRateLimiter>,
+    extractor: K,
+    cost: Option<C>,
+}
+
+impl<S, K> RateLimitService<S, K> {
+    pub fn new(inner: S, limiter: Arc<AsyncRateLimiter>, extractor: K) -> Self {
+        RateLimitService {
+            inner,
+            limiter,
+            extractor,
+            cost: None,
+        }
+    }
+}
+
+impl<S, K, C> RateLimitService<S, K, C> {
+    /// Weighs each request by `cost` instead of counting it as one.
+    pub fn with_cost<C2>(self, cost: C2) -> RateLimitService<S, K, C2> {
+        RateLimitService {
+            inner: self.inner,
+            limiter: self.limiter,
+            extractor: self.extractor,
+            cost: Some(cost),
+        }
+    }
+}
+
+impl<S, K, C, Req> tower_service::Service<Req> for RateLimitService<S, K, C>
+where
+    S: tower_service::Service<Req> + Clone + Send + 'static,
+    S::Future: Send,
+    K: KeyExtractor<Req>,
+    C: RequestCost<Req>,
+    Req: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = RateLimitServiceError<S::Error>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner
+            .poll_ready(cx)
+            .map_err(RateLimitServiceError::Inner)
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        // Keep the ready service for this request, as `RateLimit::call` does.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let key = self.extractor.extract(&request);
+        let cost = self.cost.as_ref().map(|cost| cost.cost(&request));
+
+        Box::pin(async move {
+            if let Some(key) = key {
+                let decision = limiter
+                    .check_detailed_on(key.as_str(), cost)
+                    .await
+                    .map_err(RateLimitServiceError::Limiter)?;
+                if !decision.allowed {
+                    return Err(RateLimitServiceError::Limited(decision));
+                }
+            }
+            inner
+                .call(request)
+                .await
+                .map_err(RateLimitServiceError::Inner)
+        })
+    }
+}
+
+/// Why [`RateLimitService`] failed a request.
+pub enum RateLimitServiceError<E> {
+    /// The request was denied; `RateLimitDecision::headers` gives the details to send back.
+    Limited(RateLimitDecision),
+    /// The check itself failed, e.g. Redis couldn't be reached.
+    Limiter(RateLimiterError),
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: fmt::Debug> fmt::Debug for RateLimitServiceError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitServiceError::Limited(decision) => {
+                f.debug_tuple("Limited").field(decision).finish()
+            }
+            RateLimitServiceError::Limiter(error) => f.debug_tuple("Limiter").field(error).finish(),
+            RateLimitServiceError::Inner(error) => f.debug_tuple("Inner").field(error).finish(),
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for RateLimitServiceError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitServiceError::Limited(decision) => write!(
+                f,
+                "Rate limit exceeded, retry after {:?}",
+                decision.retry_after.unwrap_or_default()
+            ),
+            RateLimitServiceError::Limiter(error) => error.fmt(f),
+            RateLimitServiceError::Inner(error) => error.fmt(f),
+        }
+    }
+}
+
+impl<E> std::error::Error for RateLimitServiceError<E>
+where
+    E: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RateLimitServiceError::Limited(_) => None,
+            RateLimitServiceError::Limiter(error) => Some(error),
+            RateLimitServiceError::Inner(error) => Some(error),
+        }
+    }
+}
+
+fn status_response<B: Default>(status: StatusCode) -> Response<B> {
+    let mut response = Response::new(B::default());
+    *response.status_mut() = status;
+    response
+}
+
+fn too_many_requests<B: Default>(decision: &RateLimitDecision) -> Response<B> {
+    let mut response = status_response(StatusCode::TOO_MANY_REQUESTS);
+    response.headers_mut().extend(decision.header_map());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    use http::HeaderValue;
+
+    use super::*;
+    use crate::FailurePolicy;
+
+    #[derive(Clone)]
+    struct Ok200;
+
+    impl Service<Request<()>> for Ok200 {
+        type Response = Response<()>;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<Response<()>, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: Request<()>) -> Self::Future {
+            std::future::ready(Ok(Response::new(())))
+        }
+    }
+
+    #[derive(Clone)]
+    struct ServiceFn<F>(F);
+
+    impl<F: Fn(&'static str) -> usize> Service<&'static str> for ServiceFn<F> {
+        type Response = usize;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<usize, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: &'static str) -> Self::Future {
+            std::future::ready(Ok((self.0)(request)))
+        }
+    }
+
+    fn limiter(policy: Option<FailurePolicy>) -> Arc<AsyncRateLimiter> {
+        // Nothing listens on port 1.
+        let limiter =
+            AsyncRateLimiter::new("redis://127.0.0.1:1", "layer", 10, Duration::from_secs(60))
+                .unwrap();
+        Arc::new(match policy {
+            Some(policy) => limiter.with_failure_policy(policy),
+            None => limiter,
+        })
+    }
+
+    fn request(api_key: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(api_key) = api_key {
+            request
+                .headers_mut()
+                .insert("x-api-key", HeaderValue::from_str(api_key).unwrap());
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn test_layer_statuses() {
+        let key = HeaderKey(HeaderName::from_static("x-api-key"));
+        let status = |policy, api_key| {
+            let mut service = RateLimitLayer::new(limiter(policy), key.clone()).layer(Ok200);
+            async move { service.call(request(api_key)).await.unwrap().status() }
+        };
+
+        assert_eq!(
+            status(Some(FailurePolicy::Open), Some("k")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Some(FailurePolicy::Closed), Some("k")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            status(None, Some("k")).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // Without a key the request isn't checked.
+        assert_eq!(
+            status(Some(FailurePolicy::Closed), None).await,
+            StatusCode::OK
+        );
+
+        // The cost is validated before the failure policy applies.
+        let weighted = |cost: u64| {
+            let mut service = RateLimitLayer::new(limiter(Some(FailurePolicy::Open)), key.clone())
+                .with_cost(move |_: &Request<()>| cost)
+                .layer(Ok200);
+            async move { service.call(request(Some("k"))).await.unwrap().status() }
+        };
+        assert_eq!(weighted(5).await, StatusCode::OK);
+        assert_eq!(weighted(0).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_service_errors() {
+        // Any request type works with a closure extractor.
+        let len = ServiceFn(|request: &'static str| request.len());
+        let key = |request: &&'static str| (!request.is_empty()).then(|| request.to_string());
+        let call = |policy, request| {
+            let mut service = RateLimitServiceLayer::new(limiter(policy), key).layer(len.clone());
+            async move { service.call(request).await }
+        };
+
+        assert_eq!(call(Some(FailurePolicy::Open), "abc").await.unwrap(), 3);
+        assert!(matches!(
+            call(Some(FailurePolicy::Closed), "abc").await,
+            Err(RateLimitServiceError::Limited(decision)) if !decision.allowed
+        ));
+        assert!(matches!(
+            call(None, "abc").await,
+            Err(RateLimitServiceError::Limiter(_))
+        ));
+        assert_eq!(call(Some(FailurePolicy::Closed), "").await.unwrap(), 0);
+
+        // Any request type can be weighed with a closure too.
+        let mut weighted = RateLimitServiceLayer::new(limiter(Some(FailurePolicy::Open)), key)
+            .with_cost(|request: &&'static str| request.len() as u64)
+            .layer(len.clone());
+        assert_eq!(weighted.call("abc").await.unwrap(), 3);
+        // Unkeyed requests aren't checked, whatever they would cost.
+        assert_eq!(weighted.call("").await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_extractors() {
+        let mut request = Request::new(());
+        assert_eq!(ForwardedIp.extract(&request), None);
+        request
+            .headers_mut()
+            .insert("x-real-ip", HeaderValue::from_static("10.0.0.2"));
+        assert_eq!(ForwardedIp.extract(&request).as_deref(), Some("10.0.0.2"));
+        request.headers_mut().insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("203.0.113.7, 10.0.0.1"),
+        );
+        assert_eq!(
+            ForwardedIp.extract(&request).as_deref(),
+            Some("203.0.113.7")
+        );
+
+        request
+            .extensions_mut()
+            .insert(SocketAddr::from(([192, 0, 2, 1], 4242)));
+        assert_eq!(PeerIp.extract(&request).as_deref(), Some("192.0.2.1"));
+    }
+
+    #[test]
+    fn test_too_many_requests_headers() {
+        let decision = RateLimitDecision {
+            allowed: false,
+            limit: 10,
+            remaining: 0,
+            reset_after: Duration::from_millis(1500),
+            retry_after: Some(Duration::from_millis(200)),
+            reason: None,
+            violated_rule: Some(0),
+        };
+        let response: Response<()> = too_many_requests(&decision);
+        let header = |name: &str| response.headers()[name].to_str().unwrap().to_string();
+        assert_eq!(header("retry-after"), "1");
+        assert_eq!(header("ratelimit-limit"), "10");
+        assert_eq!(header("ratelimit-remaining"), "0");
+        assert_eq!(header("ratelimit-reset"), "2");
+    }
+}
@@ -0,0 +1,117 @@
+//! Client-side leases of permits, trading accuracy for fewer Redis round trips.
+//!
+//! With leasing, a `check` that finds no permit left in its identifier's lease reserves
+//! `permits` at once from Redis (a weighted check, so the whole batch is taken atomically or
+//! not at all), uses one and keeps the rest in process. The following checks of that
+//! identifier are answered from the lease without a round trip until it runs out or reaches
+//! `max_age`. When Redis can't grant a whole batch the check falls back to taking a single
+//! unit, so the last permits of a window are still handed out one at a time.
+//!
+//! Leased permits count as consumed in Redis as soon as they are reserved: permits a process
+//! doesn't use before its lease expires are lost for the window, and one process's lease is
+//! invisible to the others. A lease outliving its window serves up to `permits - 1` requests
+//! against the next one, so keep `max_age` short compared to the window.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Leases above which expired ones are pruned when a new one is granted.
+const PRUNE_AT: usize = 1024;
+
+#[derive(Debug)]
+pub(crate) struct Leases {
+    pub(crate) permits: u64,
+    max_age: Duration,
+    leases: Mutex<HashMap<String, Lease>>,
+}
+
+#[derive(Debug)]
+struct Lease {
+    remaining: u64,
+    expires: Instant,
+}
+
+impl Leases {
+    pub(crate) fn new(permits: u64, max_age: Duration) -> Self {
+        Leases {
+            permits,
+            max_age,
+            leases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a permit from `identifier`'s lease, if it has an unexpired one left.
+    pub(crate) fn take(&self, identifier: &str) -> bool {
+        let mut leases = self
+            .leases
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match leases.get_mut(identifier) {
+            Some(lease) if lease.remaining > 0 && lease.expires > Instant::now() => {
+                lease.remaining -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Keeps a batch of `permits` just reserved for `identifier`, less the one its check used.
+    pub(crate) fn grant(&self, identifier: &str) {
+        let now = Instant::now();
+        let mut leases = self
+            .leases
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if leases.len() >= PRUNE_AT {
+            leases.retain(|_, lease| lease.remaining > 0 && lease.expires > now);
+        }
+        leases.insert(
+            identifier.to_string(),
+            Lease {
+                remaining: self.permits - 1,
+                expires: now + self.max_age,
+            },
+        );
+    }
+
+    /// Drops `identifier`'s lease, e.g. once its state in Redis was reset.
+    pub(crate) fn forget(&self, identifier: &str) {
+        self.leases
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(identifier);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.leases
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_serves_permits_until_empty_or_expired() {
+        let leases = Leases::new(3, Duration::from_millis(20));
+        assert!(!leases.take("alice"));
+
+        leases.grant("alice");
+        assert!(leases.take("alice"));
+        assert!(leases.take("alice"));
+        assert!(!leases.take("alice"));
+        assert!(!leases.take("bob"));
+
+        leases.grant("alice");
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(!leases.take("alice"));
+
+        leases.grant("alice");
+        leases.forget("alice");
+        assert!(!leases.take("alice"));
+    }
+}
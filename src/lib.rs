@@ -1,180 +1,303 @@
-use std::time::Duration;
-use redis::Commands;
+use std::time::{Duration, SystemTime};
+
 use thiserror::Error;
 
+mod access;
+mod active;
+#[cfg(feature = "actix")]
+mod actix;
+mod aio;
+mod backend;
+mod batch;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod breaker;
+mod builder;
+mod calendar;
+mod cardinality;
+mod clock;
+mod compat;
+mod concurrency;
+mod core;
+mod custom;
+mod decision;
+mod denial;
+mod drain;
+mod effective;
+mod escalation;
+mod eviction;
+#[cfg(feature = "metrics")]
+mod facade;
+mod failover;
+mod failure;
+mod health;
+mod history;
+mod identifier;
+#[cfg(feature = "blocking")]
+mod iter;
+mod key;
+mod latency;
+#[cfg(feature = "tower")]
+mod layer;
+mod lease;
+#[cfg(feature = "memcached")]
+mod memcached;
+mod memory;
+mod metrics;
+#[cfg(feature = "notifications")]
+mod notify;
+#[cfg(feature = "otel")]
+mod otel;
+mod overrides;
+mod pacer;
+mod parse;
+mod plain;
+#[cfg(feature = "blocking")]
+mod pool;
+mod quota;
+#[cfg(feature = "blocking")]
+mod registry;
+mod reservation;
+mod retry;
+mod runtime;
+mod saturation;
+mod sentinel;
+#[cfg(feature = "serde")]
+mod serde_duration;
+mod session;
+#[cfg(feature = "statsd")]
+mod statsd;
+mod stream;
+mod telemetry;
+mod template;
+#[cfg(feature = "blocking")]
+mod tenant;
+mod top;
+mod trace;
+mod transform;
+mod usage;
+
+pub use access::AccessList;
+#[cfg(feature = "blocking")]
+pub use active::ActiveIdentifiers;
+pub use active::{ActiveIdentifier, ActiveIdentifierStream};
+#[cfg(feature = "actix")]
+pub use actix::{ActixRateLimit, ActixRateLimitService};
+pub use aio::AsyncRateLimiter;
+pub use backend::RateLimitBackend;
+#[cfg(feature = "blocking")]
+pub use blocking::RateLimiter;
+pub use breaker::{BreakerState, CircuitBreakerConfig};
+pub use builder::RateLimiterBuilder;
+pub use cardinality::{CardinalityLimit, CardinalityPolicy};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use compat::{ServerCapabilities, ServerKind};
+pub use concurrency::{ConcurrencyLimiter, Permit};
+pub use custom::CustomScript;
+pub use decision::{RateLimitDecision, RunOutcome};
+pub use denial::DenialReason;
+pub use drain::spawn_drainer;
+pub use effective::{ConfigSource, EffectiveConfig};
+pub use escalation::{EscalationPolicy, PenaltyPolicy};
+pub use eviction::{EvictionCanary, EvictionPolicyAction};
+#[cfg(feature = "metrics")]
+pub use facade::MetricsFacadeSink;
+pub use failure::FailurePolicy;
+pub use health::HealthReport;
+pub use history::{UsageBucket, UsageHistory};
+pub use identifier::{
+    CaseNormalization, IdentifierPolicy, Normalization, OversizedIdentifier, ToIdentifier,
+    UnsafeCharacters,
+};
+#[cfg(feature = "blocking")]
+pub use iter::{RateLimitedIter, RateLimitedIteratorExt};
+pub use key::Key;
+pub use latency::{LatencyStats, SlowOperation};
+#[cfg(feature = "tower")]
+pub use layer::{
+    ForwardedIp, HeaderKey, KeyExtractor, PeerIp, RateLimit, RateLimitLayer, RateLimitService,
+    RateLimitServiceError, RateLimitServiceLayer, RequestCost, UnitCost,
+};
+#[cfg(feature = "memcached")]
+pub use memcached::MemcachedRateLimiter;
+pub use memory::InMemoryRateLimiter;
+pub use metrics::{CheckEvent, CheckOutcome, MetricsSink, TimingEvent};
+#[cfg(feature = "notifications")]
+pub use notify::ResetEvents;
+#[cfg(feature = "otel")]
+pub use otel::OtelSink;
+pub use overrides::{ActiveOverride, LimitOverride};
+pub use pacer::Pacer;
+#[cfg(feature = "serde")]
+pub use parse::deserialize_duration;
+pub use parse::{parse_duration, Rate};
+#[cfg(feature = "blocking")]
+pub use pool::PoolConfig;
+pub use quota::Quota;
+#[cfg(feature = "blocking")]
+pub use registry::{LimiterConfig, RateLimiterRegistry, RegistryConfig};
+pub use reservation::Reservation;
+pub use retry::{RetryPolicy, TransientError};
+pub use saturation::SaturationSmoother;
+pub use session::SessionLimiter;
+#[cfg(feature = "statsd")]
+pub use statsd::StatsdSink;
+pub use stream::{RateLimited, RateLimitedStreamExt, ThrottleError};
+pub use telemetry::TelemetrySampling;
+pub use template::{Template, TemplateRegistry};
+#[cfg(feature = "blocking")]
+pub use tenant::{TenantConfig, TenantRateLimiter};
+pub use top::TopConsumer;
+#[cfg(feature = "tracing")]
+pub use trace::TracedIdentifier;
+pub use transform::{KeyTransform, SaltedHash};
+pub use usage::{RateLimitStatus, Usage};
+
 #[derive(Error, Debug)]
 pub enum RateLimiterError {
     #[error("Redis error: {0}")]
     Redis(#[from] redis::RedisError),
-    #[error("Rate limit exceeded")]
-    RateLimitExceeded,
+    #[cfg(feature = "memcached")]
+    #[error("Memcached error: {0}")]
+    Memcached(#[from] memcache::MemcacheError),
+    /// Other clients kept changing the memcached window between reads and writes.
+    #[cfg(feature = "memcached")]
+    #[error("Gave up counting after {attempts} conflicting memcached updates")]
+    MemcachedContention { attempts: usize },
+    /// `retry_after` is how long until the request would be allowed, as computed by the check
+    /// script; zero if unknown (e.g. a denial from a custom script's decoder).
+    #[error("Rate limit exceeded, retry after {retry_after:?}")]
+    RateLimitExceeded { retry_after: Duration },
+    #[error("Deadline exceeded before the rate limit check completed")]
+    DeadlineExceeded,
+    #[error("Redis maxmemory-policy `{policy}` may evict rate limit keys")]
+    UnsafeEvictionPolicy { policy: String },
+    #[error("Too many distinct identifiers in the current window")]
+    CardinalityLimitExceeded,
+    #[error("Unknown limit template `{0}`")]
+    UnknownTemplate(String),
+    #[error("Unknown limiter `{0}`")]
+    UnknownLimiter(String),
+    #[error("Unknown tenant `{0}`")]
+    UnknownTenant(String),
+    #[error("Timed out waiting for a pooled Redis connection")]
+    PoolExhausted,
+    #[error("Invalid identifier: {0}")]
+    InvalidIdentifier(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("{server} does not support {feature}")]
+    Unsupported { feature: String, server: String },
+    #[error("Too many callers waiting for capacity (at most {max_waiters})")]
+    WaitQueueFull { max_waiters: usize },
+    /// The identifier was banned by the limiter's `EscalationPolicy` until `until`.
+    #[error("Banned until {until:?} for repeatedly exceeding the rate limit")]
+    Banned { until: SystemTime },
+    #[error("Identifier is denylisted")]
+    Denylisted,
+    #[error("Too many operations in flight (at most {max_in_flight})")]
+    ConcurrencyLimitExceeded { max_in_flight: u64 },
 }
 
-pub struct RateLimiter {
-    redis_client: redis::Client,
-    key_prefix: String,
-    max_requests: u64,
-    window: Duration,
+/// Name of the canary key used to detect evictions, under the limiter's prefix.
+const EVICTION_CANARY: &str = "__eviction_canary__";
+
+/// How a window's expiry is set as requests come in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum WindowMode {
+    /// The window starts with the first request and expires a fixed `window` later,
+    /// regardless of later traffic: "N requests per window".
+    FixedFromFirstRequest,
+    /// Every allowed request pushes the expiry out to a full `window` again, so the counter
+    /// only resets after `window` without allowed requests: "N requests per window since the
+    /// last activity".
+    #[default]
+    SlidingInactivity,
+    /// Windows are UTC calendar days: the counter expires at the next midnight UTC, whenever
+    /// the first request came in, for quotas like "10,000 requests per day". The configured
+    /// `window` is not used.
+    CalendarDay,
+    /// Like `CalendarDay`, for UTC calendar months: the counter expires at the start of the
+    /// next month.
+    CalendarMonth,
 }
 
-impl RateLimiter {
-    /// Creates a new RateLimiter instance.
-    pub fn new(
-        redis_url: &str,
-        key_prefix: &str,
-        max_requests: u64,
-        window: Duration,
-    ) -> Result<Self, RateLimiterError> {
-        let client = redis::Client::open(redis_url)?;
-        Ok(RateLimiter {
-            redis_client: client,
-            key_prefix: key_prefix.to_string(),
-            max_requests,
-            window,
-        })
-    }
-
-    fn get_redis_key(&self, identifier: &str) -> String {
-        format!("{}:{}", self.key_prefix, identifier)
-    }
-
-    pub fn check(&self, identifier: &str) -> Result<(), RateLimiterError> {
-        let key = self.get_redis_key(identifier);
-        let mut conn = self.redis_client.get_connection()?;
-        let window_seconds = self.window.as_secs() as usize;
-
-        let script = redis::Script::new(r#"
-            local key = KEYS[1]
-            local limit = tonumber(ARGV[1])
-            local expiry = tonumber(ARGV[2])
-            local current = redis.call("INCR", key)
-            if current > limit then
-                return 0
-            else
-                redis.call("EXPIRE", key, expiry)
-                return 1
-            end
-        "#);
-
-        let result: Result<u64, redis::RedisError> = script
-            .key(&key)
-            .arg(self.max_requests)
-            .arg(window_seconds)
-            .invoke(&mut conn);
-
-        match result {
-            Ok(1) => Ok(()),
-            Ok(0) => Err(RateLimiterError::RateLimitExceeded),
-            Ok(_) => Ok(()), // Any other value means we're under the limit
-            Err(e) => Err(RateLimiterError::Redis(e)),
+impl WindowMode {
+    fn as_arg(self) -> u8 {
+        match self {
+            WindowMode::FixedFromFirstRequest => 0,
+            WindowMode::SlidingInactivity => 1,
+            WindowMode::CalendarDay => 3,
+            WindowMode::CalendarMonth => 4,
         }
     }
-
-    pub fn get_remaining(&self, identifier: &str) -> Result<u64, RateLimiterError> {
-        let key = self.get_redis_key(identifier);
-        let mut conn = self.redis_client.get_connection()?;
-        let count: Option<u64> = conn.get(&key)?;
-        Ok(self.max_requests.saturating_sub(count.unwrap_or(0)))
-    }
-
-    pub fn get_time_remaining(&self, identifier: &str) -> Result<i64, RateLimiterError> {
-        let key = self.get_redis_key(identifier);
-        let mut conn = self.redis_client.get_connection()?;
-        let ttl: i64 = conn.ttl(&key)?;
-        Ok(if ttl == -2 { -1 } else { ttl })
-    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::thread::sleep;
-    use std::sync::Mutex;
-
-    const REDIS_URL: &str = "redis://127.0.0.1:6379";
-
-    // Simple counter for generating unique prefixes
-    static PREFIX_COUNTER: Mutex<u32> = Mutex::new(0);
-
-    fn get_unique_prefix() -> String {
-        let mut counter = PREFIX_COUNTER.lock().unwrap();
-        *counter += 1;
-        format!("test_rate_limiter_{}", *counter)
-    }
-
-    #[test]
-    fn test_basic_rate_limiting() -> Result<(), RateLimiterError> {
-        let prefix = get_unique_prefix();
-        let limiter = RateLimiter::new(REDIS_URL, &prefix, 3, Duration::from_secs(1))?;
-        let identifier = "user_1";
-
-        assert!(limiter.check(identifier).is_ok());
-        assert!(limiter.check(identifier).is_ok());
-        assert!(limiter.check(identifier).is_ok());
-        assert!(limiter.check(identifier).is_err()); // Rate limit exceeded
-
-        sleep(Duration::from_secs(2)); // Wait for the window to expire
-
-        assert!(limiter.check(identifier).is_ok()); // Should allow again
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_multiple_identifiers() -> Result<(), RateLimiterError> {
-        let prefix = get_unique_prefix();
-        let limiter = RateLimiter::new(REDIS_URL, &prefix, 2, Duration::from_secs(1))?;
-        let user_1 = "user_1";
-        let user_2 = "user_2";
-
-        assert!(limiter.check(user_1).is_ok());
-        assert!(limiter.check(user_2).is_ok());
-        assert!(limiter.check(user_1).is_ok());
-        assert!(limiter.check(user_2).is_ok());
-        assert!(limiter.check(user_1).is_err());
-        assert!(limiter.check(user_2).is_err());
-
-        sleep(Duration::from_secs(2));
-
-        assert!(limiter.check(user_1).is_ok());
-        assert!(limiter.check(user_2).is_ok());
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_get_remaining() -> Result<(), RateLimiterError> {
-        let prefix = get_unique_prefix();
-        let limiter = RateLimiter::new(REDIS_URL, &prefix, 5, Duration::from_secs(5))?;
-        let identifier = "user_3";
-
-        assert_eq!(limiter.get_remaining(identifier)?, 5);
-        assert!(limiter.check(identifier).is_ok());
-        assert_eq!(limiter.get_remaining(identifier)?, 4);
-        assert!(limiter.check(identifier).is_ok());
-        assert_eq!(limiter.get_remaining(identifier)?, 3);
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_get_time_remaining() -> Result<(), RateLimiterError> {
-        let prefix = get_unique_prefix();
-        let limiter = RateLimiter::new(REDIS_URL, &prefix, 2, Duration::from_secs(3))?;
-        let identifier = "user_4";
-
-        assert!(limiter.check(identifier).is_ok());
-        let ttl1 = limiter.get_time_remaining(identifier)?;
-        assert!(ttl1 > 0 && ttl1 <= 3);
-
-        sleep(Duration::from_secs(2));
-        let ttl2 = limiter.get_time_remaining(identifier)?;
-        assert!(ttl2 >= 0 && ttl2 <= 1);
-
-        sleep(Duration::from_secs(2));
-        let ttl3 = limiter.get_time_remaining(identifier)?;
-        assert_eq!(ttl3, -1); // Key should have expired
+/// How requests are counted against the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Algorithm {
+    /// A counter per window, shaped by `WindowMode`. Cheap, but a client can send up to twice
+    /// the limit around a window boundary.
+    #[default]
+    FixedWindow,
+    /// A sorted set holding the timestamp of every allowed request, so at most the limit is
+    /// allowed in any `window`-long span. Costs memory per request and ignores `WindowMode`
+    /// and borrowing; timestamps come from the Redis server clock.
+    SlidingWindowLog,
+    /// A bucket of up to the limit's tokens, refilled continuously (by default at the limit per
+    /// window; see `with_refill_rate`), where every allowed request takes one token. Idle
+    /// clients can burst up to the full bucket, unless `with_idle_credit` caps what a gap
+    /// between requests refills (and what a new bucket starts with) below the limit, which
+    /// then only bounds the tokens a steadily active client can save up. Stored as a hash of
+    /// the token count and the last refill time by the Redis server clock; ignores
+    /// `WindowMode` and borrowing.
+    TokenBucket,
+    /// The generic cell rate algorithm: requests are spaced one `window / max_requests` apart
+    /// on average, with bursts of up to the limit, tracked by a single timestamp per key (the
+    /// theoretical arrival time, see `theoretical_arrival`) by the Redis server clock. Ignores
+    /// `WindowMode` and borrowing.
+    Gcra,
+    /// Counters for the current and the previous window (aligned to multiples of `window` by
+    /// the Redis server clock), with the previous count weighted by how much of it still
+    /// overlaps the last `window`. Approximates the sliding window log with two counters per
+    /// key instead of one entry per request. Only allowed requests are counted; ignores
+    /// `WindowMode` and borrowing.
+    ///
+    /// The weighting assumes the oldest counter's requests were evenly spread, so any `window`
+    /// can admit up to the limit plus what that counter holds: almost twice the limit in the
+    /// worst case, when a client spends its whole budget at the end of a window. With
+    /// `with_counter_buckets(n)` the window is split into `n` counters and only the oldest,
+    /// covering `window / n`, is weighted, which brings the excess down to what the client
+    /// spent in that slice: about `limit / n` for evenly spread traffic.
+    SlidingWindowCounter,
+    /// A bucket holding up to the limit, drained continuously (by default at the limit per
+    /// window; see `with_drain_rate`), which every allowed request fills by its cost. Unlike
+    /// `TokenBucket`, an idle client starts empty rather than full, and a denial's
+    /// `retry_after` is how long until enough has drained for the request to fit. Stored as a
+    /// hash of the level and the last drain time by the Redis server clock; ignores
+    /// `WindowMode` and borrowing.
+    LeakyBucket,
+}
 
-        Ok(())
+impl Algorithm {
+    fn as_arg(self) -> u8 {
+        match self {
+            Algorithm::FixedWindow => 0,
+            Algorithm::SlidingWindowLog => 1,
+            Algorithm::TokenBucket => 2,
+            Algorithm::Gcra => 3,
+            Algorithm::SlidingWindowCounter => 4,
+            Algorithm::LeakyBucket => 5,
+        }
     }
 }
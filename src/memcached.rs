@@ -0,0 +1,377 @@
+//! A fixed window limiter on memcached, available with the `memcached` feature.
+//!
+//! [`MemcachedRateLimiter`] stores each identifier's window as `<count>:<expires at, ms since
+//! the epoch>` and counts requests with `gets` and `cas`, reading the window again whenever
+//! another client changed it in between, so processes sharing a memcached server share the
+//! limit. Like the check script's fixed window, every request counts, denied ones included,
+//! and the window starts with the first request (`WindowMode::FixedFromFirstRequest`).
+//! Windows are timed by each client's own clock, so clients' clocks should be kept in sync.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use memcache::{CommandError, MemcacheError};
+
+use crate::{RateLimitBackend, RateLimiterError, ToIdentifier, Usage};
+
+/// Memcached's longest key.
+const MAX_KEY_LEN: usize = 250;
+
+/// Expirations longer than this are read by memcached as a Unix timestamp.
+const MAX_RELATIVE_EXPIRATION: u64 = 30 * 24 * 60 * 60;
+
+/// `cas` attempts per check before giving up under contention.
+const MAX_CAS_ATTEMPTS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Window {
+    count: u64,
+    expires_at_ms: u64,
+}
+
+impl Window {
+    fn parse(value: &[u8]) -> Option<Self> {
+        let (count, expires_at_ms) = std::str::from_utf8(value).ok()?.split_once(':')?;
+        Some(Window {
+            count: count.parse().ok()?,
+            expires_at_ms: expires_at_ms.parse().ok()?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!("{}:{}", self.count, self.expires_at_ms)
+    }
+
+    /// The window after one more request at `now_ms`: this one, or a new one if it expired.
+    fn next(current: Option<Window>, now_ms: u64, window_ms: u64) -> Window {
+        match current {
+            Some(window) if window.expires_at_ms > now_ms => Window {
+                count: window.count + 1,
+                ..window
+            },
+            _ => Window {
+                count: 1,
+                expires_at_ms: now_ms + window_ms,
+            },
+        }
+    }
+
+    /// The item expiration memcached should apply, in whole seconds, rounded up.
+    fn expiration(&self, now_ms: u64) -> u32 {
+        let secs = (self.expires_at_ms.saturating_sub(now_ms) + 999) / 1000;
+        let secs = secs.max(1);
+        if secs > MAX_RELATIVE_EXPIRATION {
+            ((self.expires_at_ms + 999) / 1000) as u32
+        } else {
+            secs as u32
+        }
+    }
+}
+
+/// A fixed window limiter on memcached, with the fixed window semantics of `RateLimiter`
+/// (denied requests count, millisecond expiry) and its `RateLimitBackend` operations.
+pub struct MemcachedRateLimiter {
+    client: memcache::Client,
+    key_prefix: String,
+    max_requests: u64,
+    window: Duration,
+}
+
+impl MemcachedRateLimiter {
+    /// Connects to memcached at `url`, e.g. `memcache://127.0.0.1:11211` (append
+    /// `?protocol=ascii` for servers without the binary protocol).
+    pub fn new(
+        url: &str,
+        key_prefix: &str,
+        max_requests: u64,
+        window: Duration,
+    ) -> Result<Self, RateLimiterError> {
+        let client = memcache::Client::connect(url)?;
+        Self::from_client(client, key_prefix, max_requests, window)
+    }
+
+    /// Like `new`, over an existing client, e.g. one built with `memcache::Client::builder`
+    /// for several servers or custom timeouts.
+    pub fn from_client(
+        client: memcache::Client,
+        key_prefix: &str,
+        max_requests: u64,
+        window: Duration,
+    ) -> Result<Self, RateLimiterError> {
+        if !is_valid_key(key_prefix) {
+            return Err(RateLimiterError::InvalidConfig(format!(
+                "key prefix `{}` is not a valid memcached key",
+                key_prefix
+            )));
+        }
+        Ok(MemcachedRateLimiter {
+            client,
+            key_prefix: key_prefix.to_string(),
+            max_requests,
+            window: window.max(Duration::from_millis(1)),
+        })
+    }
+
+    /// Counts a request against `identifier`'s window. Fails with
+    /// `RateLimiterError::MemcachedContention` if other clients kept changing the window
+    /// between reading and writing it for `MAX_CAS_ATTEMPTS` attempts.
+    pub fn check(&self, identifier: impl ToIdentifier) -> Result<(), RateLimiterError> {
+        let key = self.key(&identifier.to_identifier())?;
+        let window_ms = self.window.as_millis() as u64;
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let now_ms = now_ms();
+            let Some((current, cas)) = self.read(&key)? else {
+                // Create the window, then count on it like any other; if another client
+                // created it first, this adds nothing.
+                let created = Window::next(None, now_ms, window_ms);
+                let empty = Window {
+                    count: 0,
+                    ..created
+                };
+                match self
+                    .client
+                    .add(&key, empty.encode(), empty.expiration(now_ms))
+                {
+                    Ok(()) | Err(MemcacheError::CommandError(CommandError::KeyExists)) => {}
+                    Err(e) => return Err(e.into()),
+                }
+                continue;
+            };
+            let next = Window::next(current, now_ms, window_ms);
+            // Another client changed (or the server evicted) the window since it was read.
+            match self
+                .client
+                .cas(&key, next.encode(), next.expiration(now_ms), cas)
+            {
+                Ok(true) => {}
+                Ok(false)
+                | Err(MemcacheError::CommandError(
+                    CommandError::KeyExists | CommandError::KeyNotFound,
+                )) => continue,
+                Err(e) => return Err(e.into()),
+            }
+            if next.count > self.max_requests {
+                return Err(RateLimiterError::RateLimitExceeded {
+                    retry_after: Duration::from_millis(next.expires_at_ms - now_ms),
+                });
+            }
+            return Ok(());
+        }
+        Err(RateLimiterError::MemcachedContention {
+            attempts: MAX_CAS_ATTEMPTS,
+        })
+    }
+
+    pub fn get_remaining(&self, identifier: impl ToIdentifier) -> Result<u64, RateLimiterError> {
+        Ok(self.get_usage(identifier)?.remaining)
+    }
+
+    /// Time until `identifier`'s window resets, or `None` if it has no active window.
+    pub fn get_time_remaining(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<Option<Duration>, RateLimiterError> {
+        Ok(self.get_usage(identifier)?.resets_in)
+    }
+
+    pub fn get_usage(&self, identifier: impl ToIdentifier) -> Result<Usage, RateLimiterError> {
+        let key = self.key(&identifier.to_identifier())?;
+        let now_ms = now_ms();
+        let live = self
+            .read(&key)?
+            .and_then(|(window, _)| window)
+            .filter(|window| window.expires_at_ms > now_ms);
+        let (count, pttl) = match live {
+            Some(window) => (Some(window.count), (window.expires_at_ms - now_ms) as i64),
+            None => (None, -2),
+        };
+        Ok(Usage::from_raw(count, pttl, self.max_requests, self.window))
+    }
+
+    /// Clears `identifier`'s window, so its next request starts a fresh one.
+    pub fn reset(&self, identifier: impl ToIdentifier) -> Result<(), RateLimiterError> {
+        let key = self.key(&identifier.to_identifier())?;
+        self.client.delete(&key)?;
+        Ok(())
+    }
+
+    fn key(&self, identifier: &str) -> Result<String, RateLimiterError> {
+        let key = format!("{}:{}", self.key_prefix, identifier);
+        if !is_valid_key(&key) {
+            return Err(RateLimiterError::InvalidIdentifier(format!(
+                "`{}` does not make a valid memcached key",
+                identifier
+            )));
+        }
+        Ok(key)
+    }
+
+    /// The stored window and its CAS token, or `None` if there is no item. An unreadable
+    /// value reads as no window, to be replaced by the next check.
+    fn read(&self, key: &str) -> Result<Option<(Option<Window>, u64)>, RateLimiterError> {
+        let mut items: std::collections::HashMap<String, (Vec<u8>, u32, Option<u64>)> =
+            self.client.gets(&[key])?;
+        Ok(items
+            .remove(key)
+            .and_then(|(value, _, cas)| Some((Window::parse(&value), cas?))))
+    }
+}
+
+impl std::fmt::Debug for MemcachedRateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemcachedRateLimiter")
+            .field("key_prefix", &self.key_prefix)
+            .field("max_requests", &self.max_requests)
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RateLimitBackend for MemcachedRateLimiter {
+    fn check(&self, identifier: &str) -> Result<(), RateLimiterError> {
+        MemcachedRateLimiter::check(self, identifier)
+    }
+
+    fn remaining(&self, identifier: &str) -> Result<u64, RateLimiterError> {
+        self.get_remaining(identifier)
+    }
+
+    fn ttl(&self, identifier: &str) -> Result<Option<Duration>, RateLimiterError> {
+        self.get_time_remaining(identifier)
+    }
+
+    fn reset(&self, identifier: &str) -> Result<(), RateLimiterError> {
+        MemcachedRateLimiter::reset(self, identifier)
+    }
+}
+
+/// Memcached keys are at most 250 bytes, without whitespace or control characters.
+fn is_valid_key(key: &str) -> bool {
+    key.len() <= MAX_KEY_LEN && !key.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+    use std::thread::sleep;
+
+    use super::*;
+
+    const MEMCACHED_URL: &str = "memcache://127.0.0.1:11211";
+
+    static PREFIX_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    fn get_unique_prefix() -> String {
+        format!(
+            "test_memcached_{}_{}_{}",
+            std::process::id(),
+            now_ms(),
+            PREFIX_COUNTER.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    #[test]
+    fn test_window_counts_and_rolls_over() {
+        let first = Window::next(None, 1_000, 500);
+        assert_eq!(
+            first,
+            Window {
+                count: 1,
+                expires_at_ms: 1_500
+            }
+        );
+        assert_eq!(Window::parse(first.encode().as_bytes()), Some(first));
+        assert_eq!(Window::next(Some(first), 1_499, 500).count, 2);
+        assert_eq!(Window::next(Some(first), 1_500, 500).expires_at_ms, 2_000);
+        assert_eq!(Window::parse(b"garbage"), None);
+
+        // Memcached reads expirations over 30 days as a Unix timestamp.
+        assert_eq!(first.expiration(1_000), 1);
+        let month = Window::next(None, 1_000_000, 40 * 24 * 3_600_000);
+        assert_eq!(month.expiration(1_000_000), 3_457_000);
+    }
+
+    #[test]
+    fn test_rejects_invalid_keys() {
+        assert!(is_valid_key("api:user_1"));
+        assert!(!is_valid_key("api:user 1"));
+        assert!(!is_valid_key(&"x".repeat(251)));
+    }
+
+    #[test]
+    fn test_window_counts_and_expires() -> Result<(), RateLimiterError> {
+        let prefix = get_unique_prefix();
+        let limiter = MemcachedRateLimiter::new(MEMCACHED_URL, &prefix, 2, Duration::from_secs(1))?;
+
+        limiter.check("user_1")?;
+        limiter.check("user_1")?;
+        assert!(matches!(
+            limiter.check("user_1"),
+            Err(RateLimiterError::RateLimitExceeded { .. })
+        ));
+        assert_eq!(limiter.get_remaining("user_1")?, 0);
+        assert_eq!(limiter.get_remaining("user_2")?, 2);
+
+        // The window ends by the client's clock, before memcached drops the item.
+        sleep(Duration::from_millis(1100));
+        limiter.check("user_1")?;
+        assert_eq!(limiter.get_remaining("user_1")?, 1);
+
+        // Memcached expires the item itself, in whole seconds, after the window.
+        sleep(Duration::from_millis(2500));
+        let key = limiter.key("user_1")?;
+        assert_eq!(limiter.client.get::<String>(&key)?, None);
+        assert_eq!(limiter.get_time_remaining("user_1")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_checks_share_the_limit() -> Result<(), RateLimiterError> {
+        let prefix = get_unique_prefix();
+        let limiter =
+            MemcachedRateLimiter::new(MEMCACHED_URL, &prefix, 25, Duration::from_secs(60))?;
+        let allowed = AtomicUsize::new(0);
+
+        // Every thread starts on a missing window, so they race to `add` it, then to `cas`.
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..10 {
+                        match limiter.check("user_1") {
+                            Ok(()) => {
+                                allowed.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(RateLimiterError::RateLimitExceeded { .. }) => {}
+                            Err(e) => panic!("unexpected error: {}", e),
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(allowed.load(Ordering::Relaxed), 25);
+        assert_eq!(limiter.get_usage("user_1")?.consumed, 80);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_starts_a_fresh_window() -> Result<(), RateLimiterError> {
+        let prefix = get_unique_prefix();
+        let limiter =
+            MemcachedRateLimiter::new(MEMCACHED_URL, &prefix, 1, Duration::from_secs(60))?;
+
+        limiter.check("user_1")?;
+        assert!(limiter.check("user_1").is_err());
+        limiter.reset("user_1")?;
+        limiter.check("user_1")?;
+
+        Ok(())
+    }
+}
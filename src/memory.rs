@@ -0,0 +1,396 @@
+//! A process-local limiter for tests and local development.
+//!
+//! [`InMemoryRateLimiter`] keeps fixed window counters in a map instead of Redis, following the
+//! check script's fixed window rules: every request counts, denied ones included, the window
+//! starts with the first request, and `WindowMode` decides whether allowed requests restart it
+//! or it ends at a calendar boundary.
+//! Expired windows are dropped lazily. State is not shared between processes.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::calendar;
+use crate::{
+    Clock, DenialReason, RateLimitBackend, RateLimitDecision, RateLimiterError, SystemClock,
+    ToIdentifier, Usage, WindowMode,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    count: u64,
+    expires_at: SystemTime,
+}
+
+/// A fixed window limiter held in memory, with the same semantics as `RateLimiter` under
+/// `Algorithm::FixedWindow`.
+#[derive(Debug)]
+pub struct InMemoryRateLimiter {
+    max_requests: u64,
+    window: Duration,
+    window_mode: WindowMode,
+    clock: Arc<dyn Clock>,
+    windows: Mutex<Windows>,
+}
+
+#[derive(Debug)]
+struct Windows {
+    by_identifier: HashMap<String, Window>,
+    /// Expired windows are swept at most once per window length.
+    last_sweep: SystemTime,
+}
+
+impl InMemoryRateLimiter {
+    pub fn new(max_requests: u64, window: Duration) -> Self {
+        InMemoryRateLimiter {
+            max_requests,
+            window: window.max(Duration::from_millis(1)),
+            window_mode: WindowMode::default(),
+            clock: Arc::new(SystemClock),
+            windows: Mutex::new(Windows {
+                by_identifier: HashMap::new(),
+                last_sweep: SystemTime::now(),
+            }),
+        }
+    }
+
+    /// Sets how the window expiry is maintained. Defaults to `WindowMode::SlidingInactivity`.
+    pub fn with_window_mode(mut self, window_mode: WindowMode) -> Self {
+        self.window_mode = window_mode;
+        self
+    }
+
+    /// Times windows by `clock` instead of the system clock, e.g. a shared `ManualClock` to
+    /// test expiry without sleeping.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.windows.get_mut().unwrap().last_sweep = clock.now();
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn check(&self, identifier: impl ToIdentifier) -> Result<(), RateLimiterError> {
+        self.count(&identifier.to_identifier(), 1, false)
+            .into_result()
+    }
+
+    /// Like `check`, but the request takes `cost` units, and nothing if denied.
+    pub fn check_with_cost(
+        &self,
+        identifier: impl ToIdentifier,
+        cost: u64,
+    ) -> Result<(), RateLimiterError> {
+        if cost == 0 {
+            return Err(RateLimiterError::InvalidConfig(
+                "cost must be at least 1".to_string(),
+            ));
+        }
+        self.count(&identifier.to_identifier(), cost, true)
+            .into_result()
+    }
+
+    /// Like `check`, but a denial is reported in the returned decision rather than as an
+    /// error.
+    pub fn check_detailed(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        Ok(self.count(&identifier.to_identifier(), 1, false))
+    }
+
+    /// Like `check_detailed`, but the request takes `cost` units, and nothing if denied.
+    pub fn check_detailed_with_cost(
+        &self,
+        identifier: impl ToIdentifier,
+        cost: u64,
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        if cost == 0 {
+            return Err(RateLimiterError::InvalidConfig(
+                "cost must be at least 1".to_string(),
+            ));
+        }
+        Ok(self.count(&identifier.to_identifier(), cost, true))
+    }
+
+    pub fn get_remaining(&self, identifier: impl ToIdentifier) -> Result<u64, RateLimiterError> {
+        Ok(self.get_usage(identifier)?.remaining)
+    }
+
+    /// Time until `identifier`'s window resets, or `None` if it has no active window.
+    pub fn get_time_remaining(
+        &self,
+        identifier: impl ToIdentifier,
+    ) -> Result<Option<Duration>, RateLimiterError> {
+        Ok(self.get_usage(identifier)?.resets_in)
+    }
+
+    pub fn get_usage(&self, identifier: impl ToIdentifier) -> Result<Usage, RateLimiterError> {
+        let now = self.clock.now();
+        let windows = self
+            .windows
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let live = windows
+            .by_identifier
+            .get(identifier.to_identifier().as_ref())
+            .filter(|window| window.expires_at > now);
+        let (count, pttl) = match live {
+            Some(window) => (
+                Some(window.count),
+                window
+                    .expires_at
+                    .duration_since(now)
+                    .unwrap_or_default()
+                    .as_millis() as i64,
+            ),
+            None => (None, -2),
+        };
+        Ok(Usage::from_raw(count, pttl, self.max_requests, self.window))
+    }
+
+    /// Clears `identifier`'s window, so its next request starts a fresh one.
+    pub fn reset(&self, identifier: impl ToIdentifier) -> Result<(), RateLimiterError> {
+        self.windows
+            .lock()
+            .unwrap()
+            .by_identifier
+            .remove(identifier.to_identifier().as_ref());
+        Ok(())
+    }
+
+    /// Gives up to `units` back to `identifier`'s current window, keeping its expiry, and
+    /// returns the units given back: none once the window has expired.
+    pub fn refund(
+        &self,
+        identifier: impl ToIdentifier,
+        units: u64,
+    ) -> Result<u64, RateLimiterError> {
+        if units == 0 {
+            return Err(RateLimiterError::InvalidConfig(
+                "cost must be at least 1".to_string(),
+            ));
+        }
+        let now = self.clock.now();
+        let mut windows = self
+            .windows
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let live = windows
+            .by_identifier
+            .get_mut(identifier.to_identifier().as_ref())
+            .filter(|window| window.expires_at > now);
+        Ok(live.map_or(0, |window| {
+            let refunded = window.count.min(units);
+            window.count -= refunded;
+            refunded
+        }))
+    }
+
+    /// Clears every window and returns how many were active.
+    pub fn reset_all(&self) -> Result<u64, RateLimiterError> {
+        let now = self.clock.now();
+        let mut windows = self
+            .windows
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let live = windows
+            .by_identifier
+            .values()
+            .filter(|w| w.expires_at > now)
+            .count();
+        windows.by_identifier.clear();
+        Ok(live as u64)
+    }
+
+    fn count(&self, identifier: &str, cost: u64, weighted: bool) -> RateLimitDecision {
+        let now = self.clock.now();
+        let mut windows = self
+            .windows
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if now.duration_since(windows.last_sweep).unwrap_or_default() >= self.window {
+            // Keep the map from growing with identifiers that went quiet.
+            windows
+                .by_identifier
+                .retain(|_, window| window.expires_at > now);
+            windows.last_sweep = now;
+        }
+        let fresh = Window {
+            count: 0,
+            expires_at: calendar::window_end(self.window_mode, now).unwrap_or(now + self.window),
+        };
+        let window = windows
+            .by_identifier
+            .entry(identifier.to_string())
+            .or_insert(fresh);
+        if window.expires_at <= now {
+            *window = fresh;
+        }
+        window.count += cost;
+        if window.count > self.max_requests {
+            let retry_after = window.expires_at.duration_since(now).unwrap_or_default();
+            if weighted {
+                window.count -= cost;
+                if window.count == 0 {
+                    windows.by_identifier.remove(identifier);
+                }
+            }
+            return RateLimitDecision {
+                allowed: false,
+                limit: self.max_requests,
+                remaining: 0,
+                reset_after: retry_after,
+                retry_after: Some(retry_after),
+                reason: Some(DenialReason::WindowExhausted),
+                violated_rule: Some(0),
+            };
+        }
+        if self.window_mode == WindowMode::SlidingInactivity {
+            window.expires_at = now + self.window;
+        }
+        RateLimitDecision {
+            allowed: true,
+            limit: self.max_requests,
+            remaining: self.max_requests - window.count,
+            reset_after: window.expires_at.duration_since(now).unwrap_or_default(),
+            retry_after: None,
+            reason: None,
+            violated_rule: None,
+        }
+    }
+}
+
+impl RateLimitBackend for InMemoryRateLimiter {
+    fn check(&self, identifier: &str) -> Result<(), RateLimiterError> {
+        InMemoryRateLimiter::check(self, identifier)
+    }
+
+    fn remaining(&self, identifier: &str) -> Result<u64, RateLimiterError> {
+        self.get_remaining(identifier)
+    }
+
+    fn ttl(&self, identifier: &str) -> Result<Option<Duration>, RateLimiterError> {
+        self.get_time_remaining(identifier)
+    }
+
+    fn reset(&self, identifier: &str) -> Result<(), RateLimiterError> {
+        InMemoryRateLimiter::reset(self, identifier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn test_in_memory_limits_and_expires() -> Result<(), RateLimiterError> {
+        let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+        let limiter = InMemoryRateLimiter::new(2, Duration::from_millis(100))
+            .with_window_mode(WindowMode::FixedFromFirstRequest)
+            .with_clock(clock.clone());
+
+        limiter.check("user_1")?;
+        limiter.check("user_1")?;
+        assert!(matches!(
+            limiter.check("user_1"),
+            Err(RateLimiterError::RateLimitExceeded { retry_after }) if retry_after == Duration::from_millis(100)
+        ));
+        // Denied requests count too, as in the fixed window script.
+        assert_eq!(limiter.get_usage("user_1")?.consumed, 3);
+        assert_eq!(limiter.get_remaining("user_2")?, 2);
+
+        clock.advance(Duration::from_millis(99));
+        assert_eq!(
+            limiter.get_time_remaining("user_1")?,
+            Some(Duration::from_millis(1))
+        );
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(limiter.get_time_remaining("user_1")?, None);
+        limiter.check("user_1")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_sliding_mode_extends() -> Result<(), RateLimiterError> {
+        let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+        let limiter =
+            InMemoryRateLimiter::new(5, Duration::from_millis(200)).with_clock(clock.clone());
+
+        limiter.check("user_1")?;
+        clock.advance(Duration::from_millis(120));
+        limiter.check("user_1")?;
+        assert_eq!(
+            limiter.get_time_remaining("user_1")?,
+            Some(Duration::from_millis(200))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_calendar_day() -> Result<(), RateLimiterError> {
+        // 2024-02-29T23:59:00Z.
+        let clock = Arc::new(ManualClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_251_140),
+        ));
+        let limiter = InMemoryRateLimiter::new(1, Duration::from_secs(3600))
+            .with_window_mode(WindowMode::CalendarDay)
+            .with_clock(clock.clone());
+
+        limiter.check("user_1")?;
+        assert!(limiter.check("user_1").is_err());
+        assert_eq!(
+            limiter.get_time_remaining("user_1")?,
+            Some(Duration::from_secs(60))
+        );
+        clock.advance(Duration::from_secs(60));
+        limiter.check("user_1")?;
+        assert_eq!(
+            limiter.get_time_remaining("user_1")?,
+            Some(Duration::from_secs(86_400))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_weighted_and_reset() -> Result<(), RateLimiterError> {
+        let limiter = InMemoryRateLimiter::new(10, Duration::from_secs(60));
+
+        limiter.check_with_cost("user_1", 7)?;
+        assert!(limiter.check_with_cost("user_1", 5).is_err());
+        assert_eq!(limiter.get_remaining("user_1")?, 3);
+
+        limiter.reset("user_1")?;
+        assert_eq!(limiter.get_remaining("user_1")?, 10);
+        limiter.check("user_2")?;
+        assert_eq!(limiter.reset_all()?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_refund() -> Result<(), RateLimiterError> {
+        let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+        let limiter = InMemoryRateLimiter::new(3, Duration::from_secs(60))
+            .with_window_mode(WindowMode::FixedFromFirstRequest)
+            .with_clock(clock.clone());
+
+        limiter.check_with_cost("user_1", 3)?;
+        assert_eq!(limiter.refund("user_1", 2)?, 2);
+        assert_eq!(limiter.refund("user_1", 5)?, 1);
+        assert_eq!(limiter.get_remaining("user_1")?, 3);
+        assert_eq!(
+            limiter.get_time_remaining("user_1")?,
+            Some(Duration::from_secs(60))
+        );
+
+        limiter.check("user_1")?;
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(limiter.refund("user_1", 1)?, 0);
+        assert_eq!(limiter.refund("user_2", 1)?, 0);
+
+        Ok(())
+    }
+}
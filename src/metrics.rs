@@ -0,0 +1,187 @@
+//! Pluggable metrics for checks and Redis latency.
+//!
+//! A [`MetricsSink`] receives one event per check decision and per timed Redis operation,
+//! after [`TelemetrySampling`](crate::TelemetrySampling) has been applied; the event's
+//! `sample_rate` says how many real events it stands for.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::DenialReason;
+
+/// Outcome of a check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Allowed,
+    Denied(DenialReason),
+    /// Redis failed; the request was neither allowed nor denied.
+    Error,
+}
+
+impl CheckOutcome {
+    /// `allowed`, `denied` or `error`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckOutcome::Allowed => "allowed",
+            CheckOutcome::Denied(_) => "denied",
+            CheckOutcome::Error => "error",
+        }
+    }
+}
+
+/// A sampled check decision.
+#[derive(Debug, Clone)]
+pub struct CheckEvent<'a> {
+    pub key_prefix: &'a str,
+    /// The identifier, or `other` once `TelemetrySampling::with_max_label_values` distinct
+    /// identifiers have been reported. Empty unless the sink asks for identifiers.
+    pub identifier: &'a str,
+    pub outcome: CheckOutcome,
+    /// Fraction of decisions reported, between 0 and 1.
+    pub sample_rate: f64,
+}
+
+/// A sampled Redis operation timing.
+#[derive(Debug, Clone)]
+pub struct TimingEvent<'a> {
+    pub key_prefix: &'a str,
+    pub operation: &'static str,
+    pub duration: Duration,
+    /// Fraction of operations reported, between 0 and 1.
+    pub sample_rate: f64,
+}
+
+/// Receives the limiter's metrics. Implementations must be cheap and must not block: they run
+/// on the check path.
+pub trait MetricsSink: Send + Sync {
+    fn record_check(&self, event: &CheckEvent<'_>);
+
+    fn record_timing(&self, event: &TimingEvent<'_>);
+
+    /// Whether `CheckEvent::identifier` should be filled in. Identifiers are high-cardinality,
+    /// so this defaults to `false`.
+    fn wants_identifier(&self) -> bool {
+        false
+    }
+}
+
+/// Label value reported for identifiers beyond the cap.
+const OTHER_LABEL: &str = "other";
+
+/// Passes through the first `max` distinct label values and folds the rest into `other`.
+#[derive(Debug)]
+pub(crate) struct LabelCap {
+    max: usize,
+    seen: Mutex<HashSet<String>>,
+}
+
+impl LabelCap {
+    pub(crate) fn new(max: usize) -> Self {
+        LabelCap {
+            max,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub(crate) fn label<'a>(&self, value: &'a str) -> &'a str {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if seen.contains(value) {
+            return value;
+        }
+        if seen.len() < self.max {
+            seen.insert(value.to_string());
+            return value;
+        }
+        OTHER_LABEL
+    }
+}
+
+/// A sink together with the identifier label cap applied to its check events.
+pub(crate) struct CheckRecorder {
+    sink: Arc<dyn MetricsSink>,
+    labels: LabelCap,
+}
+
+impl CheckRecorder {
+    pub(crate) fn new(sink: Arc<dyn MetricsSink>, max_label_values: usize) -> Self {
+        CheckRecorder {
+            sink,
+            labels: LabelCap::new(max_label_values),
+        }
+    }
+
+    pub(crate) fn sink(&self) -> &Arc<dyn MetricsSink> {
+        &self.sink
+    }
+
+    pub(crate) fn record(
+        &self,
+        key_prefix: &str,
+        identifier: &str,
+        outcome: CheckOutcome,
+        sample_rate: f64,
+    ) {
+        let identifier = if self.sink.wants_identifier() {
+            self.labels.label(identifier)
+        } else {
+            ""
+        };
+        self.sink.record_check(&CheckEvent {
+            key_prefix,
+            identifier,
+            outcome,
+            sample_rate,
+        });
+    }
+}
+
+impl fmt::Debug for CheckRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CheckRecorder")
+            .field("labels", &self.labels)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_cap() {
+        let cap = LabelCap::new(2);
+        assert_eq!(cap.label("a"), "a");
+        assert_eq!(cap.label("b"), "b");
+        assert_eq!(cap.label("c"), "other");
+        assert_eq!(cap.label("a"), "a");
+    }
+
+    #[derive(Default)]
+    struct Recording(Mutex<Vec<String>>);
+
+    impl MetricsSink for Recording {
+        fn record_check(&self, event: &CheckEvent<'_>) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}:{}", event.identifier, event.outcome.as_str()));
+        }
+
+        fn record_timing(&self, _event: &TimingEvent<'_>) {}
+
+        fn wants_identifier(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_check_recorder_caps_identifiers() {
+        let sink = Arc::new(Recording::default());
+        let recorder = CheckRecorder::new(sink.clone(), 1);
+        recorder.record("api", "a", CheckOutcome::Allowed, 1.0);
+        recorder.record("api", "b", CheckOutcome::Error, 1.0);
+        assert_eq!(*sink.0.lock().unwrap(), vec!["a:allowed", "other:error"]);
+    }
+}
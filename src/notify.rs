@@ -0,0 +1,124 @@
+//! Reset events from Redis keyspace notifications.
+//!
+//! [`ResetEvents`] subscribes to the keyspace notifications of a limiter's prefix on a
+//! dedicated pub/sub connection and yields the identifier of every counter that expires, the
+//! moment its window resets, e.g. to wake work queued on that identifier. Redis only publishes
+//! them with keyspace events for expired keys enabled (`notify-keyspace-events Kx` or wider).
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use crate::core::LimiterCore;
+
+type Messages = Pin<Box<dyn Stream<Item = redis::Msg> + Send>>;
+
+/// Stream returned by `AsyncRateLimiter::reset_events`, yielding identifiers whose counter
+/// just expired. Ends if the pub/sub connection is lost.
+///
+/// Notifications are fire and forget: events published while nobody is subscribed, or while
+/// the connection is down, are lost, so treat them as a hint to retry sooner rather than the
+/// only way waiting work is resumed. Counters deleted by `reset` don't expire, so they aren't
+/// reported.
+#[must_use = "streams do nothing unless polled"]
+pub struct ResetEvents {
+    messages: Messages,
+    /// Length of the `__keyspace@<db>__:<prefix>:` channel prefix.
+    channel_prefix: usize,
+}
+
+impl ResetEvents {
+    /// Subscribes `conn` to the expiry notifications of `core`'s keys in database `db`.
+    pub(crate) async fn subscribe(
+        core: &LimiterCore,
+        conn: redis::aio::Connection,
+        db: i64,
+    ) -> redis::RedisResult<Self> {
+        let channel_prefix = format!("__keyspace@{}__:{}:", db, core.key_prefix);
+        let mut pubsub = conn.into_pubsub();
+        pubsub
+            .psubscribe(format!("{}*", escape_glob(&channel_prefix)))
+            .await?;
+        Ok(ResetEvents {
+            messages: Box::pin(pubsub.into_on_message()),
+            channel_prefix: channel_prefix.len(),
+        })
+    }
+
+    /// The identifier of an expiry event on a counter, skipping other events and the
+    /// limiter's own `__name__` keys.
+    fn identifier(&self, message: &redis::Msg) -> Option<String> {
+        if message.get_payload_bytes() != b"expired" {
+            return None;
+        }
+        let identifier = message.get_channel_name().get(self.channel_prefix..)?;
+        (!identifier.starts_with("__")).then(|| identifier.to_string())
+    }
+}
+
+impl Stream for ResetEvents {
+    type Item = String;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let Some(message) = std::task::ready!(self.messages.as_mut().poll_next(cx)) else {
+                return Poll::Ready(None);
+            };
+            if let Some(identifier) = self.identifier(&message) {
+                return Poll::Ready(Some(identifier));
+            }
+        }
+    }
+}
+
+/// Escapes the glob metacharacters of `PSUBSCRIBE` patterns.
+fn escape_glob(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    fn message(channel: &str, event: &str) -> redis::Msg {
+        let data = |s: &str| redis::Value::Data(s.as_bytes().to_vec());
+        redis::Msg::from_value(&redis::Value::Bulk(vec![
+            data("pmessage"),
+            data("__keyspace@0__:api:*"),
+            data(channel),
+            data(event),
+        ]))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reset_events_yield_expired_counters() {
+        let messages = vec![
+            message("__keyspace@0__:api:user_1", "expired"),
+            message("__keyspace@0__:api:user_2", "incrby"),
+            message("__keyspace@0__:api:__ban__:user_3", "expired"),
+            message("__keyspace@0__:api:user:4", "expired"),
+        ];
+        let events = ResetEvents {
+            messages: Box::pin(futures_util::stream::iter(messages)),
+            channel_prefix: "__keyspace@0__:api:".len(),
+        };
+        assert_eq!(events.collect::<Vec<_>>().await, ["user_1", "user:4"]);
+    }
+
+    #[test]
+    fn test_escape_glob() {
+        assert_eq!(escape_glob("__keyspace@0__:api:"), "__keyspace@0__:api:");
+        assert_eq!(escape_glob("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\");
+    }
+}
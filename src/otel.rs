@@ -0,0 +1,187 @@
+//! Sink for OpenTelemetry metrics, available with the `otel` feature.
+
+use opentelemetry::metrics::{Counter, Histogram, Meter, Unit};
+use opentelemetry::KeyValue;
+
+use crate::metrics::{CheckEvent, CheckOutcome, MetricsSink, TimingEvent};
+
+/// Records check counters and Redis latency histograms as OpenTelemetry instruments, exported
+/// by whichever `MeterProvider` created the meter (e.g. an OTLP or Prometheus exporter):
+///
+/// - `<namespace>.checks{prefix, outcome[, reason]}`: a counter of check decisions, where
+///   `outcome` is `allowed`, `denied` (with the denial `reason`) or `error` for Redis failures
+/// - `<namespace>.redis.duration{prefix, operation}`: a histogram of Redis operation
+///   latencies in seconds, `check` among them
+///
+/// Sampled check events are counted with their weight (`1 / sample_rate`), so the counter
+/// still estimates every check; sampled timings are recorded as they are.
+#[derive(Debug, Clone)]
+pub struct OtelSink {
+    checks: Counter<u64>,
+    durations: Histogram<f64>,
+    identifier_attribute: bool,
+}
+
+impl OtelSink {
+    /// Records instruments named under `namespace`, e.g. `ratelimit`, through `meter`, e.g.
+    /// `opentelemetry::global::meter("redis_rate_limiter")`.
+    pub fn new(meter: &Meter, namespace: &str) -> Self {
+        OtelSink {
+            checks: meter
+                .u64_counter(format!("{}.checks", namespace))
+                .with_description("Rate limit check decisions")
+                .init(),
+            durations: meter
+                .f64_histogram(format!("{}.redis.duration", namespace))
+                .with_description("Redis operation latencies")
+                .with_unit(Unit::new("s"))
+                .init(),
+            identifier_attribute: false,
+        }
+    }
+
+    /// Attributes check counters with the identifier, capped by
+    /// `TelemetrySampling::with_max_label_values`.
+    pub fn with_identifier_attribute(mut self, enabled: bool) -> Self {
+        self.identifier_attribute = enabled;
+        self
+    }
+}
+
+impl MetricsSink for OtelSink {
+    fn record_check(&self, event: &CheckEvent<'_>) {
+        let mut attributes = vec![
+            KeyValue::new("prefix", event.key_prefix.to_string()),
+            KeyValue::new("outcome", event.outcome.as_str()),
+        ];
+        if let CheckOutcome::Denied(reason) = event.outcome {
+            attributes.push(KeyValue::new("reason", reason.as_str()));
+        }
+        if self.identifier_attribute {
+            attributes.push(KeyValue::new("identifier", event.identifier.to_string()));
+        }
+        let weight = if event.sample_rate > 0.0 {
+            (1.0 / event.sample_rate).round() as u64
+        } else {
+            0
+        };
+        self.checks.add(weight, &attributes);
+    }
+
+    fn record_timing(&self, event: &TimingEvent<'_>) {
+        let attributes = [
+            KeyValue::new("prefix", event.key_prefix.to_string()),
+            KeyValue::new("operation", event.operation.to_string()),
+        ];
+        self.durations
+            .record(event.duration.as_secs_f64(), &attributes);
+    }
+
+    fn wants_identifier(&self) -> bool {
+        self.identifier_attribute
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+    use std::borrow::Cow;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use opentelemetry::metrics::{
+        CallbackRegistration, InstrumentProvider, MetricsError, Observer, Result, SyncCounter,
+        SyncHistogram,
+    };
+
+    use super::*;
+    use crate::DenialReason;
+
+    type Recorded = Arc<Mutex<Vec<String>>>;
+
+    /// Records every update as `name{attributes} value`.
+    struct Capture(Recorded);
+
+    struct Instrument(Cow<'static, str>, Recorded);
+
+    impl Instrument {
+        fn push(&self, value: impl std::fmt::Display, attributes: &[KeyValue]) {
+            let attributes: Vec<String> = attributes
+                .iter()
+                .map(|kv| format!("{}={}", kv.key, kv.value))
+                .collect();
+            self.1.lock().unwrap().push(format!(
+                "{}{{{}}} {}",
+                self.0,
+                attributes.join(","),
+                value
+            ));
+        }
+    }
+
+    impl SyncCounter<u64> for Instrument {
+        fn add(&self, value: u64, attributes: &[KeyValue]) {
+            self.push(value, attributes);
+        }
+    }
+
+    impl SyncHistogram<f64> for Instrument {
+        fn record(&self, value: f64, attributes: &[KeyValue]) {
+            self.push(value, attributes);
+        }
+    }
+
+    impl InstrumentProvider for Capture {
+        fn u64_counter(
+            &self,
+            name: Cow<'static, str>,
+            _: Option<Cow<'static, str>>,
+            _: Option<Unit>,
+        ) -> Result<Counter<u64>> {
+            Ok(Counter::new(Arc::new(Instrument(name, self.0.clone()))))
+        }
+
+        fn f64_histogram(
+            &self,
+            name: Cow<'static, str>,
+            _: Option<Cow<'static, str>>,
+            _: Option<Unit>,
+        ) -> Result<Histogram<f64>> {
+            Ok(Histogram::new(Arc::new(Instrument(name, self.0.clone()))))
+        }
+
+        fn register_callback(
+            &self,
+            _: &[Arc<dyn Any>],
+            _: Box<dyn Fn(&dyn Observer) + Send + Sync>,
+        ) -> Result<Box<dyn CallbackRegistration>> {
+            Err(MetricsError::Other("no observable instruments".into()))
+        }
+    }
+
+    #[test]
+    fn test_records_counters_and_histograms() {
+        let recorded = Recorded::default();
+        let meter = Meter::new(Arc::new(Capture(recorded.clone())));
+        let sink = OtelSink::new(&meter, "ratelimit");
+        sink.record_check(&CheckEvent {
+            key_prefix: "api",
+            identifier: "",
+            outcome: CheckOutcome::Denied(DenialReason::WindowExhausted),
+            sample_rate: 0.25,
+        });
+        sink.record_timing(&TimingEvent {
+            key_prefix: "api",
+            operation: "check",
+            duration: Duration::from_micros(1500),
+            sample_rate: 1.0,
+        });
+        assert_eq!(
+            *recorded.lock().unwrap(),
+            [
+                "ratelimit.checks{prefix=api,outcome=denied,reason=window_exhausted} 4",
+                "ratelimit.redis.duration{prefix=api,operation=check} 0.0015",
+            ]
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Per-identifier limit overrides stored in Redis.
+//!
+//! Each override is a hash at `{prefix}:__override__:{identifier}` with optional `limit` and
+//! `window_ms` fields. The check script reads it in the same atomic step as the counter
+//! update, so a changed override applies to the very next request on every instance.
+//!
+//! Limiter-wide limits set with `set_limits` live in a hash of the same shape at
+//! `{prefix}:__limits__`, read by the check script below per-identifier overrides.
+//!
+//! Temporary overrides are plain key expiry on that hash. Active overrides are indexed in a
+//! sorted set at `{prefix}:__overrides__` scored by their expiry time (`+inf` when permanent);
+//! stale index entries are pruned when the index is listed.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Replaces the limiter's configured limit and/or window for a single identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LimitOverride {
+    pub max_requests: Option<u64>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "window_ms", with = "crate::serde_duration::option_millis")
+    )]
+    pub window: Option<Duration>,
+}
+
+impl LimitOverride {
+    pub fn max_requests(max_requests: u64) -> Self {
+        LimitOverride {
+            max_requests: Some(max_requests),
+            window: None,
+        }
+    }
+
+    pub fn window(window: Duration) -> Self {
+        LimitOverride {
+            max_requests: None,
+            window: Some(window),
+        }
+    }
+
+    pub fn with_max_requests(mut self, max_requests: u64) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    pub(crate) fn from_fields(limit: Option<u64>, window_ms: Option<u64>) -> Option<Self> {
+        if limit.is_none() && window_ms.is_none() {
+            return None;
+        }
+        Some(LimitOverride {
+            max_requests: limit,
+            window: window_ms.map(Duration::from_millis),
+        })
+    }
+}
+
+/// An override currently stored in Redis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ActiveOverride {
+    pub identifier: String,
+    pub limit_override: LimitOverride,
+    /// Time until the override reverts, or `None` if it is permanent.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "expires_in_ms",
+            with = "crate::serde_duration::option_millis"
+        )
+    )]
+    pub expires_in: Option<Duration>,
+}
+
+pub(crate) const OVERRIDE_KEY: &str = "__override__";
+pub(crate) const OVERRIDE_INDEX_KEY: &str = "__overrides__";
+pub(crate) const LIMITS_KEY: &str = "__limits__";
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Atomically replaces the override hash with the given fields, expiring it after `ttl`.
+pub(crate) fn set_pipeline(
+    key: &str,
+    index_key: &str,
+    identifier: &str,
+    limit_override: &LimitOverride,
+    ttl: Option<Duration>,
+) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(key).ignore();
+    let fields = fields(limit_override);
+    if fields.is_empty() {
+        pipe.zrem(index_key, identifier).ignore();
+        return pipe;
+    }
+    pipe.hset_multiple(key, &fields).ignore();
+    match ttl {
+        Some(ttl) => {
+            let ttl_ms = ttl.as_millis().max(1) as u64;
+            pipe.cmd("PEXPIRE").arg(key).arg(ttl_ms).ignore();
+            pipe.zadd(index_key, identifier, now_ms() + ttl_ms).ignore();
+        }
+        None => {
+            pipe.zadd(index_key, identifier, "+inf").ignore();
+        }
+    }
+    pipe
+}
+
+/// Atomically replaces the limiter-wide limits hash; empty limits just delete it.
+pub(crate) fn limits_pipeline(key: &str, limits: &LimitOverride) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(key).ignore();
+    let fields = fields(limits);
+    if !fields.is_empty() {
+        pipe.hset_multiple(key, &fields).ignore();
+    }
+    pipe
+}
+
+fn fields(limit_override: &LimitOverride) -> Vec<(&'static str, u64)> {
+    let mut fields = Vec::new();
+    if let Some(limit) = limit_override.max_requests {
+        fields.push(("limit", limit));
+    }
+    if let Some(window) = limit_override.window {
+        fields.push(("window_ms", window.as_millis().max(1) as u64));
+    }
+    fields
+}
+
+pub(crate) fn remove_pipeline(key: &str, index_key: &str, identifier: &str) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .del(key)
+        .ignore()
+        .zrem(index_key, identifier)
+        .ignore();
+    pipe
+}
+
+/// Prunes expired entries from the index and returns the identifiers left in it.
+pub(crate) fn index_pipeline(index_key: &str) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("ZREMRANGEBYSCORE")
+        .arg(index_key)
+        .arg("-inf")
+        .arg(format!("({}", now_ms()))
+        .ignore()
+        .zrange(index_key, 0, -1);
+    pipe
+}
+
+/// Reads the fields and remaining TTL of each indexed override.
+pub(crate) fn details_pipeline(keys: &[String]) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.add_command(get_cmd(key)).cmd("PTTL").arg(key);
+    }
+    pipe
+}
+
+/// Pairs identifiers with the reply of [`details_pipeline`], skipping overrides that expired
+/// in the meantime.
+pub(crate) fn parse_details(
+    identifiers: Vec<String>,
+    reply: Vec<redis::Value>,
+) -> redis::RedisResult<Vec<ActiveOverride>> {
+    let mut active = Vec::with_capacity(identifiers.len());
+    for (identifier, pair) in identifiers.into_iter().zip(reply.chunks(2)) {
+        let (limit, window): (Option<u64>, Option<u64>) = redis::from_redis_value(&pair[0])?;
+        let pttl: i64 = redis::from_redis_value(&pair[1])?;
+        if pttl == -2 {
+            continue;
+        }
+        if let Some(limit_override) = LimitOverride::from_fields(limit, window) {
+            active.push(ActiveOverride {
+                identifier,
+                limit_override,
+                expires_in: u64::try_from(pttl).ok().map(Duration::from_millis),
+            });
+        }
+    }
+    Ok(active)
+}
+
+pub(crate) fn get_cmd(key: &str) -> redis::Cmd {
+    let mut cmd = redis::cmd("HMGET");
+    cmd.arg(key).arg("limit").arg("window_ms");
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_fields() {
+        assert_eq!(LimitOverride::from_fields(None, None), None);
+        assert_eq!(
+            LimitOverride::from_fields(Some(5), Some(60_000)),
+            Some(LimitOverride::max_requests(5).with_window(Duration::from_secs(60)))
+        );
+        assert_eq!(
+            LimitOverride::from_fields(None, Some(500)),
+            Some(LimitOverride::window(Duration::from_millis(500)))
+        );
+    }
+
+    #[test]
+    fn test_parse_details_skips_expired() {
+        use redis::Value;
+
+        let reply = vec![
+            Value::Bulk(vec![Value::Data(b"10".to_vec()), Value::Nil]),
+            Value::Int(5000),
+            Value::Bulk(vec![Value::Nil, Value::Nil]),
+            Value::Int(-2),
+            Value::Bulk(vec![Value::Nil, Value::Data(b"60000".to_vec())]),
+            Value::Int(-1),
+        ];
+        let identifiers = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+
+        let active = parse_details(identifiers, reply).unwrap();
+        assert_eq!(
+            active,
+            vec![
+                ActiveOverride {
+                    identifier: "a".into(),
+                    limit_override: LimitOverride::max_requests(10),
+                    expires_in: Some(Duration::from_secs(5)),
+                },
+                ActiveOverride {
+                    identifier: "c".into(),
+                    limit_override: LimitOverride::window(Duration::from_secs(60)),
+                    expires_in: None,
+                },
+            ]
+        );
+    }
+}
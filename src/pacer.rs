@@ -0,0 +1,67 @@
+//! Scheduling background jobs at a global rate.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::runtime;
+use crate::{AsyncRateLimiter, RateLimiterError};
+
+/// Hands out "run at" times to jobs sharing `identifier`, spaced at the limiter's rate across
+/// every instance using the same prefix, without any other coordination.
+///
+/// Built on [`AsyncRateLimiter::reserve_slot`]: every reservation takes the next free slot,
+/// so a burst of jobs is spread out rather than denied.
+#[derive(Clone)]
+pub struct Pacer {
+    limiter: Arc<AsyncRateLimiter>,
+    identifier: String,
+}
+
+impl Pacer {
+    pub fn new(limiter: Arc<AsyncRateLimiter>, identifier: &str) -> Self {
+        Pacer {
+            limiter,
+            identifier: identifier.to_string(),
+        }
+    }
+
+    /// Reserves the next slot and returns when the job should run.
+    pub async fn reserve(&self) -> Result<Instant, RateLimiterError> {
+        let wait = self.limiter.reserve_slot(&self.identifier).await?;
+        Ok(Instant::now() + wait)
+    }
+
+    /// Reserves the next slot and sleeps until it arrives.
+    pub async fn wait(&self) -> Result<(), RateLimiterError> {
+        let run_at = self.reserve().await?;
+        runtime::sleep_until(run_at).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_reservations_are_spaced_at_rate() -> Result<(), RateLimiterError> {
+        let limiter = Arc::new(AsyncRateLimiter::new(
+            "redis://127.0.0.1:6379",
+            "test_pacer",
+            10,
+            Duration::from_secs(1),
+        )?);
+        let pacer = Pacer::new(limiter, "nightly_report");
+
+        let first = pacer.reserve().await?;
+        let second = pacer.reserve().await?;
+        let third = pacer.reserve().await?;
+
+        // One slot every 100ms, give or take the round trips.
+        assert!(second - first >= Duration::from_millis(80));
+        assert!(third - first >= Duration::from_millis(180));
+
+        Ok(())
+    }
+}
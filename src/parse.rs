@@ -0,0 +1,173 @@
+//! Parsing of human-friendly durations and rates, for limits loaded from files or the
+//! environment.
+//!
+//! Durations are one or more `<number><unit>` components, e.g. `500ms`, `10s`, `1.5m` or
+//! `1h30m`. Rates are `<count>/<window>`, where the window is a duration or a bare unit:
+//! `100/min`, `5/10s`, `1000/1h`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::RateLimiterError;
+
+/// Parses a duration such as `500ms`, `10s`, `5m`, `1h` or `1h30m`.
+pub fn parse_duration(s: &str) -> Result<Duration, RateLimiterError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(invalid(s, "empty duration"));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let number_end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let (number, after) = rest.split_at(number_end);
+        let after = after.trim_start();
+        let unit_end = after
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_end);
+
+        let value: f64 = number
+            .parse()
+            .map_err(|_| invalid(s, "expected a number"))?;
+        let unit = unit_duration(unit)
+            .ok_or_else(|| invalid(s, "expected a unit (ms, s, m, h or d) after every number"))?;
+        total = Duration::try_from_secs_f64(unit.as_secs_f64() * value)
+            .ok()
+            .and_then(|component| total.checked_add(component))
+            .ok_or_else(|| invalid(s, "duration is too long"))?;
+        rest = after.trim_start();
+    }
+    Ok(total)
+}
+
+fn unit_duration(unit: &str) -> Option<Duration> {
+    let secs = match unit.to_ascii_lowercase().as_str() {
+        "ms" | "msec" | "msecs" | "millisecond" | "milliseconds" => {
+            return Some(Duration::from_millis(1))
+        }
+        "s" | "sec" | "secs" | "second" | "seconds" => 1,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60,
+        "h" | "hr" | "hrs" | "hour" | "hours" => 3600,
+        "d" | "day" | "days" => 86400,
+        _ => return None,
+    };
+    Some(Duration::from_secs(secs))
+}
+
+fn invalid(input: &str, reason: &str) -> RateLimiterError {
+    RateLimiterError::InvalidConfig(format!("`{}`: {}", input, reason))
+}
+
+/// A limit of `max_requests` per `window`, parsed from strings like `100/min` or `5/10s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub max_requests: u64,
+    pub window: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = RateLimiterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, window) = s
+            .split_once('/')
+            .ok_or_else(|| invalid(s, "expected `<count>/<window>`, e.g. `100/min`"))?;
+        let max_requests = count
+            .trim()
+            .parse()
+            .map_err(|_| invalid(s, "expected a whole number of requests"))?;
+        let window = window.trim();
+        // A bare unit means one of it: `100/min` is `100/1min`.
+        let window = match unit_duration(window) {
+            Some(unit) => unit,
+            None => parse_duration(window)?,
+        };
+        if window.is_zero() {
+            return Err(invalid(s, "window must be longer than zero"));
+        }
+        Ok(Rate {
+            max_requests,
+            window,
+        })
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}ms", self.max_requests, self.window.as_millis())
+    }
+}
+
+/// Deserializes a `Duration` from a string such as `"500ms"` or `"1h"`, for use with
+/// `#[serde(deserialize_with = "redis_rate_limiter::deserialize_duration")]`.
+#[cfg(feature = "serde")]
+pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+    parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Rate {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(
+            parse_duration(" 1 hour ").unwrap(),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+
+        for bad in [
+            "",
+            "10",
+            "s",
+            "10 parsecs",
+            "1..5s",
+            "99999999999999999999999d",
+        ] {
+            assert!(
+                matches!(parse_duration(bad), Err(RateLimiterError::InvalidConfig(_))),
+                "{:?} should not parse",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        let rate: Rate = "100/min".parse().unwrap();
+        assert_eq!(rate.max_requests, 100);
+        assert_eq!(rate.window, Duration::from_secs(60));
+
+        let rate: Rate = "5 / 10s".parse().unwrap();
+        assert_eq!(
+            (rate.max_requests, rate.window),
+            (5, Duration::from_secs(10))
+        );
+
+        assert!("100".parse::<Rate>().is_err());
+        assert!("-1/min".parse::<Rate>().is_err());
+        assert!("100/0s".parse::<Rate>().is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! Checks without Lua scripting, for managed Redis offerings and proxies that block `EVAL`.
+//!
+//! A [`PlainCheck`] runs the fixed window counter as a pipeline of plain commands: `SET NX PX`
+//! starts the window, `INCRBY` counts the request and `PTTL` reads the reset time. The
+//! pipeline isn't a transaction (proxies that block scripts usually block `MULTI` too), so
+//! the guarantees are weaker than the script's: a window expiring between `SET` and `INCRBY`
+//! leaves a counter without expiry until the follow-up `PEXPIRE` that repairs it, and a
+//! denied weighted request is only taken back by a second round trip, during which it
+//! counts.
+
+use crate::core::{DecisionReply, ALLOWED, DENIED};
+
+/// A fixed window check as plain commands; see the module docs.
+#[derive(Debug)]
+pub(crate) struct PlainCheck {
+    pub(crate) key: String,
+    pub(crate) cost: u64,
+    /// Take the cost back if denied, as the script does for weighted requests.
+    pub(crate) weighted: bool,
+    pub(crate) limit: u64,
+    pub(crate) expiry_ms: u64,
+    /// 0: fixed window, 1: allowed requests restart it, 2: every request restarts it.
+    pub(crate) extend: u8,
+}
+
+impl PlainCheck {
+    fn pipeline(&self) -> redis::Pipeline {
+        let mut pipe = redis::pipe();
+        pipe.cmd("SET")
+            .arg(&self.key)
+            .arg(0)
+            .arg("PX")
+            .arg(self.expiry_ms)
+            .arg("NX")
+            .ignore()
+            .cmd("INCRBY")
+            .arg(&self.key)
+            .arg(self.cost)
+            .cmd("PTTL")
+            .arg(&self.key);
+        pipe
+    }
+
+    /// The decision for the counter and TTL the pipeline read, and the commands that still
+    /// have to run for it, if any.
+    fn decide(&self, (count, pttl): (u64, i64)) -> (DecisionReply, Option<redis::Pipeline>) {
+        let allowed = count <= self.limit;
+        let mut follow_up = redis::pipe();
+        let mut pending = false;
+        let restart = self.extend == 2 || (self.extend == 1 && allowed);
+        // Without a TTL the window expired between `SET` and `INCRBY`.
+        let reset_after = if restart || pttl < 0 {
+            follow_up
+                .cmd("PEXPIRE")
+                .arg(&self.key)
+                .arg(self.expiry_ms)
+                .ignore();
+            pending = true;
+            self.expiry_ms
+        } else {
+            pttl as u64
+        };
+        if !allowed && self.weighted {
+            follow_up
+                .cmd("DECRBY")
+                .arg(&self.key)
+                .arg(self.cost)
+                .ignore();
+            pending = true;
+        }
+        let reply = if allowed {
+            (ALLOWED, self.limit, self.limit - count, reset_after, 0, 0)
+        } else {
+            (DENIED, self.limit, 0, reset_after, reset_after, 0)
+        };
+        (reply, pending.then_some(follow_up))
+    }
+
+    #[cfg(feature = "blocking")]
+    pub(crate) fn invoke(
+        &self,
+        conn: &mut dyn redis::ConnectionLike,
+    ) -> redis::RedisResult<DecisionReply> {
+        let (reply, follow_up) = self.decide(self.pipeline().query(conn)?);
+        if let Some(follow_up) = follow_up {
+            follow_up.query::<()>(conn)?;
+        }
+        Ok(reply)
+    }
+
+    pub(crate) async fn invoke_async<C>(&self, conn: &mut C) -> redis::RedisResult<DecisionReply>
+    where
+        C: redis::aio::ConnectionLike,
+    {
+        let (reply, follow_up) = self.decide(self.pipeline().query_async(conn).await?);
+        if let Some(follow_up) = follow_up {
+            follow_up.query_async::<_, ()>(conn).await?;
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(cost: u64, weighted: bool, extend: u8) -> PlainCheck {
+        PlainCheck {
+            key: "app:user_1".to_string(),
+            cost,
+            weighted,
+            limit: 10,
+            expiry_ms: 60_000,
+            extend,
+        }
+    }
+
+    #[test]
+    fn test_plain_check_decides_and_follows_up() {
+        let (reply, follow_up) = check(1, false, 0).decide((4, 30_000));
+        assert_eq!(reply, (ALLOWED, 10, 6, 30_000, 0, 0));
+        assert!(follow_up.is_none());
+
+        // Denied plain requests still count, as in the script.
+        let (reply, follow_up) = check(1, false, 0).decide((11, 30_000));
+        assert_eq!(reply, (DENIED, 10, 0, 30_000, 30_000, 0));
+        assert!(follow_up.is_none());
+
+        let (_, follow_up) = check(5, true, 0).decide((14, 30_000));
+        let cmds: Vec<_> = follow_up
+            .unwrap()
+            .cmd_iter()
+            .map(|c| c.get_packed_command())
+            .collect();
+        assert_eq!(
+            cmds,
+            [redis::cmd("DECRBY")
+                .arg("app:user_1")
+                .arg(5)
+                .get_packed_command()]
+        );
+
+        // A lost TTL is repaired, and sliding windows restart on allowed requests.
+        let (reply, follow_up) = check(1, false, 0).decide((1, -1));
+        assert_eq!(reply.3, 60_000);
+        assert!(follow_up.is_some());
+        assert!(check(1, false, 1).decide((2, 30_000)).1.is_some());
+        assert!(check(1, false, 1).decide((11, 30_000)).1.is_none());
+    }
+}
@@ -0,0 +1,406 @@
+//! Connection pooling for the blocking limiter.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use redis::{ConnectionLike, RedisResult, Value};
+
+use crate::RateLimiterError;
+
+/// Tuning for the blocking limiter's connection pool (see `RateLimiter::with_pool`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    pub(crate) min_connections: usize,
+    pub(crate) max_connections: usize,
+    pub(crate) acquire_timeout: Duration,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_lifetime: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            min_connections: 0,
+            max_connections: 10,
+            acquire_timeout: Duration::from_secs(5),
+            idle_timeout: Some(Duration::from_secs(600)),
+            max_lifetime: Some(Duration::from_secs(1800)),
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn new() -> Self {
+        PoolConfig::default()
+    }
+
+    /// Idle connections kept open even past the idle timeout. Defaults to 0.
+    pub fn with_min_connections(mut self, min: usize) -> Self {
+        self.min_connections = min;
+        self
+    }
+
+    /// Upper bound on open connections. Defaults to 10.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// How long a caller waits for a connection when all of them are in use before failing
+    /// with `RateLimiterError::PoolExhausted`. Defaults to 5 seconds.
+    pub fn with_acquire_timeout(mut self, timeout: Duration) -> Self {
+        self.acquire_timeout = timeout;
+        self
+    }
+
+    /// Closes connections unused for longer than `timeout`. Defaults to 10 minutes.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Closes connections once they have been open for `lifetime`, e.g. to pick up DNS
+    /// changes. Defaults to 30 minutes.
+    pub fn with_max_lifetime(mut self, lifetime: Option<Duration>) -> Self {
+        self.max_lifetime = lifetime;
+        self
+    }
+}
+
+struct Idle {
+    conn: redis::Connection,
+    created: Instant,
+    returned: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    idle: Vec<Idle>,
+    open: usize,
+    /// Bumped by `clear`; connections opened under an older generation are closed when
+    /// returned.
+    generation: u64,
+}
+
+pub(crate) struct Pool {
+    config: PoolConfig,
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+impl Pool {
+    pub(crate) fn new(config: PoolConfig) -> Self {
+        Pool {
+            config,
+            state: Mutex::new(State::default()),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Hands out an idle connection, opens one with `connect` if below the maximum, or waits
+    /// up to the acquire timeout for one to be returned. Idle connections older than `max_age`
+    /// are closed rather than handed out.
+    pub(crate) fn get<F>(
+        &self,
+        max_age: Option<Duration>,
+        connect: F,
+    ) -> Result<PooledConnection<'_>, RateLimiterError>
+    where
+        F: FnOnce() -> Result<redis::Connection, RateLimiterError>,
+    {
+        self.get_within(self.config.acquire_timeout, max_age, connect)
+    }
+
+    /// Like `get`, waiting at most `acquire_timeout` (capped by the configured one).
+    pub(crate) fn get_within<F>(
+        &self,
+        acquire_timeout: Duration,
+        max_age: Option<Duration>,
+        connect: F,
+    ) -> Result<PooledConnection<'_>, RateLimiterError>
+    where
+        F: FnOnce() -> Result<redis::Connection, RateLimiterError>,
+    {
+        let deadline = Instant::now() + acquire_timeout.min(self.config.acquire_timeout);
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        loop {
+            self.prune(&mut state, max_age);
+            if let Some(idle) = state.idle.pop() {
+                return Ok(PooledConnection {
+                    pool: Some(self),
+                    conn: Some(idle.conn),
+                    created: idle.created,
+                    generation: state.generation,
+                    failed: false,
+                });
+            }
+
+            if state.open < self.config.max_connections {
+                state.open += 1;
+                let generation = state.generation;
+                drop(state);
+                return match connect() {
+                    Ok(conn) => Ok(PooledConnection {
+                        pool: Some(self),
+                        conn: Some(conn),
+                        created: Instant::now(),
+                        generation,
+                        failed: false,
+                    }),
+                    Err(e) => {
+                        self.release_slot();
+                        Err(e)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RateLimiterError::PoolExhausted);
+            }
+            state = self
+                .available
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+
+    pub(crate) fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    /// Number of connections `warm_up` opens: the configured minimum, but at least one.
+    pub(crate) fn warm_size(&self) -> usize {
+        self.config
+            .min_connections
+            .clamp(1, self.config.max_connections)
+    }
+
+    /// Closes every idle connection, e.g. after switching Redis endpoints, and the ones in use
+    /// once they are returned.
+    pub(crate) fn clear(&self) {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let closed = state.idle.len();
+        state.idle.clear();
+        state.open -= closed;
+        state.generation += 1;
+        self.available.notify_all();
+    }
+
+    /// Drops idle connections past their idle timeout or lifetime, keeping the minimum open.
+    fn prune(&self, state: &mut State, max_age: Option<Duration>) {
+        let now = Instant::now();
+        let config = &self.config;
+        let mut open = state.open;
+        state.idle.retain(|idle| {
+            let stale = config
+                .idle_timeout
+                .is_some_and(|t| now - idle.returned >= t && open > config.min_connections)
+                || config.max_lifetime.is_some_and(|t| now - idle.created >= t)
+                || max_age.is_some_and(|t| now - idle.created >= t);
+            if stale {
+                open -= 1;
+            }
+            !stale
+        });
+        state.open = open;
+    }
+
+    fn release_slot(&self) {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .open -= 1;
+        self.available.notify_one();
+    }
+
+    /// Returns a connection to the idle list, or closes it if it has expired, `failed` or
+    /// was opened before the pool was last cleared.
+    fn put_back(&self, conn: redis::Connection, created: Instant, generation: u64, failed: bool) {
+        let expired = self
+            .config
+            .max_lifetime
+            .is_some_and(|t| created.elapsed() >= t);
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if expired || failed || generation != state.generation || !conn.is_open() {
+            state.open -= 1;
+        } else {
+            state.idle.push(Idle {
+                conn,
+                created,
+                returned: Instant::now(),
+            });
+        }
+        self.available.notify_one();
+    }
+}
+
+/// A connection that goes back to its pool when dropped, or is simply closed when the
+/// limiter isn't pooled.
+pub(crate) struct PooledConnection<'a> {
+    pool: Option<&'a Pool>,
+    conn: Option<redis::Connection>,
+    created: Instant,
+    /// The pool's generation when the connection was handed out.
+    generation: u64,
+    /// Set once a command fails. A command that timed out may still get its reply later,
+    /// which the next caller would read as its own, so such a connection is closed rather
+    /// than returned to the pool.
+    failed: bool,
+}
+
+impl PooledConnection<'_> {
+    pub(crate) fn unpooled(conn: redis::Connection) -> Self {
+        PooledConnection {
+            pool: None,
+            conn: Some(conn),
+            created: Instant::now(),
+            generation: 0,
+            failed: false,
+        }
+    }
+
+    /// Bounds the connection's reads and writes by `timeout`. A pooled connection keeps it
+    /// for later callers, so set it back once done; if that fails, the connection is closed.
+    pub(crate) fn set_timeouts(&mut self, timeout: Option<Duration>) -> RedisResult<()> {
+        let result = self
+            .conn()
+            .set_read_timeout(timeout)
+            .and_then(|()| self.conn().set_write_timeout(timeout));
+        self.failed |= result.is_err();
+        result
+    }
+
+    fn conn(&self) -> &redis::Connection {
+        self.conn.as_ref().expect("connection taken")
+    }
+
+    fn conn_mut(&mut self) -> &mut redis::Connection {
+        self.conn.as_mut().expect("connection taken")
+    }
+}
+
+impl ConnectionLike for PooledConnection<'_> {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        let result = self.conn_mut().req_packed_command(cmd);
+        self.failed |= result.is_err();
+        result
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        let result = self.conn_mut().req_packed_commands(cmd, offset, count);
+        self.failed |= result.is_err();
+        result
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn().get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.conn_mut().check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.conn().is_open()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let (Some(pool), Some(conn)) = (self.pool, self.conn.take()) {
+            pool.put_back(conn, self.created, self.generation, self.failed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exhausted_pool_fails_after_acquire_timeout() {
+        let pool = Pool::new(
+            PoolConfig::new()
+                .with_max_connections(1)
+                .with_acquire_timeout(Duration::from_millis(20)),
+        );
+        // Occupy the only slot without needing a server.
+        pool.state.lock().unwrap().open = 1;
+
+        let start = Instant::now();
+        let result = pool.get(None, || unreachable!("no slot is free"));
+        assert!(matches!(result, Err(RateLimiterError::PoolExhausted)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_failed_connect_frees_its_slot() {
+        let pool = Pool::new(PoolConfig::new().with_max_connections(1));
+        let result = pool.get(None, || Err(RateLimiterError::DeadlineExceeded));
+        assert!(matches!(result, Err(RateLimiterError::DeadlineExceeded)));
+        assert_eq!(pool.state.lock().unwrap().open, 0);
+    }
+
+    #[test]
+    fn test_timed_out_connection_is_not_reused() -> Result<(), RateLimiterError> {
+        let client = redis::Client::open("redis://127.0.0.1:6379")?;
+        let pool = Pool::new(PoolConfig::new().with_max_connections(1));
+        let mut connects = 0;
+        let mut connect = || {
+            connects += 1;
+            Ok(client.get_connection()?)
+        };
+
+        {
+            let mut conn = pool.get(None, &mut connect)?;
+            conn.conn()
+                .set_read_timeout(Some(Duration::from_millis(10)))?;
+            // Blocks for a second, so the reply arrives long after the read timed out.
+            let result: RedisResult<Value> = redis::cmd("BLPOP")
+                .arg("test_pool_timed_out")
+                .arg(1)
+                .query(&mut conn);
+            assert!(result.is_err_and(|e| e.is_timeout()));
+        }
+
+        let mut conn = pool.get(None, &mut connect)?;
+        assert_eq!(connects, 2);
+        let pong: String = redis::cmd("PING").query(&mut conn)?;
+        assert_eq!(pong, "PONG");
+        Ok(())
+    }
+
+    #[test]
+    fn test_connections_in_use_when_cleared_are_closed() -> Result<(), RateLimiterError> {
+        let client = redis::Client::open("redis://127.0.0.1:6379")?;
+        let pool = Pool::new(PoolConfig::new().with_max_connections(2));
+        let connect = || Ok(client.get_connection()?);
+
+        let in_use = pool.get(None, connect)?;
+        drop(pool.get(None, connect)?);
+        // E.g. a fail-back: the idle connection and the one in use point at the old endpoint.
+        pool.clear();
+        drop(in_use);
+
+        let state = pool.state.lock().unwrap();
+        assert!(state.idle.is_empty());
+        assert_eq!(state.open, 0);
+        Ok(())
+    }
+}
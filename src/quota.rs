@@ -0,0 +1,110 @@
+//! A rate definition shaped like `governor::Quota`, for services that limit in process with
+//! governor and across instances with this crate.
+//!
+//! [`Quota`] has governor's constructors and accessors, so one definition can configure both:
+//! build this crate's limiter from it with `RateLimiterBuilder::quota`, and governor's from
+//! its `replenish_interval` and `burst_size`. Both run GCRA, so the two enforce the same
+//! sustained rate and burst.
+
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use crate::Rate;
+
+/// Cells replenished one every `replenish_interval`, with bursts of up to `burst_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    replenish_interval: Duration,
+    max_burst: NonZeroU32,
+}
+
+impl Quota {
+    /// `max_burst` cells per second, all of which can be used at once.
+    pub fn per_second(max_burst: NonZeroU32) -> Self {
+        Quota::per(Duration::from_secs(1), max_burst)
+    }
+
+    /// `max_burst` cells per minute, all of which can be used at once.
+    pub fn per_minute(max_burst: NonZeroU32) -> Self {
+        Quota::per(Duration::from_secs(60), max_burst)
+    }
+
+    /// `max_burst` cells per hour, all of which can be used at once.
+    pub fn per_hour(max_burst: NonZeroU32) -> Self {
+        Quota::per(Duration::from_secs(3600), max_burst)
+    }
+
+    /// One cell every `replenish_interval`, with no bursts; `None` for a zero interval.
+    pub fn with_period(replenish_interval: Duration) -> Option<Self> {
+        if replenish_interval.is_zero() {
+            return None;
+        }
+        Some(Quota {
+            replenish_interval,
+            max_burst: NonZeroU32::MIN,
+        })
+    }
+
+    /// Allows bursts of up to `max_burst` cells, keeping the replenish interval.
+    pub fn allow_burst(self, max_burst: NonZeroU32) -> Self {
+        Quota { max_burst, ..self }
+    }
+
+    pub fn burst_size(&self) -> NonZeroU32 {
+        self.max_burst
+    }
+
+    pub fn replenish_interval(&self) -> Duration {
+        self.replenish_interval
+    }
+
+    fn per(period: Duration, max_burst: NonZeroU32) -> Self {
+        Quota {
+            replenish_interval: period / max_burst.get(),
+            max_burst,
+        }
+    }
+}
+
+/// The limit and window an `Algorithm::Gcra` limiter needs to enforce the quota: the burst
+/// size, replenished over a burst's worth of intervals.
+impl From<Quota> for Rate {
+    fn from(quota: Quota) -> Self {
+        Rate {
+            max_requests: u64::from(quota.max_burst.get()),
+            window: quota.replenish_interval * quota.max_burst.get(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn n(value: u32) -> NonZeroU32 {
+        NonZeroU32::new(value).unwrap()
+    }
+
+    #[test]
+    fn test_quota_matches_governor() {
+        let quota = Quota::per_second(n(10)).allow_burst(n(20));
+        assert_eq!(quota.replenish_interval(), Duration::from_millis(100));
+        assert_eq!(quota.burst_size(), n(20));
+        assert_eq!(
+            Rate::from(quota),
+            Rate {
+                max_requests: 20,
+                window: Duration::from_secs(2),
+            }
+        );
+
+        let quota = Quota::per_minute(n(30));
+        assert_eq!(quota.replenish_interval(), Duration::from_secs(2));
+        assert_eq!(Rate::from(quota), "30/min".parse().unwrap());
+
+        assert_eq!(Quota::with_period(Duration::ZERO), None);
+        let quota = Quota::with_period(Duration::from_millis(250)).unwrap();
+        assert_eq!(quota.burst_size(), n(1));
+        assert_eq!(Rate::from(quota).window, Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,210 @@
+//! Named limiters sharing one connection pool.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::pool::{Pool, PoolConfig};
+use crate::{Algorithm, RateLimiter, RateLimiterError, Template, ToIdentifier, WindowMode};
+
+/// Definitions of a registry's limiters, e.g. deserialized with the `serde` feature from a
+/// deployment config file (with `toml`, `serde_yaml` or any other format crate), so limits
+/// change without a code change. See `RateLimiterRegistry::from_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct RegistryConfig {
+    pub redis_url: String,
+    pub limiters: Vec<LimiterConfig>,
+}
+
+/// One named limiter of a `RegistryConfig`. Windows deserialize from strings such as
+/// `"500ms"` or `"1h"`, as `parse_duration` reads them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct LimiterConfig {
+    pub name: String,
+    pub max_requests: u64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "crate::parse::deserialize_duration")
+    )]
+    pub window: Duration,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub algorithm: Algorithm,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub window_mode: WindowMode,
+    /// Key prefix; the name if unset.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub prefix: Option<String>,
+}
+
+/// The limits of a service, e.g. `login`, `password_reset` and `search`, looked up by name and
+/// drawing connections from one pool instead of one per limiter.
+pub struct RateLimiterRegistry {
+    redis_url: String,
+    pool: Arc<Pool>,
+    limiters: HashMap<String, RateLimiter>,
+}
+
+impl RateLimiterRegistry {
+    /// Creates an empty registry whose templated limiters connect to `redis_url`, with a
+    /// pool of `PoolConfig::default()`.
+    pub fn new(redis_url: &str) -> Self {
+        RateLimiterRegistry {
+            redis_url: redis_url.to_string(),
+            pool: Arc::new(Pool::new(PoolConfig::default())),
+            limiters: HashMap::new(),
+        }
+    }
+
+    /// Creates a registry with a limiter for each of `config`'s definitions. Fails with
+    /// `RateLimiterError::InvalidConfig` if two share a name.
+    pub fn from_config(config: &RegistryConfig) -> Result<Self, RateLimiterError> {
+        let mut registry = RateLimiterRegistry::new(&config.redis_url);
+        for limiter in &config.limiters {
+            if registry.limiters.contains_key(&limiter.name) {
+                return Err(RateLimiterError::InvalidConfig(format!(
+                    "limiter `{}` is defined twice",
+                    limiter.name
+                )));
+            }
+            let template = Template::new(&limiter.name, limiter.max_requests, limiter.window)
+                .with_algorithm(limiter.algorithm)
+                .with_window_mode(limiter.window_mode);
+            let prefix = limiter.prefix.as_deref().unwrap_or(&limiter.name);
+            let built = template.limiter(&config.redis_url, prefix)?;
+            registry.insert(&limiter.name, built);
+        }
+        Ok(registry)
+    }
+
+    /// Replaces the shared pool, for the limiters registered so far as well as later ones.
+    pub fn with_pool(mut self, config: PoolConfig) -> Self {
+        self.pool = Arc::new(Pool::new(config));
+        let pool = &self.pool;
+        self.limiters = self
+            .limiters
+            .drain()
+            .map(|(name, limiter)| (name, limiter.with_shared_pool(Arc::clone(pool))))
+            .collect();
+        self
+    }
+
+    /// Adds `limiter` as `name`, replacing any limiter of that name. Its own pool is replaced
+    /// by the shared one, so it must connect to the same Redis as the other limiters.
+    pub fn insert(&mut self, name: &str, limiter: RateLimiter) -> &mut Self {
+        let limiter = limiter.with_shared_pool(Arc::clone(&self.pool));
+        self.limiters.insert(name.to_string(), limiter);
+        self
+    }
+
+    /// Adds a limiter enforcing `template`, named and keyed under the template's name.
+    pub fn register(&mut self, template: &Template) -> Result<&mut Self, RateLimiterError> {
+        let limiter = template.limiter(&self.redis_url, template.name())?;
+        Ok(self.insert(template.name(), limiter))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RateLimiter> {
+        self.limiters.get(name)
+    }
+
+    /// The names of the registered limiters, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.limiters.keys().map(String::as_str)
+    }
+
+    /// Checks `identifier` against the limiter named `name`, failing with
+    /// `RateLimiterError::UnknownLimiter` if there is none.
+    pub fn check(&self, name: &str, identifier: impl ToIdentifier) -> Result<(), RateLimiterError> {
+        self.require(name)?.check(identifier)
+    }
+
+    fn require(&self, name: &str) -> Result<&RateLimiter, RateLimiterError> {
+        self.get(name)
+            .ok_or_else(|| RateLimiterError::UnknownLimiter(name.to_string()))
+    }
+}
+
+impl fmt::Debug for RateLimiterRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiterRegistry")
+            .field("pool", self.pool.config())
+            .field("limiters", &self.limiters)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_registry_shares_its_pool() -> Result<(), RateLimiterError> {
+        let mut registry = RateLimiterRegistry::new("redis://127.0.0.1:6379");
+        registry.register(&Template::new("login", 5, Duration::from_secs(60)))?;
+        registry.insert(
+            "search",
+            RateLimiter::new(
+                "redis://127.0.0.1:6379",
+                "search",
+                100,
+                Duration::from_secs(1),
+            )?,
+        );
+        let registry = registry.with_pool(PoolConfig::new().with_max_connections(2));
+
+        let mut names: Vec<_> = registry.names().collect();
+        names.sort_unstable();
+        assert_eq!(names, ["login", "search"]);
+        for name in names {
+            let limiter = format!("{:?}", registry.get(name).unwrap());
+            assert!(limiter.contains("max_connections: 2"), "{}", limiter);
+        }
+        assert!(matches!(
+            registry.check("export", "user_1"),
+            Err(RateLimiterError::UnknownLimiter(name)) if name == "export"
+        ));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_registry_from_config() -> Result<(), RateLimiterError> {
+        let config: RegistryConfig = serde_json::from_str(
+            r#"{
+                "redis_url": "redis://127.0.0.1:6379",
+                "limiters": [
+                    {"name": "login", "max_requests": 5, "window": "1m"},
+                    {
+                        "name": "search",
+                        "max_requests": 100,
+                        "window": "10s",
+                        "algorithm": "sliding_window_log",
+                        "prefix": "api:search"
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.limiters[0].window, Duration::from_secs(60));
+        assert_eq!(config.limiters[0].algorithm, Algorithm::FixedWindow);
+        assert_eq!(config.limiters[1].algorithm, Algorithm::SlidingWindowLog);
+
+        let registry = RateLimiterRegistry::from_config(&config)?;
+        let search = format!("{:?}", registry.get("search").unwrap());
+        assert!(search.contains("api:search"), "{}", search);
+
+        let mut twice = config.clone();
+        twice.limiters.push(config.limiters[0].clone());
+        assert!(matches!(
+            RateLimiterRegistry::from_config(&twice),
+            Err(RateLimiterError::InvalidConfig(_))
+        ));
+
+        Ok(())
+    }
+}
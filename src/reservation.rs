@@ -0,0 +1,36 @@
+//! Capacity reserved ahead in a fixed window, for planned work that mustn't collide with
+//! interactive traffic.
+
+use std::time::SystemTime;
+
+/// Units of an identifier's fixed window earmarked by `reserve` from `not_before`, to be
+/// consumed with `confirm_reservation` or given back with `cancel_reservation`.
+///
+/// Until then, checks within a window of `not_before` hold the units back from other
+/// requests. A reservation not confirmed within a window past `not_before` lapses and its
+/// units are free again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[must_use = "a reservation holds capacity until it's confirmed, cancelled or lapses"]
+pub struct Reservation {
+    pub(crate) identifier: String,
+    pub(crate) token: String,
+    pub(crate) units: u64,
+    pub(crate) not_before: SystemTime,
+}
+
+impl Reservation {
+    /// The normalized identifier the units were reserved for.
+    pub fn identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    pub fn units(&self) -> u64 {
+        self.units
+    }
+
+    /// When the units are for: the requested time, or the time of the reservation if that
+    /// was earlier.
+    pub fn not_before(&self) -> SystemTime {
+        self.not_before
+    }
+}
@@ -0,0 +1,271 @@
+//! Delays for the `retrying` helpers, and the `RetryPolicy` for transient Redis errors.
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::core::MIN_WAIT;
+use crate::runtime;
+use crate::RateLimiterError;
+
+/// Largest jitter added to a retry delay, as a fraction of the delay.
+const JITTER: f64 = 0.1;
+
+/// How long to sleep before retrying after a denial: until the window resets, plus up to 10%
+/// jitter so callers denied together don't all retry in the same instant.
+pub(crate) fn retry_delay(resets_in: Option<Duration>) -> Duration {
+    let delay = resets_in.unwrap_or_default().max(MIN_WAIT);
+    delay.mul_f64(1.0 + JITTER * random_fraction())
+}
+
+/// A fraction in `[0, 1)`, random enough for jitter without pulling in a RNG crate.
+pub(crate) fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A kind of transient failure that a `RetryPolicy` can retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransientError {
+    /// Redis refused the connection, so nothing was sent.
+    ConnectionRefused,
+    /// The connection dropped. The command may already have run, so a retried check can be
+    /// counted twice.
+    ConnectionDropped,
+    /// Any other I/O error, e.g. a broken pipe on a stale connection. As with dropped
+    /// connections, the command may already have run.
+    Io,
+    /// Connecting or the command timed out. The command may still run.
+    Timeout,
+    /// No pooled connection became free within the pool's acquire timeout.
+    PoolExhausted,
+}
+
+impl TransientError {
+    fn of(error: &RateLimiterError) -> Option<Self> {
+        match error {
+            RateLimiterError::PoolExhausted => Some(TransientError::PoolExhausted),
+            RateLimiterError::Redis(e) if e.is_connection_refusal() => {
+                Some(TransientError::ConnectionRefused)
+            }
+            RateLimiterError::Redis(e) if e.is_timeout() => Some(TransientError::Timeout),
+            RateLimiterError::Redis(e) if e.is_connection_dropped() => {
+                Some(TransientError::ConnectionDropped)
+            }
+            RateLimiterError::Redis(e) if e.kind() == redis::ErrorKind::IoError => {
+                Some(TransientError::Io)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How checks retry transient Redis errors before the failure policy or circuit breaker sees
+/// them, so a brief network blip doesn't turn into an error or a fail-open decision. The
+/// default makes a single attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_on: Vec<TransientError>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(1)
+    }
+}
+
+impl RetryPolicy {
+    /// Makes up to `max_attempts` attempts (at least one), backing off 10ms before the first
+    /// retry and doubling up to 200ms, and retries refused and dropped connections and other
+    /// I/O errors.
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(200),
+            retry_on: vec![
+                TransientError::ConnectionRefused,
+                TransientError::ConnectionDropped,
+                TransientError::Io,
+            ],
+        }
+    }
+
+    /// Backs off `initial` before the first retry, doubling for every further one up to
+    /// `max`, plus up to 10% jitter.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Retries only these kinds of errors. Leave out the kinds after which a check may
+    /// already have been counted to never count one twice.
+    pub fn with_retry_on(mut self, kinds: &[TransientError]) -> Self {
+        self.retry_on = kinds.to_vec();
+        self
+    }
+
+    fn retries(&self, attempt: u32, error: &RateLimiterError) -> bool {
+        attempt < self.max_attempts
+            && TransientError::of(error).is_some_and(|kind| self.retry_on.contains(&kind))
+    }
+
+    /// The backoff before retry number `retry`, counting from 1.
+    fn backoff(&self, retry: u32) -> Duration {
+        let doublings = (retry - 1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff);
+        backoff.mul_f64(1.0 + JITTER * random_fraction())
+    }
+
+    #[cfg(feature = "blocking")]
+    pub(crate) fn run<T>(
+        &self,
+        attempt: impl FnMut() -> Result<T, RateLimiterError>,
+    ) -> Result<T, RateLimiterError> {
+        self.run_until(None, attempt)
+    }
+
+    /// Like `run`, but fails with `RateLimiterError::DeadlineExceeded` instead of backing off
+    /// past `deadline`.
+    #[cfg(feature = "blocking")]
+    pub(crate) fn run_until<T>(
+        &self,
+        deadline: Option<Instant>,
+        mut attempt: impl FnMut() -> Result<T, RateLimiterError>,
+    ) -> Result<T, RateLimiterError> {
+        let mut attempts = 1;
+        loop {
+            match attempt() {
+                Err(e) if self.retries(attempts, &e) => {
+                    let backoff = self.backoff(attempts);
+                    if deadline.is_some_and(|deadline| Instant::now() + backoff >= deadline) {
+                        return Err(RateLimiterError::DeadlineExceeded);
+                    }
+                    std::thread::sleep(backoff);
+                    attempts += 1;
+                }
+                other => return other,
+            }
+        }
+    }
+
+    pub(crate) async fn run_async<T, F, Fut>(&self, mut attempt: F) -> Result<T, RateLimiterError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RateLimiterError>>,
+    {
+        let mut attempts = 1;
+        loop {
+            match attempt().await {
+                Err(e) if self.retries(attempts, &e) => {
+                    runtime::sleep(self.backoff(attempts)).await;
+                    attempts += 1;
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay_adds_bounded_jitter() {
+        for _ in 0..100 {
+            let delay = retry_delay(Some(Duration::from_secs(1)));
+            assert!(delay >= Duration::from_secs(1));
+            assert!(delay <= Duration::from_millis(1100));
+        }
+        assert!(retry_delay(None) >= MIN_WAIT);
+    }
+
+    fn refused() -> RateLimiterError {
+        RateLimiterError::Redis(redis::RedisError::from(std::io::Error::from(
+            std::io::ErrorKind::ConnectionRefused,
+        )))
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy_retries_transient_errors() {
+        let policy = RetryPolicy::new(3).with_backoff(Duration::ZERO, Duration::ZERO);
+        let mut attempts = 0;
+        let result = policy
+            .run_async(|| {
+                attempts += 1;
+                let result = if attempts < 3 {
+                    Err(refused())
+                } else {
+                    Ok(attempts)
+                };
+                async move { result }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        attempts = 0;
+        let result: Result<(), _> = policy
+            .run_async(|| {
+                attempts += 1;
+                async { Err(refused()) }
+            })
+            .await;
+        assert!(matches!(result, Err(RateLimiterError::Redis(_))));
+        assert_eq!(attempts, 3);
+
+        // Errors of other kinds are returned at once.
+        attempts = 0;
+        let result: Result<(), _> = policy
+            .with_retry_on(&[TransientError::Timeout])
+            .run_async(|| {
+                attempts += 1;
+                async { Err(refused()) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+        assert_eq!(RetryPolicy::default().max_attempts, 1);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_retries_stop_at_the_deadline() {
+        let policy = RetryPolicy::new(100)
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(10));
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result: Result<(), _> =
+            policy.run_until(Some(start + Duration::from_millis(50)), || {
+                attempts += 1;
+                Err(refused())
+            });
+        assert!(matches!(result, Err(RateLimiterError::DeadlineExceeded)));
+        assert!(attempts < 10);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_retry_backoff_doubles_up_to_max() {
+        let policy =
+            RetryPolicy::new(10).with_backoff(Duration::from_millis(10), Duration::from_millis(50));
+        let within = |retry, millis: u64| {
+            let backoff = policy.backoff(retry);
+            backoff >= Duration::from_millis(millis)
+                && backoff <= Duration::from_millis(millis).mul_f64(1.0 + JITTER)
+        };
+        assert!(within(1, 10));
+        assert!(within(2, 20));
+        assert!(within(3, 40));
+        assert!(within(4, 50));
+        assert!(within(40, 50));
+    }
+}
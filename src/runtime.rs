@@ -0,0 +1,107 @@
+//! Timers and background tasks for the async API, on whichever runtime it is polled from.
+//!
+//! Inside a tokio runtime these use tokio. With the `async-std` feature, they use async-std
+//! everywhere else, whose timers and executor also serve smol applications; redis picks the
+//! runtime for its connections the same way.
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// A `timeout` or `timeout_at` that ran out before its future completed.
+#[derive(Debug)]
+pub(crate) struct Elapsed;
+
+#[cfg(feature = "async-std")]
+fn in_tokio() -> bool {
+    tokio::runtime::Handle::try_current().is_ok()
+}
+
+/// Whether `spawn` has a runtime to run on.
+pub(crate) fn can_spawn() -> bool {
+    cfg!(feature = "async-std") || tokio::runtime::Handle::try_current().is_ok()
+}
+
+/// Runs `future` in the background.
+///
+/// # Panics
+///
+/// Panics outside a tokio runtime unless the `async-std` feature is enabled.
+pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    #[cfg(feature = "async-std")]
+    if !in_tokio() {
+        async_std::task::spawn(future);
+        return;
+    }
+    tokio::spawn(future);
+}
+
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(feature = "async-std")]
+    if !in_tokio() {
+        return async_std::task::sleep(duration).await;
+    }
+    tokio::time::sleep(duration).await
+}
+
+pub(crate) async fn sleep_until(deadline: Instant) {
+    #[cfg(feature = "async-std")]
+    if !in_tokio() {
+        let duration = deadline.saturating_duration_since(Instant::now());
+        return async_std::task::sleep(duration).await;
+    }
+    tokio::time::sleep_until(deadline.into()).await
+}
+
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    #[cfg(feature = "async-std")]
+    if !in_tokio() {
+        return async_std::future::timeout(duration, future)
+            .await
+            .map_err(|_| Elapsed);
+    }
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| Elapsed)
+}
+
+pub(crate) async fn timeout_at<F: Future>(
+    deadline: Instant,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    #[cfg(feature = "async-std")]
+    if !in_tokio() {
+        let duration = deadline.saturating_duration_since(Instant::now());
+        return timeout(duration, future).await;
+    }
+    tokio::time::timeout_at(deadline.into(), future)
+        .await
+        .map_err(|_| Elapsed)
+}
+
+#[cfg(all(test, feature = "async-std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timers_run_outside_tokio() {
+        async_std::task::block_on(async {
+            assert!(!in_tokio());
+            let started = Instant::now();
+            sleep(Duration::from_millis(20)).await;
+            assert!(started.elapsed() >= Duration::from_millis(20));
+            let late = timeout(Duration::from_millis(10), sleep(Duration::from_secs(5))).await;
+            assert!(late.is_err());
+            let deadline = Instant::now() + Duration::from_millis(50);
+            assert!(timeout_at(deadline, async { 7 }).await.is_ok());
+
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            spawn(async move {
+                let _ = tx.send(());
+            });
+            assert!(rx.await.is_ok());
+        });
+    }
+}
@@ -0,0 +1,74 @@
+//! Smoothing of saturation readings for graceful backpressure.
+//!
+//! A producer polling `saturation` sees the fixed window's sawtooth: climbing towards 1.0,
+//! then dropping to 0.0 when the window resets. Throttling on the raw value makes it lurch; a
+//! [`SaturationSmoother`] averages readings so it slows down steadily instead.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Exponentially weighted moving average of saturation readings. Shareable between threads.
+#[derive(Debug)]
+pub struct SaturationSmoother {
+    alpha: f64,
+    /// `f64` bits of the current average; `NaN` until the first reading.
+    value: AtomicU64,
+}
+
+impl SaturationSmoother {
+    /// `alpha` is the weight of each new reading, between 0 (exclusive) and 1: `1.0` disables
+    /// smoothing, `0.2` averages over roughly the last ten readings.
+    pub fn new(alpha: f64) -> Self {
+        let alpha = if alpha.is_nan() {
+            1.0
+        } else {
+            alpha.clamp(f64::EPSILON, 1.0)
+        };
+        SaturationSmoother {
+            alpha,
+            value: AtomicU64::new(f64::NAN.to_bits()),
+        }
+    }
+
+    /// Folds in a reading and returns the new average. The first reading is taken as is.
+    pub fn update(&self, saturation: f64) -> f64 {
+        let mut smoothed = saturation;
+        // `fetch_update` can't fail here: the closure always returns `Some`.
+        let _ = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let current = f64::from_bits(bits);
+                smoothed = if current.is_nan() {
+                    saturation
+                } else {
+                    current + self.alpha * (saturation - current)
+                };
+                Some(smoothed.to_bits())
+            });
+        smoothed
+    }
+
+    /// The current average, or `None` before the first reading.
+    pub fn value(&self) -> Option<f64> {
+        let value = f64::from_bits(self.value.load(Ordering::Relaxed));
+        (!value.is_nan()).then_some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smoother() {
+        let smoother = SaturationSmoother::new(0.5);
+        assert_eq!(smoother.value(), None);
+        assert_eq!(smoother.update(0.8), 0.8);
+        // A window reset halves the reading instead of dropping it to zero.
+        assert_eq!(smoother.update(0.0), 0.4);
+        assert_eq!(smoother.value(), Some(0.4));
+
+        let unsmoothed = SaturationSmoother::new(1.0);
+        unsmoothed.update(0.75);
+        assert_eq!(unsmoothed.update(0.25), 0.25);
+    }
+}
@@ -0,0 +1,196 @@
+//! Master discovery through Redis Sentinel.
+//!
+//! A sentinel-backed limiter asks the sentinels, in order, for the address of the master they
+//! monitor under its name (`SENTINEL get-master-addr-by-name`), once when it is created and
+//! again whenever a new connection to that address fails or finds a replica (checked with
+//! `ROLE`), i.e. after a failover. Sentinel closes client connections of a demoted master, so
+//! pooled and shared connections are replaced, and re-resolved, on their next use.
+//!
+//! The master is connected to with the TLS mode, database and credentials of the first
+//! sentinel URL.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use redis::{ConnectionAddr, ErrorKind, RedisError, RedisResult};
+
+use crate::failover::redact_url;
+use crate::runtime;
+
+pub(crate) struct Sentinel {
+    clients: Vec<redis::Client>,
+    /// The URLs with credentials redacted, for `Debug` output.
+    urls: Vec<String>,
+    master_name: String,
+}
+
+impl Sentinel {
+    pub(crate) fn open(sentinel_urls: &[&str], master_name: &str) -> RedisResult<Self> {
+        if sentinel_urls.is_empty() {
+            return Err(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "at least one sentinel URL is required",
+            )));
+        }
+        let clients = sentinel_urls
+            .iter()
+            .map(|url| redis::Client::open(*url))
+            .collect::<Result<_, _>>()?;
+        Ok(Sentinel {
+            clients,
+            urls: sentinel_urls.iter().map(|url| redact_url(url)).collect(),
+            master_name: master_name.to_string(),
+        })
+    }
+
+    /// Asks the sentinels in turn for the master's address and returns a client for it.
+    #[cfg(feature = "blocking")]
+    pub(crate) fn discover(&self, timeout: Option<Duration>) -> RedisResult<redis::Client> {
+        let mut last_error = None;
+        for client in &self.clients {
+            let reply = match timeout {
+                Some(timeout) => client.get_connection_with_timeout(timeout),
+                None => client.get_connection(),
+            }
+            .and_then(|mut conn| self.master_addr_cmd().query(&mut conn));
+            match reply {
+                Ok(Some(addr)) => return self.master_client(addr),
+                Ok(None) => last_error = Some(self.unknown_master()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| self.unknown_master()))
+    }
+
+    /// Like `discover`, over async connections.
+    pub(crate) async fn discover_async(
+        &self,
+        timeout: Option<Duration>,
+    ) -> RedisResult<redis::Client> {
+        let mut last_error = None;
+        for client in &self.clients {
+            let reply = async {
+                let mut conn = match timeout {
+                    Some(timeout) => {
+                        runtime::timeout(timeout, client.get_multiplexed_async_connection())
+                            .await
+                            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??
+                    }
+                    None => client.get_multiplexed_async_connection().await?,
+                };
+                self.master_addr_cmd().query_async(&mut conn).await
+            }
+            .await;
+            match reply {
+                Ok(Some(addr)) => return self.master_client(addr),
+                Ok(None) => last_error = Some(self.unknown_master()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| self.unknown_master()))
+    }
+
+    fn master_addr_cmd(&self) -> redis::Cmd {
+        let mut cmd = redis::cmd("SENTINEL");
+        cmd.arg("get-master-addr-by-name").arg(&self.master_name);
+        cmd
+    }
+
+    /// A client for the master at `host:port`, with the settings of the first sentinel URL.
+    fn master_client(&self, (host, port): (String, u16)) -> RedisResult<redis::Client> {
+        let mut info = self.clients[0].get_connection_info().clone();
+        info.addr = match info.addr {
+            ConnectionAddr::TcpTls {
+                insecure,
+                tls_params,
+                ..
+            } => ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure,
+                tls_params,
+            },
+            _ => ConnectionAddr::Tcp(host, port),
+        };
+        redis::Client::open(info)
+    }
+
+    fn unknown_master(&self) -> RedisError {
+        RedisError::from((
+            ErrorKind::IoError,
+            "no sentinel knows the master",
+            self.master_name.clone(),
+        ))
+    }
+}
+
+impl fmt::Debug for Sentinel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sentinel")
+            .field("urls", &self.urls)
+            .field("master_name", &self.master_name)
+            .finish()
+    }
+}
+
+pub(crate) fn role_cmd() -> redis::Cmd {
+    redis::cmd("ROLE")
+}
+
+/// Fails unless a `ROLE` reply is a master's. The error counts as unhealthy, so the caller
+/// looks the master up again.
+pub(crate) fn ensure_master(role: &[redis::Value]) -> RedisResult<()> {
+    let role: String = match role.first() {
+        Some(value) => redis::from_redis_value(value)?,
+        None => String::new(),
+    };
+    if role == "master" {
+        return Ok(());
+    }
+    Err(RedisError::from((
+        ErrorKind::IoError,
+        "the sentinel's master is no longer a master",
+        role,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Value;
+
+    #[test]
+    fn test_master_client_keeps_sentinel_settings() {
+        let sentinel = Sentinel::open(
+            &[
+                "redis://:s3cret@sentinel-1:26379/2",
+                "redis://sentinel-2:26379",
+            ],
+            "mymaster",
+        )
+        .unwrap();
+        let client = sentinel
+            .master_client(("10.0.0.5".to_string(), 6380))
+            .unwrap();
+        let info = client.get_connection_info();
+        assert_eq!(info.addr, ConnectionAddr::Tcp("10.0.0.5".to_string(), 6380));
+        assert_eq!(info.redis.db, 2);
+        assert_eq!(info.redis.password.as_deref(), Some("s3cret"));
+        assert_eq!(
+            format!("{:?}", sentinel),
+            "Sentinel { urls: [\"redis://***@sentinel-1:26379/2\", \"redis://sentinel-2:26379\"], master_name: \"mymaster\" }"
+        );
+    }
+
+    #[test]
+    fn test_ensure_master() {
+        let reply = |role: &str| vec![Value::Data(role.as_bytes().to_vec()), Value::Int(0)];
+        assert!(ensure_master(&reply("master")).is_ok());
+        assert!(matches!(
+            ensure_master(&reply("slave")),
+            Err(e) if e.kind() == ErrorKind::IoError
+        ));
+        assert!(Sentinel::open(&[], "mymaster").is_err());
+    }
+}
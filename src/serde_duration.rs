@@ -0,0 +1,88 @@
+//! Serializes `Duration`s (and `SystemTime`s) as whole milliseconds, available with the
+//! `serde` feature.
+//!
+//! Serde's default `{ "secs": .., "nanos": .. }` form is awkward in JSON API responses, so the
+//! public status types use integer milliseconds under `*_ms` field names instead.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub(crate) mod millis {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        u64::deserialize(d).map(Duration::from_millis)
+    }
+}
+
+pub(crate) mod option_millis {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => s.serialize_some(&(duration.as_millis() as u64)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<u64>::deserialize(d).map(|ms| ms.map(Duration::from_millis))
+    }
+}
+
+/// `SystemTime`s as milliseconds since the Unix epoch.
+pub(crate) mod unix_millis {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(time: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        s.serialize_u64(since_epoch.as_millis() as u64)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        u64::deserialize(d).map(|ms| UNIX_EPOCH + Duration::from_millis(ms))
+    }
+}
+
+pub(crate) mod option_unix_millis {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        time: &Option<SystemTime>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => s.serialize_some(&UnixMillis(*time)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        Option::<u64>::deserialize(d)
+            .map(|ms| ms.map(|ms| std::time::UNIX_EPOCH + Duration::from_millis(ms)))
+    }
+
+    struct UnixMillis(SystemTime);
+
+    impl serde::Serialize for UnixMillis {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            super::unix_millis::serialize(&self.0, s)
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Per-session limits whose state lives exactly as long as the session is active.
+
+use std::time::Duration;
+
+use crate::{AsyncRateLimiter, RateLimiterError, Usage};
+
+/// Limits requests per session ID rather than per user or IP: at most `max_requests` for as
+/// long as the session is active.
+///
+/// The counter's TTL follows session activity. Every request, allowed or not, pushes its
+/// expiry out to `idle_timeout`, so the budget only resets once the session has been idle that
+/// long (or is ended with [`end_session`](SessionLimiter::end_session)).
+#[derive(Debug)]
+pub struct SessionLimiter {
+    limiter: AsyncRateLimiter,
+}
+
+impl SessionLimiter {
+    pub fn new(
+        redis_url: &str,
+        key_prefix: &str,
+        max_requests: u64,
+        idle_timeout: Duration,
+    ) -> Result<Self, RateLimiterError> {
+        let limiter = AsyncRateLimiter::new(redis_url, key_prefix, max_requests, idle_timeout)?
+            .with_renewal_on_any_request();
+        Ok(SessionLimiter { limiter })
+    }
+
+    /// The underlying limiter, e.g. for `latency_stats` or overrides by session ID.
+    pub fn limiter(&self) -> &AsyncRateLimiter {
+        &self.limiter
+    }
+
+    /// Counts a request for `session_id` and renews the session.
+    pub async fn check(&self, session_id: &str) -> Result<(), RateLimiterError> {
+        self.limiter.check(session_id).await
+    }
+
+    pub async fn usage(&self, session_id: &str) -> Result<Usage, RateLimiterError> {
+        self.limiter.get_usage(session_id).await
+    }
+
+    /// Deletes the session's counter, e.g. on logout, instead of leaving it to expire.
+    pub async fn end_session(&self, session_id: &str) -> Result<(), RateLimiterError> {
+        self.limiter.reset(session_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_session_lifecycle() -> Result<(), RateLimiterError> {
+        let prefix = format!("test_session_{}", std::process::id());
+        let sessions = SessionLimiter::new(
+            "redis://127.0.0.1:6379",
+            &prefix,
+            2,
+            Duration::from_secs(60),
+        )?;
+
+        sessions.check("session_a").await?;
+        sessions.check("session_a").await?;
+        assert!(matches!(
+            sessions.check("session_a").await,
+            Err(RateLimiterError::RateLimitExceeded { .. })
+        ));
+        // Denied requests are activity too.
+        assert!(sessions.usage("session_a").await?.resets_in.unwrap() > Duration::from_secs(59));
+
+        sessions.end_session("session_a").await?;
+        sessions.check("session_a").await?;
+
+        Ok(())
+    }
+}
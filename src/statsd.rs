@@ -0,0 +1,147 @@
+//! StatsD sink with DogStatsD tags, available with the `statsd` feature.
+
+use std::fmt::Write;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+
+use crate::metrics::{CheckEvent, CheckOutcome, MetricsSink, TimingEvent};
+
+/// Sends check counters and Redis latency timings to a StatsD agent over UDP, tagged in the
+/// DogStatsD format understood by Datadog, Telegraf and the StatsD exporter:
+///
+/// - `<namespace>.check:1|c|@<rate>|#prefix:<prefix>,outcome:<outcome>[,reason:<reason>]`
+/// - `<namespace>.redis:<ms>|ms|@<rate>|#prefix:<prefix>,operation:<operation>`
+///
+/// Packets are fire-and-forget: send failures are ignored so a missing agent never affects
+/// checks.
+#[derive(Debug)]
+pub struct StatsdSink {
+    socket: UdpSocket,
+    namespace: String,
+    tags: String,
+    identifier_tag: bool,
+}
+
+impl StatsdSink {
+    /// Sends to `agent` (e.g. `127.0.0.1:8125`) with metric names under `namespace`.
+    pub fn new(agent: impl ToSocketAddrs, namespace: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(agent)?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsdSink {
+            socket,
+            namespace: namespace.to_string(),
+            tags: String::new(),
+            identifier_tag: false,
+        })
+    }
+
+    /// Adds a tag to every metric, e.g. `("env", "prod")`.
+    pub fn with_tag(mut self, key: &str, value: &str) -> Self {
+        let _ = write!(self.tags, ",{}:{}", key, value);
+        self
+    }
+
+    /// Tags check counters with the identifier, capped by
+    /// `TelemetrySampling::with_max_label_values`.
+    pub fn with_identifier_tag(mut self, enabled: bool) -> Self {
+        self.identifier_tag = enabled;
+        self
+    }
+
+    fn send(&self, packet: &str) {
+        let _ = self.socket.send(packet.as_bytes());
+    }
+}
+
+impl MetricsSink for StatsdSink {
+    fn record_check(&self, event: &CheckEvent<'_>) {
+        let mut packet = format!("{}.check:1|c", self.namespace);
+        push_sample_rate(&mut packet, event.sample_rate);
+        let _ = write!(
+            packet,
+            "|#prefix:{},outcome:{}",
+            event.key_prefix,
+            event.outcome.as_str()
+        );
+        if let CheckOutcome::Denied(reason) = event.outcome {
+            let _ = write!(packet, ",reason:{}", reason);
+        }
+        if self.identifier_tag {
+            let _ = write!(packet, ",identifier:{}", event.identifier);
+        }
+        packet.push_str(&self.tags);
+        self.send(&packet);
+    }
+
+    fn record_timing(&self, event: &TimingEvent<'_>) {
+        let mut packet = format!(
+            "{}.redis:{}|ms",
+            self.namespace,
+            event.duration.as_secs_f64() * 1000.0
+        );
+        push_sample_rate(&mut packet, event.sample_rate);
+        let _ = write!(
+            packet,
+            "|#prefix:{},operation:{}",
+            event.key_prefix, event.operation
+        );
+        packet.push_str(&self.tags);
+        self.send(&packet);
+    }
+
+    fn wants_identifier(&self) -> bool {
+        self.identifier_tag
+    }
+}
+
+fn push_sample_rate(packet: &mut String, rate: f64) {
+    if rate < 1.0 {
+        let _ = write!(packet, "|@{}", rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DenialReason;
+    use std::time::Duration;
+
+    #[test]
+    fn test_packets() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sink = StatsdSink::new(agent.local_addr().unwrap(), "ratelimit")
+            .unwrap()
+            .with_tag("env", "test");
+        let mut buf = [0; 512];
+        let mut receive = || {
+            let len = agent.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        };
+
+        sink.record_check(&CheckEvent {
+            key_prefix: "api",
+            identifier: "",
+            outcome: CheckOutcome::Denied(DenialReason::WindowExhausted),
+            sample_rate: 0.5,
+        });
+        assert_eq!(
+            receive(),
+            "ratelimit.check:1|c|@0.5|#prefix:api,outcome:denied,reason:window_exhausted,env:test"
+        );
+
+        sink.record_timing(&TimingEvent {
+            key_prefix: "api",
+            operation: "check",
+            duration: Duration::from_micros(1500),
+            sample_rate: 1.0,
+        });
+        assert_eq!(
+            receive(),
+            "ratelimit.redis:1.5|ms|#prefix:api,operation:check,env:test"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Pacing `Stream`s against a shared limit.
+
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::{AsyncRateLimiter, RateLimiterError};
+
+/// An item that couldn't be checked against the limiter, handed back with the error.
+pub struct ThrottleError<T> {
+    pub item: T,
+    pub error: RateLimiterError,
+}
+
+impl<T> fmt::Debug for ThrottleError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThrottleError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for ThrottleError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<T> std::error::Error for ThrottleError<T> {}
+
+type CheckFuture = Pin<Box<dyn Future<Output = Result<(), RateLimiterError>> + Send>>;
+
+pin_project! {
+    /// Stream returned by [`RateLimitedStreamExt::rate_limit`].
+    #[must_use = "streams do nothing unless polled"]
+    pub struct RateLimited<S, F>
+    where
+        S: Stream,
+    {
+        #[pin]
+        stream: S,
+        limiter: Arc<AsyncRateLimiter>,
+        key_fn: F,
+        pending: Option<(S::Item, CheckFuture)>,
+    }
+}
+
+impl<S, F> Stream for RateLimited<S, F>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> String,
+{
+    type Item = Result<S::Item, ThrottleError<S::Item>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if let Some((_, check)) = this.pending.as_mut() {
+                let result = match check.as_mut().poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                };
+                let (item, _) = this.pending.take().expect("pending item");
+                return Poll::Ready(Some(match result {
+                    Ok(()) => Ok(item),
+                    Err(error) => Err(ThrottleError { item, error }),
+                }));
+            }
+
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    let key = (this.key_fn)(&item);
+                    let limiter = Arc::clone(this.limiter);
+                    let check: CheckFuture = Box::pin(async move { limiter.wait(&key).await });
+                    *this.pending = Some((item, check));
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Extension trait pacing any `Stream` against a shared limiter.
+pub trait RateLimitedStreamExt: Stream + Sized {
+    /// Holds each item until `limiter` allows a request for the identifier returned by
+    /// `key_fn`, so consumers on many instances collectively respect one limit.
+    ///
+    /// Items are yielded in order. If Redis fails, the item is handed back in a
+    /// `ThrottleError` and the stream continues with the next one.
+    fn rate_limit<F>(self, limiter: Arc<AsyncRateLimiter>, key_fn: F) -> RateLimited<Self, F>
+    where
+        F: FnMut(&Self::Item) -> String,
+    {
+        RateLimited {
+            stream: self,
+            limiter,
+            key_fn,
+            pending: None,
+        }
+    }
+}
+
+impl<S: Stream> RateLimitedStreamExt for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{stream, StreamExt};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_stream_is_paced_by_limit() -> Result<(), RateLimiterError> {
+        let limiter = Arc::new(AsyncRateLimiter::new(
+            "redis://127.0.0.1:6379",
+            "test_rate_limited_stream",
+            2,
+            Duration::from_secs(1),
+        )?);
+        limiter.check("queue").await?;
+        limiter.check("queue").await?;
+
+        let start = Instant::now();
+        let items: Vec<_> = stream::iter(1..=2)
+            .rate_limit(Arc::clone(&limiter), |_| "queue".to_string())
+            .collect()
+            .await;
+
+        // The budget was used up, so the items had to wait for the window to reset.
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(
+            items.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        Ok(())
+    }
+}
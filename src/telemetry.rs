@@ -0,0 +1,138 @@
+//! Sampling of the limiter's telemetry, so observability stays affordable at high request
+//! rates.
+//!
+//! One [`TelemetrySampling`] governs every output: latency samples, per-decision logs, alert
+//! hooks and the label cardinality of metric exporters. Sampling is deterministic (every n-th
+//! event) rather than random, which keeps it cheap and the sampled share exact.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Sampling rates and caps for all telemetry outputs. The default records everything.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TelemetrySampling {
+    latency: f64,
+    decisions: f64,
+    hooks: f64,
+    max_label_values: usize,
+}
+
+impl Default for TelemetrySampling {
+    fn default() -> Self {
+        TelemetrySampling {
+            latency: 1.0,
+            decisions: 1.0,
+            hooks: 1.0,
+            max_label_values: 1000,
+        }
+    }
+}
+
+impl TelemetrySampling {
+    pub fn new() -> Self {
+        TelemetrySampling::default()
+    }
+
+    /// Fraction of Redis operations recorded in `latency_stats`, between 0 and 1. Slow
+    /// operations are still counted exactly.
+    pub fn with_latency_rate(mut self, rate: f64) -> Self {
+        self.latency = clamp_rate(rate);
+        self
+    }
+
+    /// Fraction of check decisions traced (logged at `trace` level with the identifier and
+    /// outcome), between 0 and 1.
+    pub fn with_decision_rate(mut self, rate: f64) -> Self {
+        self.decisions = clamp_rate(rate);
+        self
+    }
+
+    /// Fraction of slow operation and cardinality alerts that are logged and passed to their
+    /// hooks, between 0 and 1.
+    pub fn with_hook_rate(mut self, rate: f64) -> Self {
+        self.hooks = clamp_rate(rate);
+        self
+    }
+
+    /// Maximum number of distinct values a metric exporter reports for an identifier-derived
+    /// label; the rest are folded into one `other` value.
+    pub fn with_max_label_values(mut self, max: usize) -> Self {
+        self.max_label_values = max;
+        self
+    }
+
+    pub fn latency_rate(&self) -> f64 {
+        self.latency
+    }
+
+    pub fn decision_rate(&self) -> f64 {
+        self.decisions
+    }
+
+    pub fn hook_rate(&self) -> f64 {
+        self.hooks
+    }
+
+    pub fn max_label_values(&self) -> usize {
+        self.max_label_values
+    }
+}
+
+fn clamp_rate(rate: f64) -> f64 {
+    if rate.is_nan() {
+        0.0
+    } else {
+        rate.clamp(0.0, 1.0)
+    }
+}
+
+/// Passes every n-th event, where n is derived from a sampling rate.
+#[derive(Debug)]
+pub(crate) struct Sampler {
+    /// 0 means never.
+    every: u64,
+    seen: AtomicU64,
+}
+
+impl Sampler {
+    pub(crate) fn new(rate: f64) -> Self {
+        let every = if rate <= 0.0 {
+            0
+        } else {
+            (1.0 / rate).round().max(1.0) as u64
+        };
+        Sampler {
+            every,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn sample(&self) -> bool {
+        match self.every {
+            0 => false,
+            1 => true,
+            every => self.seen.fetch_add(1, Ordering::Relaxed) % every == 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampler() {
+        let count = |rate: f64| {
+            let sampler = Sampler::new(rate);
+            (0..1000).filter(|_| sampler.sample()).count()
+        };
+        assert_eq!(count(1.0), 1000);
+        assert_eq!(count(0.1), 100);
+        assert_eq!(count(0.0), 0);
+
+        let sampling = TelemetrySampling::new()
+            .with_latency_rate(2.0)
+            .with_hook_rate(f64::NAN);
+        assert_eq!(sampling.latency_rate(), 1.0);
+        assert_eq!(sampling.hook_rate(), 0.0);
+    }
+}
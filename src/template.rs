@@ -0,0 +1,163 @@
+//! Named, reusable limit definitions.
+//!
+//! A [`Template`] captures a policy (limit, window, window mode and algorithm) once; limiters for any
+//! number of prefixes are then instantiated from it, so a policy shared by many endpoints is
+//! defined, and updated, in a single place.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[cfg(feature = "blocking")]
+use crate::RateLimiter;
+use crate::{Algorithm, AsyncRateLimiter, RateLimiterError, WindowMode};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    name: String,
+    max_requests: u64,
+    window: Duration,
+    window_mode: WindowMode,
+    algorithm: Algorithm,
+}
+
+impl Template {
+    pub fn new(name: &str, max_requests: u64, window: Duration) -> Self {
+        Template {
+            name: name.to_string(),
+            max_requests,
+            window,
+            window_mode: WindowMode::default(),
+            algorithm: Algorithm::default(),
+        }
+    }
+
+    pub fn with_window_mode(mut self, mode: WindowMode) -> Self {
+        self.window_mode = mode;
+        self
+    }
+
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn max_requests(&self) -> u64 {
+        self.max_requests
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn window_mode(&self) -> WindowMode {
+        self.window_mode
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Creates a `RateLimiter` enforcing this template under `key_prefix`.
+    #[cfg(feature = "blocking")]
+    pub fn limiter(
+        &self,
+        redis_url: &str,
+        key_prefix: &str,
+    ) -> Result<RateLimiter, RateLimiterError> {
+        Ok(
+            RateLimiter::new(redis_url, key_prefix, self.max_requests, self.window)?
+                .with_window_mode(self.window_mode)
+                .with_algorithm(self.algorithm),
+        )
+    }
+
+    /// Creates an `AsyncRateLimiter` enforcing this template under `key_prefix`.
+    pub fn async_limiter(
+        &self,
+        redis_url: &str,
+        key_prefix: &str,
+    ) -> Result<AsyncRateLimiter, RateLimiterError> {
+        Ok(
+            AsyncRateLimiter::new(redis_url, key_prefix, self.max_requests, self.window)?
+                .with_window_mode(self.window_mode)
+                .with_algorithm(self.algorithm),
+        )
+    }
+}
+
+/// A set of templates looked up by name.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateRegistry {
+    templates: HashMap<String, Template>,
+}
+
+impl TemplateRegistry {
+    pub fn new() -> Self {
+        TemplateRegistry::default()
+    }
+
+    /// Adds `template`, replacing any template with the same name.
+    pub fn register(&mut self, template: Template) -> &mut Self {
+        self.templates.insert(template.name.clone(), template);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Template> {
+        self.templates.get(name)
+    }
+
+    fn require(&self, name: &str) -> Result<&Template, RateLimiterError> {
+        self.get(name)
+            .ok_or_else(|| RateLimiterError::UnknownTemplate(name.to_string()))
+    }
+
+    /// Creates a `RateLimiter` from the named template under `key_prefix`.
+    #[cfg(feature = "blocking")]
+    pub fn limiter(
+        &self,
+        name: &str,
+        redis_url: &str,
+        key_prefix: &str,
+    ) -> Result<RateLimiter, RateLimiterError> {
+        self.require(name)?.limiter(redis_url, key_prefix)
+    }
+
+    /// Creates an `AsyncRateLimiter` from the named template under `key_prefix`.
+    pub fn async_limiter(
+        &self,
+        name: &str,
+        redis_url: &str,
+        key_prefix: &str,
+    ) -> Result<AsyncRateLimiter, RateLimiterError> {
+        self.require(name)?.async_limiter(redis_url, key_prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_instantiates_limiters_from_templates() -> Result<(), RateLimiterError> {
+        let mut registry = TemplateRegistry::new();
+        registry.register(Template::new("strict_auth", 5, Duration::from_secs(60)));
+
+        let template = registry.get("strict_auth").unwrap();
+        assert_eq!(template.max_requests(), 5);
+        assert_eq!(template.window_mode(), WindowMode::SlidingInactivity);
+
+        // Limiters only differ by prefix; construction doesn't touch Redis.
+        registry.async_limiter("strict_auth", "redis://127.0.0.1:6379", "login")?;
+        registry.async_limiter("strict_auth", "redis://127.0.0.1:6379", "password_reset")?;
+        assert!(matches!(
+            registry.async_limiter("missing", "redis://127.0.0.1:6379", "login"),
+            Err(RateLimiterError::UnknownTemplate(name)) if name == "missing"
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,389 @@
+//! Per-tenant policies enforced through one limiter.
+//!
+//! A [`TenantRateLimiter`] maps tenant IDs to a [`TenantConfig`] (limit, window, algorithm and
+//! key prefix) through a loader, e.g. a lookup of the tenant's plan in a database, and keeps
+//! the limiter built for each tenant for a while, so heterogeneous plans are enforced without
+//! a `RateLimiter` per tenant constructed by hand. Each tenant's keys live under its own
+//! prefix, so the same identifier in two tenants is counted separately.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::pool::{Pool, PoolConfig};
+use crate::{
+    Algorithm, RateLimitDecision, RateLimiter, RateLimiterError, Template, ToIdentifier, WindowMode,
+};
+
+/// How long a tenant's configuration is used before the loader is asked again, by default.
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+type TenantLoader =
+    Arc<dyn Fn(&str) -> Result<Option<TenantConfig>, RateLimiterError> + Send + Sync>;
+
+/// The policy of one tenant. Windows deserialize from strings such as `"1m"`, as
+/// `parse_duration` reads them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct TenantConfig {
+    pub max_requests: u64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "crate::parse::deserialize_duration")
+    )]
+    pub window: Duration,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub algorithm: Algorithm,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub window_mode: WindowMode,
+    /// Key prefix; `{key_prefix}:{tenant}` if unset. Tenants given the same prefix share
+    /// their counters.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub prefix: Option<String>,
+}
+
+impl TenantConfig {
+    pub fn new(max_requests: u64, window: Duration) -> Self {
+        TenantConfig {
+            max_requests,
+            window,
+            algorithm: Algorithm::default(),
+            window_mode: WindowMode::default(),
+            prefix: None,
+        }
+    }
+
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn with_window_mode(mut self, mode: WindowMode) -> Self {
+        self.window_mode = mode;
+        self
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_string());
+        self
+    }
+}
+
+struct CachedTenant {
+    config: TenantConfig,
+    limiter: Arc<RateLimiter>,
+    loaded_at: Instant,
+}
+
+/// Limits requests per tenant, each tenant with the policy its loader returns. Limiters are
+/// built on first use, draw connections from one shared pool, and are kept until the cache
+/// TTL passes; the configuration is then loaded again, and the limiter rebuilt only if it
+/// changed.
+pub struct TenantRateLimiter {
+    redis_url: String,
+    key_prefix: String,
+    loader: TenantLoader,
+    default_config: Option<TenantConfig>,
+    cache_ttl: Duration,
+    pool: Arc<Pool>,
+    tenants: Mutex<HashMap<String, CachedTenant>>,
+}
+
+impl TenantRateLimiter {
+    /// Creates a limiter for the tenants `loader` knows, keyed under `key_prefix`. The loader
+    /// returns `None` for an unknown tenant. Doesn't connect.
+    pub fn new<F>(redis_url: &str, key_prefix: &str, loader: F) -> Self
+    where
+        F: Fn(&str) -> Result<Option<TenantConfig>, RateLimiterError> + Send + Sync + 'static,
+    {
+        TenantRateLimiter {
+            redis_url: redis_url.to_string(),
+            key_prefix: key_prefix.to_string(),
+            loader: Arc::new(loader),
+            default_config: None,
+            cache_ttl: DEFAULT_CACHE_TTL,
+            pool: Arc::new(Pool::new(PoolConfig::default())),
+            tenants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a limiter for a fixed set of tenants, e.g. deserialized with the `serde`
+    /// feature from a deployment config file.
+    pub fn from_configs(
+        redis_url: &str,
+        key_prefix: &str,
+        configs: HashMap<String, TenantConfig>,
+    ) -> Self {
+        TenantRateLimiter::new(redis_url, key_prefix, move |tenant| {
+            Ok(configs.get(tenant).cloned())
+        })
+    }
+
+    /// The policy of tenants the loader doesn't know, instead of failing with
+    /// `RateLimiterError::UnknownTenant`, e.g. a free plan.
+    pub fn with_default_config(mut self, config: TenantConfig) -> Self {
+        self.default_config = Some(config);
+        self
+    }
+
+    /// How long a tenant's configuration is used before it's loaded again (60 seconds by
+    /// default), which bounds how long a plan change takes to apply.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Replaces the shared pool. Limiters already built are dropped and rebuilt on next use.
+    pub fn with_pool(mut self, config: PoolConfig) -> Self {
+        self.pool = Arc::new(Pool::new(config));
+        self.tenants.get_mut().unwrap().clear();
+        self
+    }
+
+    /// The limiter enforcing `tenant`'s policy, loading its configuration if it isn't cached
+    /// or the cache TTL has passed. If reloading fails, the cached limiter is used until a
+    /// load succeeds. Fails with `RateLimiterError::UnknownTenant` for a tenant the loader
+    /// doesn't know without a default configuration, and `InvalidIdentifier` for an empty
+    /// tenant ID or one containing `:`, which could collide with another tenant's keys.
+    pub fn limiter(&self, tenant: &str) -> Result<Arc<RateLimiter>, RateLimiterError> {
+        if tenant.is_empty() || tenant.contains(':') {
+            return Err(RateLimiterError::InvalidIdentifier(format!(
+                "tenant `{}` must be non-empty and without `:`",
+                tenant
+            )));
+        }
+        let cached = self
+            .tenants
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(tenant)
+            .map(|cached| {
+                (
+                    cached.config.clone(),
+                    Arc::clone(&cached.limiter),
+                    cached.loaded_at,
+                )
+            });
+        if let Some((_, limiter, loaded_at)) = &cached {
+            if loaded_at.elapsed() < self.cache_ttl {
+                return Ok(Arc::clone(limiter));
+            }
+        }
+        // The loader runs without the lock held, as it may take a round trip of its own.
+        let (config, cached) = match ((self.loader)(tenant), cached) {
+            (Ok(config), cached) => (config.or_else(|| self.default_config.clone()), cached),
+            (Err(e), Some((_, limiter, _))) => {
+                log::warn!(
+                    "reloading the configuration of tenant `{}` failed, keeping the cached one: {}",
+                    tenant,
+                    e
+                );
+                return Ok(limiter);
+            }
+            (Err(e), None) => return Err(e),
+        };
+        let Some(config) = config else {
+            self.tenants
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .remove(tenant);
+            return Err(RateLimiterError::UnknownTenant(tenant.to_string()));
+        };
+        let limiter = match cached {
+            Some((cached_config, limiter, _)) if cached_config == config => limiter,
+            _ => Arc::new(self.build(tenant, &config)?),
+        };
+        self.tenants
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(
+                tenant.to_string(),
+                CachedTenant {
+                    config,
+                    limiter: Arc::clone(&limiter),
+                    loaded_at: Instant::now(),
+                },
+            );
+        Ok(limiter)
+    }
+
+    /// Checks `identifier` against `tenant`'s policy.
+    pub fn check(
+        &self,
+        tenant: &str,
+        identifier: impl ToIdentifier,
+    ) -> Result<(), RateLimiterError> {
+        self.limiter(tenant)?.check(identifier)
+    }
+
+    /// Like `check`, but the request takes `cost` units of the tenant's limit.
+    pub fn check_with_cost(
+        &self,
+        tenant: &str,
+        identifier: impl ToIdentifier,
+        cost: u64,
+    ) -> Result<(), RateLimiterError> {
+        self.limiter(tenant)?.check_with_cost(identifier, cost)
+    }
+
+    /// Like `check`, but a denial is reported in the returned decision; see
+    /// `RateLimiter::check_detailed`.
+    pub fn check_detailed(
+        &self,
+        tenant: &str,
+        identifier: impl ToIdentifier,
+    ) -> Result<RateLimitDecision, RateLimiterError> {
+        self.limiter(tenant)?.check_detailed(identifier)
+    }
+
+    /// Forgets `tenant`'s cached configuration, so the next request loads it again, e.g. right
+    /// after a plan change. Its counters in Redis are kept.
+    pub fn invalidate(&self, tenant: &str) {
+        self.tenants
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(tenant);
+    }
+
+    /// Forgets every cached configuration.
+    pub fn invalidate_all(&self) {
+        self.tenants
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+    }
+
+    fn build(&self, tenant: &str, config: &TenantConfig) -> Result<RateLimiter, RateLimiterError> {
+        let prefix = match &config.prefix {
+            Some(prefix) => prefix.clone(),
+            None => format!("{}:{}", self.key_prefix, tenant),
+        };
+        let limiter = Template::new(tenant, config.max_requests, config.window)
+            .with_algorithm(config.algorithm)
+            .with_window_mode(config.window_mode)
+            .limiter(&self.redis_url, &prefix)?;
+        Ok(limiter.with_shared_pool(Arc::clone(&self.pool)))
+    }
+}
+
+impl fmt::Debug for TenantRateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TenantRateLimiter")
+            .field("key_prefix", &self.key_prefix)
+            .field("default_config", &self.default_config)
+            .field("cache_ttl", &self.cache_ttl)
+            .field("pool", self.pool.config())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn plans(loads: Arc<AtomicUsize>) -> TenantRateLimiter {
+        TenantRateLimiter::new("redis://127.0.0.1:6379", "api", move |tenant| {
+            loads.fetch_add(1, Ordering::SeqCst);
+            match tenant {
+                "acme" => Ok(Some(
+                    TenantConfig::new(1000, Duration::from_secs(60))
+                        .with_algorithm(Algorithm::Gcra),
+                )),
+                "initech" => Ok(Some(TenantConfig::new(10, Duration::from_secs(60)))),
+                "broken" => Err(RateLimiterError::InvalidConfig("plan lookup failed".into())),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    #[test]
+    fn test_tenants_get_their_own_limiters() -> Result<(), RateLimiterError> {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let tenants = plans(Arc::clone(&loads));
+
+        // Building doesn't touch Redis, and cached limiters are reused.
+        let acme = tenants.limiter("acme")?;
+        assert!(Arc::ptr_eq(&acme, &tenants.limiter("acme")?));
+        let initech = tenants.limiter("initech")?;
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert!(format!("{:?}", acme).contains("api:acme"));
+        assert!(format!("{:?}", initech).contains("api:initech"));
+
+        tenants.invalidate("acme");
+        assert!(!Arc::ptr_eq(&acme, &tenants.limiter("acme")?));
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+
+        assert!(matches!(
+            tenants.limiter("globex"),
+            Err(RateLimiterError::UnknownTenant(tenant)) if tenant == "globex"
+        ));
+        assert!(matches!(
+            tenants.limiter("broken"),
+            Err(RateLimiterError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            tenants.limiter("acme:x"),
+            Err(RateLimiterError::InvalidIdentifier(_))
+        ));
+
+        let tenants =
+            plans(loads).with_default_config(TenantConfig::new(5, Duration::from_secs(1)));
+        assert!(format!("{:?}", tenants.limiter("globex")?).contains("api:globex"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tenant_configs_reload_after_the_ttl() -> Result<(), RateLimiterError> {
+        let limit = Arc::new(AtomicUsize::new(10));
+        let available = Arc::new(AtomicUsize::new(1));
+        let tenants = {
+            let (limit, available) = (Arc::clone(&limit), Arc::clone(&available));
+            TenantRateLimiter::new("redis://127.0.0.1:6379", "api", move |_| {
+                if available.load(Ordering::SeqCst) == 0 {
+                    return Err(RateLimiterError::InvalidConfig("plan lookup failed".into()));
+                }
+                let max_requests = limit.load(Ordering::SeqCst) as u64;
+                Ok(Some(TenantConfig::new(
+                    max_requests,
+                    Duration::from_secs(60),
+                )))
+            })
+            .with_cache_ttl(Duration::ZERO)
+        };
+
+        let first = tenants.limiter("acme")?;
+        assert!(Arc::ptr_eq(&first, &tenants.limiter("acme")?));
+        limit.store(20, Ordering::SeqCst);
+        let upgraded = tenants.limiter("acme")?;
+        assert!(!Arc::ptr_eq(&first, &upgraded));
+
+        // A failing loader leaves the last known policy in force.
+        available.store(0, Ordering::SeqCst);
+        assert!(Arc::ptr_eq(&upgraded, &tenants.limiter("acme")?));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_tenant_configs_deserialize() {
+        let configs: HashMap<String, TenantConfig> = serde_json::from_str(
+            r#"{
+                "acme": {"max_requests": 1000, "window": "1m", "algorithm": "gcra"},
+                "initech": {"max_requests": 10, "window": "1s", "prefix": "legacy:initech"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(configs["acme"].window, Duration::from_secs(60));
+        assert_eq!(configs["acme"].algorithm, Algorithm::Gcra);
+        assert_eq!(configs["initech"].prefix.as_deref(), Some("legacy:initech"));
+
+        let tenants = TenantRateLimiter::from_configs("redis://127.0.0.1:6379", "api", configs);
+        let initech = format!("{:?}", tenants.limiter("initech").unwrap());
+        assert!(initech.contains("legacy:initech"), "{}", initech);
+    }
+}
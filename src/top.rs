@@ -0,0 +1,25 @@
+/// An identifier's count in the current period of `with_top_consumers` tracking, as ranked by
+/// `top_consumers` (units allowed) or `violations_top` (requests denied).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TopConsumer {
+    pub identifier: String,
+    pub count: u64,
+}
+
+/// Reads the `n` highest scored members of a tracking sorted set, highest first.
+pub(crate) fn query_cmd(key: &str, n: usize) -> redis::Cmd {
+    let mut cmd = redis::cmd("ZREVRANGE");
+    cmd.arg(key).arg(0).arg(n as i64 - 1).arg("WITHSCORES");
+    cmd
+}
+
+pub(crate) fn from_reply(reply: Vec<(String, f64)>) -> Vec<TopConsumer> {
+    reply
+        .into_iter()
+        .map(|(identifier, score)| TopConsumer {
+            identifier,
+            count: score as u64,
+        })
+        .collect()
+}
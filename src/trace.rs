@@ -0,0 +1,171 @@
+//! `tracing` spans and events, with the `tracing` feature; without it these helpers compile to
+//! plain calls.
+//!
+//! Checks (`check`, `check_detailed`) and usage reads (`get_usage`, and so `get_remaining`)
+//! run in a `rate_limit` span at debug level with the operation, key prefix and identifier,
+//! recording the `decision`, `remaining` count and, for denials, `retry_after_ms` once known,
+//! and emitting them as a `rate_limit.decision` event too, which OpenTelemetry bridges such as
+//! `tracing-opentelemetry` export as a span event. Connecting to Redis runs in a
+//! `rate_limit_connect` span, and every timed Redis operation emits a trace-level event with
+//! its latency in microseconds, within whichever span is current.
+
+use std::future::Future;
+use std::time::Duration;
+
+use crate::core::LimiterCore;
+
+/// How identifiers are recorded in `rate_limit` spans (see `with_traced_identifiers`).
+#[cfg(feature = "tracing")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TracedIdentifier {
+    /// Not recorded.
+    Omit,
+    /// As a stable 64-bit hash in hex, so traces can be correlated without exposing the
+    /// identifier.
+    #[default]
+    Hashed,
+    /// As is.
+    Plain,
+}
+
+#[cfg(feature = "tracing")]
+fn span(core: &LimiterCore, operation: &'static str, identifier: &str) -> tracing::Span {
+    use tracing::field::Empty;
+
+    let span = tracing::debug_span!(
+        "rate_limit",
+        operation,
+        prefix = core.key_prefix.as_str(),
+        identifier = Empty,
+        decision = Empty,
+        remaining = Empty,
+        retry_after_ms = Empty,
+    );
+    match core.traced_identifier {
+        TracedIdentifier::Omit => {}
+        TracedIdentifier::Hashed => {
+            span.record(
+                "identifier",
+                format_args!("{:016x}", crate::identifier::fnv1a(identifier)),
+            );
+        }
+        TracedIdentifier::Plain => {
+            span.record("identifier", identifier);
+        }
+    }
+    span
+}
+
+/// Runs `f` in a `rate_limit` span for `operation` on `identifier`.
+#[cfg(feature = "blocking")]
+pub(crate) fn in_span<T>(
+    core: &LimiterCore,
+    operation: &'static str,
+    identifier: &str,
+    f: impl FnOnce() -> T,
+) -> T {
+    #[cfg(feature = "tracing")]
+    return span(core, operation, identifier).in_scope(f);
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (core, operation, identifier);
+        f()
+    }
+}
+
+/// Like `in_span`, for a future: the span is entered whenever it is polled.
+pub(crate) fn instrument<F: Future>(
+    core: &LimiterCore,
+    operation: &'static str,
+    identifier: &str,
+    fut: F,
+) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    return tracing::Instrument::instrument(fut, span(core, operation, identifier));
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (core, operation, identifier);
+        fut
+    }
+}
+
+/// Runs `f` in a `rate_limit_connect` span.
+#[cfg(feature = "blocking")]
+pub(crate) fn connecting<T>(core: &LimiterCore, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "tracing")]
+    return tracing::debug_span!("rate_limit_connect", prefix = core.key_prefix.as_str())
+        .in_scope(f);
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = core;
+        f()
+    }
+}
+
+/// Like `connecting`, for a future.
+pub(crate) fn connecting_async<F: Future>(
+    core: &LimiterCore,
+    fut: F,
+) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    return tracing::Instrument::instrument(
+        fut,
+        tracing::debug_span!("rate_limit_connect", prefix = core.key_prefix.as_str()),
+    );
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = core;
+        fut
+    }
+}
+
+/// Records a check's decision (`allowed`, `denied` or `error`) in the current span, with the
+/// requests left and time to retry after if known, and emits it as a `rate_limit.decision`
+/// event.
+pub(crate) fn record_decision(
+    decision: &'static str,
+    remaining: Option<u64>,
+    retry_after: Option<Duration>,
+) {
+    #[cfg(feature = "tracing")]
+    {
+        let span = tracing::Span::current();
+        span.record("decision", decision);
+        if let Some(remaining) = remaining {
+            span.record("remaining", remaining);
+        }
+        let retry_after_ms = retry_after.map(|retry_after| retry_after.as_millis() as u64);
+        if let Some(retry_after_ms) = retry_after_ms {
+            span.record("retry_after_ms", retry_after_ms);
+        }
+        tracing::debug!(
+            name: "rate_limit.decision",
+            decision,
+            remaining,
+            retry_after_ms,
+            "rate limit decision"
+        );
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = (decision, remaining, retry_after);
+}
+
+/// Records the requests left in the current span.
+pub(crate) fn record_remaining(remaining: u64) {
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("remaining", remaining);
+    #[cfg(not(feature = "tracing"))]
+    let _ = remaining;
+}
+
+/// Emits the latency of a Redis operation.
+pub(crate) fn redis_timing(operation: &'static str, duration: Duration) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        operation,
+        latency_us = duration.as_micros() as u64,
+        "redis operation"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = (operation, duration);
+}
@@ -0,0 +1,106 @@
+//! Transforms applied to identifiers before they are written into Redis keys.
+//!
+//! With a [`KeyTransform`] configured, every key, set member and script argument derived from
+//! an identifier uses the transformed form, so e.g. email addresses never appear verbatim in
+//! the keyspace, in `MONITOR` output or in RDB snapshots.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+use sha1_smol::Sha1;
+
+/// Maps an identifier, after normalization and the identifier policy, to the form keyed in
+/// Redis. Must be deterministic: every instance sharing a prefix has to map an identifier to
+/// the same output.
+pub trait KeyTransform: fmt::Debug + Send + Sync {
+    fn transform<'a>(&self, identifier: &'a str) -> Cow<'a, str>;
+}
+
+impl<T: KeyTransform + ?Sized> KeyTransform for Arc<T> {
+    fn transform<'a>(&self, identifier: &'a str) -> Cow<'a, str> {
+        (**self).transform(identifier)
+    }
+}
+
+/// Replaces identifiers with their HMAC-SHA1 keyed by a secret salt, as 40 hex digits.
+///
+/// Without the salt the hashes can't be reversed by hashing candidate identifiers (e.g. a
+/// list of known email addresses), so keep it out of Redis and share it between instances
+/// like any other secret. Changing it starts every identifier afresh. For another digest,
+/// such as SHA-256 from the `sha2` crate, implement [`KeyTransform`] instead.
+#[derive(Clone)]
+pub struct SaltedHash {
+    /// Hash states after the inner and outer padded keys, resumed for every identifier.
+    inner: Sha1,
+    outer: Sha1,
+}
+
+/// SHA-1 block size in bytes.
+const BLOCK_LEN: usize = 64;
+
+impl SaltedHash {
+    pub fn new(salt: impl AsRef<[u8]>) -> Self {
+        let salt = salt.as_ref();
+        let mut key = [0; BLOCK_LEN];
+        if salt.len() > BLOCK_LEN {
+            key[..20].copy_from_slice(&Sha1::from(salt).digest().bytes());
+        } else {
+            key[..salt.len()].copy_from_slice(salt);
+        }
+        SaltedHash {
+            inner: Sha1::from(key.map(|b| b ^ 0x36)),
+            outer: Sha1::from(key.map(|b| b ^ 0x5c)),
+        }
+    }
+
+    fn digest(&self, message: &[u8]) -> [u8; 20] {
+        let mut inner = self.inner.clone();
+        inner.update(message);
+        let mut outer = self.outer.clone();
+        outer.update(&inner.digest().bytes());
+        outer.digest().bytes()
+    }
+}
+
+impl KeyTransform for SaltedHash {
+    fn transform<'a>(&self, identifier: &'a str) -> Cow<'a, str> {
+        let mut hex = String::with_capacity(40);
+        for byte in self.digest(identifier.as_bytes()) {
+            // Writing to a `String` can't fail.
+            let _ = fmt::Write::write_fmt(&mut hex, format_args!("{:02x}", byte));
+        }
+        Cow::Owned(hex)
+    }
+}
+
+impl fmt::Debug for SaltedHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SaltedHash").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_salted_hash_is_hmac_sha1() {
+        // RFC 2202 test cases 2 and 6.
+        let hash = SaltedHash::new("Jefe");
+        assert_eq!(
+            hash.transform("what do ya want for nothing?"),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        let hash = SaltedHash::new([0xaa; 80]);
+        assert_eq!(
+            hash.transform("Test Using Larger Than Block-Size Key - Hash Key First"),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+        assert_ne!(
+            SaltedHash::new("a").transform("user@example.com"),
+            SaltedHash::new("b").transform("user@example.com")
+        );
+        assert_eq!(format!("{:?}", hash), "SaltedHash { .. }");
+    }
+}
@@ -0,0 +1,180 @@
+use std::time::{Duration, SystemTime};
+
+/// A snapshot of an identifier's usage within its current window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Usage {
+    /// Requests consumed in the current window.
+    pub consumed: u64,
+    /// Configured maximum number of requests per window.
+    pub limit: u64,
+    /// Requests still available in the current window.
+    pub remaining: u64,
+    /// Configured window length.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "window_ms", with = "crate::serde_duration::millis")
+    )]
+    pub window: Duration,
+    /// Time elapsed since the current window started (since the last allowed request with
+    /// `WindowMode::SlidingInactivity`); zero if there is no active window.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "elapsed_ms", with = "crate::serde_duration::millis")
+    )]
+    pub elapsed: Duration,
+    /// Time until the current window resets, or `None` if there is no active window.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "resets_in_ms", with = "crate::serde_duration::option_millis")
+    )]
+    pub resets_in: Option<Duration>,
+}
+
+impl Usage {
+    /// Builds a snapshot from the raw counter value and its `PTTL` in milliseconds.
+    pub(crate) fn from_raw(count: Option<u64>, pttl_ms: i64, limit: u64, window: Duration) -> Self {
+        let consumed = count.unwrap_or(0);
+        let resets_in = u64::try_from(pttl_ms).ok().map(Duration::from_millis);
+        Usage {
+            consumed,
+            limit,
+            remaining: limit.saturating_sub(consumed),
+            window,
+            elapsed: resets_in.map_or(Duration::ZERO, |left| window.saturating_sub(left)),
+            resets_in,
+        }
+    }
+
+    /// The snapshot as a `RateLimitStatus`, with the reset time counted from `now`.
+    pub fn status_at(&self, now: SystemTime) -> RateLimitStatus {
+        RateLimitStatus {
+            limit: self.limit,
+            used: self.consumed,
+            remaining: self.remaining,
+            reset_at: self.resets_in.map(|resets_in| now + resets_in),
+            window: self.window,
+        }
+    }
+
+    /// Fraction of the window's budget consumed, from 0.0 to 1.0. Denied requests count too,
+    /// so it stays at 1.0 while callers keep hammering.
+    pub fn saturation(&self) -> f64 {
+        if self.limit == 0 {
+            return 1.0;
+        }
+        (self.consumed as f64 / self.limit as f64).min(1.0)
+    }
+}
+
+/// An identifier's limiter state in the shape API responses and admin endpoints return it,
+/// e.g. `{"limit":100,"used":42,"remaining":58,"reset_at_ms":1700000060000,"window_ms":60000}`
+/// with the `serde` feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RateLimitStatus {
+    /// Limit in effect for the identifier.
+    pub limit: u64,
+    /// Requests consumed in the current window.
+    pub used: u64,
+    /// Requests still available in the current window.
+    pub remaining: u64,
+    /// When the current window resets, or `None` if there is no active window.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "reset_at_ms",
+            with = "crate::serde_duration::option_unix_millis"
+        )
+    )]
+    pub reset_at: Option<SystemTime>,
+    /// Configured window length.
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "window_ms", with = "crate::serde_duration::millis")
+    )]
+    pub window: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_from_raw() {
+        let usage = Usage::from_raw(Some(3), 40_000, 10, Duration::from_secs(60));
+        assert_eq!(usage.consumed, 3);
+        assert_eq!(usage.remaining, 7);
+        assert_eq!(usage.elapsed, Duration::from_secs(20));
+        assert_eq!(usage.resets_in, Some(Duration::from_secs(40)));
+
+        // PTTL returns -2 for a missing key.
+        let idle = Usage::from_raw(None, -2, 10, Duration::from_secs(60));
+        assert_eq!(idle.consumed, 0);
+        assert_eq!(idle.remaining, 10);
+        assert_eq!(idle.elapsed, Duration::ZERO);
+        assert_eq!(idle.resets_in, None);
+    }
+
+    #[test]
+    fn test_saturation() {
+        let window = Duration::from_secs(60);
+        assert_eq!(Usage::from_raw(Some(8), 1000, 10, window).saturation(), 0.8);
+        assert_eq!(
+            Usage::from_raw(Some(15), 1000, 10, window).saturation(),
+            1.0
+        );
+        assert_eq!(Usage::from_raw(None, -2, 10, window).saturation(), 0.0);
+        assert_eq!(Usage::from_raw(None, -2, 0, window).saturation(), 1.0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serializes_durations_as_millis() {
+        let usage = Usage::from_raw(Some(3), 1500, 10, Duration::from_secs(60));
+        let json = serde_json::to_value(&usage).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "consumed": 3,
+                "limit": 10,
+                "remaining": 7,
+                "window_ms": 60000,
+                "elapsed_ms": 58500,
+                "resets_in_ms": 1500,
+            })
+        );
+        assert_eq!(serde_json::from_value::<Usage>(json).unwrap(), usage);
+    }
+
+    #[test]
+    fn test_status_at() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let status = Usage::from_raw(Some(42), 1500, 100, Duration::from_secs(60)).status_at(now);
+        assert_eq!((status.limit, status.used, status.remaining), (100, 42, 58));
+        assert_eq!(status.reset_at, Some(now + Duration::from_millis(1500)));
+        assert_eq!(status.window, Duration::from_secs(60));
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_value(&status).unwrap();
+            assert_eq!(
+                json,
+                serde_json::json!({
+                    "limit": 100,
+                    "used": 42,
+                    "remaining": 58,
+                    "reset_at_ms": 1_700_000_001_500u64,
+                    "window_ms": 60000,
+                })
+            );
+            assert_eq!(
+                serde_json::from_value::<RateLimitStatus>(json).unwrap(),
+                status
+            );
+        }
+
+        let idle = Usage::from_raw(None, -2, 100, Duration::from_secs(60)).status_at(now);
+        assert_eq!(idle.reset_at, None);
+    }
+}